@@ -0,0 +1,366 @@
+/* RISCulator - RISC-V Emulator */
+/*   Physical bus and devices   */
+
+// Libraries here
+use std::fmt;
+use colored::*;
+use crate::fdt::Fdt;
+
+// Device trait: anything that can be mapped on the physical bus
+pub trait Device {
+    // Name shown in the memory map
+    fn name(&self) -> &str;
+
+    // Read `size` bytes (1, 2 or 4) at an offset into the device, None on an access fault
+    fn read(&mut self, offset: usize, size: usize) -> Option<u64>;
+
+    // Write `size` bytes (1, 2 or 4) at an offset into the device, false on an access fault
+    fn write(&mut self, offset: usize, size: usize, value: u64) -> bool;
+
+    // Advance the device by one clock
+    fn tick(&mut self) {}
+
+    // Advance the device by a number of idle clocks (WFI fast-forward)
+    fn advance(&mut self, clocks: u64) {
+        self.tick();
+    }
+
+    // Does the device have bus-master work queued
+    fn dma_pending(&self) -> bool {
+        false
+    }
+
+    // Perform queued bus-master accesses (the device itself is unmapped meanwhile)
+    fn dma(&mut self, bus: &mut Bus) {}
+
+    // Level of the device's interrupt line
+    fn interrupt(&self) -> bool {
+        false
+    }
+
+    // Clocks until the device may raise its interrupt line, if ever
+    fn next_event(&self) -> Option<u64> {
+        None
+    }
+
+    // Return the device to its power-on state
+    fn reset(&mut self) {}
+
+    // Add the device's nodes to a device tree, given where it is mapped
+    fn describe(&self, fdt: &mut Fdt, base: usize, size: usize, irq: Option<usize>) {}
+
+    // Print the device state
+    fn print_dirty(&mut self) {}
+}
+
+// Mapped region struct
+struct Region {
+    base: usize,
+    size: usize,
+    irq: Option<usize>,
+    device: Box<dyn Device>,
+}
+
+// Bus struct
+pub struct Bus {
+    regions: Vec<Region>,
+}
+
+// Bus struct impl
+impl Bus {
+    // Initialize an empty bus
+    pub fn new() -> Self {
+        Self {
+            regions: Vec::new(),
+        }
+    }
+
+    // Map a device at [base, base + size), rejecting overlaps
+    pub fn attach(&mut self, base: usize, size: usize, device: Box<dyn Device>) -> Result<(), String> {
+        self.attach_irq(base, size, None, device)
+    }
+
+    // Map a device whose interrupt line is wired to an interrupt source number
+    pub fn attach_irq(&mut self, base: usize, size: usize, irq: Option<usize>, device: Box<dyn Device>) -> Result<(), String> {
+        if size == 0 {
+            return Err(format!("{}: region size must not be zero", device.name()));
+        }
+        for region in &self.regions {
+            if base < region.base + region.size && region.base < base + size {
+                return Err(format!("{} at {:#010x}-{:#010x} overlaps {} at {:#010x}-{:#010x}",
+                    device.name(), base, base + size - 1,
+                    region.device.name(), region.base, region.base + region.size - 1));
+            }
+        }
+        log::info!("Bus: {} mapped at {:#010x}-{:#010x}", device.name(), base, base + size - 1);
+        self.regions.push(Region { base, size, irq, device });
+        self.regions.sort_by_key(|region| region.base);
+        Ok(())
+    }
+
+    // Region holding [addr, addr + size), if any
+    fn find(&mut self, addr: usize, size: usize) -> Option<&mut Region> {
+        self.regions.iter_mut().find(|region| addr >= region.base && addr + size <= region.base + region.size)
+    }
+
+    // Read a zero-extended value, None for unmapped addresses
+    pub fn read(&mut self, addr: usize, size: usize) -> Option<isize> {
+        match self.find(addr, size) {
+            Some(region) => region.device.read(addr - region.base, size).map(|value| value as isize),
+            None => {
+                log::warn!("Bus: read of {} bytes from unmapped address {:#010x}", size, addr);
+                None
+            }
+        }
+    }
+
+    // Write a value, false for unmapped addresses
+    pub fn write(&mut self, addr: usize, size: usize, value: isize) -> bool {
+        match self.find(addr, size) {
+            Some(region) => region.device.write(addr - region.base, size, value as u64 & size_mask(size)),
+            None => {
+                log::warn!("Bus: write of {} bytes to unmapped address {:#010x}", size, addr);
+                false
+            }
+        }
+    }
+
+    // Advance every device by one clock, then let bus masters run
+    pub fn tick(&mut self) {
+        for region in self.regions.iter_mut() {
+            region.device.tick();
+        }
+        for i in 0..self.regions.len() {
+            if self.regions[i].device.dma_pending() {
+                let mut device = std::mem::replace(&mut self.regions[i].device, Box::new(Detached));
+                device.dma(self);
+                self.regions[i].device = device;
+            }
+        }
+    }
+
+    // Fast-forward every device over idle clocks
+    pub fn advance(&mut self, clocks: u64) {
+        for region in self.regions.iter_mut() {
+            region.device.advance(clocks);
+        }
+    }
+
+    // Interrupt sources whose lines are currently asserted
+    pub fn irq_lines(&self) -> Vec<usize> {
+        self.regions.iter()
+            .filter(|region| region.device.interrupt())
+            .filter_map(|region| region.irq)
+            .collect()
+    }
+
+    // Clocks until any device may raise an interrupt
+    pub fn next_event(&self) -> Option<u64> {
+        self.regions.iter().filter_map(|region| region.device.next_event()).min()
+    }
+
+    // Reset every device
+    pub fn reset(&mut self) {
+        for region in self.regions.iter_mut() {
+            region.device.reset();
+        }
+    }
+
+    // Let every device describe itself in a device tree
+    pub fn describe(&self, fdt: &mut Fdt) {
+        for region in &self.regions {
+            region.device.describe(fdt, region.base, region.size, region.irq);
+        }
+    }
+
+    // Print the memory map
+    pub fn print_map(&self) {
+        println!("{}", "--------------------------------".green());
+        println!("{}", "Memory map".green());
+        println!("{}", "--------------------------------".green());
+        for region in &self.regions {
+            match region.irq {
+                Some(irq) => println!("{:#010x}-{:#010x}: {} (irq {})", region.base, region.base + region.size - 1, region.device.name(), irq),
+                None => println!("{:#010x}-{:#010x}: {}", region.base, region.base + region.size - 1, region.device.name()),
+            }
+        }
+        println!("{}", "--------------------------------".green());
+    }
+
+    // Print the state of every device
+    pub fn print_dirty(&mut self) {
+        for region in self.regions.iter_mut() {
+            region.device.print_dirty();
+        }
+    }
+}
+
+// Bus debug output lists the memory map
+impl fmt::Debug for Bus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.regions.iter().map(|region| (region.base, region.size, region.device.name())))
+            .finish()
+    }
+}
+
+// Stand-in for a device that is busy mastering the bus
+struct Detached;
+
+impl Device for Detached {
+    fn name(&self) -> &str {
+        "detached"
+    }
+
+    fn read(&mut self, offset: usize, size: usize) -> Option<u64> {
+        None
+    }
+
+    fn write(&mut self, offset: usize, size: usize, value: u64) -> bool {
+        false
+    }
+}
+
+// ROM struct
+pub struct Rom {
+    data: Vec<u8>,
+}
+
+// ROM struct impl
+impl Rom {
+    // Initialize a ROM with its contents
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            data,
+        }
+    }
+}
+
+// ROM device: little-endian reads, writes fault
+impl Device for Rom {
+    fn name(&self) -> &str {
+        "rom"
+    }
+
+    fn read(&mut self, offset: usize, size: usize) -> Option<u64> {
+        let mut value: u64 = 0;
+        for i in 0..size {
+            value |= (*self.data.get(offset + i).unwrap_or(&0) as u64) << (8 * i);
+        }
+        Some(value)
+    }
+
+    fn write(&mut self, offset: usize, size: usize, value: u64) -> bool {
+        log::warn!("ROM: write of {:#x} to read-only offset {:#x}", value, offset);
+        false
+    }
+}
+
+// Mask for a value of `size` bytes
+pub fn size_mask(size: usize) -> u64 {
+    if size >= 8 { u64::MAX } else { (1u64 << (8 * size)) - 1 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sixteen bytes of scratch registers
+    struct Scratch {
+        name: &'static str,
+        bytes: [u8; 16],
+        irq: bool,
+    }
+
+    fn scratch(name: &'static str) -> Box<Scratch> {
+        Box::new(Scratch { name, bytes: [0; 16], irq: false })
+    }
+
+    impl Device for Scratch {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn read(&mut self, offset: usize, size: usize) -> Option<u64> {
+            Some((0..size).fold(0, |value, i| value | (self.bytes[offset + i] as u64) << (8 * i)))
+        }
+
+        fn write(&mut self, offset: usize, size: usize, value: u64) -> bool {
+            for i in 0..size {
+                self.bytes[offset + i] = (value >> (8 * i)) as u8;
+            }
+            true
+        }
+
+        fn interrupt(&self) -> bool {
+            self.irq
+        }
+    }
+
+    #[test]
+    fn overlapping_regions_are_rejected() {
+        let mut bus = Bus::new();
+        bus.attach(0x1000, 0x10, scratch("a")).unwrap();
+        assert!(bus.attach(0x100F, 0x10, scratch("b")).is_err());
+        assert!(bus.attach(0x0FF1, 0x10, scratch("b")).is_err());
+        assert!(bus.attach(0x0000, 0x2000, scratch("b")).is_err());
+        assert!(bus.attach(0x1004, 0x4, scratch("b")).is_err());
+        assert!(bus.attach(0x3000, 0, scratch("b")).is_err());
+        // Touching is fine on either side
+        bus.attach(0x1010, 0x10, scratch("b")).unwrap();
+        bus.attach(0x0FF0, 0x10, scratch("c")).unwrap();
+        assert_eq!(format!("{:?}", bus), r#"[(4080, 16, "c"), (4096, 16, "a"), (4112, 16, "b")]"#);
+    }
+
+    #[test]
+    fn accesses_reach_the_right_device() {
+        let mut bus = Bus::new();
+        bus.attach(0x1000, 0x10, scratch("a")).unwrap();
+        bus.attach(0x2000, 0x10, scratch("b")).unwrap();
+        assert!(bus.write(0x2004, 4, 0x1234_5678));
+        assert!(bus.write(0x1000, 1, 0x1FF));          // Cut to the access size
+        assert_eq!(bus.read(0x2004, 4), Some(0x1234_5678));
+        assert_eq!(bus.read(0x2006, 2), Some(0x1234));
+        assert_eq!(bus.read(0x1000, 4), Some(0xFF));
+        assert_eq!(bus.read(0x1004, 4), Some(0));
+    }
+
+    #[test]
+    fn unmapped_accesses_fault() {
+        let mut bus = Bus::new();
+        bus.attach(0x1000, 0x10, scratch("a")).unwrap();
+        assert_eq!(bus.read(0x0FFC, 4), None);
+        assert_eq!(bus.read(0x1010, 1), None);
+        assert!(!bus.write(0x5000, 4, 0));
+        // An access hanging off the end of a region is not split
+        assert_eq!(bus.read(0x100E, 4), None);
+        assert!(!bus.write(0x100D, 4, 0));
+    }
+
+    #[test]
+    fn only_asserted_lines_with_a_source_are_reported() {
+        let mut bus = Bus::new();
+        let mut raised = scratch("raised");
+        raised.irq = true;
+        bus.attach_irq(0x1000, 0x10, Some(3), raised).unwrap();
+        bus.attach_irq(0x2000, 0x10, Some(4), scratch("quiet")).unwrap();
+        let mut unwired = scratch("unwired");
+        unwired.irq = true;
+        bus.attach(0x3000, 0x10, unwired).unwrap();
+        assert_eq!(bus.irq_lines(), [3]);
+    }
+
+    #[test]
+    fn rom_reads_little_endian_and_refuses_writes() {
+        let mut bus = Bus::new();
+        bus.attach(0x1000, 0x10, Box::new(Rom::new(vec![0x11, 0x22, 0x33, 0x44, 0x55]))).unwrap();
+        assert_eq!(bus.read(0x1000, 4), Some(0x4433_2211));
+        assert_eq!(bus.read(0x1003, 2), Some(0x5544));
+        // Past the image the ROM reads as zero
+        assert_eq!(bus.read(0x1004, 4), Some(0x55));
+        assert_eq!(bus.read(0x100C, 4), Some(0));
+        assert!(!bus.write(0x1000, 4, 0));
+        assert!(!bus.write(0x100C, 1, 0));
+        assert_eq!(bus.read(0x1000, 4), Some(0x4433_2211));
+    }
+}
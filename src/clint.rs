@@ -0,0 +1,317 @@
+/* RISCulator - RISC-V Emulator */
+/*   CLINT timer and software   */
+
+// Libraries here
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+use colored::*;
+use crate::bus::{size_mask, Device};
+use crate::csr::{IRQ_MSI, IRQ_MTI};
+use crate::fdt::{Fdt, PHANDLE_CPU_INTC};
+
+// Register layout (SiFive CLINT, also the ACLINT MSWI + MTIMER layout)
+const MSIP: usize = 0x0000;         // 4 bytes per hart
+const MTIMECMP: usize = 0x4000;     // 8 bytes per hart
+const MTIME: usize = 0xBFF8;        // 8 bytes
+pub const CLINT_SIZE: usize = 0x10000;
+pub const HARTS: usize = 1;
+
+// Nominal hart clock used to convert instructions into time
+pub const CPU_FREQ: u64 = 100_000_000;
+
+// Longest single wall-clock sleep while idling (100 ms), so a far-off mtimecmp cannot hang the host
+const MAX_SLEEP: u64 = CPU_FREQ / 10;
+
+// What drives mtime
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeSource {
+    Instret,        // Derived from executed clocks (deterministic)
+    Host,           // Host wall-clock
+}
+
+// CLINT struct
+#[derive(Debug)]
+pub struct Clint {
+    mtime: u64,
+    mtimecmp: [u64; HARTS],
+    msip: [bool; HARTS],
+    timebase: u64,          // mtime frequency in Hz
+    source: TimeSource,
+    fraction: u64,          // Sub-tick remainder for Instret, in CPU_FREQ units
+    start: Instant,
+    offset: u64,            // mtime at `start` for Host (software writes move it)
+}
+
+// CLINT struct impl
+impl Clint {
+    // Initialize a CLINT with mtime running at `timebase` Hz
+    pub fn new(timebase: u64, source: TimeSource) -> Self {
+        Self {
+            mtime: 0,
+            mtimecmp: [u64::MAX; HARTS],
+            msip: [false; HARTS],
+            timebase,
+            source,
+            fraction: 0,
+            start: Instant::now(),
+            offset: 0,
+        }
+    }
+
+    // Wrap in the shared handle used by both the bus and the hart
+    pub fn shared(self) -> Rc<RefCell<Clint>> {
+        Rc::new(RefCell::new(self))
+    }
+
+    // Current mtime
+    pub fn mtime(&self) -> u64 {
+        self.mtime
+    }
+
+    pub fn timebase(&self) -> u64 {
+        self.timebase
+    }
+
+    // Machine timer interrupt pending for a hart
+    pub fn mtip(&self, hart: usize) -> bool {
+        self.mtime >= self.mtimecmp[hart]
+    }
+
+    // Program a hart's timer compare (SBI set_timer)
+    pub fn set_mtimecmp(&mut self, hart: usize, value: u64) {
+        self.mtimecmp[hart] = value;
+    }
+
+    // Machine software interrupt pending for a hart
+    pub fn msip(&self, hart: usize) -> bool {
+        self.msip[hart]
+    }
+
+    // Move mtime forward by a number of hart clocks
+    fn step(&mut self, clocks: u64) {
+        match self.source {
+            TimeSource::Instret => {
+                let total = self.fraction as u128 + clocks as u128 * self.timebase as u128;
+                self.mtime = self.mtime.wrapping_add((total / CPU_FREQ as u128) as u64);
+                self.fraction = (total % CPU_FREQ as u128) as u64;
+            }
+            TimeSource::Host => {
+                let elapsed = self.start.elapsed().as_nanos();
+                self.mtime = self.offset.wrapping_add((elapsed * self.timebase as u128 / 1_000_000_000) as u64);
+            }
+        }
+    }
+
+    // Hart clocks until mtime reaches `target`, rounded up and capped to u64
+    fn clocks_until(&self, target: u64) -> u64 {
+        let ticks = target.saturating_sub(self.mtime) as u128;
+        let clocks = (ticks * CPU_FREQ as u128 + self.timebase as u128 - 1) / self.timebase as u128;
+        clocks.min(u64::MAX as u128) as u64
+    }
+
+    // Software write to mtime
+    fn set_mtime(&mut self, value: u64) {
+        self.mtime = value;
+        self.fraction = 0;
+        self.start = Instant::now();
+        self.offset = value;
+    }
+}
+
+// Read `size` bytes at `shift` bytes into a 64-bit register
+fn extract(reg: u64, shift: usize, size: usize) -> u64 {
+    (reg >> (8 * shift)) & size_mask(size)
+}
+
+// Replace `size` bytes at `shift` bytes into a 64-bit register
+fn insert(reg: u64, shift: usize, size: usize, value: u64) -> u64 {
+    let mask = size_mask(size) << (8 * shift);
+    (reg & !mask) | ((value << (8 * shift)) & mask)
+}
+
+// CLINT device: the bus and the hart share one instance
+impl Device for Rc<RefCell<Clint>> {
+    fn name(&self) -> &str {
+        "clint"
+    }
+
+    fn read(&mut self, offset: usize, size: usize) -> Option<u64> {
+        let clint = self.borrow();
+        match offset {
+            MSIP..=0x3FFF if offset < MSIP + 4 * HARTS => {
+                Some(extract(clint.msip[(offset - MSIP) / 4] as u64, offset % 4, size))
+            }
+            MTIMECMP..=0xBFF7 if offset < MTIMECMP + 8 * HARTS => {
+                Some(extract(clint.mtimecmp[(offset - MTIMECMP) / 8], offset % 8, size))
+            }
+            MTIME..=0xBFFF => Some(extract(clint.mtime, offset - MTIME, size)),
+            default => None,
+        }
+    }
+
+    fn write(&mut self, offset: usize, size: usize, value: u64) -> bool {
+        let mut clint = self.borrow_mut();
+        match offset {
+            MSIP..=0x3FFF if offset < MSIP + 4 * HARTS => {
+                // Only bit 0 of msip is writable
+                if offset % 4 == 0 {
+                    clint.msip[(offset - MSIP) / 4] = value & 1 != 0;
+                }
+            }
+            MTIMECMP..=0xBFF7 if offset < MTIMECMP + 8 * HARTS => {
+                let hart = (offset - MTIMECMP) / 8;
+                clint.mtimecmp[hart] = insert(clint.mtimecmp[hart], offset % 8, size, value);
+            }
+            MTIME..=0xBFFF => {
+                let mtime = insert(clint.mtime, offset - MTIME, size, value);
+                clint.set_mtime(mtime);
+            }
+            default => return false,
+        }
+        true
+    }
+
+    fn tick(&mut self) {
+        self.borrow_mut().step(1);
+    }
+
+    fn advance(&mut self, clocks: u64) {
+        let mut clint = self.borrow_mut();
+        match clint.source {
+            TimeSource::Host => {
+                // Idle in real time so wall-clock timers still fire
+                let clocks = clocks.min(MAX_SLEEP);
+                thread::sleep(Duration::from_nanos(clocks * (1_000_000_000 / CPU_FREQ)));
+                clint.step(clocks);
+            }
+            TimeSource::Instret => {
+                // A fast-forward stops at the top of mtime's range instead of wrapping to 0
+                let clocks = clocks.min(clint.clocks_until(u64::MAX));
+                clint.step(clocks);
+            }
+        }
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        let clint = self.borrow();
+        let cmp = *clint.mtimecmp.iter().min().unwrap();
+        if cmp == u64::MAX {
+            return None;
+        }
+        // Hart clocks until mtime reaches the nearest mtimecmp
+        Some(clint.clocks_until(cmp).max(1))
+    }
+
+    fn reset(&mut self) {
+        let mut clint = self.borrow_mut();
+        clint.mtimecmp = [u64::MAX; HARTS];
+        clint.msip = [false; HARTS];
+        clint.set_mtime(0);
+    }
+
+    fn describe(&self, fdt: &mut Fdt, base: usize, size: usize, irq: Option<usize>) {
+        let mut wiring = vec![];
+        for hart in 0..HARTS {
+            let intc = PHANDLE_CPU_INTC + hart as u32;
+            wiring.extend_from_slice(&[intc, IRQ_MSI.trailing_zeros(), intc, IRQ_MTI.trailing_zeros()]);
+        }
+        fdt.soc_device("clint", &["sifive,clint0", "riscv,clint0"], base, size, irq)
+            .cells("interrupts-extended", &wiring);
+    }
+
+    fn print_dirty(&mut self) {
+        let clint = self.borrow();
+        println!("{}", "--------------------------------".green());
+        println!("{}", "CLINT".green());
+        println!("{}", "--------------------------------".green());
+        println!("mtime: {} ({} Hz, {:?})", clint.mtime, clint.timebase, clint.source);
+        for hart in 0..HARTS {
+            println!("hart {}: mtimecmp {:#x} msip {}", hart, clint.mtimecmp[hart], clint.msip[hart] as u8);
+        }
+        println!("{}", "--------------------------------".green());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MTIMECMP0: usize = MTIMECMP;
+
+    // A CLINT with mtime at 10 MHz, a tenth of the hart clock
+    fn clint() -> Rc<RefCell<Clint>> {
+        Clint::new(CPU_FREQ / 10, TimeSource::Instret).shared()
+    }
+
+    #[test]
+    fn registers_read_back_in_pieces() {
+        let mut clint = clint();
+        assert!(clint.write(MTIME, 8, 0x1122_3344_5566_7788));
+        assert_eq!(clint.read(MTIME, 4), Some(0x5566_7788));
+        assert_eq!(clint.read(MTIME + 4, 4), Some(0x1122_3344));
+        assert!(clint.write(MTIMECMP0 + 4, 4, 0xAABB_CCDD));
+        assert_eq!(clint.read(MTIMECMP0, 8), Some(0xAABB_CCDD_FFFF_FFFF));
+        // Only bit 0 of msip holds a value
+        assert!(clint.write(MSIP, 4, 0xFFFF_FFFF));
+        assert_eq!(clint.read(MSIP, 4), Some(1));
+        assert!(clint.borrow().msip(0));
+        assert!(clint.write(MSIP, 4, 0));
+        assert!(!clint.borrow().msip(0));
+        // Nothing past the last hart
+        assert_eq!(clint.read(MSIP + 4 * HARTS, 4), None);
+        assert!(!clint.write(MTIMECMP0 + 8 * HARTS, 8, 0));
+    }
+
+    #[test]
+    fn mtime_counts_hart_clocks_at_the_timebase() {
+        let mut clint = clint();
+        for _ in 0..25 {
+            clint.tick();
+        }
+        assert_eq!(clint.borrow().mtime(), 2);
+        clint.advance(5);
+        assert_eq!(clint.borrow().mtime(), 3);
+    }
+
+    #[test]
+    fn wfi_fast_forward_lands_on_mtimecmp() {
+        let mut clint = clint();
+        assert_eq!(clint.next_event(), None);
+        assert!(clint.write(MTIMECMP0, 8, 1000));
+        let clocks = clint.next_event().unwrap();
+        assert_eq!(clocks, 10_000);
+        clint.advance(clocks - 10);
+        assert!(!clint.borrow().mtip(0));
+        clint.advance(clint.next_event().unwrap());
+        assert!(clint.borrow().mtip(0));
+        assert_eq!(clint.borrow().mtime(), 1000);
+        // Already due: wake on the next clock
+        assert_eq!(clint.next_event(), Some(1));
+    }
+
+    #[test]
+    fn far_off_mtimecmp_does_not_overflow() {
+        let mut clint = clint();
+        assert!(clint.write(MTIMECMP0, 8, 0xFFFF_FFFF_FFFF_FFFF));
+        assert_eq!(clint.next_event(), None);
+        clint.advance(u64::MAX);
+        assert!(clint.write(MTIMECMP0, 8, 0xFFFF_FFFF_FFFF_FFFE));
+        assert!(!clint.borrow().mtip(0));
+        assert!(clint.write(MTIME, 8, 0));
+        // The wait is longer than a u64 of clocks, so WFI gets there in a few capped steps
+        assert_eq!(clint.next_event(), Some(u64::MAX));
+        let mut steps = 0;
+        while !clint.borrow().mtip(0) {
+            let clocks = clint.next_event().unwrap();
+            clint.advance(clocks);
+            steps += 1;
+            assert!(steps < 20);
+        }
+        assert_eq!(clint.borrow().mtime(), 0xFFFF_FFFF_FFFF_FFFE);
+        // Never past the top of the range
+        clint.advance(u64::MAX);
+        assert_eq!(clint.borrow().mtime(), u64::MAX);
+    }
+}
@@ -0,0 +1,191 @@
+/* RISCulator - RISC-V Emulator */
+/*   Host console input (stdin)  */
+
+// Libraries here
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::Read;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+
+/*
+ * Every guest-visible console reads host stdin through here: the UART, the HTIF
+ * console and syscall proxy, and read(0) / ":tt" in the newlib, Linux and
+ * semihosting layers. A single thread reads stdin, started by the first consumer
+ * that asks for input, so no consumer loses bytes to a reader the guest never uses
+ * and the hart itself never blocks on the host.
+ */
+
+// Input state, owned by the emulator thread
+struct Input {
+    rx: Option<Receiver<u8>>,       // None before the reader starts and after end of input
+    started: bool,
+    ahead: VecDeque<u8>,            // Bytes taken off the channel but not consumed yet
+}
+
+thread_local! {
+    static INPUT: RefCell<Input> = RefCell::new(Input {
+        rx: None,
+        started: false,
+        ahead: VecDeque::new(),
+    });
+}
+
+// Read from `source` instead of host stdin
+pub fn set_source(source: Box<dyn Read + Send>) {
+    INPUT.with(|input| {
+        let mut input = input.borrow_mut();
+        input.rx = Some(spawn(source));
+        input.started = true;
+        input.ahead.clear();
+    });
+}
+
+// Forward `source` byte by byte until it ends
+fn spawn(mut source: Box<dyn Read + Send>) -> Receiver<u8> {
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        let mut byte = [0u8; 1];
+        while let Ok(1) = source.read(&mut byte) {
+            if tx.send(byte[0]).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+// Input impl
+impl Input {
+    // Start the stdin reader on first use
+    fn start(&mut self) {
+        if !self.started {
+            self.started = true;
+            self.rx = Some(spawn(Box::new(std::io::stdin())));
+        }
+    }
+
+    // Move one byte from the channel into the lookahead, waiting for it when `block` is set
+    fn fill(&mut self, block: bool) -> bool {
+        self.start();
+        let rx = match &self.rx {
+            Some(rx) => rx,
+            None => return false,
+        };
+        let byte = if block {
+            rx.recv().ok()
+        }
+        else {
+            match rx.try_recv() {
+                Ok(byte) => Some(byte),
+                Err(TryRecvError::Empty) => return false,
+                Err(TryRecvError::Disconnected) => None,
+            }
+        };
+        match byte {
+            Some(byte) => {
+                self.ahead.push_back(byte);
+                true
+            }
+            None => {
+                self.rx = None;         // End of input
+                false
+            }
+        }
+    }
+
+    // Copy out what is ready, up to `buf.len()` bytes
+    fn drain(&mut self, buf: &mut [u8]) -> usize {
+        let mut n = 0;
+        while n < buf.len() && (!self.ahead.is_empty() || self.fill(false)) {
+            buf[n] = self.ahead.pop_front().unwrap();
+            n += 1;
+        }
+        n
+    }
+}
+
+// Next byte if one is ready, without blocking
+pub fn try_read() -> Option<u8> {
+    INPUT.with(|input| {
+        let mut input = input.borrow_mut();
+        if input.ahead.is_empty() {
+            input.fill(false);
+        }
+        input.ahead.pop_front()
+    })
+}
+
+// Is a byte ready to be read without blocking
+pub fn ready() -> bool {
+    INPUT.with(|input| {
+        let mut input = input.borrow_mut();
+        !input.ahead.is_empty() || input.fill(false)
+    })
+}
+
+// Can more input still arrive (false once the source has ended)
+pub fn is_open() -> bool {
+    INPUT.with(|input| {
+        let input = input.borrow();
+        !input.started || input.rx.is_some() || !input.ahead.is_empty()
+    })
+}
+
+// Whatever is ready now, possibly nothing
+pub fn read_ready(buf: &mut [u8]) -> usize {
+    INPUT.with(|input| input.borrow_mut().drain(buf))
+}
+
+// read(2) on the console: wait for the first byte, then take what else is ready; 0 at end of input
+pub fn read(buf: &mut [u8]) -> usize {
+    INPUT.with(|input| {
+        let mut input = input.borrow_mut();
+        if buf.is_empty() || (input.ahead.is_empty() && !input.fill(true)) {
+            return 0;
+        }
+        input.drain(buf)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // Everything up to end of input, one read at a time
+    fn read_all() -> Vec<u8> {
+        let mut out = vec![];
+        let mut buf = [0u8; 4];
+        loop {
+            let n = read(&mut buf);
+            if n == 0 {
+                return out;
+            }
+            out.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    #[test]
+    fn reads_a_fixed_source_to_the_end() {
+        set_source(Box::new(Cursor::new(b"hello\nworld\n".to_vec())));
+        assert!(is_open());
+        assert_eq!(read_all(), b"hello\nworld\n");
+        assert!(!is_open());
+        assert_eq!(read(&mut [0u8; 8]), 0);
+        assert_eq!(try_read(), None);
+    }
+
+    #[test]
+    fn polling_does_not_lose_bytes() {
+        set_source(Box::new(Cursor::new(b"abc".to_vec())));
+        // Wait for the reader thread to hand the first byte over
+        while !ready() {
+            thread::yield_now();
+        }
+        assert_eq!(try_read(), Some(b'a'));
+        let rest = read_all();
+        assert_eq!(rest, b"bc");
+        assert_eq!(read_ready(&mut [0u8; 4]), 0);
+    }
+}
@@ -0,0 +1,685 @@
+/* RISCulator - RISC-V Emulator */
+/*  Control and Status Registers  */
+
+// Libraries here
+use colored::*;
+use crate::Mode;
+use crate::entropy::{EntropySource, DEFAULT_SEED};
+
+// Constants
+pub const CSR_SIZE: usize = 4096;       // 12-bit CSR address space
+pub const HPM_COUNTERS: usize = 4;      // Default mhpmcounters, starting at mhpmcounter3 (--hpm-counters)
+pub const HPM_MAX: usize = 29;          // mhpmcounter3..mhpmcounter31
+pub const PMP_ENTRIES: usize = 16;      // Default PMP entries (--pmp-entries)
+pub const PMP_SIZES: [usize; 3] = [0, 16, 64];     // PMP entry counts the spec allows
+
+// Machine information registers
+pub const MVENDORID: usize = 0xF11;
+pub const MARCHID: usize = 0xF12;
+pub const MIMPID: usize = 0xF13;
+pub const MHARTID: usize = 0xF14;
+
+// Machine trap setup and handling
+pub const MSTATUS: usize = 0x300;
+pub const MISA: usize = 0x301;
+pub const MEDELEG: usize = 0x302;
+pub const MIDELEG: usize = 0x303;
+pub const MIE: usize = 0x304;
+pub const MTVEC: usize = 0x305;
+pub const MCOUNTEREN: usize = 0x306;
+pub const MCOUNTINHIBIT: usize = 0x320;
+pub const MHPMEVENT3: usize = 0x323;
+pub const MHPMEVENT31: usize = 0x33F;
+pub const MSCRATCH: usize = 0x340;
+pub const MEPC: usize = 0x341;
+pub const MCAUSE: usize = 0x342;
+pub const MTVAL: usize = 0x343;
+pub const MIP: usize = 0x344;
+
+// Entropy source (Zkr)
+pub const SEED: usize = 0x015;
+
+// Machine security configuration
+pub const MSECCFG: usize = 0x747;
+pub const MSECCFGH: usize = 0x757;
+pub const MSECCFG_USEED: isize = 1 << 8;
+pub const MSECCFG_SSEED: isize = 1 << 9;
+const SEED_ES16: isize = 0b10 << 30;        // OPST: 16 bits of entropy in [15:0]
+
+// Machine memory protection
+pub const PMPCFG0: usize = 0x3A0;
+pub const PMPCFG15: usize = 0x3AF;
+pub const PMPADDR0: usize = 0x3B0;
+pub const PMPADDR63: usize = 0x3EF;
+
+// Supervisor trap setup, handling and protection
+pub const SSTATUS: usize = 0x100;
+pub const SIE: usize = 0x104;
+pub const STVEC: usize = 0x105;
+pub const SCOUNTEREN: usize = 0x106;
+pub const SSCRATCH: usize = 0x140;
+pub const SEPC: usize = 0x141;
+pub const SCAUSE: usize = 0x142;
+pub const STVAL: usize = 0x143;
+pub const SIP: usize = 0x144;
+pub const SATP: usize = 0x180;
+
+// Machine counters/timers
+pub const MCYCLE: usize = 0xB00;
+pub const MINSTRET: usize = 0xB02;
+pub const MHPMCOUNTER3: usize = 0xB03;
+pub const MHPMCOUNTER31: usize = 0xB1F;
+pub const MCYCLEH: usize = 0xB80;
+pub const MINSTRETH: usize = 0xB82;
+pub const MHPMCOUNTER3H: usize = 0xB83;
+pub const MHPMCOUNTER31H: usize = 0xB9F;
+
+// Unprivileged counters/timers (Zicntr and Zihpm)
+pub const CYCLE: usize = 0xC00;
+pub const TIME: usize = 0xC01;
+pub const INSTRET: usize = 0xC02;
+pub const HPMCOUNTER31: usize = 0xC1F;
+pub const CYCLEH: usize = 0xC80;
+pub const TIMEH: usize = 0xC81;
+pub const INSTRETH: usize = 0xC82;
+pub const HPMCOUNTER31H: usize = 0xC9F;
+
+// mstatus fields
+pub const MSTATUS_SIE: isize = 1 << 1;
+pub const MSTATUS_MIE: isize = 1 << 3;
+pub const MSTATUS_SPIE: isize = 1 << 5;
+pub const MSTATUS_MPIE: isize = 1 << 7;
+pub const MSTATUS_SPP: isize = 1 << 8;
+pub const MSTATUS_MPP: isize = 3 << 11;
+pub const MSTATUS_MPRV: isize = 1 << 17;
+pub const MSTATUS_SUM: isize = 1 << 18;
+pub const MSTATUS_MXR: isize = 1 << 19;
+pub const MSTATUS_TVM: isize = 1 << 20;
+pub const MSTATUS_TW: isize = 1 << 21;
+const MSTATUS_MASK: isize = MSTATUS_SIE | MSTATUS_MIE | MSTATUS_SPIE | MSTATUS_MPIE | MSTATUS_SPP | MSTATUS_MPP
+    | MSTATUS_MPRV | MSTATUS_SUM | MSTATUS_MXR | MSTATUS_TVM | MSTATUS_TW;
+
+// Interrupt-pending/enable bits (mip/mie)
+pub const IRQ_SSI: isize = 1 << 1;
+pub const IRQ_MSI: isize = 1 << 3;
+pub const IRQ_STI: isize = 1 << 5;
+pub const IRQ_MTI: isize = 1 << 7;
+pub const IRQ_SEI: isize = 1 << 9;
+pub const IRQ_MEI: isize = 1 << 11;
+const IRQ_S_MASK: isize = IRQ_SSI | IRQ_STI | IRQ_SEI;
+const IRQ_MASK: isize = IRQ_S_MASK | IRQ_MSI | IRQ_MTI | IRQ_MEI;
+const IRQ_PRIORITY: [isize; 6] = [11, 3, 7, 9, 1, 5];       // MEI, MSI, MTI, SEI, SSI, STI
+
+// mcause/scause interrupt flag (XLEN 32)
+pub const INTERRUPT_BIT: isize = 1 << 31;
+const SSTATUS_MASK: isize = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_SUM | MSTATUS_MXR;
+
+// misa bits for the privilege modes the hart implements
+pub const MISA_S: isize = 1 << 18;
+pub const MISA_U: isize = 1 << 20;
+
+// Exception causes
+pub const CAUSE_INSTRUCTION_ADDRESS_MISALIGNED: isize = 0;
+pub const CAUSE_INSTRUCTION_ACCESS_FAULT: isize = 1;
+pub const CAUSE_ILLEGAL_INSTRUCTION: isize = 2;
+pub const CAUSE_BREAKPOINT: isize = 3;
+pub const CAUSE_LOAD_ADDRESS_MISALIGNED: isize = 4;
+pub const CAUSE_LOAD_ACCESS_FAULT: isize = 5;
+pub const CAUSE_STORE_ADDRESS_MISALIGNED: isize = 6;
+pub const CAUSE_STORE_ACCESS_FAULT: isize = 7;
+pub const CAUSE_USER_ECALL: isize = 8;          // + privilege mode of the caller
+pub const CAUSE_SUPERVISOR_ECALL: isize = 9;
+pub const CAUSE_INSTRUCTION_PAGE_FAULT: isize = 12;
+pub const CAUSE_LOAD_PAGE_FAULT: isize = 13;
+pub const CAUSE_STORE_PAGE_FAULT: isize = 15;
+const MEDELEG_MASK: isize = 0xB3FF;     // Causes 0-9, 12, 13 and 15 can be delegated
+
+// Events that can be selected by writing their number to mhpmevent3..31
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    Load = 1,
+    Store = 2,
+    Branch = 3,     // Taken control transfers
+    Trap = 4,
+}
+
+// CSR Struct
+#[derive(Debug, Clone)]
+pub struct Csr {
+    csrs: Vec<isize>,
+    dirty_bit: Vec<u32>,
+    mcycle: u64,
+    minstret: u64,
+    mtime: u64,
+    mhpmcounter: [u64; 32],
+    hw_ip: isize,
+    trapped: bool,
+    entropy: EntropySource,
+    pmp_entries: usize,
+    hpm_counters: usize,
+}
+
+// CSR Struct traits
+impl Csr {
+    // Initialize the CSRs to their reset values, with `pmp_entries` PMP entries (one of PMP_SIZES)
+    // and `hpm_counters` mhpmcounters (at most HPM_MAX)
+    pub fn new(pmp_entries: usize, hpm_counters: usize) -> Self {
+        assert!(PMP_SIZES.contains(&pmp_entries), "PMP entry count must be 0, 16 or 64");
+        assert!(hpm_counters <= HPM_MAX, "at most 29 mhpmcounters");
+        let csrs = vec![0; CSR_SIZE];
+        let dirty_bit = vec![0; CSR_SIZE];
+        Self {
+            csrs,
+            dirty_bit,
+            mcycle: 0,
+            minstret: 0,
+            mtime: 0,
+            mhpmcounter: [0; 32],
+            hw_ip: 0,
+            trapped: false,
+            entropy: EntropySource::Seeded(DEFAULT_SEED),
+            pmp_entries,
+            hpm_counters,
+        }
+    }
+
+    // Is this mhpmcounter/mhpmevent one of the implemented ones
+    fn hpm_implemented(&self, addr: usize) -> bool {
+        let n = addr & 0x1F;
+        n >= 3 && n < 3 + self.hpm_counters
+    }
+
+    // Writable bits of mcountinhibit/mcounteren/scounteren
+    fn counter_mask(&self) -> isize {
+        let hpm = ((1 << self.hpm_counters) - 1) << 3;
+        0x7 | hpm
+    }
+
+    // Number of implemented PMP entries
+    pub fn pmp_entries(&self) -> usize {
+        self.pmp_entries
+    }
+
+    // Source behind the seed CSR
+    pub fn set_entropy(&mut self, entropy: EntropySource) {
+        self.entropy = entropy;
+    }
+
+    // Read the seed CSR: every access returns fresh entropy
+    pub fn read_seed(&mut self) -> isize {
+        SEED_ES16 | (self.entropy.next() & 0xFFFF) as isize
+    }
+
+    // Is the CSR implemented at all
+    fn exists(&self, addr: usize) -> bool {
+        match addr {
+            MVENDORID | MARCHID | MIMPID | MHARTID => true,
+            MSTATUS | MISA | MTVEC | MCOUNTEREN | MCOUNTINHIBIT => true,
+            MSCRATCH | MEPC | MCAUSE | MTVAL | MEDELEG | MIDELEG | MIE | MIP => true,
+            SSTATUS | SIE | SIP | STVEC | SCOUNTEREN | SSCRATCH | SEPC | SCAUSE | STVAL | SATP => true,
+            MHPMEVENT3..=MHPMEVENT31 => true,
+            PMPCFG0..=PMPCFG15 | PMPADDR0..=PMPADDR63 => true,
+            SEED | MSECCFG | MSECCFGH => true,
+            MCYCLE | MINSTRET | MCYCLEH | MINSTRETH => true,
+            MHPMCOUNTER3..=MHPMCOUNTER31 | MHPMCOUNTER3H..=MHPMCOUNTER31H => true,
+            CYCLE..=HPMCOUNTER31 | CYCLEH..=HPMCOUNTER31H => true,
+            _ => false,
+        }
+    }
+
+    // Checks whether an access from the given privilege mode is legal
+    pub fn accessible(&self, addr: usize, mode: Mode, write: bool) -> bool {
+        if !self.exists(addr) {
+            return false;
+        }
+        if (mode as usize) < ((addr >> 8) & 0x3) {
+            return false;
+        }
+        if write && (addr >> 10) & 0x3 == 0x3 {
+            return false;
+        }
+        if addr == SATP && mode == Mode::Supervisor && self.csrs[MSTATUS] & MSTATUS_TVM != 0 {
+            return false;
+        }
+        // seed needs a read-write access; S and U need mseccfg.SSEED/USEED
+        if addr == SEED {
+            return write && match mode {
+                Mode::Machine => true,
+                Mode::Supervisor => self.csrs[MSECCFG] & MSECCFG_SSEED != 0,
+                Mode::User => self.csrs[MSECCFG] & MSECCFG_USEED != 0,
+            };
+        }
+        // User counters are gated by mcounteren (S/U) and scounteren (U)
+        if (CYCLE..=HPMCOUNTER31).contains(&addr) || (CYCLEH..=HPMCOUNTER31H).contains(&addr) {
+            let bit = 1 << (addr & 0x1F);
+            if mode != Mode::Machine && self.csrs[MCOUNTEREN] & bit == 0 {
+                return false;
+            }
+            if mode == Mode::User && self.csrs[SCOUNTEREN] & bit == 0 {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Read data from a CSR
+    pub fn read(&self, addr: usize) -> isize {
+        match addr {
+            MCYCLE | CYCLE => xlen_low(self.mcycle),
+            MCYCLEH | CYCLEH => xlen_high(self.mcycle),
+            MINSTRET | INSTRET => xlen_low(self.minstret),
+            MINSTRETH | INSTRETH => xlen_high(self.minstret),
+            TIME => xlen_low(self.mtime),
+            TIMEH => xlen_high(self.mtime),
+            SSTATUS => self.csrs[MSTATUS] & SSTATUS_MASK,
+            MIP => self.csrs[MIP] | self.hw_ip,
+            SIP => (self.csrs[MIP] | self.hw_ip) & self.csrs[MIDELEG],
+            SIE => self.csrs[MIE] & self.csrs[MIDELEG],
+            MHPMCOUNTER3..=MHPMCOUNTER31 => xlen_low(self.mhpmcounter[addr & 0x1F]),
+            MHPMCOUNTER3H..=MHPMCOUNTER31H => xlen_high(self.mhpmcounter[addr & 0x1F]),
+            0xC03..=HPMCOUNTER31 => xlen_low(self.mhpmcounter[addr & 0x1F]),
+            0xC83..=HPMCOUNTER31H => xlen_high(self.mhpmcounter[addr & 0x1F]),
+            _ => self.csrs[addr],
+        }
+    }
+
+    // Write data to a CSR, applying the WARL masks
+    pub fn write(&mut self, addr: usize, data: isize) {
+        let data = data & 0xFFFF_FFFF;
+        match addr {
+            MCYCLE => self.mcycle = set_low(self.mcycle, data),
+            MCYCLEH => self.mcycle = set_high(self.mcycle, data),
+            MINSTRET => self.minstret = set_low(self.minstret, data),
+            MINSTRETH => self.minstret = set_high(self.minstret, data),
+            MHPMCOUNTER3..=MHPMCOUNTER31 => {
+                if self.hpm_implemented(addr) {
+                    let n = addr & 0x1F;
+                    self.mhpmcounter[n] = set_low(self.mhpmcounter[n], data);
+                }
+            }
+            MHPMCOUNTER3H..=MHPMCOUNTER31H => {
+                if self.hpm_implemented(addr) {
+                    let n = addr & 0x1F;
+                    self.mhpmcounter[n] = set_high(self.mhpmcounter[n], data);
+                }
+            }
+            MHPMEVENT3..=MHPMEVENT31 => {
+                if self.hpm_implemented(addr) {
+                    self.csrs[addr] = data;
+                }
+            }
+            PMPCFG0..=PMPCFG15 => {
+                let mut cfg = self.csrs[addr];
+                for byte in 0..4 {
+                    let entry = (addr - PMPCFG0) * 4 + byte;
+                    let old = (cfg >> (8 * byte)) & 0xFF;
+                    let mut new = (data >> (8 * byte)) & 0xFF;
+                    if entry >= self.pmp_entries || old & 0x80 != 0 {
+                        continue;       // Unimplemented entries stay zero, locked entries ignore writes
+                    }
+                    new &= 0x9F;
+                    if new & 0x3 == 0x2 {
+                        new &= !0x2;    // R=0, W=1 is reserved
+                    }
+                    cfg = (cfg & !(0xFF << (8 * byte))) | (new << (8 * byte));
+                }
+                self.csrs[addr] = cfg;
+            }
+            PMPADDR0..=PMPADDR63 => {
+                let entry = addr - PMPADDR0;
+                let next = self.pmpcfg(entry + 1);
+                let next_locks_tor = entry + 1 < self.pmp_entries && next & 0x80 != 0 && (next >> 3) & 0x3 == 1;
+                if entry < self.pmp_entries && self.pmpcfg(entry) & 0x80 == 0 && !next_locks_tor {
+                    self.csrs[addr] = data;
+                }
+            }
+            MCOUNTINHIBIT => self.csrs[addr] = data & self.counter_mask() & !0x2,
+            MSECCFG => self.csrs[addr] = data & (MSECCFG_USEED | MSECCFG_SSEED),
+            SEED | MSECCFGH => {}       // Writes to seed are ignored
+            MCOUNTEREN | SCOUNTEREN => self.csrs[addr] = data & self.counter_mask(),
+            MTVEC | STVEC => self.csrs[addr] = data & !0x2,     // Direct (0) or vectored (1) only
+            MEPC | SEPC => self.csrs[addr] = data & !0x3,
+            MEDELEG => self.csrs[addr] = data & MEDELEG_MASK,
+            MIDELEG => self.csrs[addr] = data & IRQ_S_MASK,
+            MIE => self.csrs[addr] = data & IRQ_MASK,
+            MIP => self.csrs[addr] = data & IRQ_S_MASK,       // MSIP/MTIP/MEIP are driven by devices
+            SIE => {
+                let mask = self.csrs[MIDELEG];
+                self.csrs[MIE] = (self.csrs[MIE] & !mask) | (data & mask);
+                self.dirty_bit[MIE] = 1;
+            }
+            SIP => {
+                let mask = self.csrs[MIDELEG] & IRQ_SSI;
+                self.csrs[MIP] = (self.csrs[MIP] & !mask) | (data & mask);
+                self.dirty_bit[MIP] = 1;
+            }
+            MSTATUS => {
+                let mut mstatus = data & MSTATUS_MASK;
+                if mstatus & MSTATUS_MPP == 2 << 11 {       // Reserved encoding reads back as U
+                    mstatus &= !MSTATUS_MPP;
+                }
+                self.csrs[addr] = mstatus;
+            }
+            SSTATUS => {
+                self.csrs[MSTATUS] = (self.csrs[MSTATUS] & !SSTATUS_MASK) | (data & SSTATUS_MASK);
+                self.dirty_bit[MSTATUS] = 1;
+            }
+            _ => self.csrs[addr] = data,
+        }
+        self.dirty_bit[addr] = 1;
+    }
+
+    // Configuration byte of a PMP entry
+    pub fn pmpcfg(&self, entry: usize) -> isize {
+        if entry >= self.pmp_entries {
+            return 0;
+        }
+        (self.csrs[PMPCFG0 + entry / 4] >> (8 * (entry % 4))) & 0xFF
+    }
+
+    // Advance mcycle by one clock
+    pub fn tick(&mut self) {
+        if self.csrs[MCOUNTINHIBIT] & 0x1 == 0 {
+            self.mcycle = self.mcycle.wrapping_add(1);
+        }
+    }
+
+    // Advance mcycle by a number of idle clocks (WFI fast-forward)
+    pub fn advance(&mut self, clocks: u64) {
+        if self.csrs[MCOUNTINHIBIT] & 0x1 == 0 {
+            self.mcycle = self.mcycle.wrapping_add(clocks);
+        }
+    }
+
+    // The time CSR shadows the CLINT's mtime
+    pub fn set_time(&mut self, mtime: u64) {
+        self.mtime = mtime;
+    }
+
+    // Drive an interrupt-pending line from a device
+    pub fn set_irq(&mut self, bit: isize, level: bool) {
+        if level {
+            self.hw_ip |= bit;
+        }
+        else {
+            self.hw_ip &= !bit;
+        }
+    }
+
+    // Is any enabled interrupt pending, ignoring the global enables (WFI wake-up)
+    pub fn wfi_wakeup(&self) -> bool {
+        self.read(MIP) & self.csrs[MIE] != 0
+    }
+
+    // Highest-priority interrupt that can be taken in the given mode
+    pub fn pending_interrupt(&self, mode: Mode) -> Option<isize> {
+        let mstatus = self.csrs[MSTATUS];
+        let pending = self.read(MIP) & self.csrs[MIE];
+        let mideleg = self.csrs[MIDELEG];
+        let m_enabled = mode < Mode::Machine || mstatus & MSTATUS_MIE != 0;
+        let s_enabled = mode < Mode::Supervisor || (mode == Mode::Supervisor && mstatus & MSTATUS_SIE != 0);
+
+        // Interrupts for M-mode win over those delegated to S-mode
+        for code in IRQ_PRIORITY {
+            if pending & !mideleg & (1 << code) != 0 && m_enabled {
+                return Some(code);
+            }
+        }
+        for code in IRQ_PRIORITY {
+            if pending & mideleg & (1 << code) != 0 && s_enabled {
+                return Some(code);
+            }
+        }
+        None
+    }
+
+    // Advance minstret for a retired instruction; trapping instructions do not retire
+    pub fn retire(&mut self) {
+        if self.trapped {
+            self.trapped = false;
+            return;
+        }
+        if self.csrs[MCOUNTINHIBIT] & 0x4 == 0 {
+            self.minstret = self.minstret.wrapping_add(1);
+        }
+    }
+
+    // Count an event in every mhpmcounter whose mhpmevent selects it
+    pub fn event(&mut self, event: Event) {
+        for n in 3..3 + self.hpm_counters {
+            if self.csrs[MHPMEVENT3 + n - 3] == event as isize && self.csrs[MCOUNTINHIBIT] & (1 << n) == 0 {
+                self.mhpmcounter[n] = self.mhpmcounter[n].wrapping_add(1);
+            }
+        }
+    }
+
+    // Take a trap, delegated to S-mode when medeleg/mideleg ask for it; returns the new mode and PC
+    pub fn trap(&mut self, mode: Mode, pc: isize, cause: isize, tval: isize) -> (Mode, isize) {
+        self.event(Event::Trap);
+        self.trapped = true;
+        let interrupt = cause & INTERRUPT_BIT != 0;
+        let code = cause & 0x3F;
+        let deleg = if interrupt { self.csrs[MIDELEG] } else { self.csrs[MEDELEG] };
+        let mut mstatus = self.csrs[MSTATUS];
+        if mode != Mode::Machine && (deleg >> code) & 1 == 1 {
+            mstatus = (mstatus & !MSTATUS_SPP) | if mode == Mode::Supervisor { MSTATUS_SPP } else { 0 };
+            mstatus = (mstatus & !MSTATUS_SPIE) | if mstatus & MSTATUS_SIE != 0 { MSTATUS_SPIE } else { 0 };
+            mstatus &= !MSTATUS_SIE;
+            self.write(MSTATUS, mstatus);
+            self.write(SEPC, pc);
+            self.write(SCAUSE, cause);
+            self.write(STVAL, tval);
+            return (Mode::Supervisor, vector(self.csrs[STVEC], interrupt, code));
+        }
+        mstatus = (mstatus & !MSTATUS_MPP) | ((mode as isize) << 11);
+        mstatus = (mstatus & !MSTATUS_MPIE) | if mstatus & MSTATUS_MIE != 0 { MSTATUS_MPIE } else { 0 };
+        mstatus &= !MSTATUS_MIE;
+        self.write(MSTATUS, mstatus);
+        self.write(MEPC, pc);
+        self.write(MCAUSE, cause);
+        self.write(MTVAL, tval);
+        (Mode::Machine, vector(self.csrs[MTVEC], interrupt, code))
+    }
+
+    // Return from an M-mode trap, returns the previous mode and PC
+    pub fn mret(&mut self) -> (Mode, isize) {
+        let mut mstatus = self.csrs[MSTATUS];
+        let mode = match (mstatus & MSTATUS_MPP) >> 11 {
+            3 => Mode::Machine,
+            1 => Mode::Supervisor,
+            _ => Mode::User,
+        };
+        mstatus = (mstatus & !MSTATUS_MIE) | if mstatus & MSTATUS_MPIE != 0 { MSTATUS_MIE } else { 0 };
+        mstatus |= MSTATUS_MPIE;
+        mstatus &= !MSTATUS_MPP;
+        if mode != Mode::Machine {
+            mstatus &= !MSTATUS_MPRV;
+        }
+        self.write(MSTATUS, mstatus);
+        (mode, self.csrs[MEPC])
+    }
+
+    // Return from an S-mode trap, returns the previous mode and PC
+    pub fn sret(&mut self) -> (Mode, isize) {
+        let mut mstatus = self.csrs[MSTATUS];
+        let mode = if mstatus & MSTATUS_SPP != 0 { Mode::Supervisor } else { Mode::User };
+        mstatus = (mstatus & !MSTATUS_SIE) | if mstatus & MSTATUS_SPIE != 0 { MSTATUS_SIE } else { 0 };
+        mstatus |= MSTATUS_SPIE;
+        mstatus &= !(MSTATUS_SPP | MSTATUS_MPRV);
+        self.write(MSTATUS, mstatus);
+        (mode, self.csrs[SEPC])
+    }
+
+    // Print only dirty CSRs
+    pub fn print_dirty(&mut self) {
+        println!("{}", "--------------------------------".green());
+        println!("{}", "CSRs (dirty lines only)".green());
+        println!("{}", "--------------------------------".green());
+        for i in 0..CSR_SIZE {
+            if self.dirty_bit[i] == 1 {
+                println!("{:#05x}: {:032b}: {} : {:08x}", i, self.read(i), self.dirty_bit[i], self.read(i));
+            }
+        }
+        println!("{}", "--------------------------------".green());
+    }
+
+    // Resets CSR state and counters to zero
+    pub fn reset(&mut self) {
+        for i in 0..CSR_SIZE {
+            if i != MISA {
+                self.csrs[i] = 0;
+            }
+            self.dirty_bit[i] = 0;
+        }
+        self.mcycle = 0;
+        self.minstret = 0;
+        self.mtime = 0;
+        self.mhpmcounter = [0; 32];
+        self.hw_ip = 0;
+        self.trapped = false;
+    }
+}
+
+// Trap target: vectored mode sends interrupts to BASE + 4 * cause
+fn vector(tvec: isize, interrupt: bool, code: isize) -> isize {
+    let base = tvec & !0x3;
+    if interrupt && tvec & 0x1 == 1 {
+        base + 4 * code
+    }
+    else {
+        base
+    }
+}
+
+// Low and high XLEN halves of a 64-bit counter
+fn xlen_low(value: u64) -> isize {
+    value as u32 as i32 as isize
+}
+
+fn xlen_high(value: u64) -> isize {
+    (value >> 32) as u32 as i32 as isize
+}
+
+fn set_low(value: u64, data: isize) -> u64 {
+    (value & 0xFFFF_FFFF_0000_0000) | (data as u64 & 0xFFFF_FFFF)
+}
+
+fn set_high(value: u64, data: isize) -> u64 {
+    (value & 0xFFFF_FFFF) | ((data as u64 & 0xFFFF_FFFF) << 32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_configured_hpm_counters_count() {
+        let mut csrs = Csr::new(PMP_ENTRIES, 2);
+        for n in 0..4 {
+            csrs.write(MHPMEVENT3 + n, Event::Branch as isize);
+        }
+        csrs.event(Event::Branch);
+        csrs.event(Event::Load);
+        assert_eq!(csrs.read(MHPMCOUNTER3), 1);
+        assert_eq!(csrs.read(MHPMCOUNTER3 + 1), 1);
+        assert_eq!(csrs.read(MHPMCOUNTER3 + 2), 0);
+        assert_eq!(csrs.read(MHPMEVENT3 + 2), 0);
+
+        csrs.write(MCOUNTEREN, -1);
+        assert_eq!(csrs.read(MCOUNTEREN), 0x1F);
+        let csrs = Csr::new(PMP_ENTRIES, 0);
+        assert_eq!(csrs.counter_mask(), 0x7);
+        let csrs = Csr::new(PMP_ENTRIES, HPM_MAX);
+        assert_eq!(csrs.counter_mask(), -1i32 as u32 as isize);
+    }
+
+    // Raise every interrupt line: the M-level ones as devices do, the S-level ones through mip
+    fn all_pending() -> Csr {
+        let mut csrs = Csr::new(PMP_ENTRIES, 0);
+        for bit in [IRQ_MEI, IRQ_MSI, IRQ_MTI] {
+            csrs.set_irq(bit, true);
+        }
+        csrs.write(MIP, IRQ_SEI | IRQ_SSI | IRQ_STI);
+        csrs.write(MIE, -1);
+        csrs.write(MSTATUS, MSTATUS_MIE);
+        csrs
+    }
+
+    #[test]
+    fn interrupts_are_taken_in_priority_order() {
+        let mut csrs = all_pending();
+        let mut order = vec![];
+        while let Some(code) = csrs.pending_interrupt(Mode::Machine) {
+            order.push(code);
+            csrs.write(MIE, csrs.read(MIE) & !(1 << code));
+        }
+        assert_eq!(order, [11, 3, 7, 9, 1, 5]);
+    }
+
+    #[test]
+    fn global_enables_gate_by_privilege() {
+        let mut csrs = Csr::new(PMP_ENTRIES, 0);
+        csrs.set_irq(IRQ_MTI, true);
+        csrs.write(MIE, IRQ_MTI | IRQ_SSI);
+        // M-level interrupts need mstatus.MIE only while in M-mode
+        assert_eq!(csrs.pending_interrupt(Mode::Machine), None);
+        assert_eq!(csrs.pending_interrupt(Mode::Supervisor), Some(7));
+        assert_eq!(csrs.pending_interrupt(Mode::User), Some(7));
+        csrs.write(MSTATUS, MSTATUS_MIE);
+        assert_eq!(csrs.pending_interrupt(Mode::Machine), Some(7));
+
+        // Delegated ones need mstatus.SIE only in S-mode, and never interrupt M-mode
+        csrs.set_irq(IRQ_MTI, false);
+        csrs.write(MIDELEG, IRQ_SSI);
+        csrs.write(MIP, IRQ_SSI);
+        assert_eq!(csrs.pending_interrupt(Mode::Machine), None);
+        assert_eq!(csrs.pending_interrupt(Mode::Supervisor), None);
+        assert_eq!(csrs.pending_interrupt(Mode::User), Some(1));
+        csrs.write(MSTATUS, MSTATUS_SIE);
+        assert_eq!(csrs.pending_interrupt(Mode::Supervisor), Some(1));
+        // Not enabled in mie: never taken, but still visible in mip
+        csrs.write(MIE, 0);
+        assert_eq!(csrs.pending_interrupt(Mode::User), None);
+        assert!(!csrs.wfi_wakeup());
+        assert_eq!(csrs.read(MIP), IRQ_SSI);
+    }
+
+    #[test]
+    fn delegated_interrupts_trap_to_s_mode() {
+        let mut csrs = all_pending();
+        csrs.write(MIDELEG, IRQ_S_MASK);
+        csrs.write(MIE, IRQ_S_MASK);
+        csrs.write(MSTATUS, MSTATUS_SIE);
+        csrs.write(MTVEC, 0x100);
+        csrs.write(STVEC, 0x201);        // Vectored
+        assert_eq!(csrs.pending_interrupt(Mode::Supervisor), Some(9));
+        let (mode, pc) = csrs.trap(Mode::Supervisor, 0x1000, INTERRUPT_BIT | 9, 0);
+        assert_eq!((mode, pc), (Mode::Supervisor, 0x200 + 4 * 9));
+        assert_eq!(csrs.read(SCAUSE), INTERRUPT_BIT | 9);
+        assert_eq!(csrs.read(SEPC), 0x1000);
+        assert_eq!(csrs.read(MCAUSE), 0);
+        let mstatus = csrs.read(MSTATUS);
+        assert_eq!(mstatus & (MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP), MSTATUS_SPIE | MSTATUS_SPP);
+        // With SIE now clear, S-mode takes no more until SRET
+        assert_eq!(csrs.pending_interrupt(Mode::Supervisor), None);
+
+        // The same interrupt from U-mode records SPP = U; undelegated ones still go to M
+        let (mode, _) = csrs.trap(Mode::User, 0x2000, INTERRUPT_BIT | 5, 0);
+        assert_eq!(mode, Mode::Supervisor);
+        assert_eq!(csrs.read(MSTATUS) & MSTATUS_SPP, 0);
+        let (mode, pc) = csrs.trap(Mode::User, 0x3000, INTERRUPT_BIT | 7, 0);
+        assert_eq!((mode, pc), (Mode::Machine, 0x100));
+        assert_eq!(csrs.read(MCAUSE), INTERRUPT_BIT | 7);
+    }
+
+    #[test]
+    fn seed_follows_the_entropy_seed() {
+        let mut a = Csr::new(PMP_ENTRIES, 0);
+        let mut b = Csr::new(PMP_ENTRIES, 0);
+        a.set_entropy(EntropySource::Seeded(42));
+        b.set_entropy(EntropySource::Seeded(42));
+        let seeds: Vec<_> = (0..8).map(|_| a.read_seed()).collect();
+        assert_eq!(seeds, (0..8).map(|_| b.read_seed()).collect::<Vec<_>>());
+        assert!(seeds.iter().all(|&seed| seed & !0xFFFF == SEED_ES16));
+    }
+}
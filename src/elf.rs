@@ -0,0 +1,244 @@
+/* RISCulator - RISC-V Emulator */
+/*        ELF32 loader          */
+
+// Libraries here
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use crate::bus::Bus;
+
+// Header fields
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 243;
+const EF_RISCV_RVC: u32 = 0x1;
+
+// Program header types
+const PT_LOAD: u32 = 1;
+const PT_INTERP: u32 = 3;
+const PT_PHDR: u32 = 6;
+
+// Section header types
+const SHT_SYMTAB: u32 = 2;
+
+// Loaded image
+#[derive(Debug, Clone)]
+pub struct Elf {
+    pub entry: usize,
+    pub phdr: usize,        // Address of the program headers in memory (AT_PHDR)
+    pub phent: usize,
+    pub phnum: usize,
+    pub end: usize,         // End of the highest segment, where the heap starts
+    pub symbols: HashMap<String, usize>,
+}
+
+// Elf impl
+impl Elf {
+    // Address of a symbol from the symbol table
+    pub fn symbol(&self, name: &str) -> Option<usize> {
+        self.symbols.get(name).copied()
+    }
+}
+
+// Whether a file starts with the ELF magic
+pub fn is_elf(path: &str) -> bool {
+    let mut magic = [0u8; 4];
+    File::open(path).and_then(|mut file| file.read_exact(&mut magic)).is_ok() && magic == ELF_MAGIC
+}
+
+// Little-endian fields
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+// Load a static RV32 executable's segments onto the bus (user programs and bare-metal images)
+pub fn load(path: &str, bus: &mut Bus) -> Result<Elf, String> {
+    let data = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+    if data.len() < 52 || data[..4] != ELF_MAGIC {
+        return Err(format!("{}: not an ELF file", path));
+    }
+    if data[4] != ELFCLASS32 || data[5] != ELFDATA2LSB {
+        return Err(format!("{}: not a little-endian ELF32 file (only RV32 is emulated)", path));
+    }
+    if u16_at(&data, 16) != ET_EXEC || u16_at(&data, 18) != EM_RISCV {
+        return Err(format!("{}: not a RISC-V executable (static-pie and shared objects are not supported)", path));
+    }
+    if u32_at(&data, 36) & EF_RISCV_RVC != 0 {
+        log::warn!("ELF: {} may use compressed instructions, which are not implemented", path);
+    }
+    let entry = u32_at(&data, 24) as usize;
+    let phoff = u32_at(&data, 28) as usize;
+    let phent = u16_at(&data, 42) as usize;
+    let phnum = u16_at(&data, 44) as usize;
+    if phent < 32 || phoff + phent * phnum > data.len() {
+        return Err(format!("{}: truncated program headers", path));
+    }
+
+    let mut phdr = None;
+    let mut end = 0;
+    for i in 0..phnum {
+        let ph = &data[phoff + i * phent..];
+        let (kind, offset, vaddr) = (u32_at(ph, 0), u32_at(ph, 4) as usize, u32_at(ph, 8) as usize);
+        let (filesz, memsz) = (u32_at(ph, 16) as usize, u32_at(ph, 20) as usize);
+        match kind {
+            PT_INTERP => return Err(format!("{}: dynamically linked executables are not supported", path)),
+            PT_PHDR => phdr = Some(vaddr),
+            PT_LOAD => {
+                if offset + filesz > data.len() || filesz > memsz {
+                    return Err(format!("{}: segment {} is truncated", path, i));
+                }
+                // File bytes, then zeroes up to memsz (.bss)
+                for j in 0..memsz {
+                    let byte = if j < filesz { data[offset + j] } else { 0 };
+                    if !bus.write(vaddr + j, 1, byte as isize) {
+                        return Err(format!("{}: segment {} at {:#010x}+{:#x} is outside memory", path, i, vaddr, memsz));
+                    }
+                }
+                if phdr.is_none() && offset <= phoff && phoff < offset + filesz {
+                    phdr = Some(vaddr + phoff - offset);
+                }
+                end = end.max(vaddr + memsz);
+                log::info!("ELF: loaded segment {:#010x}-{:#010x}", vaddr, vaddr + memsz);
+            }
+            default => {}
+        }
+    }
+    Ok(Elf {
+        entry,
+        phdr: phdr.unwrap_or(0),
+        phent,
+        phnum,
+        end,
+        symbols: symbols(&data),
+    })
+}
+
+// Symbols of an ELF file without loading it
+pub fn read_symbols(path: &str) -> Result<HashMap<String, usize>, String> {
+    let data = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+    if data.len() < 52 || data[..4] != ELF_MAGIC || data[4] != ELFCLASS32 {
+        return Err(format!("{}: not an ELF32 file", path));
+    }
+    Ok(symbols(&data))
+}
+
+// Named symbols from the symbol table (empty when the file is stripped)
+fn symbols(data: &[u8]) -> HashMap<String, usize> {
+    let mut symbols = HashMap::new();
+    let (shoff, shentsize, shnum) = (u32_at(data, 32) as usize, u16_at(data, 46) as usize, u16_at(data, 48) as usize);
+    if shoff == 0 || shentsize < 40 || shoff + shentsize * shnum > data.len() {
+        return symbols;
+    }
+    let section = |i: usize| &data[shoff + i * shentsize..];
+    for i in 0..shnum {
+        let sh = section(i);
+        if u32_at(sh, 4) != SHT_SYMTAB || u32_at(sh, 24) as usize >= shnum {
+            continue;
+        }
+        let (offset, size) = (u32_at(sh, 16) as usize, u32_at(sh, 20) as usize);
+        let strtab = section(u32_at(sh, 24) as usize);
+        let (str_offset, str_size) = (u32_at(strtab, 16) as usize, u32_at(strtab, 20) as usize);
+        if offset + size > data.len() || str_offset + str_size > data.len() {
+            continue;
+        }
+        let strings = &data[str_offset..str_offset + str_size];
+        for sym in data[offset..offset + size].chunks_exact(16) {
+            let name = u32_at(sym, 0) as usize;
+            if name == 0 || name >= strings.len() {
+                continue;
+            }
+            let len = strings[name..].iter().position(|byte| *byte == 0).unwrap_or(strings.len() - name);
+            let name = String::from_utf8_lossy(&strings[name..name + len]).into_owned();
+            symbols.insert(name, u32_at(sym, 4) as usize);
+        }
+    }
+    symbols
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ELF32 header with only the section header fields filled in
+    fn header(shoff: usize, shnum: usize) -> Vec<u8> {
+        let mut data = vec![0u8; 52];
+        data[..4].copy_from_slice(&ELF_MAGIC);
+        data[4] = ELFCLASS32;
+        data[32..36].copy_from_slice(&(shoff as u32).to_le_bytes());
+        data[46..48].copy_from_slice(&40u16.to_le_bytes());
+        data[48..50].copy_from_slice(&(shnum as u16).to_le_bytes());
+        data
+    }
+
+    fn section(kind: u32, offset: usize, size: usize, link: u32) -> Vec<u8> {
+        let mut sh = vec![0u8; 40];
+        sh[4..8].copy_from_slice(&kind.to_le_bytes());
+        sh[16..20].copy_from_slice(&(offset as u32).to_le_bytes());
+        sh[20..24].copy_from_slice(&(size as u32).to_le_bytes());
+        sh[24..28].copy_from_slice(&link.to_le_bytes());
+        sh
+    }
+
+    fn symbol(name: u32, value: u32) -> Vec<u8> {
+        let mut sym = vec![0u8; 16];
+        sym[0..4].copy_from_slice(&name.to_le_bytes());
+        sym[4..8].copy_from_slice(&value.to_le_bytes());
+        sym
+    }
+
+    // Header, string table, symbol table, then null/symtab/strtab section headers
+    fn image(syms: &[Vec<u8>], strtab_link: u32) -> Vec<u8> {
+        let strings = b"\0tohost\0fromhost\0begin_signature";      // Last name runs to the end unterminated
+        let str_offset = 52;
+        let sym_offset = str_offset + strings.len();
+        let symtab: Vec<u8> = syms.concat();
+        let shoff = sym_offset + symtab.len();
+        let mut data = header(shoff, 3);
+        data.extend_from_slice(strings);
+        data.extend_from_slice(&symtab);
+        data.extend(section(0, 0, 0, 0));
+        data.extend(section(SHT_SYMTAB, sym_offset, symtab.len(), strtab_link));
+        data.extend(section(3, str_offset, strings.len(), 0));
+        data
+    }
+
+    #[test]
+    fn reads_named_symbols() {
+        let data = image(&[symbol(0, 0), symbol(1, 0x1800), symbol(8, 0x1808), symbol(17, 0x2000)], 2);
+        let symbols = symbols(&data);
+        assert_eq!(symbols.len(), 3);
+        assert_eq!(symbols["tohost"], 0x1800);
+        assert_eq!(symbols["fromhost"], 0x1808);
+        assert_eq!(symbols["begin_signature"], 0x2000);
+    }
+
+    #[test]
+    fn skips_bad_names_and_links() {
+        // Name offset past the string table
+        let data = image(&[symbol(1, 0x1800), symbol(500, 0x1900)], 2);
+        assert_eq!(symbols(&data).len(), 1);
+        // String table link past the section headers
+        let data = image(&[symbol(1, 0x1800)], 7);
+        assert!(symbols(&data).is_empty());
+    }
+
+    #[test]
+    fn stripped_or_truncated_files_have_no_symbols() {
+        assert!(symbols(&header(0, 0)).is_empty());
+        let mut data = image(&[symbol(1, 0x1800)], 2);
+        data.truncate(data.len() - 1);      // Section headers cut short
+        assert!(symbols(&data).is_empty());
+        // Symbol table running past the end of the file
+        let mut data = image(&[symbol(1, 0x1800)], 2);
+        let sh = data.len() - 80;
+        data[sh + 20..sh + 24].copy_from_slice(&0x1000u32.to_le_bytes());
+        assert!(symbols(&data).is_empty());
+    }
+}
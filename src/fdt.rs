@@ -0,0 +1,255 @@
+/* RISCulator - RISC-V Emulator */
+/*  Flattened device tree (DTB) */
+
+// Libraries here
+use std::collections::HashMap;
+
+// Structure block tokens
+const FDT_MAGIC: u32 = 0xD00D_FEED;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_END: u32 = 0x9;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const HEADER_SIZE: usize = 40;
+
+// Phandles shared between the devices that describe themselves
+pub const PHANDLE_CPU_INTC: u32 = 1;        // Hart n uses PHANDLE_CPU_INTC + n
+pub const PHANDLE_PLIC: u32 = 0x100;
+
+// Property value
+#[derive(Debug, Clone)]
+pub enum Prop {
+    Empty,
+    Cells(Vec<u32>),
+    Str(String),
+    Strs(Vec<String>),
+}
+
+// Device tree node
+#[derive(Debug, Clone)]
+pub struct Node {
+    name: String,
+    props: Vec<(String, Prop)>,
+    children: Vec<Node>,
+}
+
+// Node impl
+impl Node {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            props: vec![],
+            children: vec![],
+        }
+    }
+
+    // Set a property, replacing any earlier value
+    pub fn prop(&mut self, name: &str, value: Prop) -> &mut Self {
+        match self.props.iter_mut().find(|(n, _)| n == name) {
+            Some(prop) => prop.1 = value,
+            None => self.props.push((name.to_string(), value)),
+        }
+        self
+    }
+
+    pub fn empty(&mut self, name: &str) -> &mut Self {
+        self.prop(name, Prop::Empty)
+    }
+
+    pub fn u32(&mut self, name: &str, value: u32) -> &mut Self {
+        self.prop(name, Prop::Cells(vec![value]))
+    }
+
+    pub fn cells(&mut self, name: &str, cells: &[u32]) -> &mut Self {
+        self.prop(name, Prop::Cells(cells.to_vec()))
+    }
+
+    pub fn str(&mut self, name: &str, value: &str) -> &mut Self {
+        self.prop(name, Prop::Str(value.to_string()))
+    }
+
+    pub fn strs(&mut self, name: &str, values: &[&str]) -> &mut Self {
+        self.prop(name, Prop::Strs(values.iter().map(|v| v.to_string()).collect()))
+    }
+
+    // Child node by name, created if missing
+    pub fn child(&mut self, name: &str) -> &mut Node {
+        let index = match self.children.iter().position(|child| child.name == name) {
+            Some(index) => index,
+            None => {
+                self.children.push(Node::new(name));
+                self.children.len() - 1
+            }
+        };
+        &mut self.children[index]
+    }
+}
+
+// Device tree struct
+#[derive(Debug, Clone)]
+pub struct Fdt {
+    root: Node,
+    boot_cpuid: u32,
+}
+
+// Device tree impl
+impl Fdt {
+    pub fn new() -> Self {
+        Self {
+            root: Node::new(""),
+            boot_cpuid: 0,
+        }
+    }
+
+    // Node at an absolute path like /soc/serial@10000000, created if missing
+    pub fn node(&mut self, path: &str) -> &mut Node {
+        let mut node = &mut self.root;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            node = node.child(name);
+        }
+        node
+    }
+
+    // Node for a device on /soc with its registers and interrupt wiring
+    pub fn soc_device(&mut self, name: &str, compatible: &[&str], base: usize, size: usize, irq: Option<usize>) -> &mut Node {
+        let node = self.node(&format!("/soc/{}@{:x}", name, base));
+        node.strs("compatible", compatible).cells("reg", &[base as u32, size as u32]);
+        if let Some(irq) = irq {
+            node.u32("interrupt-parent", PHANDLE_PLIC).u32("interrupts", irq as u32);
+        }
+        node
+    }
+
+    // Serialize to a version 17 DTB
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut structure: Vec<u8> = vec![];
+        let mut strings: Vec<u8> = vec![];
+        let mut offsets: HashMap<String, u32> = HashMap::new();
+        emit(&self.root, &mut structure, &mut strings, &mut offsets);
+        push_u32(&mut structure, FDT_END);
+
+        let off_rsvmap = HEADER_SIZE;
+        let off_struct = off_rsvmap + 16;           // Empty reservation map (terminator only)
+        let off_strings = off_struct + structure.len();
+        let total = off_strings + strings.len();
+
+        let mut out = vec![];
+        for word in [
+            FDT_MAGIC, total as u32, off_struct as u32, off_strings as u32, off_rsvmap as u32,
+            FDT_VERSION, FDT_LAST_COMP_VERSION, self.boot_cpuid, strings.len() as u32, structure.len() as u32,
+        ] {
+            push_u32(&mut out, word);
+        }
+        out.extend_from_slice(&[0; 16]);
+        out.extend_from_slice(&structure);
+        out.extend_from_slice(&strings);
+        out
+    }
+}
+
+// Big-endian word
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+// Pad to a 4-byte boundary
+fn align(out: &mut Vec<u8>) {
+    while out.len() % 4 != 0 {
+        out.push(0);
+    }
+}
+
+// Structure block for a node and its children
+fn emit(node: &Node, out: &mut Vec<u8>, strings: &mut Vec<u8>, offsets: &mut HashMap<String, u32>) {
+    push_u32(out, FDT_BEGIN_NODE);
+    out.extend_from_slice(node.name.as_bytes());
+    out.push(0);
+    align(out);
+    for (name, value) in &node.props {
+        let value: Vec<u8> = match value {
+            Prop::Empty => vec![],
+            Prop::Cells(cells) => cells.iter().flat_map(|cell| cell.to_be_bytes()).collect(),
+            Prop::Str(s) => s.bytes().chain(Some(0)).collect(),
+            Prop::Strs(list) => list.iter().flat_map(|s| s.bytes().chain(Some(0))).collect(),
+        };
+        let nameoff = *offsets.entry(name.clone()).or_insert_with(|| {
+            let off = strings.len() as u32;
+            strings.extend_from_slice(name.as_bytes());
+            strings.push(0);
+            off
+        });
+        push_u32(out, FDT_PROP);
+        push_u32(out, value.len() as u32);
+        push_u32(out, nameoff);
+        out.extend_from_slice(&value);
+        align(out);
+    }
+    for child in &node.children {
+        emit(child, out, strings, offsets);
+    }
+    push_u32(out, FDT_END_NODE);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(words: &[u32]) -> Vec<u8> {
+        words.iter().flat_map(|word| word.to_be_bytes()).collect()
+    }
+
+    #[test]
+    fn serializes_a_small_tree() {
+        let mut fdt = Fdt::new();
+        fdt.node("/").u32("#address-cells", 1);
+        fdt.node("/chosen").str("bootargs", "x");
+        let dtb = fdt.to_bytes();
+
+        let mut expected = words(&[FDT_MAGIC, 144, 56, 120, 40, 17, 16, 0, 24, 64]);
+        expected.extend_from_slice(&[0; 16]);
+        expected.extend(words(&[FDT_BEGIN_NODE, 0, FDT_PROP, 4, 0, 1]));
+        expected.extend(words(&[FDT_BEGIN_NODE]));
+        expected.extend_from_slice(b"chosen\0\0");
+        expected.extend(words(&[FDT_PROP, 2, 15]));
+        expected.extend_from_slice(b"x\0\0\0");
+        expected.extend(words(&[FDT_END_NODE, FDT_END_NODE, FDT_END]));
+        expected.extend_from_slice(b"#address-cells\0bootargs\0");
+        assert_eq!(dtb, expected);
+    }
+
+    #[test]
+    fn shares_property_names_and_replaces_values() {
+        let mut fdt = Fdt::new();
+        fdt.node("/a").u32("reg", 1).u32("reg", 2);
+        fdt.node("/b").empty("reg");
+        fdt.node("/a").strs("compatible", &["x", "yz"]);
+        let dtb = fdt.to_bytes();
+
+        // One copy of each name in the strings block
+        let strings = &dtb[u32::from_be_bytes(dtb[12..16].try_into().unwrap()) as usize..];
+        assert_eq!(strings, b"reg\0compatible\0");
+        // /a has reg = <2> then the string list; /b has an empty reg
+        let structure = &dtb[56..];
+        assert_eq!(&structure[8..16], [&words(&[FDT_BEGIN_NODE])[..], b"a\0\0\0"].concat());
+        assert_eq!(&structure[16..32], &words(&[FDT_PROP, 4, 0, 2])[..]);
+        assert_eq!(&structure[32..44], &words(&[FDT_PROP, 5, 4])[..]);
+        assert_eq!(&structure[44..52], b"x\0yz\0\0\0\0");
+        assert_eq!(&structure[52..60], &words(&[FDT_END_NODE, FDT_BEGIN_NODE])[..]);
+        assert_eq!(&structure[60..64], b"b\0\0\0");
+        assert_eq!(&structure[64..76], &words(&[FDT_PROP, 0, 0])[..]);
+        assert_eq!(dtb.len(), u32::from_be_bytes(dtb[4..8].try_into().unwrap()) as usize);
+    }
+
+    #[test]
+    fn describes_soc_devices() {
+        let mut fdt = Fdt::new();
+        fdt.soc_device("serial", &["ns16550a"], 0x1000_0000, 0x100, Some(10));
+        let node = fdt.node("/soc/serial@10000000");
+        let props: Vec<&str> = node.props.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(props, ["compatible", "reg", "interrupt-parent", "interrupts"]);
+        assert!(matches!(&node.props[1].1, Prop::Cells(cells) if cells == &[0x1000_0000, 0x100]));
+        assert!(matches!(&node.props[2].1, Prop::Cells(cells) if cells == &[PHANDLE_PLIC]));
+    }
+}
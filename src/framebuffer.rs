@@ -0,0 +1,361 @@
+/* RISCulator - RISC-V Emulator */
+/*   Headless framebuffer       */
+
+// Libraries here
+use std::fs;
+use std::path::PathBuf;
+use colored::*;
+use crate::bus::{size_mask, Device};
+use crate::fdt::Fdt;
+
+// Control registers
+const WIDTH: usize = 0x00;
+const HEIGHT: usize = 0x04;
+const FORMAT: usize = 0x08;
+const STRIDE: usize = 0x0C;
+const PRESENT: usize = 0x10;        // Write to present the current frame
+const FRAME: usize = 0x14;          // Frames presented so far
+const PIXELS: usize = 0x1000;       // Pixel memory starts one page in
+
+// Pixel formats
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PixelFormat {
+    Gray8 = 0,
+    Rgb565 = 1,
+    Xrgb8888 = 2,       // 0x00RRGGBB per 32-bit word
+}
+
+// PixelFormat impl
+impl PixelFormat {
+    pub fn parse(name: &str) -> Option<PixelFormat> {
+        match name {
+            "gray8" => Some(PixelFormat::Gray8),
+            "rgb565" => Some(PixelFormat::Rgb565),
+            "xrgb8888" => Some(PixelFormat::Xrgb8888),
+            default => None,
+        }
+    }
+
+    // simple-framebuffer format name, if there is one
+    fn fdt_name(&self) -> Option<&'static str> {
+        match self {
+            PixelFormat::Gray8 => None,
+            PixelFormat::Rgb565 => Some("r5g6b5"),
+            PixelFormat::Xrgb8888 => Some("x8r8g8b8"),
+        }
+    }
+
+    fn bytes(&self) -> usize {
+        match self {
+            PixelFormat::Gray8 => 1,
+            PixelFormat::Rgb565 => 2,
+            PixelFormat::Xrgb8888 => 4,
+        }
+    }
+}
+
+// Frame dump file format
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DumpFormat {
+    Ppm,
+    Png,
+}
+
+// Framebuffer struct
+pub struct Framebuffer {
+    width: usize,
+    height: usize,
+    format: PixelFormat,
+    pixels: Vec<u8>,
+    frames: u64,
+    every: u64,             // Dump every Nth presented frame
+    dump: DumpFormat,
+    dir: PathBuf,
+}
+
+// Framebuffer struct impl
+impl Framebuffer {
+    // Initialize a blank framebuffer that dumps frames into `dir`
+    pub fn new(width: usize, height: usize, format: PixelFormat, every: u64, dump: DumpFormat, dir: &str) -> Result<Self, String> {
+        if width == 0 || height == 0 {
+            return Err("framebuffer size must not be zero".to_string());
+        }
+        if let Err(err) = fs::create_dir_all(dir) {
+            return Err(format!("{}: {}", dir, err));
+        }
+        Ok(Self {
+            width,
+            height,
+            format,
+            pixels: vec![0; width * height * format.bytes()],
+            frames: 0,
+            every: every.max(1),
+            dump,
+            dir: PathBuf::from(dir),
+        })
+    }
+
+    // Bytes of address space: control page plus pixel memory
+    pub fn size(&self) -> usize {
+        PIXELS + ((self.pixels.len() + 0xFFF) & !0xFFF)
+    }
+
+    // Pixel at (x, y) as 8-bit RGB
+    fn rgb(&self, x: usize, y: usize) -> [u8; 3] {
+        let bytes = self.format.bytes();
+        let i = (y * self.width + x) * bytes;
+        let mut value: u32 = 0;
+        for b in 0..bytes {
+            value |= (self.pixels[i + b] as u32) << (8 * b);
+        }
+        match self.format {
+            PixelFormat::Gray8 => [value as u8; 3],
+            PixelFormat::Rgb565 => {
+                let r = (value >> 11) & 0x1F;
+                let g = (value >> 5) & 0x3F;
+                let b = value & 0x1F;
+                [((r << 3) | (r >> 2)) as u8, ((g << 2) | (g >> 4)) as u8, ((b << 3) | (b >> 2)) as u8]
+            }
+            PixelFormat::Xrgb8888 => [(value >> 16) as u8, (value >> 8) as u8, value as u8],
+        }
+    }
+
+    // Rows of packed RGB
+    fn rgb_rows(&self) -> Vec<Vec<u8>> {
+        (0..self.height).map(|y| (0..self.width).flat_map(|x| self.rgb(x, y)).collect()).collect()
+    }
+
+    // Present the current frame, dumping it if it is due
+    fn present(&mut self) {
+        let frame = self.frames;
+        self.frames += 1;
+        if frame % self.every != 0 {
+            return;
+        }
+        let (ext, data) = match self.dump {
+            DumpFormat::Ppm => ("ppm", encode_ppm(self.width, self.height, &self.rgb_rows())),
+            DumpFormat::Png => ("png", encode_png(self.width, self.height, &self.rgb_rows())),
+        };
+        let path = self.dir.join(format!("frame{:05}.{}", frame, ext));
+        match fs::write(&path, data) {
+            Ok(()) => log::info!("Framebuffer: frame {} written to {}", frame, path.display()),
+            Err(err) => log::error!("Framebuffer: could not write {}: {}", path.display(), err),
+        }
+    }
+}
+
+// Binary PPM (P6)
+fn encode_ppm(width: usize, height: usize, rows: &[Vec<u8>]) -> Vec<u8> {
+    let mut out = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    for row in rows {
+        out.extend_from_slice(row);
+    }
+    out
+}
+
+// 8-bit RGB PNG with an uncompressed (stored) zlib stream
+fn encode_png(width: usize, height: usize, rows: &[Vec<u8>]) -> Vec<u8> {
+    let mut raw = Vec::new();
+    for row in rows {
+        raw.push(0);        // Filter type: none
+        raw.extend_from_slice(row);
+    }
+
+    // zlib header, stored deflate blocks of up to 65535 bytes, Adler-32
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        zlib.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none() as u8;
+        let len = block.len() as u16;
+        zlib.push(last);
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut ihdr = Vec::new();
+    ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);      // 8-bit, truecolour, deflate, no filter, no interlace
+
+    let mut out = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+    png_chunk(&mut out, b"IHDR", &ihdr);
+    png_chunk(&mut out, b"IDAT", &zlib);
+    png_chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+// Length, type, data and CRC of one PNG chunk
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+// Framebuffer device: control registers, then byte-addressable pixels
+impl Device for Framebuffer {
+    fn name(&self) -> &str {
+        "framebuffer"
+    }
+
+    fn read(&mut self, offset: usize, size: usize) -> Option<u64> {
+        if offset >= PIXELS {
+            let i = offset - PIXELS;
+            let mut value = 0u64;
+            for b in 0..size {
+                value |= (*self.pixels.get(i + b)? as u64) << (8 * b);
+            }
+            return Some(value);
+        }
+        let value = match offset {
+            WIDTH => self.width as u64,
+            HEIGHT => self.height as u64,
+            FORMAT => self.format as u64,
+            STRIDE => (self.width * self.format.bytes()) as u64,
+            FRAME => self.frames & 0xFFFF_FFFF,
+            default => 0,
+        };
+        Some(value & size_mask(size))
+    }
+
+    fn write(&mut self, offset: usize, size: usize, value: u64) -> bool {
+        if offset >= PIXELS {
+            let i = offset - PIXELS;
+            if i + size > self.pixels.len() {
+                return false;
+            }
+            for b in 0..size {
+                self.pixels[i + b] = (value >> (8 * b)) as u8;
+            }
+            return true;
+        }
+        if offset == PRESENT {
+            self.present();
+        }
+        true        // Other control registers are read-only
+    }
+
+    fn reset(&mut self) {
+        self.pixels.iter_mut().for_each(|p| *p = 0);
+        self.frames = 0;
+    }
+
+    // Pixel memory as a simple-framebuffer when Linux knows the format
+    fn describe(&self, fdt: &mut Fdt, base: usize, size: usize, irq: Option<usize>) {
+        let compatible: &[&str] = match self.format.fdt_name() {
+            Some(_) => &["risculator,framebuffer", "simple-framebuffer"],
+            None => &["risculator,framebuffer"],
+        };
+        let node = fdt.soc_device("framebuffer", compatible, base + PIXELS, self.pixels.len(), irq);
+        node.u32("width", self.width as u32)
+            .u32("height", self.height as u32)
+            .u32("stride", (self.width * self.format.bytes()) as u32);
+        if let Some(format) = self.format.fdt_name() {
+            node.str("format", format);
+        }
+    }
+
+    fn print_dirty(&mut self) {
+        println!("{}", "--------------------------------".green());
+        println!("{}", "Framebuffer".green());
+        println!("{}", "--------------------------------".green());
+        println!("{}x{} {:?}, {} frames presented", self.width, self.height, self.format, self.frames);
+        println!("{}", "--------------------------------".green());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums_match_reference_values() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(crc32(&[0xFF; 100_000]), 0x68C6_CEC4);
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+        assert_eq!(adler32(&[0xFF; 100_000]), 0x149A_302C);
+    }
+
+    #[test]
+    fn encodes_a_one_pixel_png() {
+        let png = encode_png(1, 1, &[vec![0xFF, 0x00, 0x80]]);
+        assert_eq!(png, [
+            0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a,
+            0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44, 0x52, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01,
+            0x08, 0x02, 0x00, 0x00, 0x00, 0x90, 0x77, 0x53, 0xde,
+            0x00, 0x00, 0x00, 0x0f, 0x49, 0x44, 0x41, 0x54, 0x78, 0x01, 0x01, 0x04, 0x00, 0xfb, 0xff,
+            0x00, 0xff, 0x00, 0x80, 0x03, 0x81, 0x01, 0x80, 0x30, 0x76, 0xcd, 0xb0,
+            0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4e, 0x44, 0xae, 0x42, 0x60, 0x82,
+        ]);
+    }
+
+    #[test]
+    fn splits_large_frames_into_stored_blocks() {
+        // 200x120 RGB is 72120 filtered bytes: one full block and one last block
+        let rows: Vec<Vec<u8>> = (0..120).map(|y| (0..600).map(|x| (x + y) as u8).collect()).collect();
+        let png = encode_png(200, 120, &rows);
+        let idat_len = u32::from_be_bytes(png[33..37].try_into().unwrap()) as usize;
+        assert_eq!(&png[37..41], b"IDAT");
+        let zlib = &png[41..41 + idat_len];
+        assert_eq!(u32::from_be_bytes(png[41 + idat_len..45 + idat_len].try_into().unwrap()), crc32(&png[37..41 + idat_len]));
+
+        let mut raw = vec![];
+        let mut pos = 2;
+        loop {
+            let last = zlib[pos];
+            let len = u16::from_le_bytes([zlib[pos + 1], zlib[pos + 2]]) as usize;
+            assert_eq!(u16::from_le_bytes([zlib[pos + 3], zlib[pos + 4]]) as usize, !len & 0xFFFF);
+            raw.extend_from_slice(&zlib[pos + 5..pos + 5 + len]);
+            pos += 5 + len;
+            if last == 1 {
+                break;
+            }
+            assert_eq!(len, 0xFFFF);
+        }
+        assert_eq!(raw.len(), 120 * 601);
+        assert_eq!(u32::from_be_bytes(zlib[pos..pos + 4].try_into().unwrap()), adler32(&raw));
+        assert_eq!(pos + 4, zlib.len());
+        for (y, row) in rows.iter().enumerate() {
+            assert_eq!(raw[y * 601], 0);
+            assert_eq!(&raw[y * 601 + 1..(y + 1) * 601], &row[..]);
+        }
+    }
+
+    #[test]
+    fn encodes_ppm_and_empty_png() {
+        assert_eq!(encode_ppm(2, 1, &[vec![1, 2, 3, 4, 5, 6]]), b"P6\n2 1\n255\n\x01\x02\x03\x04\x05\x06");
+        // No rows still makes a valid zlib stream: one empty last block
+        let png = encode_png(0, 0, &[]);
+        assert_eq!(&png[41..52], &[0x78, 0x01, 0x01, 0x00, 0x00, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x01]);
+    }
+}
@@ -0,0 +1,252 @@
+/* RISCulator - RISC-V Emulator */
+/*          GPIO device         */
+
+// Libraries here
+use std::cell::RefCell;
+use std::rc::Rc;
+use colored::*;
+use crate::bus::{size_mask, Device};
+use crate::fdt::Fdt;
+use crate::pins::PinLog;
+
+// Register offsets
+const INPUT_VAL: usize = 0x00;      // Pin levels (read-only)
+const INPUT_EN: usize = 0x04;
+const OUTPUT_EN: usize = 0x08;
+const OUTPUT_VAL: usize = 0x0C;
+const RISE_IE: usize = 0x10;
+const FALL_IE: usize = 0x14;
+const IP: usize = 0x18;             // Edge interrupt pending, write 1 to clear
+pub const GPIO_SIZE: usize = 0x1000;
+
+// External side driving the GPIO inputs
+pub trait GpioInput {
+    // Input pin levels at a clock
+    fn sample(&mut self, clock: u64) -> u32;
+}
+
+// Rust callbacks can drive the inputs directly
+impl<F: FnMut(u64) -> u32> GpioInput for F {
+    fn sample(&mut self, clock: u64) -> u32 {
+        self(clock)
+    }
+}
+
+// Input waveform: the pins take each value from its clock onwards
+#[derive(Debug, Clone, Default)]
+pub struct Waveform {
+    steps: Vec<(u64, u32)>,
+}
+
+// Waveform impl
+impl Waveform {
+    pub fn new() -> Self {
+        Self {
+            steps: vec![],
+        }
+    }
+
+    // Add a step, keeping the waveform in clock order
+    pub fn add(&mut self, clock: u64, value: u32) {
+        self.steps.push((clock, value));
+        self.steps.sort_by_key(|step| step.0);
+    }
+}
+
+impl GpioInput for Waveform {
+    fn sample(&mut self, clock: u64) -> u32 {
+        self.steps.iter().take_while(|step| step.0 <= clock).last().map_or(0, |step| step.1)
+    }
+}
+
+// GPIO struct
+pub struct Gpio {
+    input: Box<dyn GpioInput>,
+    input_val: u32,
+    input_en: u32,
+    output_en: u32,
+    output_val: u32,
+    rise_ie: u32,
+    fall_ie: u32,
+    ip: u32,
+    clock: u64,
+    log: Rc<RefCell<PinLog>>,
+}
+
+// GPIO struct impl
+impl Gpio {
+    // Initialize a 32-pin GPIO block with its external input source
+    pub fn new(input: Box<dyn GpioInput>, log: Rc<RefCell<PinLog>>) -> Self {
+        Self {
+            input,
+            input_val: 0,
+            input_en: 0,
+            output_en: 0,
+            output_val: 0,
+            rise_ie: 0,
+            fall_ie: 0,
+            ip: 0,
+            clock: 0,
+            log,
+        }
+    }
+
+    // Log the driven pins after an output change
+    fn log_outputs(&self) {
+        self.log.borrow_mut().record(self.clock, "gpio", &format!("out={:#010x} oe={:#010x}", self.output_val & self.output_en, self.output_en));
+    }
+}
+
+// GPIO device
+impl Device for Gpio {
+    fn name(&self) -> &str {
+        "gpio"
+    }
+
+    fn read(&mut self, offset: usize, size: usize) -> Option<u64> {
+        let value = match offset {
+            INPUT_VAL => self.input_val,
+            INPUT_EN => self.input_en,
+            OUTPUT_EN => self.output_en,
+            OUTPUT_VAL => self.output_val,
+            RISE_IE => self.rise_ie,
+            FALL_IE => self.fall_ie,
+            IP => self.ip,
+            default => return None,
+        };
+        Some(value as u64 & size_mask(size))
+    }
+
+    fn write(&mut self, offset: usize, size: usize, value: u64) -> bool {
+        let value = value as u32;
+        match offset {
+            INPUT_VAL => {}
+            INPUT_EN => self.input_en = value,
+            OUTPUT_EN => {
+                if self.output_en != value {
+                    self.output_en = value;
+                    self.log_outputs();
+                }
+            }
+            OUTPUT_VAL => {
+                if self.output_val != value {
+                    self.output_val = value;
+                    self.log_outputs();
+                }
+            }
+            RISE_IE => self.rise_ie = value,
+            FALL_IE => self.fall_ie = value,
+            IP => self.ip &= !value,
+            default => return false,
+        }
+        true
+    }
+
+    fn tick(&mut self) {
+        self.clock += 1;
+        let level = self.input.sample(self.clock) & self.input_en;
+        if level != self.input_val {
+            let rise = level & !self.input_val;
+            let fall = !level & self.input_val;
+            self.ip |= (rise & self.rise_ie) | (fall & self.fall_ie);
+            self.input_val = level;
+            self.log.borrow_mut().record(self.clock, "gpio", &format!("in={:#010x}", level));
+        }
+    }
+
+    fn interrupt(&self) -> bool {
+        self.ip != 0
+    }
+
+    fn reset(&mut self) {
+        self.input_val = 0;
+        self.input_en = 0;
+        self.output_en = 0;
+        self.output_val = 0;
+        self.rise_ie = 0;
+        self.fall_ie = 0;
+        self.ip = 0;
+    }
+
+    fn describe(&self, fdt: &mut Fdt, base: usize, size: usize, irq: Option<usize>) {
+        fdt.soc_device("gpio", &["risculator,gpio"], base, size, irq)
+            .empty("gpio-controller")
+            .u32("#gpio-cells", 2);
+    }
+
+    fn print_dirty(&mut self) {
+        println!("{}", "--------------------------------".green());
+        println!("{}", "GPIO".green());
+        println!("{}", "--------------------------------".green());
+        println!("in: {:#010x} out: {:#010x} oe: {:#010x} ip: {:#010x}", self.input_val, self.output_val, self.output_en, self.ip);
+        println!("{}", "--------------------------------".green());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Pins 0 and 2 rise at clock 3; pin 0 falls again at clock 6
+    fn gpio() -> (Gpio, Rc<RefCell<PinLog>>) {
+        let log = PinLog::new(None).unwrap();
+        let input = |clock: u64| match clock {
+            0..=2 => 0,
+            3..=5 => 0b101,
+            default => 0b100,
+        };
+        (Gpio::new(Box::new(input), log.clone()), log)
+    }
+
+    fn run(gpio: &mut Gpio, clocks: u64) {
+        for _ in 0..clocks {
+            gpio.tick();
+        }
+    }
+
+    #[test]
+    fn inputs_are_sampled_only_when_enabled() {
+        let (mut gpio, _) = gpio();
+        gpio.write(INPUT_EN, 4, 0b001);
+        run(&mut gpio, 3);
+        assert_eq!(gpio.read(INPUT_VAL, 4), Some(0b001));
+        gpio.write(INPUT_EN, 4, 0b111);
+        run(&mut gpio, 1);
+        assert_eq!(gpio.read(INPUT_VAL, 4), Some(0b101));
+        // The pin levels are read-only
+        assert!(gpio.write(INPUT_VAL, 4, 0));
+        assert_eq!(gpio.read(INPUT_VAL, 4), Some(0b101));
+        assert_eq!(gpio.read(0x1C, 4), None);
+        assert!(!gpio.write(0x1C, 4, 0));
+    }
+
+    #[test]
+    fn edges_raise_interrupts_until_cleared() {
+        let (mut gpio, _) = gpio();
+        gpio.write(INPUT_EN, 4, 0b111);
+        gpio.write(RISE_IE, 4, 0b100);
+        gpio.write(FALL_IE, 4, 0b001);
+        run(&mut gpio, 3);
+        assert_eq!(gpio.read(IP, 4), Some(0b100));
+        assert!(gpio.interrupt());
+        gpio.write(IP, 4, 0b100);       // Write 1 to clear
+        assert!(!gpio.interrupt());
+        run(&mut gpio, 3);
+        assert_eq!(gpio.read(IP, 4), Some(0b001));
+        gpio.write(IP, 4, 0b010);
+        assert_eq!(gpio.read(IP, 4), Some(0b001));
+    }
+
+    #[test]
+    fn output_changes_are_logged() {
+        let (mut gpio, log) = gpio();
+        gpio.write(OUTPUT_VAL, 4, 0xF0);
+        gpio.write(OUTPUT_EN, 4, 0x30);
+        gpio.write(OUTPUT_EN, 4, 0x30);         // No change, no event
+        assert_eq!(gpio.read(OUTPUT_VAL, 4), Some(0xF0));
+        assert_eq!(gpio.read(OUTPUT_EN, 1), Some(0x30));
+        assert_eq!(log.borrow().events(), 2);
+        gpio.reset();
+        assert_eq!(gpio.read(OUTPUT_EN, 4), Some(0));
+    }
+}
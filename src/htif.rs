@@ -0,0 +1,352 @@
+/* RISCulator - RISC-V Emulator */
+/*  HTIF tohost/fromhost (Spike) */
+
+// Libraries here
+use std::fs;
+use std::io;
+use std::io::Write;
+use crate::{console, utils, Vproc};
+use crate::linux::IO_CHUNK;
+
+/*
+ * Host-Target Interface as used by Spike and the riscv-tests
+ *
+ * The program writes a 64-bit command to `tohost`: device in bits 63:56, command in
+ * bits 55:48 and a 48-bit payload. The host clears `tohost` once it has taken the
+ * command and answers, where one is due, through `fromhost`. A command is taken once
+ * `tohost` has held the same non-zero value for two consecutive steps, so RV32 code
+ * may store the two halves in either order.
+ *
+ * Input comes from the shared host console (console.rs): getchar answers -1 when
+ * nothing has been typed, and a proxied read of stdin waits in `tohost` until input
+ * arrives, so the timers and devices keep running meanwhile.
+ *
+ * In signature mode (--signature) the words between `begin_signature` and
+ * `end_signature` are written out when the program stops, one per line as RISCOF
+ * expects.
+ */
+
+// Devices and commands
+const DEV_SYSCALL: u64 = 0;         // Command 0: exit code (payload bit 0 set) or syscall block
+const DEV_CONSOLE: u64 = 1;
+
+// Failing test numbers above this share its exit status, clear of Rust's panic status
+// (101) and the runner's own statuses (suite.rs)
+pub const FAIL_MAX: u64 = 100;
+const CONSOLE_GETCHAR: u64 = 0;
+const CONSOLE_PUTCHAR: u64 = 1;
+const NO_CHAR: u64 = 0xFFFF_FFFF_FFFF;     // Getchar answer when nothing has been typed (-1)
+
+// Proxied syscalls (riscv-pk numbering)
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_EXIT: u64 = 93;
+const ENOSYS: i64 = 38;
+const EBADF: i64 = 9;
+
+// HTIF state
+#[derive(Debug)]
+pub struct Htif {
+    tohost: usize,
+    fromhost: Option<usize>,
+    last: u64,              // tohost at the previous step
+    signature: Option<(String, usize, usize)>,      // File and [begin, end) to dump at exit
+}
+
+// Htif impl
+impl Htif {
+    pub fn new(tohost: usize, fromhost: Option<usize>) -> Self {
+        log::info!("HTIF: tohost at {:#010x}, fromhost at {}", tohost, fromhost.map_or("none".to_string(), |addr| format!("{:#010x}", addr)));
+        Self {
+            tohost,
+            fromhost,
+            last: 0,
+            signature: None,
+        }
+    }
+
+    // Dump [begin, end) to `path` when the program stops
+    pub fn set_signature(&mut self, path: &str, begin: usize, end: usize) {
+        self.signature = Some((path.to_string(), begin, end));
+    }
+}
+
+// Write the signature region, if one was asked for
+pub fn dump_signature(proc: &mut Vproc) {
+    let (path, begin, end) = match proc.htif.as_ref().and_then(|htif| htif.signature.clone()) {
+        Some(signature) => signature,
+        None => return,
+    };
+    let mut text = String::new();
+    for addr in (begin..end).step_by(4) {
+        let word = proc.bus.read(addr, 4).unwrap_or(0) & 0xFFFF_FFFF;
+        text.push_str(&format!("{:08x}\n", word));
+    }
+    match fs::write(&path, text) {
+        Ok(()) => log::info!("Signature {:#010x}-{:#010x} written to {}", begin, end, path),
+        Err(err) => log::error!("Could not write signature {}: {}", path, err),
+    }
+}
+
+// 64-bit words as two 32-bit halves over the bus
+fn read64(proc: &mut Vproc, addr: usize) -> Option<u64> {
+    let low = proc.bus.read(addr, 4)? as u64 & 0xFFFF_FFFF;
+    let high = proc.bus.read(addr + 4, 4)? as u64 & 0xFFFF_FFFF;
+    Some(high << 32 | low)
+}
+
+fn write64(proc: &mut Vproc, addr: usize, value: u64) {
+    proc.bus.write(addr, 4, (value & 0xFFFF_FFFF) as isize);
+    proc.bus.write(addr + 4, 4, (value >> 32) as isize);
+}
+
+// Check tohost once per step and carry out a settled command
+pub fn poll(proc: &mut Vproc) {
+    let (tohost, fromhost, last) = match &proc.htif {
+        Some(htif) => (htif.tohost, htif.fromhost, htif.last),
+        None => return,
+    };
+    let value = read64(proc, tohost).unwrap_or(0);
+    if let Some(htif) = proc.htif.as_mut() {
+        htif.last = value;
+    }
+    if value == 0 || value != last {
+        return;
+    }
+    let (device, command, payload) = (value >> 56, (value >> 48) & 0xFF, value & 0xFFFF_FFFF_FFFF);
+    // A console read with nothing typed yet stays in tohost, so the rest of the machine keeps running
+    if device == DEV_SYSCALL && command == 0 && payload & 1 == 0 && read_would_block(proc, payload as usize) {
+        return;
+    }
+    write64(proc, tohost, 0);
+    if let Some(htif) = proc.htif.as_mut() {
+        htif.last = 0;
+    }
+    let response = match (device, command) {
+        (DEV_SYSCALL, 0) if payload & 1 == 1 => exit(proc, payload >> 1),
+        (DEV_SYSCALL, 0) => {
+            syscall(proc, payload as usize);
+            Some(1)
+        }
+        (DEV_CONSOLE, CONSOLE_PUTCHAR) => {
+            let _ = io::stdout().write_all(&[payload as u8]).and_then(|_| io::stdout().flush());
+            Some(0x100 | (payload & 0xFF))
+        }
+        (DEV_CONSOLE, CONSOLE_GETCHAR) => match console::try_read() {
+            Some(byte) => Some(0x100 | byte as u64),
+            None => Some(NO_CHAR),
+        },
+        default => {
+            log::warn!("HTIF: unknown device {} command {} (payload {:#x})", device, command, payload);
+            None
+        }
+    };
+    if let (Some(response), Some(fromhost)) = (response, fromhost) {
+        write64(proc, fromhost, device << 56 | command << 48 | response);
+    }
+}
+
+// Exit code 0 is a pass; riscv-tests report the failing test number otherwise
+fn exit(proc: &mut Vproc, code: u64) -> ! {
+    dump_signature(proc);
+    proc.htif = None;       // The result is in (see utils::exit)
+    if code == 0 {
+        log::info!("HTIF: PASS");
+    }
+    else {
+        log::error!("HTIF: FAIL (exit code {})", code);
+    }
+    utils::exit(proc, code.min(FAIL_MAX) as i32);
+}
+
+// Would the syscall block at `block` wait for console input
+fn read_would_block(proc: &mut Vproc, block: usize) -> bool {
+    let (nr, fd) = (read64(proc, block).unwrap_or(0), read64(proc, block + 8).unwrap_or(0));
+    nr == SYS_READ && fd == 0 && !console::ready() && console::is_open()
+}
+
+// Syscall proxy: an 8 x u64 block of number and arguments, with the result stored in word 0
+fn syscall(proc: &mut Vproc, block: usize) {
+    let words: Vec<u64> = (0..4).map(|i| read64(proc, block + 8 * i).unwrap_or(0)).collect();
+    let (nr, fd, buf, len) = (words[0], words[1], words[2] as usize, words[3] as usize);
+    // Guest-controlled length: move at most one chunk and report the short count
+    let len = len.min(IO_CHUNK);
+    let result: i64 = match nr {
+        SYS_WRITE => {
+            let data: Vec<u8> = (0..len).map(|i| proc.bus.read(buf + i, 1).unwrap_or(0) as u8).collect();
+            let written = match fd {
+                1 => io::stdout().write_all(&data).and_then(|_| io::stdout().flush()),
+                2 => io::stderr().write_all(&data),
+                default => Err(io::Error::from_raw_os_error(EBADF as i32)),
+            };
+            match written {
+                Ok(()) => len as i64,
+                Err(_) => -EBADF,
+            }
+        }
+        SYS_READ if fd == 0 => {
+            // poll() holds the command back until input is ready or has ended
+            let mut data = vec![0u8; len];
+            let n = console::read_ready(&mut data);
+            for (i, byte) in data[..n].iter().enumerate() {
+                proc.bus.write(buf + i, 1, *byte as isize);
+            }
+            n as i64
+        }
+        SYS_READ => -EBADF,
+        SYS_EXIT => exit(proc, fd),
+        default => {
+            log::warn!("HTIF: unsupported proxied syscall {}", nr);
+            -ENOSYS
+        }
+    };
+    write64(proc, block, result as u64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_hart;
+
+    const TOHOST: usize = 0x1000;
+    const FROMHOST: usize = 0x1008;
+    const BLOCK: usize = 0x1040;
+
+    // A hart with RAM and HTIF attached, nothing else
+    fn hart() -> Vproc {
+        let mut proc = test_hart();
+        proc.htif = Some(Htif::new(TOHOST, Some(FROMHOST)));
+        proc
+    }
+
+    #[test]
+    fn commands_settle_for_a_step_before_they_run() {
+        let mut proc = hart();
+        write64(&mut proc, BLOCK, 1234);        // Unsupported syscall number
+        // High half first: device 0 command 0 with a zero payload is not a command yet
+        write64(&mut proc, TOHOST, 0);
+        poll(&mut proc);
+        write64(&mut proc, TOHOST, BLOCK as u64);
+        poll(&mut proc);
+        assert_eq!(read64(&mut proc, FROMHOST), Some(0));
+        assert_eq!(read64(&mut proc, BLOCK), Some(1234));
+        poll(&mut proc);
+        assert_eq!(read64(&mut proc, TOHOST), Some(0));
+        assert_eq!(read64(&mut proc, FROMHOST), Some(1));
+        assert_eq!(read64(&mut proc, BLOCK), Some(-ENOSYS as u64));
+    }
+
+    #[test]
+    fn proxies_syscalls_through_a_block() {
+        let mut proc = hart();
+        // A guest length far past RAM is capped to one chunk, not allocated
+        for (i, word) in [SYS_WRITE, 7, 0x2000, 1 << 40].iter().enumerate() {
+            write64(&mut proc, BLOCK + 8 * i, *word);
+        }
+        syscall(&mut proc, BLOCK);
+        assert_eq!(read64(&mut proc, BLOCK), Some(-EBADF as u64));
+
+        for (i, word) in [SYS_READ, 3, 0x2000, 16].iter().enumerate() {
+            write64(&mut proc, BLOCK + 8 * i, *word);
+        }
+        syscall(&mut proc, BLOCK);
+        assert_eq!(read64(&mut proc, BLOCK), Some(-EBADF as u64));
+    }
+
+    // Input that arrives only when the test sends it
+    struct Gate(std::sync::mpsc::Receiver<Vec<u8>>, Vec<u8>);
+
+    impl std::io::Read for Gate {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.1.is_empty() {
+                match self.0.recv() {
+                    Ok(bytes) => self.1 = bytes,
+                    Err(_) => return Ok(0),
+                }
+            }
+            let n = buf.len().min(self.1.len());
+            buf[..n].copy_from_slice(&self.1[..n]);
+            self.1.drain(..n);
+            Ok(n)
+        }
+    }
+
+    // Poll until tohost has been taken
+    fn settle(proc: &mut Vproc) {
+        for _ in 0..100_000 {
+            poll(proc);
+            if read64(proc, TOHOST) == Some(0) {
+                return;
+            }
+            std::thread::yield_now();
+        }
+        panic!("HTIF command never completed");
+    }
+
+    #[test]
+    fn getchar_answers_minus_one_without_input() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        console::set_source(Box::new(Gate(rx, vec![])));
+        let mut proc = hart();
+        let getchar = DEV_CONSOLE << 56 | CONSOLE_GETCHAR << 48;
+        write64(&mut proc, TOHOST, getchar);
+        settle(&mut proc);
+        assert_eq!(read64(&mut proc, FROMHOST), Some(getchar | NO_CHAR));
+
+        tx.send(b"q".to_vec()).unwrap();
+        while !console::ready() {
+            std::thread::yield_now();
+        }
+        write64(&mut proc, TOHOST, getchar);
+        settle(&mut proc);
+        assert_eq!(read64(&mut proc, FROMHOST), Some(getchar | 0x100 | b'q' as u64));
+    }
+
+    #[test]
+    fn console_reads_wait_in_tohost_until_input_arrives() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        console::set_source(Box::new(Gate(rx, vec![])));
+        let mut proc = hart();
+        for (i, word) in [SYS_READ, 0, 0x2000, 8].iter().enumerate() {
+            write64(&mut proc, BLOCK + 8 * i, *word);
+        }
+        write64(&mut proc, TOHOST, BLOCK as u64);
+        for _ in 0..10 {
+            poll(&mut proc);
+        }
+        assert_eq!(read64(&mut proc, TOHOST), Some(BLOCK as u64));
+        assert_eq!(read64(&mut proc, FROMHOST), Some(0));
+
+        tx.send(b"hi".to_vec()).unwrap();
+        settle(&mut proc);
+        assert_eq!(read64(&mut proc, FROMHOST), Some(1));
+        let n = read64(&mut proc, BLOCK).unwrap() as usize;
+        assert!(n == 1 || n == 2);
+        assert_eq!(proc.bus.read(0x2000, 1), Some(b'h' as isize));
+
+        // End of input answers 0 straight away
+        drop(tx);
+        for (i, word) in [SYS_READ, 0, 0x2000, 8].iter().enumerate() {
+            write64(&mut proc, BLOCK + 8 * i, *word);
+        }
+        while console::is_open() {
+            console::read_ready(&mut [0u8; 8]);     // Whatever of "hi" is left
+            std::thread::yield_now();
+        }
+        write64(&mut proc, TOHOST, BLOCK as u64);
+        settle(&mut proc);
+        assert_eq!(read64(&mut proc, BLOCK), Some(0));
+    }
+
+    #[test]
+    fn dumps_the_signature_region() {
+        let mut proc = hart();
+        let path = std::env::temp_dir().join(format!("riscsig-{}", std::process::id()));
+        proc.bus.write(0x3000, 4, 0xDEAD_BEEFu32 as isize);
+        proc.bus.write(0x3004, 4, 1);
+        proc.htif.as_mut().unwrap().set_signature(&path.to_string_lossy(), 0x3000, 0x300C);
+        dump_signature(&mut proc);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "deadbeef\n00000001\n00000000\n");
+        fs::remove_file(path).unwrap();
+    }
+}
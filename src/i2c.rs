@@ -0,0 +1,348 @@
+/* RISCulator - RISC-V Emulator */
+/*       I2C master device      */
+
+// Libraries here
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use colored::*;
+use crate::bus::{size_mask, Device};
+use crate::clint::CPU_FREQ;
+use crate::fdt::Fdt;
+use crate::pins::PinLog;
+
+// Register offsets (OpenCores i2c_master layout, one register per word)
+const PRER_LO: usize = 0x00;        // Clock prescaler
+const PRER_HI: usize = 0x04;
+const CTR: usize = 0x08;            // Control: EN, IEN
+const TXR_RXR: usize = 0x0C;        // Transmit (write) / receive (read)
+const CR_SR: usize = 0x10;          // Command (write) / status (read)
+pub const I2C_SIZE: usize = 0x1000;
+
+// Control, command and status bits
+const CTR_EN: u8 = 1 << 7;
+const CTR_IEN: u8 = 1 << 6;
+const CR_STA: u8 = 1 << 7;
+const CR_STO: u8 = 1 << 6;
+const CR_RD: u8 = 1 << 5;
+const CR_WR: u8 = 1 << 4;
+const CR_ACK: u8 = 1 << 3;          // Master sends NACK when set
+const CR_IACK: u8 = 1 << 0;
+const SR_RXACK: u8 = 1 << 7;        // Target did not acknowledge
+const SR_BUSY: u8 = 1 << 6;
+const SR_IF: u8 = 1 << 0;
+
+// External side: a target at one 7-bit address
+pub trait I2cDevice {
+    // Addressed after a (repeated) start
+    fn start(&mut self, read: bool) {}
+
+    // Byte from the master, true to acknowledge
+    fn write(&mut self, byte: u8) -> bool;
+
+    // Byte to the master
+    fn read(&mut self) -> u8;
+
+    fn stop(&mut self) {}
+}
+
+// Register-file target: the first byte written sets the register pointer,
+// later writes and reads auto-increment it (typical of sensors like the LM75)
+#[derive(Debug, Clone)]
+pub struct CannedRegs {
+    regs: [u8; 256],
+    pointer: u8,
+    pointer_set: bool,
+}
+
+// Canned register impl
+impl CannedRegs {
+    pub fn new() -> Self {
+        Self {
+            regs: [0; 256],
+            pointer: 0,
+            pointer_set: false,
+        }
+    }
+
+    pub fn set(&mut self, reg: u8, value: u8) {
+        self.regs[reg as usize] = value;
+    }
+}
+
+impl I2cDevice for CannedRegs {
+    fn start(&mut self, read: bool) {
+        self.pointer_set = read;        // A write transaction starts with the pointer
+    }
+
+    fn write(&mut self, byte: u8) -> bool {
+        if !self.pointer_set {
+            self.pointer = byte;
+            self.pointer_set = true;
+        }
+        else {
+            self.regs[self.pointer as usize] = byte;
+            self.pointer = self.pointer.wrapping_add(1);
+        }
+        true
+    }
+
+    fn read(&mut self) -> u8 {
+        let byte = self.regs[self.pointer as usize];
+        self.pointer = self.pointer.wrapping_add(1);
+        byte
+    }
+}
+
+// I2C master struct
+pub struct I2c {
+    targets: HashMap<u8, Box<dyn I2cDevice>>,
+    prescale: u16,
+    ctr: u8,
+    txr: u8,
+    rxr: u8,
+    sr: u8,
+    addressed: Option<u8>,      // Target in the current transaction
+    pending_start: bool,        // Next written byte is an address
+    clock: u64,
+    log: Rc<RefCell<PinLog>>,
+}
+
+// I2C master struct impl
+impl I2c {
+    pub fn new(log: Rc<RefCell<PinLog>>) -> Self {
+        Self {
+            targets: HashMap::new(),
+            prescale: 0xFFFF,
+            ctr: 0,
+            txr: 0,
+            rxr: 0,
+            sr: 0,
+            addressed: None,
+            pending_start: false,
+            clock: 0,
+            log,
+        }
+    }
+
+    // Connect a target at a 7-bit address
+    pub fn connect(&mut self, addr: u8, device: Box<dyn I2cDevice>) -> Result<(), String> {
+        if addr > 0x7F {
+            return Err(format!("I2C address {:#x} is not a 7-bit address", addr));
+        }
+        if self.targets.insert(addr, device).is_some() {
+            return Err(format!("I2C address {:#04x} connected twice", addr));
+        }
+        Ok(())
+    }
+
+    fn record(&self, event: &str) {
+        self.log.borrow_mut().record(self.clock, "i2c", event);
+    }
+
+    // Run a command; transfers complete immediately
+    fn command(&mut self, cr: u8) {
+        if cr & CR_IACK != 0 {
+            self.sr &= !SR_IF;
+        }
+        if self.ctr & CTR_EN == 0 || cr & (CR_STA | CR_STO | CR_RD | CR_WR) == 0 {
+            return;
+        }
+        if cr & CR_STA != 0 {
+            self.pending_start = true;
+            self.sr |= SR_BUSY;
+            self.record("start");
+        }
+        if cr & CR_WR != 0 {
+            let byte = self.txr;
+            let ack = if self.pending_start {
+                // Address byte: 7-bit address and R/W
+                self.pending_start = false;
+                let addr = byte >> 1;
+                let read = byte & 1 != 0;
+                let found = match self.targets.get_mut(&addr) {
+                    Some(target) => {
+                        target.start(read);
+                        true
+                    }
+                    None => false,
+                };
+                self.addressed = if found { Some(addr) } else { None };
+                self.record(&format!("addr={:#04x} {} {}", addr, if read { "read" } else { "write" }, if found { "ack" } else { "nack" }));
+                found
+            }
+            else {
+                let ack = match self.addressed.and_then(|addr| self.targets.get_mut(&addr)) {
+                    Some(target) => target.write(byte),
+                    None => false,
+                };
+                self.record(&format!("write={:#04x} {}", byte, if ack { "ack" } else { "nack" }));
+                ack
+            };
+            if ack { self.sr &= !SR_RXACK } else { self.sr |= SR_RXACK }
+        }
+        if cr & CR_RD != 0 {
+            self.rxr = match self.addressed.and_then(|addr| self.targets.get_mut(&addr)) {
+                Some(target) => target.read(),
+                None => 0xFF,       // Bus pulled high
+            };
+            self.record(&format!("read={:#04x} {}", self.rxr, if cr & CR_ACK != 0 { "nack" } else { "ack" }));
+        }
+        if cr & CR_STO != 0 {
+            if let Some(target) = self.addressed.and_then(|addr| self.targets.get_mut(&addr)) {
+                target.stop();
+            }
+            self.addressed = None;
+            self.sr &= !SR_BUSY;
+            self.record("stop");
+        }
+        self.sr |= SR_IF;
+    }
+}
+
+// I2C master device
+impl Device for I2c {
+    fn name(&self) -> &str {
+        "i2c"
+    }
+
+    fn read(&mut self, offset: usize, size: usize) -> Option<u64> {
+        let value = match offset {
+            PRER_LO => self.prescale & 0xFF,
+            PRER_HI => self.prescale >> 8,
+            CTR => self.ctr as u16,
+            TXR_RXR => self.rxr as u16,
+            CR_SR => self.sr as u16,
+            default => return None,
+        };
+        Some(value as u64 & size_mask(size))
+    }
+
+    fn write(&mut self, offset: usize, size: usize, value: u64) -> bool {
+        let byte = value as u8;
+        match offset {
+            PRER_LO => self.prescale = (self.prescale & 0xFF00) | byte as u16,
+            PRER_HI => self.prescale = (self.prescale & 0x00FF) | (byte as u16) << 8,
+            CTR => self.ctr = byte & (CTR_EN | CTR_IEN),
+            TXR_RXR => self.txr = byte,
+            CR_SR => self.command(byte),
+            default => return false,
+        }
+        true
+    }
+
+    fn tick(&mut self) {
+        self.clock += 1;
+    }
+
+    fn interrupt(&self) -> bool {
+        self.ctr & CTR_IEN != 0 && self.sr & SR_IF != 0
+    }
+
+    fn reset(&mut self) {
+        self.prescale = 0xFFFF;
+        self.ctr = 0;
+        self.txr = 0;
+        self.rxr = 0;
+        self.sr = 0;
+        self.addressed = None;
+        self.pending_start = false;
+    }
+
+    // One byte-wide register per word
+    fn describe(&self, fdt: &mut Fdt, base: usize, size: usize, irq: Option<usize>) {
+        fdt.soc_device("i2c", &["opencores,i2c-ocores"], base, size, irq)
+            .u32("reg-shift", 2)
+            .u32("reg-io-width", 1)
+            .u32("clock-frequency", CPU_FREQ as u32)
+            .u32("#address-cells", 1)
+            .u32("#size-cells", 0);
+    }
+
+    fn print_dirty(&mut self) {
+        println!("{}", "--------------------------------".green());
+        println!("{}", "I2C".green());
+        println!("{}", "--------------------------------".green());
+        println!("ctr: {:#04x} sr: {:#04x}, {} targets", self.ctr, self.sr, self.targets.len());
+        println!("{}", "--------------------------------".green());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEMP: u8 = 0x48;
+
+    // An enabled I2C master with a register-file target at 0x48
+    fn i2c() -> I2c {
+        let mut i2c = I2c::new(PinLog::new(None).unwrap());
+        let mut regs = CannedRegs::new();
+        regs.set(0, 0x19);
+        regs.set(1, 0x80);
+        i2c.connect(TEMP, Box::new(regs)).unwrap();
+        i2c.write(CTR, 4, CTR_EN as u64);
+        i2c
+    }
+
+    // Shift out a byte with the given command bits, returning the status
+    fn send(i2c: &mut I2c, byte: u8, cr: u8) -> u8 {
+        i2c.write(TXR_RXR, 4, byte as u64);
+        i2c.write(CR_SR, 4, (cr | CR_WR) as u64);
+        i2c.read(CR_SR, 4).unwrap() as u8
+    }
+
+    fn receive(i2c: &mut I2c, cr: u8) -> u8 {
+        i2c.write(CR_SR, 4, (cr | CR_RD) as u64);
+        i2c.read(TXR_RXR, 4).unwrap() as u8
+    }
+
+    #[test]
+    fn register_reads_and_writes_reach_the_target() {
+        let mut i2c = i2c();
+        // Set the pointer to register 1, then read two registers after a repeated start
+        let status = send(&mut i2c, TEMP << 1, CR_STA);
+        assert_eq!(status & (SR_RXACK | SR_BUSY | SR_IF), SR_BUSY | SR_IF);
+        assert_eq!(send(&mut i2c, 1, 0) & SR_RXACK, 0);
+        assert_eq!(send(&mut i2c, TEMP << 1 | 1, CR_STA) & SR_RXACK, 0);
+        assert_eq!(receive(&mut i2c, 0), 0x80);
+        assert_eq!(receive(&mut i2c, CR_ACK | CR_STO), 0);
+        assert_eq!(i2c.read(CR_SR, 4).unwrap() as u8 & SR_BUSY, 0);
+
+        // Write register 0, then read it back
+        send(&mut i2c, TEMP << 1, CR_STA);
+        send(&mut i2c, 0, 0);
+        send(&mut i2c, 0x42, CR_STO);
+        send(&mut i2c, TEMP << 1, CR_STA);
+        send(&mut i2c, 0, 0);
+        send(&mut i2c, TEMP << 1 | 1, CR_STA);
+        assert_eq!(receive(&mut i2c, CR_ACK | CR_STO), 0x42);
+    }
+
+    #[test]
+    fn absent_targets_nack_and_read_high() {
+        let mut i2c = i2c();
+        assert_ne!(send(&mut i2c, 0x50 << 1 | 1, CR_STA) & SR_RXACK, 0);
+        assert_eq!(receive(&mut i2c, CR_ACK | CR_STO), 0xFF);
+        assert!(i2c.connect(TEMP, Box::new(CannedRegs::new())).is_err());
+        assert!(i2c.connect(0x80, Box::new(CannedRegs::new())).is_err());
+    }
+
+    #[test]
+    fn interrupt_follows_ien_and_iack() {
+        let mut i2c = i2c();
+        send(&mut i2c, TEMP << 1, CR_STA | CR_STO);
+        assert!(!i2c.interrupt());
+        i2c.write(CTR, 4, (CTR_EN | CTR_IEN) as u64);
+        assert!(i2c.interrupt());
+        i2c.write(CR_SR, 4, CR_IACK as u64);
+        assert!(!i2c.interrupt());
+        // A disabled core ignores commands
+        i2c.write(CTR, 4, CTR_IEN as u64);
+        assert_eq!(send(&mut i2c, TEMP << 1, CR_STA), 0);
+        // Prescaler halves read back
+        i2c.write(PRER_LO, 4, 0x1C7);
+        i2c.write(PRER_HI, 4, 0x02);
+        assert_eq!((i2c.read(PRER_LO, 4), i2c.read(PRER_HI, 4)), (Some(0xC7), Some(0x02)));
+    }
+}
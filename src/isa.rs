@@ -0,0 +1,418 @@
+/* RISCulator - RISC-V Emulator */
+/*      ISA string parsing      */
+
+// Libraries here
+use std::fmt;
+
+// Single-letter extensions in canonical order (after the base)
+const CANONICAL_ORDER: &str = "mafdqlcbkjtpvh";
+
+// Single-letter extensions the emulator implements
+const SUPPORTED_LETTERS: &str = "ie";
+
+// Multi-letter extensions the emulator implements
+const SUPPORTED_Z: [&str; 5] = ["zicsr", "zicntr", "zihpm", "zifencei", "zkr"];
+
+// Multi-letter extensions the parser knows about
+const KNOWN_Z: [&str; 24] = [
+    "zicsr", "zicntr", "zihpm", "zifencei", "zicbom", "zicboz", "zihintpause",
+    "zmmul", "zaamo", "zalrsc", "zfh", "zfhmin", "zfinx", "zdinx",
+    "zca", "zcb", "zcf", "zcd", "zba", "zbb", "zbc", "zbs", "zkr", "zkn",
+];
+
+// Extension dependencies: (extension, requires)
+const DEPENDENCIES: [(&str, &str); 13] = [
+    ("f", "zicsr"),
+    ("d", "f"),
+    ("q", "d"),
+    ("v", "d"),
+    ("h", "i"),
+    ("zicntr", "zicsr"),
+    ("zihpm", "zicsr"),
+    ("zfh", "f"),
+    ("zfhmin", "f"),
+    ("zcf", "f"),
+    ("zcd", "d"),
+    ("zdinx", "zfinx"),
+    ("zkr", "zicsr"),
+];
+
+// Extension pairs that cannot be enabled together
+const CONFLICTS: [(&str, &str); 3] = [
+    ("i", "e"),
+    ("f", "zfinx"),
+    ("q", "e"),
+];
+
+// ISA Struct
+#[derive(Debug, Clone)]
+pub struct Isa {
+    pub xlen: usize,
+    pub misa: isize,
+    pub zext: Vec<String>,
+    pub unimplemented: Vec<String>,     // Known extensions in the string that RISCulator cannot execute
+}
+
+// ISA Struct traits
+impl Isa {
+    // Parse and validate an ISA string like rv32i_zicsr_zifencei
+    pub fn parse(isa: &str) -> Result<Isa, String> {
+        let lower = isa.to_lowercase();
+        let rest = if let Some(rest) = lower.strip_prefix("rv32") {
+            rest
+        }
+        else if lower.starts_with("rv64") || lower.starts_with("rv128") {
+            return Err(format!("{}: only RV32 is supported", isa));
+        }
+        else {
+            return Err(format!("{}: ISA string must start with rv32", isa));
+        };
+
+        let mut tokens = rest.split('_').filter(|t| !t.is_empty());
+        let letters = match tokens.next() {
+            Some(letters) => letters,
+            None => return Err(format!("{}: missing base ISA (i, e or g)", isa)),
+        };
+
+        // Single-letter extensions, with g expanded to imafd_zicsr_zifencei
+        let mut exts: Vec<String> = vec![];
+        let mut chars = split_letters(letters, isa)?.into_iter();
+        match chars.next() {
+            Some('i') => exts.push("i".to_string()),
+            Some('e') => exts.push("e".to_string()),
+            Some('g') => {
+                for ext in ["i", "m", "a", "f", "d", "zicsr", "zifencei"] {
+                    exts.push(ext.to_string());
+                }
+            }
+            _ => return Err(format!("{}: base ISA must be i, e or g", isa)),
+        }
+        let mut last = 0;
+        for letter in chars {
+            let pos = match CANONICAL_ORDER.find(letter) {
+                Some(pos) => pos + 1,
+                None if letter == 'i' || letter == 'e' || letter == 'g' => {
+                    return Err(format!("{}: only one base ISA (i, e or g) may be given", isa));
+                }
+                None => return Err(format!("{}: unknown extension '{}'", isa, letter)),
+            };
+            if pos <= last {
+                return Err(format!("{}: extension '{}' is out of canonical order ({})", isa, letter, CANONICAL_ORDER));
+            }
+            last = pos;
+            push_unique(&mut exts, &letter.to_string(), isa)?;
+        }
+
+        // Multi-letter extensions
+        for token in tokens {
+            let token = strip_version(token);
+            if token.len() == 1 && CANONICAL_ORDER.contains(token) {
+                return Err(format!("{}: single-letter extension '{}' must come before multi-letter ones", isa, token));
+            }
+            if !KNOWN_Z.contains(&token) {
+                return Err(format!("{}: unknown extension '{}'", isa, token));
+            }
+            push_unique(&mut exts, token, isa)?;
+        }
+
+        for (ext, requires) in DEPENDENCIES {
+            if exts.iter().any(|e| e == ext) && !exts.iter().any(|e| e == requires) {
+                return Err(format!("{}: extension '{}' requires '{}'", isa, ext, requires));
+            }
+        }
+        for (a, b) in CONFLICTS {
+            if exts.iter().any(|e| e == a) && exts.iter().any(|e| e == b) {
+                return Err(format!("{}: extensions '{}' and '{}' cannot be combined", isa, a, b));
+            }
+        }
+
+        // misa and zext only advertise what the hart can execute, the rest is recorded and trapped at decode
+        let mut misa: isize = 1 << 30;      // MXL = 1 (XLEN 32)
+        let mut zext: Vec<String> = vec![];
+        let mut unimplemented: Vec<String> = vec![];
+        for ext in exts {
+            if !implemented(&ext) {
+                unimplemented.push(ext);
+            }
+            else if ext.len() == 1 {
+                misa |= 1 << (ext.as_bytes()[0] - b'a');
+            }
+            else {
+                zext.push(ext);
+            }
+        }
+        Ok(Isa {
+            xlen: 32,
+            misa,
+            zext,
+            unimplemented,
+        })
+    }
+
+    // Check a single-letter extension
+    pub fn has(&self, ext: char) -> bool {
+        let bit = (ext.to_ascii_lowercase() as u8).wrapping_sub(b'a');
+        bit < 26 && self.misa & (1 << bit) != 0
+    }
+
+    // Check a multi-letter extension
+    pub fn has_z(&self, ext: &str) -> bool {
+        self.zext.iter().any(|e| e == ext)
+    }
+
+    // Is an extension named in the ISA string, implemented or not
+    pub fn names(&self, ext: &str) -> bool {
+        let named = if ext.len() == 1 { self.has(ext.chars().next().unwrap()) } else { self.has_z(ext) };
+        named || self.unimplemented.iter().any(|e| e == ext)
+    }
+
+    // Number of integer registers (16 for RV32E)
+    pub fn reg_count(&self) -> usize {
+        if self.has('e') { 16 } else { 32 }
+    }
+}
+
+// Canonical ISA string
+impl fmt::Display for Isa {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "rv{}", self.xlen)?;
+        for bit in 0..26 {
+            if self.misa & (1 << bit) != 0 {
+                write!(f, "{}", (b'a' + bit as u8) as char)?;
+            }
+        }
+        for ext in &self.zext {
+            write!(f, "_{}", ext)?;
+        }
+        Ok(())
+    }
+}
+
+// Can the hart execute this extension
+fn implemented(ext: &str) -> bool {
+    if ext.len() == 1 { SUPPORTED_LETTERS.contains(ext) } else { SUPPORTED_Z.contains(&ext) }
+}
+
+// Split the single-letter run into letters, dropping the version after each one (i2p1m2p0 is i, m)
+fn split_letters(run: &str, isa: &str) -> Result<Vec<char>, String> {
+    let bytes = run.as_bytes();
+    let digits = |mut i: usize| {
+        while i < bytes.len() && bytes[i].is_ascii_digit() {
+            i += 1;
+        }
+        i
+    };
+    let mut letters = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let letter = bytes[i] as char;
+        if !letter.is_ascii_lowercase() {
+            return Err(format!("{}: unexpected '{}' in '{}'", isa, letter, run));
+        }
+        letters.push(letter);
+        i += 1;
+        // <major>[p<minor>]; a p not followed by a digit is the P extension
+        let major = digits(i);
+        if major > i && major + 1 < bytes.len() && bytes[major] == b'p' && bytes[major + 1].is_ascii_digit() {
+            i = digits(major + 1);
+        }
+        else {
+            i = major;
+        }
+    }
+    Ok(letters)
+}
+
+// Drop a trailing version number like 2p0
+fn strip_version(token: &str) -> &str {
+    let trimmed = token.trim_end_matches(|c: char| c.is_ascii_digit());
+    match trimmed.strip_suffix('p') {
+        Some(major) if major.ends_with(|c: char| c.is_ascii_digit()) => major.trim_end_matches(|c: char| c.is_ascii_digit()),
+        _ => trimmed,
+    }
+}
+
+// Add an extension, rejecting duplicates
+fn push_unique(exts: &mut Vec<String>, ext: &str, isa: &str) -> Result<(), String> {
+    if exts.iter().any(|e| e == ext) {
+        return Err(format!("{}: extension '{}' given twice", isa, ext));
+    }
+    exts.push(ext.to_string());
+    Ok(())
+}
+
+// Which extension an instruction (as a 32-character bit string) belongs to, None for the base ISA
+fn extension_of(instr: &[&str]) -> Option<&'static str> {
+    let opcode = instr[25..].join("");
+    let funct3 = instr[17..20].join("");
+    let funct7 = instr[0..7].join("");
+    let fmt = instr[5..7].join("");
+    match opcode.as_str() {
+        "0110011" => match (funct7.as_str(), funct3.as_str()) {
+            ("0000001", _) => Some("m"),
+            ("0010000", _) => Some("zba"),
+            ("0100000", "100") | ("0100000", "110") | ("0100000", "111") => Some("zbb"),
+            ("0110000", _) | ("0000100", "100") => Some("zbb"),
+            ("0000101", "001") | ("0000101", "010") | ("0000101", "011") => Some("zbc"),
+            ("0000101", _) => Some("zbb"),
+            ("0100100", _) | ("0010100", _) | ("0110100", _) => Some("zbs"),
+            _ => None,
+        },
+        "0010011" if funct3 == "001" || funct3 == "101" => match funct7.as_str() {
+            "0110000" => Some("zbb"),
+            "0010100" if funct3 == "101" => Some("zbb"),      // orc.b
+            "0110100" if funct3 == "101" => Some("zbb"),      // rev8
+            "0100100" | "0010100" | "0110100" => Some("zbs"),
+            _ => None,
+        },
+        "0101111" => Some("a"),
+        "0000111" | "0100111" => match funct3.as_str() {
+            "001" => Some("zfh"),
+            "011" => Some("d"),
+            "100" => Some("q"),
+            _ => Some("f"),
+        },
+        "1000011" | "1000111" | "1001011" | "1001111" | "1010011" => match fmt.as_str() {
+            "01" => Some("d"),
+            "10" => Some("zfh"),
+            "11" => Some("q"),
+            _ => Some("f"),
+        },
+        "0001111" if funct3 == "001" => Some("zifencei"),
+        "0001111" if funct3 == "010" => if instr[7..12].join("") == "00100" { Some("zicboz") } else { Some("zicbom") },
+        "1110011" if funct3 != "000" => Some("zicsr"),
+        _ => None,
+    }
+}
+
+// Is this instruction (as a 32-character bit string) part of an enabled and implemented extension
+pub fn permitted(instr: &[&str], isa: &Isa) -> Result<(), String> {
+    let opcode = instr[25..].join("");
+    let funct3 = instr[17..20].join("");
+    if let Some(ext) = extension_of(instr) {
+        // A covers Zaamo/Zalrsc and M covers Zmmul
+        let named = isa.names(ext) || match ext {
+            "a" => isa.names("zaamo") || isa.names("zalrsc"),
+            "m" => isa.names("zmmul") && funct3.starts_with('0'),
+            default => false,
+        };
+        if !named {
+            return Err(format!("not part of the enabled ISA ({})", isa));
+        }
+        if isa.unimplemented.iter().any(|e| e == ext) || !implemented(ext) {
+            return Err(format!("'{}' is enabled but not implemented by RISCulator", ext));
+        }
+    }
+
+    // RV32E only has x0-x15
+    if isa.reg_count() == 16 {
+        let rd = instr[20] == "1";
+        let rs1 = instr[12] == "1";
+        let rs2 = instr[7] == "1";
+        let bad = match opcode.as_str() {
+            "0110011" => rd || rs1 || rs2,                  // R-type
+            "0100011" | "1100011" => rs1 || rs2,            // S/B-type
+            "0110111" | "0010111" | "1101111" => rd,        // U/J-type
+            "1110011" if funct3.starts_with('1') => rd,     // CSR immediate forms
+            _ => rd || rs1,                                 // I-type
+        };
+        if bad {
+            return Err(format!("RV32E only has x0-x15 ({})", isa));
+        }
+    }
+    Ok(())
+}
+
+// Are counter and entropy CSRs enabled (Zicntr for cycle/time/instret, Zihpm for hpmcounters, Zkr for seed)
+pub fn csr_permitted(addr: usize, isa: &Isa) -> bool {
+    match addr {
+        0xC00..=0xC02 | 0xC80..=0xC82 => isa.has_z("zicntr"),
+        0xC03..=0xC1F | 0xC83..=0xC9F => isa.has_z("zihpm"),
+        0x015 => isa.has_z("zkr"),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 32-character bit string split the way the decoder sees it
+    fn bits(word: u32) -> String {
+        format!("{:032b}", word)
+    }
+
+    fn split(line: &str) -> Vec<&str> {
+        line.split("").filter(|b| !b.is_empty()).collect()
+    }
+
+    #[test]
+    fn parses_the_default_isa() {
+        let isa = Isa::parse("rv32i_zicsr_zicntr_zihpm_zifencei_zkr").unwrap();
+        assert_eq!(isa.xlen, 32);
+        assert_eq!(isa.misa, (1 << 30) | (1 << 8));
+        assert_eq!(isa.zext, ["zicsr", "zicntr", "zihpm", "zifencei", "zkr"]);
+        assert!(isa.unimplemented.is_empty());
+        assert_eq!(isa.to_string(), "rv32i_zicsr_zicntr_zihpm_zifencei_zkr");
+    }
+
+    #[test]
+    fn records_known_but_unimplemented_extensions() {
+        let isa = Isa::parse("rv32imac_zicsr_zifencei_zba_zbb").unwrap();
+        assert_eq!(isa.unimplemented, ["m", "a", "c", "zba", "zbb"]);
+        assert!(!isa.has('m'));
+        assert!(isa.names("m"));
+        assert!(isa.names("zbb"));
+        assert_eq!(isa.to_string(), "rv32i_zicsr_zifencei");
+    }
+
+    #[test]
+    fn strips_versions_after_every_letter() {
+        let isa = Isa::parse("RV32I2P1M2P0_Zicsr2p0").unwrap();
+        assert_eq!(isa.unimplemented, ["m"]);
+        assert_eq!(isa.zext, ["zicsr"]);
+        assert_eq!(split_letters("i2p1m2p0", "").unwrap(), ['i', 'm']);
+        assert_eq!(split_letters("i2m", "").unwrap(), ['i', 'm']);
+        // A p without a minor number is the P extension
+        assert_eq!(split_letters("i2p", "").unwrap(), ['i', 'p']);
+        assert_eq!(strip_version("zicsr2p0"), "zicsr");
+        assert_eq!(strip_version("zba"), "zba");
+    }
+
+    #[test]
+    fn rejects_malformed_strings() {
+        for bad in ["rv64i", "i", "rv32", "rv32m", "rv32ie", "rv32ima_zfoo", "rv32iam", "rv32im_zicsr_m", "rv32ii", "rv32if", "rv32i_zfh", "rv32i#"] {
+            assert!(Isa::parse(bad).is_err(), "{} should be rejected", bad);
+        }
+    }
+
+    #[test]
+    fn expands_g() {
+        let isa = Isa::parse("rv32g").unwrap();
+        assert_eq!(isa.unimplemented, ["m", "a", "f", "d"]);
+        assert_eq!(isa.zext, ["zicsr", "zifencei"]);
+    }
+
+    #[test]
+    fn gates_instructions_at_decode() {
+        let mul = bits(0x0220_81b3);        // mul x3, x1, x2
+        let add = bits(0x0020_81b3);        // add x3, x1, x2
+        let sh1add = bits(0x2020_a1b3);     // sh1add x3, x1, x2
+        let csrrs = bits(0xc000_2573);      // csrr x10, cycle
+        let base = Isa::parse("rv32i").unwrap();
+        let wide = Isa::parse("rv32im_zicsr_zba").unwrap();
+
+        assert!(permitted(&split(&add), &base).is_ok());
+        assert!(permitted(&split(&mul), &base).unwrap_err().contains("not part of the enabled ISA"));
+        assert!(permitted(&split(&mul), &wide).unwrap_err().contains("not implemented"));
+        assert!(permitted(&split(&sh1add), &base).is_err());
+        assert!(permitted(&split(&sh1add), &wide).unwrap_err().contains("'zba'"));
+        assert!(permitted(&split(&csrrs), &base).is_err());
+        assert!(permitted(&split(&csrrs), &wide).is_ok());
+
+        // RV32E has no x16-x31
+        let e = Isa::parse("rv32e").unwrap();
+        assert!(permitted(&split(&bits(0x0020_81b3)), &e).is_ok());
+        assert!(permitted(&split(&bits(0x0020_8833)), &e).is_err());     // add x16, x1, x2
+    }
+}
@@ -0,0 +1,868 @@
+/* RISCulator - RISC-V Emulator */
+/*  Linux user-mode emulation   */
+
+// Libraries here
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{File, Metadata, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::{utils, Mode, Register, Vproc};
+use crate::bus::Bus;
+use crate::console;
+use crate::csr;
+use crate::elf;
+use crate::elf::Elf;
+use crate::entropy::EntropySource;
+
+/*
+ * User-mode emulation (--user): a static RV32 Linux executable runs in U-mode with
+ * no kernel underneath; ECALLs are translated to host calls here and any other
+ * exception ends the program the way the matching signal would.
+ */
+
+// Syscall numbers (asm-generic table used by RISC-V)
+const SYS_IOCTL: isize = 29;
+const SYS_OPENAT: isize = 56;
+const SYS_CLOSE: isize = 57;
+const SYS_LSEEK: isize = 62;            // llseek on RV32
+const SYS_READ: isize = 63;
+const SYS_WRITE: isize = 64;
+const SYS_WRITEV: isize = 66;
+const SYS_NEWFSTATAT: isize = 79;
+const SYS_FSTAT: isize = 80;
+const SYS_EXIT: isize = 93;
+const SYS_EXIT_GROUP: isize = 94;
+const SYS_SET_TID_ADDRESS: isize = 96;
+const SYS_SET_ROBUST_LIST: isize = 99;
+const SYS_CLOCK_GETTIME: isize = 113;
+const SYS_RT_SIGACTION: isize = 134;
+const SYS_RT_SIGPROCMASK: isize = 135;
+const SYS_UNAME: isize = 160;
+const SYS_GETPID: isize = 172;
+const SYS_GETUID: isize = 174;
+const SYS_GETEUID: isize = 175;
+const SYS_GETGID: isize = 176;
+const SYS_GETEGID: isize = 177;
+const SYS_GETTID: isize = 178;
+const SYS_BRK: isize = 214;
+const SYS_MUNMAP: isize = 215;
+const SYS_MMAP: isize = 222;            // mmap2 on RV32: offset in pages
+const SYS_MPROTECT: isize = 226;
+const SYS_GETRANDOM: isize = 278;
+const SYS_STATX: isize = 291;
+const SYS_CLOCK_GETTIME64: isize = 403;
+
+// Error numbers (returned negated)
+const ENOENT: isize = 2;
+const EBADF: isize = 9;
+const ENOMEM: isize = 12;
+const EFAULT: isize = 14;
+const EINVAL: isize = 22;
+const ENOTTY: isize = 25;
+const ESPIPE: isize = 29;
+const ENOSYS: isize = 38;
+
+// Flags
+const AT_FDCWD: isize = -100;
+const AT_SYMLINK_NOFOLLOW: isize = 0x100;
+const AT_EMPTY_PATH: isize = 0x1000;
+const O_ACCMODE: isize = 0x3;
+const O_CREAT: isize = 0x40;
+const O_EXCL: isize = 0x80;
+const O_TRUNC: isize = 0x200;
+const O_APPEND: isize = 0x400;
+const MAP_FIXED: isize = 0x10;
+const MAP_ANONYMOUS: isize = 0x20;
+const CLOCK_REALTIME: isize = 0;
+const STATX_BASIC_STATS: u32 = 0x7FF;
+
+// Auxiliary vector keys
+const AT_NULL: usize = 0;
+const AT_PHDR: usize = 3;
+const AT_PHENT: usize = 4;
+const AT_PHNUM: usize = 5;
+const AT_PAGESZ: usize = 6;
+const AT_ENTRY: usize = 9;
+const AT_UID: usize = 11;
+const AT_EUID: usize = 12;
+const AT_GID: usize = 13;
+const AT_EGID: usize = 14;
+const AT_HWCAP: usize = 16;
+const AT_CLKTCK: usize = 17;
+const AT_SECURE: usize = 23;
+const AT_RANDOM: usize = 25;
+const AT_EXECFN: usize = 31;
+
+const PAGE_SIZE: usize = 4096;
+const STACK_SIZE: usize = 0x10000;      // mmap allocations grow down from below the stack
+const GUEST_PID: isize = 1;
+const IOV_MAX: isize = 1024;
+pub const IO_CHUNK: usize = 0x10000;    // Largest single read/write
+
+// Guest file descriptor
+#[derive(Debug)]
+pub enum Fd {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+// Fd impl: host-side I/O for a guest descriptor
+impl Fd {
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Fd::Stdin => Ok(console::read(buf)),
+            Fd::File(file) => file.read(buf),
+            default => Err(io::Error::from_raw_os_error(EBADF as i32)),
+        }
+    }
+
+    // Stdout is flushed so guest output keeps its place among the log lines
+    pub fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Fd::Stdout => io::stdout().write_all(buf).and_then(|_| io::stdout().flush()),
+            Fd::Stderr => io::stderr().write_all(buf),
+            Fd::File(file) => file.write_all(buf),
+            default => Err(io::Error::from_raw_os_error(EBADF as i32)),
+        }
+    }
+
+    // lseek with SEEK_SET/SEEK_CUR/SEEK_END; the standard streams cannot seek
+    pub fn seek(&mut self, offset: i64, whence: isize) -> Result<u64, isize> {
+        let pos = match (self, whence) {
+            (Fd::File(file), 0) => file.seek(SeekFrom::Start(offset as u64)),
+            (Fd::File(file), 1) => file.seek(SeekFrom::Current(offset)),
+            (Fd::File(file), 2) => file.seek(SeekFrom::End(offset)),
+            (Fd::File(_), _) => return Err(-EINVAL),
+            default => return Err(-ESPIPE),
+        };
+        pos.map_err(|err| errno(&err))
+    }
+
+    pub fn stat(&self) -> Result<Stat, isize> {
+        match self {
+            Fd::File(file) => file.metadata().map(|meta| Stat::from_metadata(&meta)).map_err(|err| errno(&err)),
+            default => Ok(Stat::stdio()),
+        }
+    }
+}
+
+// Where an open() flavour keeps its flag bits (O_ACCMODE is the same everywhere)
+pub struct OpenFlags {
+    pub creat: isize,
+    pub excl: isize,
+    pub trunc: isize,
+    pub append: isize,
+}
+
+const LINUX_OPEN: OpenFlags = OpenFlags { creat: O_CREAT, excl: O_EXCL, trunc: O_TRUNC, append: O_APPEND };
+
+// Descriptor table shared by the Linux and newlib syscall layers
+#[derive(Debug)]
+pub struct Fds {
+    fds: HashMap<isize, Fd>,
+}
+
+// Fds impl
+impl Fds {
+    pub fn new() -> Self {
+        Self {
+            fds: HashMap::from([(0, Fd::Stdin), (1, Fd::Stdout), (2, Fd::Stderr)]),
+        }
+    }
+
+    pub fn get(&mut self, fd: isize) -> Option<&mut Fd> {
+        self.fds.get_mut(&fd)
+    }
+
+    // Lowest free descriptor
+    pub fn add(&mut self, fd: Fd) -> isize {
+        let n = (0..).find(|n| !self.fds.contains_key(n)).unwrap();
+        self.fds.insert(n, fd);
+        n
+    }
+
+    pub fn close(&mut self, fd: isize) -> isize {
+        match self.fds.remove(&fd) {
+            Some(_) => 0,
+            None => -EBADF,
+        }
+    }
+
+    // read(2) into guest memory, at most a chunk at a time
+    pub fn read(&mut self, bus: &mut Bus, fd: isize, addr: usize, len: usize) -> isize {
+        let mut buf = vec![0u8; len.min(IO_CHUNK)];
+        let count = match self.fds.get_mut(&fd) {
+            Some(file) => file.read(&mut buf),
+            None => return -EBADF,
+        };
+        match count {
+            Ok(n) if write_mem(bus, addr, &buf[..n]) => n as isize,
+            Ok(_) => -EFAULT,
+            Err(err) => errno(&err),
+        }
+    }
+
+    pub fn write(&mut self, fd: isize, buf: &[u8]) -> isize {
+        let result = match self.fds.get_mut(&fd) {
+            Some(file) => file.write(buf),
+            None => return -EBADF,
+        };
+        match result {
+            Ok(()) => buf.len() as isize,
+            Err(err) => errno(&err),
+        }
+    }
+
+    // open(2) with `flags` in the caller's encoding
+    pub fn open(&mut self, path: &str, flags: isize, mode: isize, bits: &OpenFlags) -> isize {
+        let mut options = OpenOptions::new();
+        options.read(flags & O_ACCMODE != 1)
+            .write(flags & O_ACCMODE != 0)
+            .append(flags & bits.append != 0)
+            .truncate(flags & bits.trunc != 0)
+            .mode(mode as u32 & 0o7777);
+        if flags & bits.creat != 0 {
+            if flags & bits.excl != 0 { options.create_new(true); } else { options.create(true); }
+        }
+        match options.open(path) {
+            Ok(file) => self.add(Fd::File(file)),
+            Err(err) => errno(&err),
+        }
+    }
+}
+
+// File status, encoded as struct stat, stat64 or statx for the guest
+pub struct Stat {
+    dev: u64,
+    ino: u64,
+    mode: u32,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    rdev: u64,
+    size: u64,
+    blksize: u32,
+    blocks: u64,
+    atime: (i64, u32),
+    mtime: (i64, u32),
+    ctime: (i64, u32),
+}
+
+// Stat impl
+impl Stat {
+    pub fn from_metadata(meta: &Metadata) -> Self {
+        Self {
+            dev: meta.dev(),
+            ino: meta.ino(),
+            mode: meta.mode(),
+            nlink: meta.nlink() as u32,
+            uid: meta.uid(),
+            gid: meta.gid(),
+            rdev: meta.rdev(),
+            size: meta.size(),
+            blksize: meta.blksize() as u32,
+            blocks: meta.blocks(),
+            atime: (meta.atime(), meta.atime_nsec() as u32),
+            mtime: (meta.mtime(), meta.mtime_nsec() as u32),
+            ctime: (meta.ctime(), meta.ctime_nsec() as u32),
+        }
+    }
+
+    // Standard streams look like a terminal-less character device
+    pub fn stdio() -> Self {
+        Self {
+            dev: 0, ino: 0, mode: 0o020620, nlink: 1, uid: 0, gid: 0, rdev: 0,
+            size: 0, blksize: 1024, blocks: 0, atime: (0, 0), mtime: (0, 0), ctime: (0, 0),
+        }
+    }
+
+    // asm-generic struct stat for 32-bit targets
+    fn to_stat(&self) -> Vec<u8> {
+        let words = [
+            self.dev as u32, self.ino as u32, self.mode, self.nlink, self.uid, self.gid, self.rdev as u32, 0,
+            self.size as u32, self.blksize, 0, self.blocks as u32,
+            self.atime.0 as u32, self.atime.1, self.mtime.0 as u32, self.mtime.1, self.ctime.0 as u32, self.ctime.1, 0, 0,
+        ];
+        words.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+
+    // 64-bit layout with 64-bit time_t, as libgloss expects it
+    pub fn to_stat64(&self) -> Vec<u8> {
+        let mut out = vec![0u8; 128];
+        let mut put = |offset: usize, bytes: &[u8]| out[offset..offset + bytes.len()].copy_from_slice(bytes);
+        put(0, &self.dev.to_le_bytes());
+        put(8, &self.ino.to_le_bytes());
+        put(16, &self.mode.to_le_bytes());
+        put(20, &self.nlink.to_le_bytes());
+        put(24, &self.uid.to_le_bytes());
+        put(28, &self.gid.to_le_bytes());
+        put(32, &self.rdev.to_le_bytes());
+        put(48, &self.size.to_le_bytes());
+        put(56, &self.blksize.to_le_bytes());
+        put(64, &self.blocks.to_le_bytes());
+        for (offset, (sec, nsec)) in [(72, self.atime), (88, self.mtime), (104, self.ctime)] {
+            put(offset, &sec.to_le_bytes());
+            put(offset + 8, &nsec.to_le_bytes());
+        }
+        out
+    }
+
+    // struct statx
+    fn to_statx(&self) -> Vec<u8> {
+        let mut out = vec![0u8; 0x100];
+        let mut put = |offset: usize, bytes: &[u8]| out[offset..offset + bytes.len()].copy_from_slice(bytes);
+        put(0x00, &STATX_BASIC_STATS.to_le_bytes());
+        put(0x04, &self.blksize.to_le_bytes());
+        put(0x10, &self.nlink.to_le_bytes());
+        put(0x14, &self.uid.to_le_bytes());
+        put(0x18, &self.gid.to_le_bytes());
+        put(0x1C, &(self.mode as u16).to_le_bytes());
+        put(0x20, &self.ino.to_le_bytes());
+        put(0x28, &self.size.to_le_bytes());
+        put(0x30, &self.blocks.to_le_bytes());
+        for (offset, (sec, nsec)) in [(0x40, self.atime), (0x60, self.ctime), (0x70, self.mtime)] {
+            put(offset, &sec.to_le_bytes());
+            put(offset + 8, &nsec.to_le_bytes());
+        }
+        put(0x80, &major(self.rdev).to_le_bytes());
+        put(0x84, &minor(self.rdev).to_le_bytes());
+        put(0x88, &major(self.dev).to_le_bytes());
+        put(0x8C, &minor(self.dev).to_le_bytes());
+        out
+    }
+}
+
+// Device number halves (glibc encoding)
+fn major(dev: u64) -> u32 {
+    (((dev >> 8) & 0xFFF) | ((dev >> 32) & !0xFFF)) as u32
+}
+
+fn minor(dev: u64) -> u32 {
+    ((dev & 0xFF) | ((dev >> 12) & !0xFF)) as u32
+}
+
+// Linux process state
+#[derive(Debug)]
+pub struct Linux {
+    fds: Fds,
+    brk_start: usize,
+    brk: usize,
+    mmap_top: usize,
+    stack_base: usize,      // Lowest stack address; mappings stay below it
+    entropy: EntropySource,
+}
+
+// Load a static executable and start it in U-mode with argv and envp on its stack
+pub fn start(proc: &mut Vproc, argv: &[String], envp: &[String], top: usize, entropy: EntropySource) -> Result<(), String> {
+    let elf = elf::load(&argv[0], &mut proc.bus)?;
+    let brk = page_up(elf.end);
+    if brk + STACK_SIZE > top {
+        return Err(format!("{}: no room left for the heap and stack", argv[0]));
+    }
+    let mut linux = Linux {
+        fds: Fds::new(),
+        brk_start: brk,
+        brk,
+        mmap_top: top - STACK_SIZE,
+        stack_base: top - STACK_SIZE,
+        entropy,
+    };
+    let sp = linux.setup_stack(&mut proc.bus, &elf, argv, envp, top, proc.misa as usize & 0x3FF_FFFF)?;
+
+    // U-mode may touch all memory and read the counters
+    proc.csrs.write(csr::PMPADDR0, -1);
+    proc.csrs.write(csr::PMPCFG0, 0x1F);
+    proc.csrs.write(csr::MCOUNTEREN, -1);
+    proc.csrs.write(csr::SCOUNTEREN, -1);
+    proc.regs.write(2, sp as isize);
+    proc.mode = Mode::User;
+    proc.linux = Some(linux);
+    utils::set_pc(elf.entry as isize);
+    log::info!("Linux user mode: {} entry {:#010x}, sp {:#010x}, brk {:#010x}", argv[0], elf.entry, sp, brk);
+    Ok(())
+}
+
+// Linux impl
+impl Linux {
+    // Initial stack: argc, argv, envp and auxv, with the strings above them
+    fn setup_stack(&mut self, bus: &mut Bus, elf: &Elf, argv: &[String], envp: &[String], top: usize, hwcap: usize) -> Result<usize, String> {
+        let mut sp = top;
+        let mut push = |bus: &mut Bus, bytes: &[u8]| -> usize {
+            sp -= bytes.len();
+            write_mem(bus, sp, bytes);
+            sp
+        };
+        let execfn = push(bus, format!("{}\0", argv[0]).as_bytes());
+        let argv_ptrs: Vec<usize> = argv.iter().map(|arg| push(bus, format!("{}\0", arg).as_bytes())).collect();
+        let envp_ptrs: Vec<usize> = envp.iter().map(|var| push(bus, format!("{}\0", var).as_bytes())).collect();
+        let random: Vec<u8> = (0..2).flat_map(|_| self.entropy.next().to_le_bytes()).collect();
+        let random = push(bus, &random);
+
+        let mut table = vec![argv.len()];
+        table.extend(&argv_ptrs);
+        table.push(0);
+        table.extend(&envp_ptrs);
+        table.push(0);
+        for (key, value) in [
+            (AT_PHDR, elf.phdr), (AT_PHENT, elf.phent), (AT_PHNUM, elf.phnum), (AT_PAGESZ, PAGE_SIZE),
+            (AT_ENTRY, elf.entry), (AT_UID, 0), (AT_EUID, 0), (AT_GID, 0), (AT_EGID, 0),
+            (AT_HWCAP, hwcap), (AT_CLKTCK, 100), (AT_SECURE, 0), (AT_RANDOM, random), (AT_EXECFN, execfn), (AT_NULL, 0),
+        ] {
+            table.push(key);
+            table.push(value);
+        }
+        let sp = (random - table.len() * 4) & !0xF;
+        if sp < self.mmap_top {
+            return Err("arguments and environment do not fit on the stack".to_string());
+        }
+        let bytes: Vec<u8> = table.iter().flat_map(|word| (*word as u32).to_le_bytes()).collect();
+        write_mem(bus, sp, &bytes);
+        Ok(sp)
+    }
+
+    // Run one system call, returning the value for a0 (negative errno on failure)
+    fn call(&mut self, proc: &mut Vproc, nr: isize, a: &[isize]) -> isize {
+        match nr {
+            SYS_READ => self.fds.read(&mut proc.bus, signed(a[0]), a[1] as usize, a[2] as usize),
+            SYS_WRITE => match read_mem(&mut proc.bus, a[1] as usize, (a[2] as usize).min(IO_CHUNK)) {
+                Some(buf) => self.fds.write(signed(a[0]), &buf),
+                None => -EFAULT,
+            },
+            SYS_WRITEV => {
+                let count = signed(a[2]);
+                if !(0..=IOV_MAX).contains(&count) {
+                    return -EINVAL;
+                }
+                let mut total = 0;
+                for i in 0..count as usize {
+                    let iov = match read_mem(&mut proc.bus, a[1] as usize + 8 * i, 8) {
+                        Some(iov) => iov,
+                        None => return -EFAULT,
+                    };
+                    let base = u32::from_le_bytes([iov[0], iov[1], iov[2], iov[3]]) as usize;
+                    let len = u32::from_le_bytes([iov[4], iov[5], iov[6], iov[7]]) as usize;
+                    let written = match read_mem(&mut proc.bus, base, len.min(IO_CHUNK)) {
+                        Some(buf) => self.fds.write(signed(a[0]), &buf),
+                        None => -EFAULT,
+                    };
+                    if written < 0 {
+                        return if total > 0 { total } else { written };
+                    }
+                    total += written;
+                }
+                total
+            }
+            SYS_OPENAT => {
+                let path = match read_cstr(&mut proc.bus, a[1] as usize) {
+                    Some(path) => path,
+                    None => return -EFAULT,
+                };
+                if signed(a[0]) != AT_FDCWD && !path.starts_with('/') {
+                    log::warn!("Linux: openat relative to descriptor {} is not supported", signed(a[0]));
+                    return -EBADF;
+                }
+                self.fds.open(&path, a[2], a[3], &LINUX_OPEN)
+            }
+            SYS_CLOSE => self.fds.close(signed(a[0])),
+            SYS_LSEEK => {
+                // llseek: the offset comes in two halves and the result goes to memory
+                let offset = (a[1] as i64) << 32 | a[2] as i64;
+                let pos = match self.fds.get(signed(a[0])) {
+                    Some(file) => file.seek(offset, a[4]),
+                    None => return -EBADF,
+                };
+                match pos {
+                    Ok(pos) if write_mem(&mut proc.bus, a[3] as usize, &pos.to_le_bytes()) => 0,
+                    Ok(_) => -EFAULT,
+                    Err(err) => err,
+                }
+            }
+            SYS_FSTAT => match self.stat_fd(signed(a[0])) {
+                Ok(stat) => self.put(proc, a[1], &stat.to_stat()),
+                Err(err) => err,
+            },
+            SYS_NEWFSTATAT => match self.stat_at(proc, signed(a[0]), a[1], a[3]) {
+                Ok(stat) => self.put(proc, a[2], &stat.to_stat()),
+                Err(err) => err,
+            },
+            SYS_STATX => match self.stat_at(proc, signed(a[0]), a[1], a[2]) {
+                Ok(stat) => self.put(proc, a[4], &stat.to_statx()),
+                Err(err) => err,
+            },
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                let status = signed(a[0]) as i32 & 0xFF;
+                log::info!("Linux: guest exited with status {}", status);
+                utils::exit(proc, status);
+            }
+            SYS_BRK => {
+                let addr = a[0] as usize;
+                if addr >= self.brk_start && addr <= self.mmap_top {
+                    if addr > self.brk {
+                        write_mem(&mut proc.bus, self.brk, &vec![0; addr - self.brk]);
+                    }
+                    self.brk = addr;
+                }
+                self.brk as isize
+            }
+            SYS_MMAP => {
+                let size = page_up(a[1] as usize);
+                let (flags, fd, offset) = (a[3], signed(a[4]), a[5] as u64 * PAGE_SIZE as u64);
+                if size == 0 {
+                    return -EINVAL;
+                }
+                let start = if flags & MAP_FIXED != 0 {
+                    // Only between the heap and the stack, never over the image
+                    let addr = a[0] as usize;
+                    if addr % PAGE_SIZE != 0 || addr < page_up(self.brk) || addr + size > self.stack_base {
+                        return -EINVAL;
+                    }
+                    addr
+                }
+                else {
+                    match self.mmap_top.checked_sub(size).filter(|start| *start >= page_up(self.brk)) {
+                        Some(start) => {
+                            self.mmap_top = start;
+                            start
+                        }
+                        None => return -ENOMEM,
+                    }
+                };
+                let mut data = vec![0u8; size];
+                if flags & MAP_ANONYMOUS == 0 {
+                    let read = match self.fds.get(fd) {
+                        Some(Fd::File(file)) => file.seek(SeekFrom::Start(offset)).and_then(|_| read_up_to(file, &mut data)),
+                        default => return -EBADF,
+                    };
+                    if let Err(err) = read {
+                        return errno(&err);
+                    }
+                }
+                if !write_mem(&mut proc.bus, start, &data) {
+                    return -ENOMEM;
+                }
+                start as isize
+            }
+            SYS_MUNMAP => {
+                // Only the most recent mapping is given back
+                if a[0] as usize == self.mmap_top {
+                    self.mmap_top += page_up(a[1] as usize);
+                }
+                0
+            }
+            SYS_CLOCK_GETTIME | SYS_CLOCK_GETTIME64 => {
+                let (sec, nsec) = self.clock(proc, signed(a[0]));
+                let bytes: Vec<u8> = if nr == SYS_CLOCK_GETTIME64 {
+                    [(sec as u64).to_le_bytes(), (nsec as u64).to_le_bytes()].concat()
+                } else {
+                    [(sec as u32).to_le_bytes(), (nsec as u32).to_le_bytes()].concat()
+                };
+                self.put(proc, a[1], &bytes)
+            }
+            SYS_GETRANDOM => {
+                let len = (a[1] as usize).min(IO_CHUNK);
+                let bytes: Vec<u8> = (0..len.div_ceil(8)).flat_map(|_| self.entropy.next().to_le_bytes()).take(len).collect();
+                match self.put(proc, a[0], &bytes) {
+                    0 => len as isize,
+                    err => err,
+                }
+            }
+            SYS_UNAME => {
+                let mut buf = vec![0u8; 6 * 65];
+                for (i, field) in ["Linux", "risculator", "6.1.0", "#1 RISCulator", "riscv32", "(none)"].iter().enumerate() {
+                    buf[i * 65..i * 65 + field.len()].copy_from_slice(field.as_bytes());
+                }
+                self.put(proc, a[0], &buf)
+            }
+            SYS_IOCTL => -ENOTTY,       // No terminals: stdio is fully buffered
+            SYS_SET_TID_ADDRESS | SYS_GETPID | SYS_GETTID => GUEST_PID,
+            SYS_GETUID | SYS_GETEUID | SYS_GETGID | SYS_GETEGID => 0,
+            SYS_SET_ROBUST_LIST | SYS_RT_SIGACTION | SYS_RT_SIGPROCMASK | SYS_MPROTECT => 0,   // Signals are never delivered
+            default => {
+                log::warn!("Linux: unimplemented syscall {} ({:#x}, {:#x}, {:#x})", nr, a[0], a[1], a[2]);
+                -ENOSYS
+            }
+        }
+    }
+
+    // Copy a result out to the guest
+    fn put(&self, proc: &mut Vproc, addr: isize, bytes: &[u8]) -> isize {
+        if write_mem(&mut proc.bus, addr as usize, bytes) { 0 } else { -EFAULT }
+    }
+
+    fn stat_fd(&mut self, fd: isize) -> Result<Stat, isize> {
+        self.fds.get(fd).ok_or(-EBADF)?.stat()
+    }
+
+    // fstatat/statx path resolution (relative to the working directory only)
+    fn stat_at(&mut self, proc: &mut Vproc, dirfd: isize, path: isize, flags: isize) -> Result<Stat, isize> {
+        let path = read_cstr(&mut proc.bus, path as usize).ok_or(-EFAULT)?;
+        if path.is_empty() {
+            return if flags & AT_EMPTY_PATH != 0 { self.stat_fd(dirfd) } else { Err(-ENOENT) };
+        }
+        if dirfd != AT_FDCWD && !path.starts_with('/') {
+            return Err(-EBADF);
+        }
+        let meta = if flags & AT_SYMLINK_NOFOLLOW != 0 { fs::symlink_metadata(&path) } else { fs::metadata(&path) };
+        meta.map(|meta| Stat::from_metadata(&meta)).map_err(|err| errno(&err))
+    }
+
+    // Wall-clock time for CLOCK_REALTIME, emulated time for every other clock
+    fn clock(&self, proc: &Vproc, clock: isize) -> (u64, u64) {
+        if clock == CLOCK_REALTIME {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            return (now.as_secs(), now.subsec_nanos() as u64);
+        }
+        let clint = proc.clint.borrow();
+        let (mtime, timebase) = (clint.mtime(), clint.timebase());
+        (mtime / timebase, (mtime % timebase) * 1_000_000_000 / timebase)
+    }
+}
+
+// Serve an ECALL from U-mode
+pub fn syscall(proc: &mut Vproc, regs: &mut Register) {
+    let mut linux = match proc.linux.take() {
+        Some(linux) => linux,
+        None => return,
+    };
+    let args: Vec<isize> = (10..16).map(|reg| proc.regs.regs[reg] & 0xFFFF_FFFF).collect();
+    let nr = proc.regs.regs[17] & 0xFFFF_FFFF;
+    let ret = linux.call(proc, nr, &args);
+    log::info!("Linux: syscall {} = {}", nr, ret);
+    regs.write(10, ret);
+    proc.linux = Some(linux);
+}
+
+// An exception with no kernel to handle it ends the program like the matching signal
+pub fn fatal_trap(cause: isize, tval: isize, pc: isize) -> ! {
+    let (name, signal) = match cause {
+        csr::CAUSE_ILLEGAL_INSTRUCTION => ("SIGILL", 4),
+        csr::CAUSE_BREAKPOINT => ("SIGTRAP", 5),
+        0 | csr::CAUSE_LOAD_ADDRESS_MISALIGNED | csr::CAUSE_STORE_ADDRESS_MISALIGNED => ("SIGBUS", 7),
+        default => ("SIGSEGV", 11),
+    };
+    let _ = io::stdout().flush();
+    log::error!("Linux: guest killed by {} (cause {}, tval {:#010x}, pc {:#010x})", name, cause, tval, pc);
+    process::exit(128 + signal);
+}
+
+// Sign-extend a 32-bit argument
+pub fn signed(value: isize) -> isize {
+    value as u32 as i32 as isize
+}
+
+fn page_up(addr: usize) -> usize {
+    (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+// Host error as a negated errno
+pub fn errno(err: &io::Error) -> isize {
+    -(err.raw_os_error().unwrap_or(EINVAL as i32) as isize)
+}
+
+// Read until the buffer is full or the file ends
+fn read_up_to(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+// Guest memory, byte by byte through the bus (physical addresses)
+pub fn read_mem(bus: &mut Bus, addr: usize, len: usize) -> Option<Vec<u8>> {
+    (0..len).map(|i| bus.read(addr + i, 1).map(|byte| byte as u8)).collect()
+}
+
+pub fn write_mem(bus: &mut Bus, addr: usize, data: &[u8]) -> bool {
+    data.iter().enumerate().all(|(i, byte)| bus.write(addr + i, 1, *byte as isize))
+}
+
+pub fn read_cstr(bus: &mut Bus, addr: usize) -> Option<String> {
+    let mut bytes = vec![];
+    for i in 0..PAGE_SIZE {
+        match bus.read(addr + i, 1)? {
+            0 => return Some(String::from_utf8_lossy(&bytes).into_owned()),
+            byte => bytes.push(byte as u8),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_hart;
+
+    const TOP: usize = 0x40000;             // End of RAM in the test hart
+    const HEAP: usize = 0x8000;
+    const ANON: isize = MAP_ANONYMOUS | 0x2;       // MAP_PRIVATE | MAP_ANONYMOUS
+
+    // A process with its heap at HEAP and the stack at the top of RAM
+    fn linux() -> Linux {
+        Linux {
+            fds: Fds::new(),
+            brk_start: HEAP,
+            brk: HEAP,
+            mmap_top: TOP - STACK_SIZE,
+            stack_base: TOP - STACK_SIZE,
+            entropy: EntropySource::Seeded(1),
+        }
+    }
+
+    fn word(proc: &mut Vproc, addr: usize) -> usize {
+        let bytes = read_mem(&mut proc.bus, addr, 4).unwrap();
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize
+    }
+
+    fn put_words(proc: &mut Vproc, addr: usize, words: &[usize]) {
+        let bytes: Vec<u8> = words.iter().flat_map(|word| (*word as u32).to_le_bytes()).collect();
+        assert!(write_mem(&mut proc.bus, addr, &bytes));
+    }
+
+    fn temp_path(tag: &str) -> String {
+        std::env::temp_dir().join(format!("risculator-linux-{}-{}", tag, std::process::id())).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn brk_and_mmap_stay_between_heap_and_stack() {
+        let mut proc = test_hart();
+        let mut linux = linux();
+        let stack_base = (TOP - STACK_SIZE) as isize;
+        assert_eq!(linux.call(&mut proc, SYS_BRK, &[0; 6]), HEAP as isize);
+        assert_eq!(linux.call(&mut proc, SYS_BRK, &[0x9000, 0, 0, 0, 0, 0]), 0x9000);
+        assert_eq!(linux.call(&mut proc, SYS_BRK, &[stack_base + 1, 0, 0, 0, 0, 0]), 0x9000);
+        assert_eq!(linux.call(&mut proc, SYS_BRK, &[0x7000, 0, 0, 0, 0, 0]), 0x9000);
+
+        // Anonymous mappings grow down from the stack and the last one can be returned
+        let mmap = |linux: &mut Linux, proc: &mut Vproc, addr: isize, len: isize, flags: isize| {
+            linux.call(proc, SYS_MMAP, &[addr, len, 3, flags, -1, 0])
+        };
+        assert_eq!(mmap(&mut linux, &mut proc, 0, 0x1800, ANON), stack_base - 0x2000);
+        assert_eq!(mmap(&mut linux, &mut proc, 0, 0x1000, ANON), stack_base - 0x3000);
+        assert_eq!(linux.call(&mut proc, SYS_MUNMAP, &[stack_base - 0x3000, 0x1000, 0, 0, 0, 0]), 0);
+        assert_eq!(mmap(&mut linux, &mut proc, 0, 0x1000, ANON), stack_base - 0x3000);
+        assert_eq!(mmap(&mut linux, &mut proc, 0, 0, ANON), -EINVAL);
+        assert_eq!(mmap(&mut linux, &mut proc, 0, stack_base, ANON), -ENOMEM);
+
+        // The heap cannot grow into the mappings
+        assert_eq!(linux.call(&mut proc, SYS_BRK, &[stack_base - 0x2000, 0, 0, 0, 0, 0]), 0x9000);
+
+        // Fixed mappings only between the heap and the stack, page-aligned
+        let fixed = ANON | MAP_FIXED;
+        assert_eq!(mmap(&mut linux, &mut proc, 0xA000, 0x1000, fixed), 0xA000);
+        assert_eq!(mmap(&mut linux, &mut proc, 0xA800, 0x1000, fixed), -EINVAL);
+        assert_eq!(mmap(&mut linux, &mut proc, 0x8000, 0x1000, fixed), -EINVAL);
+        assert_eq!(mmap(&mut linux, &mut proc, stack_base - 0x1000, 0x2000, fixed), -EINVAL);
+    }
+
+    #[test]
+    fn writev_gathers_and_bounds_the_vector() {
+        let mut proc = test_hart();
+        let mut linux = linux();
+        let path = temp_path("writev");
+        let fd = linux.fds.open(&path, 1 | O_CREAT | O_TRUNC, 0o644, &LINUX_OPEN);
+        assert!(fd > 2);
+        assert!(write_mem(&mut proc.bus, 0x2000, b"abc"));
+        assert!(write_mem(&mut proc.bus, 0x2200, b"defg"));
+        put_words(&mut proc, 0x1000, &[0x2000, 3, 0x2100, 0, 0x2200, 4]);
+        assert_eq!(linux.call(&mut proc, SYS_WRITEV, &[fd, 0x1000, 3, 0, 0, 0]), 7);
+        assert_eq!(linux.call(&mut proc, SYS_WRITEV, &[fd, 0x1000, 0, 0, 0, 0]), 0);
+        assert_eq!(fs::read(&path).unwrap(), b"abcdefg");
+
+        // iovcnt outside 0..=IOV_MAX
+        assert_eq!(linux.call(&mut proc, SYS_WRITEV, &[fd, 0x1000, 0xFFFF_FFFF, 0, 0, 0]), -EINVAL);
+        assert_eq!(linux.call(&mut proc, SYS_WRITEV, &[fd, 0x1000, IOV_MAX + 1, 0, 0, 0]), -EINVAL);
+        // A bad buffer after a good one reports the bytes already written
+        put_words(&mut proc, 0x1000, &[0x2000, 3, 0x8000_0000, 4]);
+        assert_eq!(linux.call(&mut proc, SYS_WRITEV, &[fd, 0x1000, 2, 0, 0, 0]), 3);
+        assert_eq!(linux.call(&mut proc, SYS_WRITEV, &[fd, 0x1008, 1, 0, 0, 0]), -EFAULT);
+        assert_eq!(linux.call(&mut proc, SYS_WRITEV, &[9, 0x1000, 1, 0, 0, 0]), -EBADF);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn stat_layouts_match_the_abi() {
+        let mut proc = test_hart();
+        let mut linux = linux();
+        let path = temp_path("stat");
+        fs::write(&path, b"12345").unwrap();
+        assert!(write_mem(&mut proc.bus, 0x1000, format!("{}\0", path).as_bytes()));
+
+        // struct stat: st_mode at 8, st_size at 32, st_blksize at 36
+        let fd = linux.fds.open(&path, 0, 0, &LINUX_OPEN);
+        assert_eq!(linux.call(&mut proc, SYS_FSTAT, &[fd, 0x2000, 0, 0, 0, 0]), 0);
+        assert_eq!(word(&mut proc, 0x2000 + 8) & 0o170000, 0o100000);
+        assert_eq!(word(&mut proc, 0x2000 + 32), 5);
+        let meta = fs::metadata(&path).unwrap();
+        assert_eq!(word(&mut proc, 0x2000 + 36), meta.blksize() as usize);
+        assert_eq!(word(&mut proc, 0x2000 + 48), meta.mtime() as u32 as usize);
+
+        // struct statx: stx_mask at 0, stx_mode at 0x1C, stx_size at 0x28
+        assert_eq!(linux.call(&mut proc, SYS_STATX, &[AT_FDCWD & 0xFFFF_FFFF, 0x1000, 0, 0, 0x3000, 0]), 0);
+        assert_eq!(word(&mut proc, 0x3000) as u32, STATX_BASIC_STATS);
+        assert_eq!(word(&mut proc, 0x3000 + 0x1C) & 0o170000, 0o100000);
+        assert_eq!(word(&mut proc, 0x3000 + 0x28), 5);
+
+        // Standard streams are character devices; a closed descriptor is EBADF
+        assert_eq!(linux.call(&mut proc, SYS_FSTAT, &[1, 0x2000, 0, 0, 0, 0]), 0);
+        assert_eq!(word(&mut proc, 0x2000 + 8), 0o020620);
+        assert_eq!(linux.call(&mut proc, SYS_FSTAT, &[9, 0x2000, 0, 0, 0, 0]), -EBADF);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn initial_stack_holds_argv_envp_and_auxv() {
+        let mut proc = test_hart();
+        let mut linux = linux();
+        let elf = Elf { entry: 0x100, phdr: 0x34, phent: 32, phnum: 2, end: 0x1000, symbols: HashMap::new() };
+        let argv = ["prog".to_string(), "arg".to_string()];
+        let envp = ["A=1".to_string()];
+        let sp = linux.setup_stack(&mut proc.bus, &elf, &argv, &envp, TOP, 0x1100).unwrap();
+        assert_eq!(sp % 16, 0);
+        assert!(sp >= TOP - STACK_SIZE);
+
+        let cstr = |proc: &mut Vproc, addr: usize| read_cstr(&mut proc.bus, addr).unwrap();
+        assert_eq!(word(&mut proc, sp), 2);
+        let arg0 = word(&mut proc, sp + 4);
+        let arg1 = word(&mut proc, sp + 8);
+        assert_eq!(cstr(&mut proc, arg0), "prog");
+        assert_eq!(cstr(&mut proc, arg1), "arg");
+        assert_eq!(word(&mut proc, sp + 12), 0);
+        let env0 = word(&mut proc, sp + 16);
+        assert_eq!(cstr(&mut proc, env0), "A=1");
+        assert_eq!(word(&mut proc, sp + 20), 0);
+
+        // auxv pairs up to AT_NULL
+        let mut auxv = HashMap::new();
+        let mut at = sp + 24;
+        loop {
+            let (key, value) = (word(&mut proc, at), word(&mut proc, at + 4));
+            if key == AT_NULL {
+                break;
+            }
+            auxv.insert(key, value);
+            at += 8;
+        }
+        assert_eq!(auxv[&AT_PAGESZ], PAGE_SIZE);
+        assert_eq!(auxv[&AT_ENTRY], 0x100);
+        assert_eq!((auxv[&AT_PHDR], auxv[&AT_PHENT], auxv[&AT_PHNUM]), (0x34, 32, 2));
+        assert_eq!(auxv[&AT_HWCAP], 0x1100);
+        let execfn = auxv[&AT_EXECFN];
+        assert_eq!(cstr(&mut proc, execfn), "prog");
+        // 16 random bytes between the table and the strings
+        assert!(auxv[&AT_RANDOM] > at && auxv[&AT_RANDOM] + 16 <= arg0.min(env0));
+
+        // Arguments that would run into the mappings are refused
+        let huge = vec!["x".repeat(0x1000); 20];
+        assert!(linux.setup_stack(&mut proc.bus, &elf, &huge, &[], TOP, 0).is_err());
+    }
+}
@@ -0,0 +1,373 @@
+/* RISCulator - RISC-V Emulator */
+/*  Lockstep against a commit log */
+
+// Libraries here
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::process;
+use colored::*;
+use crate::{Mode, Register, Vproc, REG_SIZE};
+
+/*
+ * Differential testing (--lockstep <log>)
+ *
+ * Every retired instruction is checked against the next entry of a reference commit
+ * log, as written by `spike --log-commits`:
+ *
+ *   core   0: 3 0x80000010 (0x0182a283) x5  0x80000000 mem 0x80000018
+ *   core   0: 3 0x80000014 (0x00b2a023) mem 0x80001000 0x00000001
+ *   core   0: 3 0x80000018 (0x30529073) c773_mtvec 0x80000004
+ *
+ * that is privilege, PC and instruction followed by the register writes, the memory
+ * accesses (stores carry the value) and the CSR writes. Other lines are ignored, as are
+ * the entries before the first one at our start PC (Spike's boot ROM). Instructions
+ * that trap are not retired, so they have no entry of their own. At the first
+ * divergence the last --lockstep-context instructions are shown side by side and the
+ * emulator exits.
+ */
+
+pub const DIVERGED: i32 = 3;       // Exit status on a mismatch
+pub const CONTEXT: usize = 10;     // Default instructions shown before the divergence
+const MASK: u64 = 0xFFFF_FFFF;      // XLEN is 32; RV64 logs are compared on the low half
+
+// One retired instruction: what it wrote
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Commit {
+    prv: u64,
+    pc: u64,
+    insn: u64,
+    regs: Vec<(usize, u64)>,
+    mem: Vec<(u64, u64)>,       // Stores: address and value
+    csrs: Vec<(usize, u64)>,
+    trap: Option<isize>,        // Only ours: the cause, when the instruction trapped
+}
+
+// Commit log layout, with stores as address=value
+impl fmt::Display for Commit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {:#010x} ({:#010x})", self.prv, self.pc, self.insn)?;
+        for (reg, value) in &self.regs {
+            write!(f, " x{} {:#010x}", reg, value)?;
+        }
+        for (addr, value) in &self.mem {
+            write!(f, " mem {:#010x}={:#x}", addr, value)?;
+        }
+        for (csr, value) in &self.csrs {
+            write!(f, " c{:03x} {:#010x}", csr, value)?;
+        }
+        if let Some(cause) = self.trap {
+            write!(f, " trap {}", cause)?;
+        }
+        Ok(())
+    }
+}
+
+// Lockstep state
+#[derive(Debug)]
+pub struct Lockstep {
+    path: String,
+    reference: Vec<(usize, Commit)>,        // Log line number and entry
+    next: usize,
+    synced: bool,
+    retired: usize,
+    context: usize,
+    history: VecDeque<(Commit, Commit)>,    // Ours and the reference, oldest first
+    stores: Vec<(u64, u64)>,                // Written by the current instruction
+    csr_writes: Vec<usize>,
+    trap: Option<isize>,
+}
+
+// Lockstep impl
+impl Lockstep {
+    pub fn load(path: &str, context: usize) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+        let reference: Vec<(usize, Commit)> = text.lines().enumerate()
+            .filter_map(|(n, line)| parse_line(line).map(|commit| (n + 1, commit)))
+            .collect();
+        if reference.is_empty() {
+            return Err(format!("{}: no commit log entries (was it written with --log-commits?)", path));
+        }
+        log::info!("Lockstep: {} reference instructions from {}", reference.len(), path);
+        Ok(Self {
+            path: path.to_string(),
+            reference,
+            next: 0,
+            synced: false,
+            retired: 0,
+            context,
+            history: VecDeque::new(),
+            stores: vec![],
+            csr_writes: vec![],
+            trap: None,
+        })
+    }
+
+    // Reference entries not reached yet
+    pub fn remaining(&self) -> usize {
+        self.reference.len() - self.next
+    }
+
+    // Print the window before the divergence and stop
+    fn diverge(&self, ours: &Commit, reference: Option<&(usize, Commit)>, reasons: &[String]) -> ! {
+        let _ = io::stdout().flush();
+        let position = match reference {
+            Some((line, _)) => format!("{}:{}", self.path, line),
+            None => format!("{} (end of log)", self.path),
+        };
+        log::error!("Lockstep: divergence at instruction {} ({})", self.retired + 1, position);
+        for reason in reasons {
+            log::error!("Lockstep: {}", reason);
+        }
+        let rows: Vec<(String, String)> = self.history.iter()
+            .map(|(ours, theirs)| (ours.to_string(), theirs.to_string()))
+            .chain(std::iter::once((ours.to_string(), reference.map_or("<none>".to_string(), |(_, commit)| commit.to_string()))))
+            .collect();
+        let width = rows.iter().map(|(ours, _)| ours.len()).max().unwrap_or(0).max("RISCulator".len());
+        let first = self.retired + 1 - self.history.len();
+        println!("{}", "--------------------------------".green());
+        println!("         {:<width$} | {}", "RISCulator", "Reference", width = width);
+        for (i, (ours, theirs)) in rows.iter().enumerate() {
+            let row = format!("{:>8} {:<width$} | {}", first + i, ours, theirs, width = width);
+            if i == rows.len() - 1 { println!("{}", row.red()); } else { println!("{}", row); }
+        }
+        println!("{}", "--------------------------------".green());
+        process::exit(DIVERGED);
+    }
+}
+
+fn hex(token: &str) -> Option<u64> {
+    u64::from_str_radix(token.strip_prefix("0x")?, 16).ok()
+}
+
+// A commit line, or None for anything else Spike prints
+fn parse_line(line: &str) -> Option<Commit> {
+    let (_, rest) = line.trim().strip_prefix("core")?.split_once(':')?;
+    let mut tokens = rest.split_whitespace().peekable();
+    let prv = tokens.next()?.parse::<u64>().ok()?;
+    let pc = hex(tokens.next()?)? & MASK;
+    let insn = hex(tokens.next()?.strip_prefix('(')?.strip_suffix(')')?)?;
+    let mut commit = Commit { prv, pc, insn, ..Default::default() };
+    while let Some(token) = tokens.next() {
+        if token == "mem" {
+            let addr = hex(tokens.next()?)? & MASK;
+            // Loads log only the address
+            if let Some(value) = tokens.peek().and_then(|value| hex(value)) {
+                tokens.next();
+                commit.mem.push((addr, value));
+            }
+        }
+        else if let Some(reg) = token.strip_prefix('x').and_then(|reg| reg.parse::<usize>().ok()) {
+            let value = hex(tokens.next()?)? & MASK;
+            if reg != 0 {       // Spike logs the discarded result of x0 writes
+                commit.regs.push((reg, value));
+            }
+        }
+        else if let Some((csr, _)) = token.strip_prefix('c').and_then(|csr| csr.split_once('_')) {
+            let csr = csr.parse::<usize>().ok()?;      // Decimal: c768_mstatus
+            commit.csrs.push((csr, hex(tokens.next()?)? & MASK));
+        }
+        // FP and vector state is not modelled; its tokens are skipped
+    }
+    commit.regs.sort();
+    commit.csrs.sort();
+    Some(commit)
+}
+
+// Start of an instruction: forget the previous one's side effects
+pub fn begin(proc: &mut Vproc) {
+    if let Some(lockstep) = proc.lockstep.as_mut() {
+        lockstep.stores.clear();
+        lockstep.csr_writes.clear();
+        lockstep.trap = None;
+    }
+}
+
+// A store that reached the bus
+pub fn store(proc: &mut Vproc, addr: isize, size: usize, value: isize) {
+    if let Some(lockstep) = proc.lockstep.as_mut() {
+        let mask = if size >= 8 { u64::MAX } else { (1u64 << (8 * size)) - 1 };
+        lockstep.stores.push((addr as u64 & MASK, value as u64 & mask));
+    }
+}
+
+// An explicit CSR write (CSRRW and friends)
+pub fn csr_write(proc: &mut Vproc, csr: usize) {
+    if let Some(lockstep) = proc.lockstep.as_mut() {
+        lockstep.csr_writes.push(csr);
+    }
+}
+
+// A synchronous trap; interrupts come between instructions and are not compared
+pub fn trap(proc: &mut Vproc, cause: isize) {
+    if let Some(lockstep) = proc.lockstep.as_mut() {
+        lockstep.trap = Some(cause);
+    }
+}
+
+// Compare the instruction at `pc` against the next reference entry
+pub fn check(proc: &mut Vproc, prv: Mode, pc: isize, insn: isize, regs: &Register) {
+    let mut lockstep = match proc.lockstep.take() {
+        Some(lockstep) => lockstep,
+        None => return,
+    };
+    let pc = pc as u64 & MASK;
+    let mut ours = Commit {
+        prv: prv as u64,
+        pc,
+        insn: insn as u64 & MASK,
+        regs: (1..REG_SIZE).filter(|&reg| regs.dirty_bit[reg] == 1)
+            .map(|reg| (reg, regs.regs[reg] as u64 & MASK))
+            .collect(),
+        mem: lockstep.stores.clone(),
+        csrs: vec![],
+        trap: lockstep.trap,
+    };
+
+    // Skip whatever ran before our entry point (Spike's reset vector and boot ROM)
+    if !lockstep.synced {
+        match lockstep.reference.iter().position(|(_, commit)| commit.pc == pc) {
+            Some(start) => {
+                if start > 0 {
+                    log::info!("Lockstep: skipping {} reference instructions before {:#010x}", start, pc);
+                }
+                lockstep.next = start;
+                lockstep.synced = true;
+            }
+            None => lockstep.diverge(&ours, None, &[format!("start PC {:#010x} never appears in the reference", pc)]),
+        }
+    }
+
+    // A trapping instruction has no entry; the reference must not have retired it
+    if let Some(cause) = ours.trap {
+        if let Some(entry) = lockstep.reference.get(lockstep.next) {
+            if entry.1.pc == pc {
+                let reason = format!("trapped with cause {} where the reference retired the instruction", cause);
+                lockstep.diverge(&ours, Some(entry), &[reason]);
+            }
+        }
+        proc.lockstep = Some(lockstep);
+        return;
+    }
+
+    let entry = match lockstep.reference.get(lockstep.next) {
+        Some(entry) => entry.clone(),
+        None => {
+            log::info!("Lockstep: all {} reference instructions matched, comparison stopped", lockstep.retired);
+            return;
+        }
+    };
+    let theirs = &entry.1;
+
+    // Our CSR values after the instruction, for the ones either side wrote
+    let mut written: Vec<usize> = lockstep.csr_writes.iter().copied()
+        .chain(theirs.csrs.iter().map(|(csr, _)| *csr))
+        .collect();
+    written.sort();
+    written.dedup();
+    ours.csrs = written.iter().map(|&csr| (csr, proc.csrs.read(csr & 0xFFF) as u64 & MASK)).collect();
+
+    let mut reasons: Vec<String> = vec![];
+    if ours.pc != theirs.pc {
+        reasons.push(format!("PC {:#010x}, reference {:#010x}", ours.pc, theirs.pc));
+    }
+    else if ours.insn != theirs.insn {
+        reasons.push(format!("instruction {:#010x}, reference {:#010x}", ours.insn, theirs.insn));
+    }
+    else {
+        if ours.regs != theirs.regs {
+            reasons.push(format!("register writes [{}], reference [{}]", list(&ours.regs, "x"), list(&theirs.regs, "x")));
+        }
+        if ours.mem != theirs.mem {
+            let stores = |mem: &[(u64, u64)]| mem.iter().map(|(addr, value)| format!("{:#010x}={:#x}", addr, value)).collect::<Vec<_>>().join(", ");
+            reasons.push(format!("memory writes [{}], reference [{}]", stores(&ours.mem), stores(&theirs.mem)));
+        }
+        for (csr, value) in &theirs.csrs {
+            let mine = ours.csrs.iter().find(|(ours, _)| ours == csr).map_or(0, |(_, value)| *value);
+            if mine != *value {
+                reasons.push(format!("CSR {:#05x} = {:#010x}, reference {:#010x}", csr, mine, value));
+            }
+        }
+        for csr in &lockstep.csr_writes {
+            if !theirs.csrs.iter().any(|(theirs, _)| theirs == csr) {
+                reasons.push(format!("CSR {:#05x} written, reference has no such write", csr));
+            }
+        }
+    }
+    if !reasons.is_empty() {
+        lockstep.diverge(&ours, Some(&entry), &reasons);
+    }
+
+    lockstep.next += 1;
+    lockstep.retired += 1;
+    lockstep.history.push_back((ours, entry.1));
+    if lockstep.history.len() > lockstep.context {
+        lockstep.history.pop_front();
+    }
+    proc.lockstep = Some(lockstep);
+}
+
+fn list(values: &[(usize, u64)], prefix: &str) -> String {
+    values.iter().map(|(n, value)| format!("{}{}={:#010x}", prefix, n, value)).collect::<Vec<_>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_register_load_and_store_entries() {
+        let load = parse_line("core   0: 3 0x80000010 (0x0182a283) x5  0x80000000 mem 0x80000018").unwrap();
+        assert_eq!(load, Commit { prv: 3, pc: 0x8000_0010, insn: 0x0182_a283, regs: vec![(5, 0x8000_0000)], ..Default::default() });
+
+        let store = parse_line("core   0: 1 0x80000014 (0x00b2a023) mem 0x80001000 0x00000001").unwrap();
+        assert_eq!(store.prv, 1);
+        assert_eq!(store.mem, [(0x8000_1000, 1)]);
+        assert!(store.regs.is_empty());
+    }
+
+    #[test]
+    fn parses_csr_writes_in_decimal_and_sorts_them() {
+        let commit = parse_line("core   0: 3 0x00000018 (0x30529073) c833_mepc 0x00000020 c773_mtvec 0x80000004").unwrap();
+        assert_eq!(commit.csrs, [(0x305, 0x8000_0004), (0x341, 0x20)]);
+        assert_eq!(commit.to_string(), "3 0x00000018 (0x30529073) c305 0x80000004 c341 0x00000020");
+    }
+
+    #[test]
+    fn masks_rv64_values_and_skips_x0_and_fp_state() {
+        let commit = parse_line("core   0: 3 0xffffffff80000000 (0x00000013) x0 0x0000000000000000 f1 0x3ff0000000000000 x10 0xffffffffdeadbeef").unwrap();
+        assert_eq!(commit.pc, 0x8000_0000);
+        assert_eq!(commit.regs, [(10, 0xDEAD_BEEF)]);
+        assert_eq!(commit.to_string(), "3 0x80000000 (0x00000013) x10 0xdeadbeef");
+    }
+
+    #[test]
+    fn ignores_lines_that_are_not_commits() {
+        for line in [
+            "",
+            "bbl loader",
+            "core   0: 0x00001000 (0x00000297) auipc   t0, 0x0",        // --log without --log-commits
+            "core   0: 3 0x00001000 0x00000297",
+            "core   0: 3 0x00001000 (0x00000297) x5",                   // Value cut off
+            "core   0: 3 0x00001000 (0x00000297) c7x_bad 0x1",
+        ] {
+            assert_eq!(parse_line(line), None, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn loads_a_log_by_line_number() {
+        let path = std::env::temp_dir().join(format!("risclockstep-{}", std::process::id()));
+        fs::write(&path, "bbl loader\ncore   0: 3 0x00000000 (0x00500293) x5 0x00000005\n\ncore   0: 3 0x00000004 (0x00000013)\n").unwrap();
+        let lockstep = Lockstep::load(&path.to_string_lossy(), CONTEXT).unwrap();
+        assert_eq!(lockstep.remaining(), 2);
+        assert_eq!(lockstep.reference[0].0, 2);
+        assert_eq!(lockstep.reference[1].0, 4);
+
+        fs::write(&path, "no commits here\n").unwrap();
+        assert!(Lockstep::load(&path.to_string_lossy(), CONTEXT).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -17,22 +17,89 @@ use std::fmt::Binary;
 use colored::*;
 use std::mem::MaybeUninit;
 use std::env;
+use std::collections::HashMap;
+use std::process;
 
 // Utilities and other imports here
 mod utils;
+mod csr;
+mod isa;
+mod mmu;
+mod pmp;
+mod bus;
+mod uart;
+mod clint;
+mod plic;
+mod virtio;
+mod framebuffer;
+mod pins;
+mod gpio;
+mod spi;
+mod i2c;
+mod script;
+mod rtc;
+mod entropy;
+mod fdt;
+mod sbi;
+mod elf;
+mod linux;
+mod newlib;
+mod semihost;
+mod htif;
+mod console;
+mod suite;
+mod lockstep;
+use bus::{Bus, Device, Rom};
+use uart::{Sink, Uart};
+use clint::{Clint, TimeSource};
+use plic::Plic;
+use virtio::VirtioBlk;
+use framebuffer::{DumpFormat, Framebuffer, PixelFormat};
+use pins::PinLog;
+use gpio::Gpio;
+use spi::Spi;
+use i2c::I2c;
+use script::Script;
+use rtc::Rtc;
+use entropy::{EntropySource, Trng};
+use fdt::Fdt;
+use linux::Linux;
+use newlib::Newlib;
+use semihost::Semihost;
+use htif::Htif;
+use lockstep::Lockstep;
+use std::cell::RefCell;
+use std::rc::Rc;
+use csr::Csr;
+use isa::Isa;
+use mmu::Tlb;
 
 // Constants here (might change to yaml soon)
-const EXTENSION: &str = "I";
+const ISA: &str = "rv32i_zicsr_zicntr_zihpm_zifencei_zkr";     // Default ISA string (--isa)
 const REG_SIZE: usize = 32;
-const RAM_SIZE: usize = 1024;
+const RAM_SIZE: usize = 0x10000;   // RAM words (256 KiB)
+const RAM_BASE: usize = 0x0000_0000;
+const CLINT_BASE: usize = 0x0200_0000;
+const PLIC_BASE: usize = 0x0C00_0000;
+const UART_BASE: usize = 0x1000_0000;
+const UART_IRQ: usize = 10;
+const VIRTIO_BASE: usize = 0x1000_1000;
+const VIRTIO_IRQ: usize = 1;
+const GPIO_BASE: usize = 0x1001_2000;
+const GPIO_IRQ: usize = 3;
+const SPI_BASE: usize = 0x1001_3000;
+const I2C_BASE: usize = 0x1001_4000;
+const I2C_IRQ: usize = 5;
+const TRNG_BASE: usize = 0x1001_5000;
+const RTC_BASE: usize = 0x0010_1000;
+const RTC_IRQ: usize = 11;
+const FB_BASE: usize = 0x3000_0000;
+const STACK_SIZE: usize = 0x4000;  // Kept free below the initial sp when a bare-metal heap is handed out
 const XLEN: usize = 32;
 const PATH: &str = "bin.txt";
 const SPEED: usize = 1;
 const INI: isize = 4; // Init address offset
 
-// Static
-static mut RAM_SIZE_NEW: usize = RAM_SIZE;
-
 // Register Struct
 #[derive(Debug, Clone, Copy)]
 pub struct Register {
@@ -97,7 +164,7 @@ impl Register {
 }
 
 
-// RAM struct
+// RAM struct (one cell per 32-bit word)
 #[derive(Debug, Clone)]
 pub struct RAM {
     address: Vec<usize>,
@@ -112,6 +179,9 @@ impl RAM {
         let mut address = vec![0; RAM_SIZE];
         let mut ram_module = vec![0; RAM_SIZE];
         let mut dirty_bit = vec![0; RAM_SIZE];
+        for i in 0..RAM_SIZE {
+            address[i] = i * INI as usize;
+        }
         Self {
             address,
             ram_module,
@@ -130,27 +200,14 @@ impl RAM {
         self.dirty_bit[index] = 1;
     }
 
-    // Write to new address (RAM emulation)
+    // Write a word to an address offset (RAM emulation)
     fn write_to_addr(&mut self, index_addr: usize, data: isize) {
-        for i in 0..RAM_SIZE {      // Not RAM_SIZE_NEW because temp_ram
-            if self.dirty_bit[i] == 0 {
-                self.address[i] = index_addr;
-                self.ram_module[i] = data;
-                self.dirty_bit[i] = 1;
-                break;
-            }
-        }
+        self.write(index_addr / INI as usize, data);
     }
 
-    // Read from address (RAM emulation)
+    // Read a word from an address offset (RAM emulation)
     fn read_from_addr(&mut self, index_addr: usize) -> isize {
-        let ret_val: isize = 0;
-        for i in 0..RAM_SIZE {      // Not RAM_SIZE_NEW because temp_ram
-            if index_addr == self.address[i] {
-                return self.ram_module[i];
-            }
-        }
-        return ret_val
+        self.read(index_addr / INI as usize)
     }
 
     // Print all RAM data
@@ -158,7 +215,7 @@ impl RAM {
         println!("{}", "--------------------------------".green());
         println!("{}", "RAM".green());
         println!("{}", "--------------------------------".green());
-        for iter in 0..unsafe{RAM_SIZE_NEW} {
+        for iter in 0..self.ram_module.len() {
             println!("{:#010x}: {:032b}: {} : {}", self.address[iter], self.ram_module[iter], self.dirty_bit[iter], self.ram_module[iter]);
         }
         println!("{}", "--------------------------------".green());
@@ -169,69 +226,209 @@ impl RAM {
         println!("{}", "--------------------------------".green());
         println!("{}", "RAM (dirty lines only)".green());
         println!("{}", "--------------------------------".green());
-        for iter in 0..unsafe{RAM_SIZE_NEW} {
+        for iter in 0..self.ram_module.len() {
             if self.dirty_bit[iter] == 1 {
                 println!("{:#010x}: {:032b}: {} : {:08x}", self.address[iter], self.ram_module[iter], self.dirty_bit[iter], self.ram_module[iter]);
             }
-            else {
-                break;
-            }
         }
         println!("{}", "--------------------------------".green());
     }
 
     // Reset RAM to zero
     fn reset(&mut self) {
-        for i in 0..RAM_SIZE {
+        for i in 0..self.ram_module.len() {
             self.ram_module[i] = 0;
             self.dirty_bit[i] = 0;
         }
     }
 }
 
-// Virtual Processor (RISCulator Proc) Struct
+// RAM device: sized little-endian accesses within a word
+impl Device for RAM {
+    fn name(&self) -> &str {
+        "ram"
+    }
+
+    fn read(&mut self, offset: usize, size: usize) -> Option<u64> {
+        let word = self.read_from_addr(offset) as u64 & 0xFFFF_FFFF;
+        let shift = 8 * (offset % INI as usize);
+        if shift / 8 + size > INI as usize {
+            return None;        // Accesses never straddle a word
+        }
+        Some((word >> shift) & bus::size_mask(size))
+    }
+
+    fn write(&mut self, offset: usize, size: usize, value: u64) -> bool {
+        let shift = 8 * (offset % INI as usize);
+        if shift / 8 + size > INI as usize {
+            return false;
+        }
+        let mask = bus::size_mask(size) << shift;
+        let word = self.read_from_addr(offset) as u64 & 0xFFFF_FFFF;
+        let word = (word & !mask) | ((value << shift) & mask);
+        self.write_to_addr(offset, word as isize);
+        true
+    }
+
+    fn reset(&mut self) {
+        RAM::reset(self);
+    }
+
+    fn describe(&self, fdt: &mut Fdt, base: usize, size: usize, irq: Option<usize>) {
+        fdt.node(&format!("/memory@{:x}", base))
+            .str("device_type", "memory")
+            .cells("reg", &[base as u32, size as u32]);
+    }
+
+    fn print_dirty(&mut self) {
+        RAM::print_dirty(self);
+    }
+}
+
+// Decoded-instruction cache struct
 #[derive(Debug, Clone)]
+pub struct ICache {
+    lines: HashMap<usize, String>,
+}
+
+// Decoded-instruction cache impl
+impl ICache {
+    // Initialize an empty cache
+    fn new() -> Self {
+        Self {
+            lines: HashMap::new(),
+        }
+    }
+
+    // Look up the decoded instruction held for an address
+    fn lookup(&self, addr: usize) -> Option<String> {
+        self.lines.get(&addr).cloned()
+    }
+
+    // Fill the line for an address
+    fn insert(&mut self, addr: usize, line: String) {
+        self.lines.insert(addr, line);
+    }
+
+    // Drop every line so the next fetch sees memory again (FENCE.I)
+    fn invalidate(&mut self) {
+        self.lines.clear();
+    }
+}
+
+// Virtual Processor (RISCulator Proc) Struct
+#[derive(Debug)]
 pub struct Vproc {
     regs: Register,
     misa: isize,
+    isa: Isa,
     pc: isize,
     mode: Mode,
-    ram_module: RAM,
+    bus: Bus,
+    clint: Rc<RefCell<Clint>>,
+    plic: Rc<RefCell<Plic>>,
+    csrs: Csr,
+    icache: ICache,
+    tlb: Tlb,
+    wfi: bool,
+    sbi: bool,
+    linux: Option<Linux>,       // User-mode emulation state (--user)
+    newlib: Option<Newlib>,     // Bare-metal syscall shim (--newlib)
+    semihost: Option<Semihost>, // Semihosting (--semihosting)
+    htif: Option<Htif>,         // tohost/fromhost (ELF symbols or --tohost)
+    lockstep: Option<Lockstep>, // Reference commit log (--lockstep)
 }
 
-// Enumerated processor modes
-#[derive(Debug, Clone, Copy)]
+// Enumerated processor modes (privilege level encoding)
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
 enum Mode {
-    User,
-    Supervisor,
-    Machine,
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
+// Exit status of an HTIF run that ended without a result on tohost
+fn htif_status(exhausted: bool, steps: usize) -> i32 {
+    if exhausted {
+        log::error!("HTIF: no result on tohost within {} steps", steps);
+        suite::BUDGET_EXHAUSTED
+    }
+    else {
+        log::error!("HTIF: program stopped without a result on tohost");
+        suite::NO_RESULT
+    }
+}
+
+// Unit-test hart: the default ISA with RAM mapped and nothing else
+#[cfg(test)]
+fn test_hart() -> Vproc {
+    let mut proc = Vproc::new(
+        Isa::parse(ISA).unwrap(),
+        Csr::new(csr::PMP_ENTRIES, csr::HPM_COUNTERS),
+        Clint::new(clint::CPU_FREQ, TimeSource::Instret),
+    );
+    proc.bus.attach(RAM_BASE, RAM_SIZE * INI as usize, Box::new(RAM::new())).unwrap();
+    proc
+}
+
+// Run a unit-test hart from `pc` for at most `steps` steps, returning whether the budget ran
+// out and where the PC ended up. The PC is global, so tests take turns.
+#[cfg(test)]
+fn run_hart(proc: &mut Vproc, pc: usize, steps: usize) -> (bool, usize) {
+    static TURN: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    let _turn = TURN.lock().unwrap_or_else(|err| err.into_inner());
+    utils::set_pc(pc as isize);
+    let exhausted = utils::stage2(proc, steps);
+    (exhausted, utils::pc() as usize)
 }
 
 // Virtual Processor (RISCulator Proc) traits
 impl Vproc {
-    // Initialize the Vproc object with default values
-    fn new(regs: Register, misa: isize, pc: isize, mode: Mode, ram_module: RAM) -> Self {
-        Vproc {
-            regs,
+    // A hart in M-mode at PC 0 with an empty bus and the given ISA, CSR file and timer;
+    // the optional layers (SBI, syscall shims, HTIF, lockstep) start switched off
+    fn new(isa: Isa, csrs: Csr, timer: Clint) -> Self {
+        let misa = isa.misa | csr::MISA_S | csr::MISA_U;
+        let mut proc = Vproc {
+            regs: Register::new(),
             misa,
-            pc,
-            mode,
-            ram_module,
-        }
+            isa,
+            pc: 0,
+            mode: Mode::Machine,
+            bus: Bus::new(),
+            clint: timer.shared(),
+            plic: Plic::new(plic::PLIC_SOURCES, 2 * clint::HARTS).shared(),     // M and S context per hart
+            csrs,
+            icache: ICache::new(),
+            tlb: Tlb::new(),
+            wfi: false,
+            sbi: false,
+            linux: None,
+            newlib: None,
+            semihost: None,
+            htif: None,
+            lockstep: None,
+        };
+        proc.csrs.write(csr::MISA, proc.misa);
+        proc
     }
 
     // Resets the Vproc
     fn reset(&mut self) {
         self.pc = 0;
+        self.mode = Mode::Machine;
         for i in 0..REG_SIZE-1 {
             self.regs.write(i.try_into().unwrap(), 0);
-            self.ram_module.write(i.try_into().unwrap(), 0);
         }
+        self.bus.reset();
+        self.csrs.reset();
+        self.icache.invalidate();
+        self.tlb.flush(None, None);
+        self.wfi = false;
     }
 
     // Updates registers
     fn update_regs(&mut self, mut new_regs: Register) {
-        for i in 0..REG_SIZE {
+        for i in 1..REG_SIZE {      // x0 is hardwired to zero
             if new_regs.dirty_bit[i] == 1 {
                 let new_regs_up_line = new_regs.read(i.try_into().unwrap());
                 self.regs.write(i.try_into().unwrap(), new_regs_up_line);
@@ -239,95 +436,455 @@ impl Vproc {
         }
     }
 
-    // Updates RAM
-    fn update_ram(&mut self, mut new_ram: RAM) {
-        let mut iter = 0;
-        loop {
-            if new_ram.dirty_bit[iter] == 1 && iter < unsafe{RAM_SIZE_NEW} {
-                let new_ram_up_line = new_ram.read(iter.try_into().unwrap());
-                let new_address = new_ram.address[iter];
-                for i in 0..unsafe{RAM_SIZE_NEW} {
-                    if self.ram_module.dirty_bit[i] == 0  {
-                        self.ram_module.address[i] = new_address;
-                        self.ram_module.write(i.try_into().unwrap(), new_ram_up_line);
-                        unsafe{RAM_SIZE_NEW += 1};
-                        break;
-                    }
-                    else {
-                        continue;
-                    }
-                }
-                break;
+    // misa breakdown: extension letters set in misa
+    fn misa_slice(&self) -> String {
+        let mut ext_vec: Vec<char> = vec![]; // Extension vector
+        for bit in 0..26 {
+            if self.misa & (1 << bit) != 0 {
+                ext_vec.push((b'A' + bit as u8) as char);
             }
-            iter += 1;
         }
+        let ext_vec_rec: String = ext_vec.iter().collect();
+        ext_vec_rec
     }
+}
 
-    // misa breakdown and process
-    fn misa_slice(&self) -> String {
-        let temp_misa = self.misa.to_le();
-        let temp_misa_bin = format!("{:032b}", temp_misa);
-        let mut slice_misa = temp_misa_bin.to_string().chars().collect::<Vec<_>>();
-        slice_misa.reverse();
-        let mut misa_ext = Vec::new();
-        for i in 0..25 {
-            let temp_ext_slice = &slice_misa[i];
-            misa_ext.push(temp_ext_slice);
-        }
-        let misa_ext_con: String = misa_ext.clone().into_iter().collect(); // Little endian Extension slice
-        let misa_ext_be: String = misa_ext_con.clone().chars().rev().collect(); // Big endian
-        println!("{}",misa_ext_be);                                                                            
-        let misa_ext_be_vec: Vec<char> = misa_ext_be.chars().collect();
-        
-        let mut ext_vec: Vec<char> = vec![]; // Extension vector
-        
-        // Extension check
-        if misa_ext_be_vec[24] == '1' { // Atomic Extension 
-            ext_vec.push('A');
-        }
-        if misa_ext_be_vec[23] == '1' { // Bit manip extensions
-            ext_vec.push('B');
-        }
-        if misa_ext_be_vec[22] == '1' { // Compressed extensions
-            ext_vec.push('C');
-        }
-        if misa_ext_be_vec[21] == '1' { // Double-precision FP extension
-            ext_vec.push('D');
-        }
-        if misa_ext_be_vec[20] == '1' { // Embedded extension
-            ext_vec.push('E');
-        }
-        if misa_ext_be_vec[19] == '1' { // Single-precision FP extension
-            ext_vec.push('F');
-        }
-        if misa_ext_be_vec[16] == '1' { // Integer extension
-            ext_vec.push('I');
+// Command line options
+struct Options {
+    program: String,
+    isa: String,
+    roms: Vec<(String, usize)>,
+    uart_out: Option<String>,
+    timebase: u64,
+    time_source: TimeSource,
+    drive: Option<String>,
+    snapshot: bool,
+    fb: Option<(usize, usize, PixelFormat)>,
+    fb_dir: String,
+    fb_every: u64,
+    fb_dump: DumpFormat,
+    periph: Option<String>,
+    pin_log: Option<String>,
+    rtc_epoch: Option<u64>,
+    entropy: EntropySource,
+    dtb: Option<String>,
+    dump_dtb: Option<String>,
+    bootargs: String,
+    sbi: bool,
+    newlib: bool,
+    semihosting: bool,
+    semihost_root: String,
+    semihost_cmdline: Option<String>,
+    tohost: Option<usize>,
+    fromhost: Option<usize>,
+    signature: Option<String>,
+    lockstep: Option<String>,
+    lockstep_context: usize,
+    kernel: Option<String>,
+    initrd: Option<String>,
+    user: Option<Vec<String>>,     // Guest program and its arguments
+    max_steps: Option<usize>,
+    pmp_entries: usize,
+    hpm_counters: usize,
+}
+
+// Parse the command line: <disassembly|elf> [--isa <isa-string>] [--rom <file>@<addr>]... [--uart-out <file>]
+//                   [--timebase <hz>] [--time-source <instret|host>] [--drive <image> [--snapshot]]
+//                   [--fb <w>x<h>[:<gray8|rgb565|xrgb8888>] [--fb-dir <dir>] [--fb-every <n>] [--fb-dump <ppm|png>]]
+//                   [--periph <script>] [--pin-log <file>] [--rtc-epoch <secs>] [--entropy <seed|host>]
+//                   [--dtb <file>] [--dump-dtb <file>] [--bootargs <args>] [--sbi] [--newlib]
+//                   [--kernel <image>] [--initrd <image>] [--max-steps <n>]
+//                   [--pmp-entries <0|16|64>] [--hpm-counters <0-29>]
+//                   [--semihosting [--semihost-root <dir>] [--semihost-cmdline <args>]]
+//                   [--tohost <addr>] [--fromhost <addr>] [--signature <file>]
+//                   [--lockstep <commit-log> [--lockstep-context <n>]]
+//        or: test-suite <dir> [suite options] [-- <options for each test>]
+//        or: [options] --user <program> [args]...
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    // Everything after --user is the guest's command line
+    let (args, user) = match args.iter().position(|arg| arg == "--user") {
+        Some(pos) if pos + 1 < args.len() => (&args[..pos], Some(args[pos + 1..].to_vec())),
+        Some(_) => return Err("--user expects the program to run".to_string()),
+        None => (args, None),
+    };
+    if args.len() < 2 && user.is_none() {
+        return Err("Not enough arguments! Must pass the path of the disassembly as the first argument".to_string());
+    }
+    let mut opts = Options {
+        program: match &user {
+            Some(argv) => argv[0].clone(),
+            None => args[1].clone(),
+        },
+        isa: ISA.to_string(),
+        roms: vec![],
+        uart_out: None,
+        timebase: clint::CPU_FREQ,
+        time_source: TimeSource::Instret,
+        drive: None,
+        snapshot: false,
+        fb: None,
+        fb_dir: "frames".to_string(),
+        fb_every: 1,
+        fb_dump: DumpFormat::Ppm,
+        periph: None,
+        pin_log: None,
+        rtc_epoch: None,
+        entropy: EntropySource::Seeded(entropy::DEFAULT_SEED),
+        dtb: None,
+        dump_dtb: None,
+        bootargs: "console=ttyS0".to_string(),
+        sbi: false,
+        newlib: false,
+        semihosting: false,
+        semihost_root: ".".to_string(),
+        semihost_cmdline: None,
+        tohost: None,
+        fromhost: None,
+        signature: None,
+        lockstep: None,
+        lockstep_context: lockstep::CONTEXT,
+        kernel: None,
+        initrd: None,
+        user: None,
+        max_steps: None,
+        pmp_entries: csr::PMP_ENTRIES,
+        hpm_counters: csr::HPM_COUNTERS,
+    };
+    let mut i = if user.is_some() { 1 } else { 2 };
+    opts.user = user;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--isa" if i + 1 < args.len() => {
+                opts.isa = args[i + 1].clone();
+                i += 1;
+            }
+            "--rom" if i + 1 < args.len() => {
+                let (path, addr) = match args[i + 1].rsplit_once('@') {
+                    Some(split) => split,
+                    None => return Err(format!("--rom expects <file>@<addr>, got {}", args[i + 1])),
+                };
+                let addr = match usize::from_str_radix(addr.trim_start_matches("0x"), 16) {
+                    Ok(addr) => addr,
+                    Err(_) => return Err(format!("--rom: invalid hex address {}", addr)),
+                };
+                opts.roms.push((path.to_string(), addr));
+                i += 1;
+            }
+            "--uart-out" if i + 1 < args.len() => {
+                opts.uart_out = Some(args[i + 1].clone());
+                i += 1;
+            }
+            "--timebase" if i + 1 < args.len() => {
+                opts.timebase = match args[i + 1].parse::<u64>() {
+                    Ok(hz) if hz > 0 => hz,
+                    _ => return Err(format!("--timebase: invalid frequency {}", args[i + 1])),
+                };
+                i += 1;
+            }
+            "--drive" if i + 1 < args.len() => {
+                opts.drive = Some(args[i + 1].clone());
+                i += 1;
+            }
+            "--snapshot" => opts.snapshot = true,
+            "--sbi" => opts.sbi = true,
+            "--newlib" => opts.newlib = true,
+            "--semihosting" => opts.semihosting = true,
+            "--semihost-root" if i + 1 < args.len() => {
+                opts.semihost_root = args[i + 1].clone();
+                i += 1;
+            }
+            "--tohost" | "--fromhost" if i + 1 < args.len() => {
+                let addr = match usize::from_str_radix(args[i + 1].trim_start_matches("0x"), 16) {
+                    Ok(addr) => addr,
+                    Err(_) => return Err(format!("{}: invalid hex address {}", args[i], args[i + 1])),
+                };
+                if args[i] == "--tohost" { opts.tohost = Some(addr); } else { opts.fromhost = Some(addr); }
+                i += 1;
+            }
+            "--signature" if i + 1 < args.len() => {
+                opts.signature = Some(args[i + 1].clone());
+                i += 1;
+            }
+            "--lockstep" if i + 1 < args.len() => {
+                opts.lockstep = Some(args[i + 1].clone());
+                i += 1;
+            }
+            "--lockstep-context" if i + 1 < args.len() => {
+                opts.lockstep_context = match args[i + 1].parse::<usize>() {
+                    Ok(count) => count,
+                    Err(_) => return Err(format!("--lockstep-context: invalid instruction count {}", args[i + 1])),
+                };
+                i += 1;
+            }
+            "--semihost-cmdline" if i + 1 < args.len() => {
+                opts.semihost_cmdline = Some(args[i + 1].clone());
+                i += 1;
+            }
+            "--fb" if i + 1 < args.len() => {
+                opts.fb = Some(parse_fb(&args[i + 1])?);
+                i += 1;
+            }
+            "--periph" if i + 1 < args.len() => {
+                opts.periph = Some(args[i + 1].clone());
+                i += 1;
+            }
+            "--pin-log" if i + 1 < args.len() => {
+                opts.pin_log = Some(args[i + 1].clone());
+                i += 1;
+            }
+            "--rtc-epoch" if i + 1 < args.len() => {
+                opts.rtc_epoch = match args[i + 1].parse::<u64>() {
+                    Ok(secs) => Some(secs),
+                    Err(_) => return Err(format!("--rtc-epoch: invalid seconds {}", args[i + 1])),
+                };
+                i += 1;
+            }
+            "--entropy" if i + 1 < args.len() => {
+                opts.entropy = match EntropySource::parse(&args[i + 1]) {
+                    Some(source) => source,
+                    None => return Err(format!("--entropy expects a seed or host, got {}", args[i + 1])),
+                };
+                i += 1;
+            }
+            "--dtb" if i + 1 < args.len() => {
+                opts.dtb = Some(args[i + 1].clone());
+                i += 1;
+            }
+            "--dump-dtb" if i + 1 < args.len() => {
+                opts.dump_dtb = Some(args[i + 1].clone());
+                i += 1;
+            }
+            "--kernel" if i + 1 < args.len() => {
+                opts.kernel = Some(args[i + 1].clone());
+                i += 1;
+            }
+            "--initrd" if i + 1 < args.len() => {
+                opts.initrd = Some(args[i + 1].clone());
+                i += 1;
+            }
+            "--max-steps" if i + 1 < args.len() => {
+                opts.max_steps = match args[i + 1].parse::<usize>() {
+                    Ok(steps) => Some(steps),
+                    Err(_) => return Err(format!("--max-steps: invalid step count {}", args[i + 1])),
+                };
+                i += 1;
+            }
+            "--pmp-entries" if i + 1 < args.len() => {
+                opts.pmp_entries = match args[i + 1].parse::<usize>() {
+                    Ok(n) if csr::PMP_SIZES.contains(&n) => n,
+                    _ => return Err(format!("--pmp-entries: expected 0, 16 or 64, got {}", args[i + 1])),
+                };
+                i += 1;
+            }
+            "--hpm-counters" if i + 1 < args.len() => {
+                opts.hpm_counters = match args[i + 1].parse::<usize>() {
+                    Ok(n) if n <= csr::HPM_MAX => n,
+                    _ => return Err(format!("--hpm-counters: expected 0 to {}, got {}", csr::HPM_MAX, args[i + 1])),
+                };
+                i += 1;
+            }
+            "--bootargs" if i + 1 < args.len() => {
+                opts.bootargs = args[i + 1].clone();
+                i += 1;
+            }
+            "--fb-dir" if i + 1 < args.len() => {
+                opts.fb_dir = args[i + 1].clone();
+                i += 1;
+            }
+            "--fb-every" if i + 1 < args.len() => {
+                opts.fb_every = match args[i + 1].parse::<u64>() {
+                    Ok(n) if n > 0 => n,
+                    _ => return Err(format!("--fb-every: invalid frame count {}", args[i + 1])),
+                };
+                i += 1;
+            }
+            "--fb-dump" if i + 1 < args.len() => {
+                opts.fb_dump = match args[i + 1].as_str() {
+                    "ppm" => DumpFormat::Ppm,
+                    "png" => DumpFormat::Png,
+                    other => return Err(format!("--fb-dump must be ppm or png, got {}", other)),
+                };
+                i += 1;
+            }
+            "--time-source" if i + 1 < args.len() => {
+                opts.time_source = match args[i + 1].as_str() {
+                    "instret" => TimeSource::Instret,
+                    "host" => TimeSource::Host,
+                    other => return Err(format!("--time-source must be instret or host, got {}", other)),
+                };
+                i += 1;
+            }
+            other => return Err(format!("Unknown or incomplete option: {}", other)),
         }
-        if misa_ext_be_vec[12] == '1' { // Multiply extension
-            ext_vec.push('M');
+        i += 1;
+    }
+    Ok(opts)
+}
+
+// Parse a framebuffer geometry like 320x240:rgb565 (xrgb8888 by default)
+fn parse_fb(spec: &str) -> Result<(usize, usize, PixelFormat), String> {
+    let (size, format) = match spec.split_once(':') {
+        Some((size, format)) => match PixelFormat::parse(format) {
+            Some(format) => (size, format),
+            None => return Err(format!("--fb: unknown pixel format {}", format)),
+        },
+        None => (spec, PixelFormat::Xrgb8888),
+    };
+    let dims: Vec<usize> = size.split('x').filter_map(|d| d.parse().ok()).collect();
+    if dims.len() != 2 {
+        return Err(format!("--fb expects <width>x<height>, got {}", spec));
+    }
+    Ok((dims[0], dims[1], format))
+}
+
+// Device tree for the machine as configured
+fn device_tree(proc: &Vproc, bootargs: &str, initrd: Option<(usize, usize)>) -> Fdt {
+    let mut fdt = Fdt::new();
+    fdt.node("/")
+        .u32("#address-cells", 1)
+        .u32("#size-cells", 1)
+        .str("compatible", "risculator,virt")
+        .str("model", "RISCulator virt");
+    fdt.node("/cpus")
+        .u32("#address-cells", 1)
+        .u32("#size-cells", 0)
+        .u32("timebase-frequency", proc.clint.borrow().timebase() as u32);
+    for hart in 0..clint::HARTS {
+        let cpu = fdt.node(&format!("/cpus/cpu@{}", hart));
+        cpu.str("device_type", "cpu")
+            .u32("reg", hart as u32)
+            .str("compatible", "riscv")
+            .str("riscv,isa", &proc.isa.to_string())
+            .str("mmu-type", "riscv,sv32")
+            .str("status", "okay");
+        cpu.child("interrupt-controller")
+            .str("compatible", "riscv,cpu-intc")
+            .u32("#interrupt-cells", 1)
+            .empty("interrupt-controller")
+            .u32("phandle", fdt::PHANDLE_CPU_INTC + hart as u32);
+    }
+    fdt.node("/soc")
+        .u32("#address-cells", 1)
+        .u32("#size-cells", 1)
+        .str("compatible", "simple-bus")
+        .empty("ranges");
+    proc.bus.describe(&mut fdt);
+    fdt.node("/chosen")
+        .str("bootargs", bootargs)
+        .str("stdout-path", &format!("/soc/serial@{:x}", UART_BASE));
+    if let Some((start, end)) = initrd {
+        fdt.node("/chosen")
+            .u32("linux,initrd-start", start as u32)
+            .u32("linux,initrd-end", end as u32);
+    }
+    fdt
+}
+
+// Copy an image into memory through the bus
+fn copy_to_bus(proc: &mut Vproc, addr: usize, data: &[u8]) {
+    for (i, byte) in data.iter().enumerate() {
+        proc.bus.write(addr + i, 1, *byte as isize);
+    }
+}
+
+// Boot images: a raw kernel at the reset vector, the initrd at the top of RAM and the DTB
+// below it; returns the end of the program image and the DTB address
+fn load_boot_images(proc: &mut Vproc, opts: &Options, program_end: usize) -> Result<(usize, usize), String> {
+    let mut top = RAM_BASE + RAM_SIZE * INI as usize;
+    let mut kernel_end = program_end;
+    if let Some(path) = &opts.kernel {
+        let data = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+        if data.len() > top - RAM_BASE {
+            return Err(format!("{}: kernel of {} bytes does not fit in RAM", path, data.len()));
         }
-        if misa_ext_be_vec[9] == '1' { // Packed SIMD extension
-            ext_vec.push('P');
+        copy_to_bus(proc, RAM_BASE, &data);
+        kernel_end = kernel_end.max(RAM_BASE + data.len());
+        log::info!("Kernel of {} bytes at {:#010x}", data.len(), RAM_BASE);
+    }
+    let mut initrd = None;
+    if let Some(path) = &opts.initrd {
+        let data = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+        let start = top.saturating_sub(data.len()) & !0xFFF;
+        if start < kernel_end || data.len() > top {
+            return Err(format!("{}: initrd of {} bytes does not fit in RAM", path, data.len()));
         }
+        copy_to_bus(proc, start, &data);
+        initrd = Some((start, start + data.len()));
+        top = start;
+        log::info!("Initrd of {} bytes at {:#010x}", data.len(), start);
+    }
+    let dtb = load_dtb(proc, opts, top, kernel_end, initrd)?;
+    Ok((kernel_end, dtb))
+}
 
-        println!("{:?}", ext_vec);
-        let ext_vec_rec: String = ext_vec.iter().collect();
-        ext_vec_rec
+// Place the generated (or user-supplied) DTB just below `top` and above the image ending
+// at `kernel_end`, returning its address
+fn load_dtb(proc: &mut Vproc, opts: &Options, top: usize, kernel_end: usize, initrd: Option<(usize, usize)>) -> Result<usize, String> {
+    let dtb = match &opts.dtb {
+        Some(path) => match fs::read(path) {
+            Ok(data) if data.len() >= 4 && data[..4] == [0xD0, 0x0D, 0xFE, 0xED] => data,
+            Ok(_) => return Err(format!("{}: not a flattened device tree", path)),
+            Err(err) => return Err(format!("{}: {}", path, err)),
+        },
+        None => device_tree(proc, &opts.bootargs, initrd).to_bytes(),
+    };
+    if let Some(path) = &opts.dump_dtb {
+        if let Err(err) = fs::write(path, &dtb) {
+            return Err(format!("{}: {}", path, err));
+        }
+    }
+    if dtb.len() + 16 > top - RAM_BASE {
+        return Err(format!("DTB of {} bytes does not fit in RAM", dtb.len()));
+    }
+    let addr = (top - dtb.len()) & !7;
+    if addr < kernel_end {
+        return Err(format!("DTB of {} bytes at {:#010x} would overwrite the image ending at {:#010x}", dtb.len(), addr, kernel_end));
     }
+    copy_to_bus(proc, addr, &dtb);
+    log::info!("DTB of {} bytes at {:#010x}", dtb.len(), addr);
+    Ok(addr)
+}
+
+// Boot convention: a0 = hart id, a1 = DTB address, sp below the DTB. Returns the end of
+// the image, the DTB address and the limit for a heap growing up from the image.
+fn boot_handoff(proc: &mut Vproc, opts: &Options, program_end: usize) -> Result<(usize, usize, usize), String> {
+    let (image_end, dtb_addr) = load_boot_images(proc, opts, program_end)?;
+    // The stack (and any bare-metal heap) needs room between the image and the DTB
+    let heap_limit = match dtb_addr.checked_sub(STACK_SIZE).filter(|limit| *limit >= image_end) {
+        Some(limit) => limit,
+        None => return Err(format!("no room for a {:#x} byte stack between the image ending at {:#010x} and the DTB at {:#010x}", STACK_SIZE, image_end, dtb_addr)),
+    };
+    proc.regs.write(10, 0);
+    proc.regs.write(11, dtb_addr as isize);
+    proc.regs.write(2, ((dtb_addr - 16) & !15) as isize);
+    Ok((image_end, dtb_addr, heap_limit))
 }
 
 // RISCulator main function
 fn main() {
     let args: Vec<String> = env::args().collect();
-
-    if args.len() < 2 {
-      println!("Not enough arguments! Must pass the path of the disassembly as the first argument");
-      return
+    if args.len() > 1 && args[1] == "test-suite" {
+        process::exit(suite::run(&args[2..]));
     }
 
+    let opts = match parse_args(&args) {
+        Ok(opts) => opts,
+        Err(err) => {
+            println!("{}", err);
+            process::exit(suite::SETUP_ERROR);
+        }
+    };
+    let isa = match Isa::parse(&opts.isa) {
+        Ok(isa) => isa,
+        Err(err) => {
+            println!("Invalid ISA string {}", err);
+            process::exit(suite::SETUP_ERROR);
+        }
+    };
+
     utils::logo_display();
     println!("{}", "|----------------- A lightweight RISC-V emulator -----------------|".red());
-    utils::boot_seq(XLEN, EXTENSION, REG_SIZE, RAM_SIZE);
+    utils::boot_seq(XLEN, &isa.to_string(), REG_SIZE, RAM_SIZE);
 
     // Logging
     Builder::new()
@@ -342,13 +899,17 @@ fn main() {
             .filter(None, LevelFilter::Info);
 
     log::info!("Creating a virtual processor with the given configuration");
-    let mut proc = Vproc {
-        regs: Register::new(),
-        misa: 4352,
-        pc: 0,
-        mode: Mode::User,
-        ram_module: RAM::new(),
-    };
+    if !isa.unimplemented.is_empty() {
+        log::warn!("ISA extensions not implemented by RISCulator: {} (their instructions trap as illegal)", isa.unimplemented.join(", "));
+    }
+    let mut proc = Vproc::new(
+        isa.clone(),
+        Csr::new(opts.pmp_entries, opts.hpm_counters),
+        Clint::new(opts.timebase, opts.time_source),
+    );
+    proc.sbi = opts.sbi;
+    proc.csrs.set_entropy(opts.entropy.fork(0));
+    log::info!("misa extensions: {}", proc.misa_slice());
     log::info!("Registers of length = {} bits initialized", XLEN);
     log::warn!("Read/write test>s for Registers starting");
     proc.regs.print();;
@@ -356,7 +917,8 @@ fn main() {
     log::warn!("Register tests passed!");
     log::info!("RAM module of size = {} initialized", RAM_SIZE);
     log::warn!("Read/write tests for RAM starting");
-    utils::ram_tests(RAM_SIZE, &mut proc.ram_module);
+    let mut ram = RAM::new();
+    utils::ram_tests(RAM_SIZE, &mut ram);
     log::warn!("RAM tests passed!");
     println!("
                                          RISCulator emulation stages
@@ -392,9 +954,215 @@ fn main() {
     ", "Fetch".green());
     log::info!("Stage 1: Fetch stage starting");
     log::info!("Prepping for fetch operations");
-    let program_parsed = utils::program_parser(&args[1], &mut proc.ram_module);
-    log::info!("Program loaded to main memory!");
-    proc.ram_module.print_dirty();
+    // Bare-metal programs are a disassembly listing or an ELF (loaded once the bus is up)
+    let elf_program = opts.user.is_none() && elf::is_elf(&opts.program);
+    if opts.user.is_none() && !elf_program {
+        let program_parsed = utils::program_parser(&opts.program, &mut ram);
+        log::info!("Program loaded to main memory!");
+        ram.print_dirty();
+    }
+
+    // Physical memory map
+    if let Err(err) = proc.bus.attach(RAM_BASE, RAM_SIZE * INI as usize, Box::new(ram)) {
+        println!("Bus configuration error: {}", err);
+        process::exit(suite::SETUP_ERROR);
+    }
+    if let Err(err) = proc.bus.attach(CLINT_BASE, clint::CLINT_SIZE, Box::new(proc.clint.clone())) {
+        println!("Bus configuration error: {}", err);
+        process::exit(suite::SETUP_ERROR);
+    }
+    log::info!("CLINT timebase {} Hz ({:?})", opts.timebase, opts.time_source);
+    if let Err(err) = proc.bus.attach(PLIC_BASE, plic::PLIC_SIZE, Box::new(proc.plic.clone())) {
+        println!("Bus configuration error: {}", err);
+        process::exit(suite::SETUP_ERROR);
+    }
+
+    // UART transmit goes to the terminal unless a file (or pty) is given
+    let sink = match &opts.uart_out {
+        Some(path) => match fs::OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => Sink::File(file),
+            Err(err) => {
+                println!("Could not open UART output {}: {}", path, err);
+                process::exit(suite::SETUP_ERROR);
+            }
+        },
+        None => Sink::Stdout,
+    };
+    // (a user-mode guest owns stdin itself)
+    if opts.user.is_none() {
+        if let Err(err) = proc.bus.attach_irq(UART_BASE, uart::UART_SIZE, Some(UART_IRQ), Box::new(Uart::new(sink))) {
+            println!("Bus configuration error: {}", err);
+            process::exit(suite::SETUP_ERROR);
+        }
+    }
+    if let Some(path) = &opts.drive {
+        let blk = match VirtioBlk::open(path, opts.snapshot) {
+            Ok(blk) => blk,
+            Err(err) => {
+                println!("Could not open drive {}", err);
+                process::exit(suite::SETUP_ERROR);
+            }
+        };
+        if let Err(err) = proc.bus.attach_irq(VIRTIO_BASE, virtio::VIRTIO_SIZE, Some(VIRTIO_IRQ), Box::new(blk)) {
+            println!("Bus configuration error: {}", err);
+            process::exit(suite::SETUP_ERROR);
+        }
+    }
+    // GPIO, SPI and I2C with their outside world from the peripheral script
+    let script = match &opts.periph {
+        Some(path) => match Script::load(path) {
+            Ok(script) => script,
+            Err(err) => {
+                println!("Peripheral script error: {}", err);
+                process::exit(suite::SETUP_ERROR);
+            }
+        },
+        None => Script::empty(),
+    };
+    let pin_log = match PinLog::new(opts.pin_log.as_deref()) {
+        Ok(pin_log) => pin_log,
+        Err(err) => {
+            println!("Could not create pin log {}", err);
+            process::exit(suite::SETUP_ERROR);
+        }
+    };
+    let mut spi = Spi::new(pin_log.clone());
+    for (cs, flash) in script.spi {
+        if let Err(err) = spi.connect(cs, Box::new(flash)) {
+            println!("Peripheral script error: {}", err);
+            process::exit(suite::SETUP_ERROR);
+        }
+    }
+    let mut i2c = I2c::new(pin_log.clone());
+    for (addr, target) in script.i2c {
+        if let Err(err) = i2c.connect(addr, Box::new(target)) {
+            println!("Peripheral script error: {}", err);
+            process::exit(suite::SETUP_ERROR);
+        }
+    }
+    let gpio = Gpio::new(Box::new(script.gpio), pin_log.clone());
+    for result in [
+        proc.bus.attach_irq(GPIO_BASE, gpio::GPIO_SIZE, Some(GPIO_IRQ), Box::new(gpio)),
+        proc.bus.attach(SPI_BASE, spi::SPI_SIZE, Box::new(spi)),
+        proc.bus.attach_irq(I2C_BASE, i2c::I2C_SIZE, Some(I2C_IRQ), Box::new(i2c)),
+    ] {
+        if let Err(err) = result {
+            println!("Bus configuration error: {}", err);
+            process::exit(suite::SETUP_ERROR);
+        }
+    }
+
+    // RTC (host time unless an epoch is fixed) and the MMIO entropy source
+    for result in [
+        proc.bus.attach_irq(RTC_BASE, rtc::RTC_SIZE, Some(RTC_IRQ), Box::new(Rtc::new(opts.rtc_epoch))),
+        proc.bus.attach(TRNG_BASE, entropy::TRNG_SIZE, Box::new(Trng::new(opts.entropy.fork(1)))),
+    ] {
+        if let Err(err) = result {
+            println!("Bus configuration error: {}", err);
+            process::exit(suite::SETUP_ERROR);
+        }
+    }
+
+    if let Some((width, height, format)) = opts.fb {
+        let fb = match Framebuffer::new(width, height, format, opts.fb_every, opts.fb_dump, &opts.fb_dir) {
+            Ok(fb) => fb,
+            Err(err) => {
+                println!("Could not create framebuffer: {}", err);
+                process::exit(suite::SETUP_ERROR);
+            }
+        };
+        if let Err(err) = proc.bus.attach(FB_BASE, fb.size(), Box::new(fb)) {
+            println!("Bus configuration error: {}", err);
+            process::exit(suite::SETUP_ERROR);
+        }
+    }
+    for (path, addr) in &opts.roms {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(err) => {
+                println!("Could not read ROM image {}: {}", path, err);
+                process::exit(suite::SETUP_ERROR);
+            }
+        };
+        let size = data.len().max(1);
+        if let Err(err) = proc.bus.attach(*addr, size, Box::new(Rom::new(data))) {
+            println!("Bus configuration error: {}", err);
+            process::exit(suite::SETUP_ERROR);
+        }
+    }
+    proc.bus.print_map();
+
+    if let Some(argv) = &opts.user {
+        // Linux user mode: the ELF runs in U-mode with the host environment
+        let envp: Vec<String> = env::vars().map(|(key, value)| format!("{}={}", key, value)).collect();
+        let top = RAM_BASE + RAM_SIZE * INI as usize;
+        if let Err(err) = linux::start(&mut proc, argv, &envp, top, opts.entropy.fork(2)) {
+            println!("User program error: {}", err);
+            process::exit(suite::SETUP_ERROR);
+        }
+    }
+    else {
+        let mut program_end = RAM_BASE + utils::program_end();
+        let mut symbols = HashMap::new();
+        if elf_program {
+            match elf::load(&opts.program, &mut proc.bus) {
+                Ok(image) => {
+                    utils::set_pc(image.entry as isize);
+                    program_end = image.end;
+                    symbols = image.symbols;
+                }
+                Err(err) => {
+                    println!("Program load error: {}", err);
+                    process::exit(suite::SETUP_ERROR);
+                }
+            }
+        }
+        // HTIF from the tohost/fromhost symbols unless given on the command line
+        if let Some(tohost) = opts.tohost.or(symbols.get("tohost").copied()) {
+            proc.htif = Some(Htif::new(tohost, opts.fromhost.or(symbols.get("fromhost").copied())));
+        }
+        if let Some(path) = &opts.signature {
+            let region = (symbols.get("begin_signature"), symbols.get("end_signature"));
+            match (proc.htif.as_mut(), region) {
+                (Some(htif), (Some(begin), Some(end))) => htif.set_signature(path, *begin, *end),
+                (None, _) => {
+                    println!("Signature mode needs HTIF: no tohost symbol and no --tohost");
+                    process::exit(suite::SETUP_ERROR);
+                }
+                default => {
+                    println!("Signature mode needs begin_signature and end_signature symbols in {}", opts.program);
+                    process::exit(suite::SETUP_ERROR);
+                }
+            }
+        }
+
+        let (image_end, dtb_addr, heap_limit) = match boot_handoff(&mut proc, &opts, program_end) {
+            Ok(layout) => layout,
+            Err(err) => {
+                println!("Boot image error: {}", err);
+                process::exit(suite::SETUP_ERROR);
+            }
+        };
+        if proc.sbi {
+            sbi::boot(&mut proc);
+        }
+        // Bare-metal heaps grow from the end of the image towards the stack
+        if opts.newlib {
+            proc.newlib = Some(Newlib::new(image_end, heap_limit));
+        }
+        if opts.semihosting {
+            let cmdline = opts.semihost_cmdline.clone().unwrap_or_else(|| opts.program.clone());
+            let mut semihost = match Semihost::new(&opts.semihost_root, &cmdline) {
+                Ok(semihost) => semihost,
+                Err(err) => {
+                    println!("Semihosting root error: {}", err);
+                    process::exit(suite::SETUP_ERROR);
+                }
+            };
+            semihost.set_heap(image_end, heap_limit, (dtb_addr - 16) & !15, heap_limit);
+            proc.semihost = Some(semihost);
+        }
+    }
     println!("
                                          RISCulator emulation stages
 
@@ -427,7 +1195,126 @@ fn main() {
                       │         │         │         │         │         │         │         │
                       └─────────┘         └─────────┘         └─────────┘         └─────────┘
     ", "Decode".green(), "Execute".green());
+    if let Some(path) = &opts.lockstep {
+        match Lockstep::load(path, opts.lockstep_context) {
+            Ok(lockstep) => proc.lockstep = Some(lockstep),
+            Err(err) => {
+                println!("Lockstep reference error: {}", err);
+                process::exit(suite::SETUP_ERROR);
+            }
+        }
+    }
     log::info!("Stage 2: Decode and Execute stage starting");
-    utils::stage2(&mut proc);
+    // User programs run until they exit
+    let steps = match opts.max_steps {
+        Some(steps) => steps,
+        None if opts.user.is_some() => usize::MAX,
+        None => utils::PROGRAM_LENGTH,
+    };
+    let exhausted = utils::stage2(&mut proc, steps);
     proc.regs.print_dirty();
+    if let Some(lockstep) = &proc.lockstep {
+        log::warn!("Lockstep: stopped with {} reference instructions not reached", lockstep.remaining());
+    }
+    if proc.htif.is_some() {
+        htif::dump_signature(&mut proc);
+        process::exit(htif_status(exhausted, steps));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAM_TOP: usize = RAM_BASE + RAM_SIZE * INI as usize;
+
+    // Options for a boot with the given kernel and initrd sizes (images filled with 0xA5 / 0x5A)
+    fn boot_opts(tag: &str, kernel: Option<usize>, initrd: Option<usize>) -> Options {
+        let dir = std::env::temp_dir();
+        let mut args: Vec<String> = vec!["RISCulator".into(), "prog".into()];
+        for (flag, size, fill) in [("--kernel", kernel, 0xA5), ("--initrd", initrd, 0x5A)] {
+            if let Some(size) = size {
+                let path = dir.join(format!("risculator-boot-{}{}-{}", tag, flag, std::process::id()));
+                fs::write(&path, vec![fill; size]).unwrap();
+                args.push(flag.into());
+                args.push(path.to_str().unwrap().into());
+            }
+        }
+        parse_args(&args).unwrap()
+    }
+
+    fn byte(proc: &mut Vproc, addr: usize) -> isize {
+        proc.bus.read(addr, 1).unwrap()
+    }
+
+    #[test]
+    fn images_are_placed_around_the_dtb() {
+        let mut proc = test_hart();
+        let opts = boot_opts("place", Some(0x1234), Some(0x2345));
+        let (image_end, dtb, heap_limit) = boot_handoff(&mut proc, &opts, RAM_BASE + 0x100).unwrap();
+        // Kernel at the reset vector
+        assert_eq!(image_end, RAM_BASE + 0x1234);
+        assert_eq!(byte(&mut proc, RAM_BASE), 0xA5);
+        assert_eq!(byte(&mut proc, RAM_BASE + 0x1233), 0xA5);
+        // Initrd page-aligned at the top of RAM, the DTB right below it
+        let initrd = (RAM_TOP - 0x2345) & !0xFFF;
+        assert_eq!(byte(&mut proc, initrd), 0x5A);
+        assert_eq!(byte(&mut proc, initrd + 0x2344), 0x5A);
+        assert!(dtb < initrd && dtb % 8 == 0);
+        assert_eq!(heap_limit, dtb - STACK_SIZE);
+        let magic: Vec<isize> = (0..4).map(|i| byte(&mut proc, dtb + i)).collect();
+        assert_eq!(magic, [0xD0, 0x0D, 0xFE, 0xED]);
+    }
+
+    #[test]
+    fn hands_the_dtb_to_the_kernel() {
+        let mut proc = test_hart();
+        let opts = boot_opts("handoff", Some(0x100), None);
+        let (_, dtb, _) = boot_handoff(&mut proc, &opts, RAM_BASE).unwrap();
+        assert_eq!(proc.regs.regs[10], 0);
+        assert_eq!(proc.regs.regs[11], dtb as isize);
+        let sp = proc.regs.regs[2] as usize;
+        assert!(sp < dtb && sp % 16 == 0);
+        assert!(dtb - sp <= 32);
+    }
+
+    #[test]
+    fn overlapping_images_are_refused() {
+        // Kernel larger than RAM
+        let mut proc = test_hart();
+        assert!(boot_handoff(&mut proc, &boot_opts("big", Some(RAM_TOP + 1), None), RAM_BASE).is_err());
+        // Initrd that would land on the kernel
+        let mut proc = test_hart();
+        let opts = boot_opts("initrd", Some(RAM_TOP / 2), Some(RAM_TOP / 2 + 0x1000));
+        assert!(boot_handoff(&mut proc, &opts, RAM_BASE).is_err());
+        // No room left for the DTB
+        let mut proc = test_hart();
+        assert!(boot_handoff(&mut proc, &boot_opts("dtb", Some(RAM_TOP - 0x40), None), RAM_BASE).is_err());
+        // Room for the DTB but not for the stack below it
+        let mut proc = test_hart();
+        let err = boot_handoff(&mut proc, &boot_opts("stack", Some(RAM_TOP - STACK_SIZE), None), RAM_BASE).unwrap_err();
+        assert!(err.contains("stack"));
+        // Nothing was handed over
+        assert_eq!(proc.regs.regs[11], 0);
+    }
+
+    const WFI: isize = 0x1050_0073;
+    const LOOP: isize = 0x0000_006F;       // jal x0, 0
+
+    #[test]
+    fn a_stuck_wfi_is_not_a_timeout() {
+        let mut proc = test_hart();
+        proc.bus.write(0x100, 4, WFI);
+        let (exhausted, pc) = run_hart(&mut proc, 0x100, 1000);
+        assert!(!exhausted);
+        assert_eq!(pc, 0x104);
+        assert_eq!(htif_status(exhausted, 1000), suite::NO_RESULT);
+
+        let mut proc = test_hart();
+        proc.bus.write(0x200, 4, LOOP);
+        let (exhausted, pc) = run_hart(&mut proc, 0x200, 1000);
+        assert!(exhausted);
+        assert_eq!(pc, 0x200);
+        assert_eq!(htif_status(exhausted, 1000), suite::BUDGET_EXHAUSTED);
+    }
 }
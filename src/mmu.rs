@@ -0,0 +1,405 @@
+/* RISCulator - RISC-V Emulator */
+/*   Sv32 MMU and TLB model     */
+
+// Libraries here
+use crate::Vproc;
+use crate::Mode;
+use crate::csr;
+use crate::pmp;
+
+// Constants
+const TLB_SIZE: usize = 64;         // Direct-mapped TLB entries
+const PAGE_SHIFT: usize = 12;
+const LEVELS: usize = 2;            // Sv32 has two levels of page table
+const PTE_SIZE: usize = 4;
+
+// PTE bits
+const PTE_V: usize = 1 << 0;
+const PTE_R: usize = 1 << 1;
+const PTE_W: usize = 1 << 2;
+const PTE_X: usize = 1 << 3;
+const PTE_U: usize = 1 << 4;
+const PTE_G: usize = 1 << 5;
+const PTE_A: usize = 1 << 6;
+const PTE_D: usize = 1 << 7;
+
+// Kind of memory access being translated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Fetch,
+    Load,
+    Store,
+}
+
+// TLB entry struct
+#[derive(Debug, Clone, Copy)]
+struct TlbEntry {
+    valid: bool,
+    vpn: usize,
+    asid: usize,
+    pte: usize,
+    pte_addr: usize,
+    level: usize,
+}
+
+// TLB struct
+#[derive(Debug, Clone)]
+pub struct Tlb {
+    entries: Vec<TlbEntry>,
+    hits: u64,
+    misses: u64,
+}
+
+// TLB struct impl
+impl Tlb {
+    // Initialize an empty TLB
+    pub fn new() -> Self {
+        let empty = TlbEntry { valid: false, vpn: 0, asid: 0, pte: 0, pte_addr: 0, level: 0 };
+        Self {
+            entries: vec![empty; TLB_SIZE],
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    // Look up a virtual page number for an address space
+    fn lookup(&mut self, vpn: usize, asid: usize) -> Option<TlbEntry> {
+        // Superpage entries are filed under their 4 MiB-aligned VPN
+        for level in 0..LEVELS {
+            let key = vpn & !((1 << (10 * level)) - 1);
+            let entry = self.entries[key % TLB_SIZE];
+            if entry.valid && entry.level == level && entry.vpn == key && (entry.asid == asid || entry.pte & PTE_G != 0) {
+                self.hits += 1;
+                return Some(entry);
+            }
+        }
+        self.misses += 1;
+        None
+    }
+
+    // Fill an entry after a successful walk
+    fn insert(&mut self, entry: TlbEntry) {
+        self.entries[entry.vpn % TLB_SIZE] = entry;
+    }
+
+    // SFENCE.VMA: drop entries matching the (optional) address and ASID
+    pub fn flush(&mut self, vaddr: Option<usize>, asid: Option<usize>) {
+        for entry in self.entries.iter_mut() {
+            let span = (1 << (10 * entry.level)) - 1;
+            let addr_match = match vaddr {
+                Some(va) => entry.vpn == (va >> PAGE_SHIFT) & !span,
+                None => true,
+            };
+            let asid_match = match asid {
+                Some(asid) => entry.asid == asid && entry.pte & PTE_G == 0,
+                None => true,
+            };
+            if addr_match && asid_match {
+                entry.valid = false;
+            }
+        }
+    }
+
+    // Hit and miss counters
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+}
+
+// Translate a virtual address and check PMP for `size` bytes, returning the physical address or (cause, tval) for a fault
+pub fn translate(proc: &mut Vproc, vaddr: usize, size: usize, access: Access) -> Result<usize, (isize, isize)> {
+    let vaddr = vaddr & 0xFFFF_FFFF;
+    let mstatus = proc.csrs.read(csr::MSTATUS);
+
+    // MPRV makes M-mode loads and stores use the privilege in MPP
+    let mut mode = proc.mode;
+    if access != Access::Fetch && mode == Mode::Machine && mstatus & csr::MSTATUS_MPRV != 0 {
+        mode = match (mstatus & csr::MSTATUS_MPP) >> 11 {
+            3 => Mode::Machine,
+            1 => Mode::Supervisor,
+            _ => Mode::User,
+        };
+    }
+
+    let satp = proc.csrs.read(csr::SATP) as usize & 0xFFFF_FFFF;
+    if mode == Mode::Machine || satp >> 31 == 0 {
+        return protect(proc, vaddr, vaddr, size, mode, access);        // Bare
+    }
+    let asid = (satp >> 22) & 0x1FF;
+    let vpn = vaddr >> PAGE_SHIFT;
+    let fault = (page_fault_cause(access), vaddr as isize);
+
+    let entry = match proc.tlb.lookup(vpn, asid) {
+        Some(entry) if access != Access::Store || entry.pte & PTE_D != 0 => entry,
+        _ => match walk(proc, satp, vaddr, asid, access) {
+            Ok(entry) => entry,
+            Err(cause) => return Err((cause, vaddr as isize)),
+        },
+    };
+
+    if !permitted(entry.pte, mode, access, mstatus) {
+        log::warn!("Page fault: {:?} at {:#010x} denied by PTE {:#010x}", access, vaddr, entry.pte);
+        return Err(fault);
+    }
+
+    // Hardware-managed A/D bits
+    let mut pte = entry.pte | PTE_A;
+    if access == Access::Store {
+        pte |= PTE_D;
+    }
+    if pte != entry.pte {
+        // The PTE update is an implicit S-mode store, under PMP like the walk itself
+        if !pmp::check(&proc.csrs, entry.pte_addr, PTE_SIZE, Mode::Supervisor, Access::Store)
+            || !proc.bus.write(entry.pte_addr, PTE_SIZE, pte as isize) {
+            return Err((pmp::access_fault_cause(access), vaddr as isize));
+        }
+    }
+    proc.tlb.insert(TlbEntry { pte, ..entry });
+
+    let ppn = pte >> 10;
+    let offset_mask = (1 << (PAGE_SHIFT + 10 * entry.level)) - 1;
+    let paddr = ((ppn << PAGE_SHIFT) & !offset_mask) | (vaddr & offset_mask);
+    protect(proc, vaddr, paddr, size, mode, access)
+}
+
+// PMP check on the final physical address
+fn protect(proc: &Vproc, vaddr: usize, paddr: usize, size: usize, mode: Mode, access: Access) -> Result<usize, (isize, isize)> {
+    if pmp::check(&proc.csrs, paddr, size, mode, access) {
+        Ok(paddr)
+    }
+    else {
+        Err((pmp::access_fault_cause(access), vaddr as isize))
+    }
+}
+
+// Walk the Sv32 page table, returning the leaf entry or the fault cause
+fn walk(proc: &mut Vproc, satp: usize, vaddr: usize, asid: usize, access: Access) -> Result<TlbEntry, isize> {
+    let mut table = (satp & 0x3F_FFFF) << PAGE_SHIFT;
+    let mut level = LEVELS - 1;
+    loop {
+        let index = (vaddr >> (PAGE_SHIFT + 10 * level)) & 0x3FF;
+        let pte_addr = table + index * PTE_SIZE;
+        if !pmp::check(&proc.csrs, pte_addr, PTE_SIZE, Mode::Supervisor, Access::Load) {
+            return Err(pmp::access_fault_cause(access));      // Implicit PTE accesses are checked as S-mode loads
+        }
+        let pte = match proc.bus.read(pte_addr, PTE_SIZE) {
+            Some(pte) => pte as usize & 0xFFFF_FFFF,
+            None => return Err(pmp::access_fault_cause(access)),
+        };
+        if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+            log::warn!("Page fault: invalid PTE {:#010x} at {:#010x}", pte, pte_addr);
+            return Err(page_fault_cause(access));
+        }
+        if pte & (PTE_R | PTE_X) != 0 {
+            // Leaf: a superpage must be aligned to its size
+            if level > 0 && (pte >> 10) & ((1 << (10 * level)) - 1) != 0 {
+                log::warn!("Page fault: misaligned superpage PTE {:#010x}", pte);
+                return Err(page_fault_cause(access));
+            }
+            let span = (1 << (10 * level)) - 1;
+            return Ok(TlbEntry {
+                valid: true,
+                vpn: (vaddr >> PAGE_SHIFT) & !span,
+                asid,
+                pte,
+                pte_addr,
+                level,
+            });
+        }
+        if level == 0 {
+            log::warn!("Page fault: no leaf PTE for {:#010x}", vaddr);
+            return Err(page_fault_cause(access));
+        }
+        level -= 1;
+        table = (pte >> 10) << PAGE_SHIFT;
+    }
+}
+
+// Check a leaf PTE against the access, privilege mode, SUM and MXR
+fn permitted(pte: usize, mode: Mode, access: Access, mstatus: isize) -> bool {
+    if pte & PTE_U != 0 {
+        if mode == Mode::Supervisor && (access == Access::Fetch || mstatus & csr::MSTATUS_SUM == 0) {
+            return false;
+        }
+    }
+    else if mode == Mode::User {
+        return false;
+    }
+    match access {
+        Access::Fetch => pte & PTE_X != 0,
+        Access::Load => pte & PTE_R != 0 || (mstatus & csr::MSTATUS_MXR != 0 && pte & PTE_X != 0),
+        Access::Store => pte & PTE_W != 0,
+    }
+}
+
+// Page fault cause for each access type
+fn page_fault_cause(access: Access) -> isize {
+    match access {
+        Access::Fetch => csr::CAUSE_INSTRUCTION_PAGE_FAULT,
+        Access::Load => csr::CAUSE_LOAD_PAGE_FAULT,
+        Access::Store => csr::CAUSE_STORE_PAGE_FAULT,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{run_hart, test_hart};
+
+    const ROOT: usize = 0x10000;        // Root page table
+    const TABLE: usize = 0x11000;       // The one second-level table
+    const ASID: usize = 5;
+    const SFENCE_ALL: isize = 0x1200_0073;         // sfence.vma x0, x0
+    const SFENCE_X5: isize = 0x1202_8073;          // sfence.vma x5, x0
+
+    // S-mode hart with Sv32 on, PMP open over everything and empty page tables
+    fn hart() -> Vproc {
+        let mut proc = test_hart();
+        proc.csrs.write(csr::PMPADDR0, -1);
+        proc.csrs.write(csr::PMPCFG0, 0x1F);
+        proc.csrs.write(csr::SATP, (1 << 31 | ASID << 22 | ROOT >> PAGE_SHIFT) as isize);
+        proc.mode = Mode::Supervisor;
+        proc
+    }
+
+    fn pte(paddr: usize, flags: usize) -> isize {
+        ((paddr >> PAGE_SHIFT) << 10 | flags) as isize
+    }
+
+    // Map the 4 KiB page at `vaddr` through TABLE
+    fn map(proc: &mut Vproc, vaddr: usize, paddr: usize, flags: usize) {
+        proc.bus.write(ROOT + (vaddr >> 22) * PTE_SIZE, PTE_SIZE, pte(TABLE, PTE_V));
+        proc.bus.write(TABLE + ((vaddr >> PAGE_SHIFT) & 0x3FF) * PTE_SIZE, PTE_SIZE, pte(paddr, flags));
+    }
+
+    // Leaf PTE of the 4 KiB page at `vaddr`
+    fn leaf(proc: &mut Vproc, vaddr: usize) -> usize {
+        proc.bus.read(TABLE + ((vaddr >> PAGE_SHIFT) & 0x3FF) * PTE_SIZE, PTE_SIZE).unwrap() as usize
+    }
+
+    const RW: usize = PTE_V | PTE_R | PTE_W | PTE_A | PTE_D;
+
+    #[test]
+    fn megapages_map_4_mib() {
+        let mut proc = hart();
+        proc.bus.write(ROOT + PTE_SIZE, PTE_SIZE, pte(0, RW));      // 0x0040_0000 -> 0x0
+        assert_eq!(translate(&mut proc, 0x0040_1234, 4, Access::Load), Ok(0x1234));
+        assert_eq!(translate(&mut proc, 0x0042_5678, 4, Access::Store), Ok(0x2_5678));
+        // The second access hit the superpage entry
+        assert_eq!(proc.tlb.stats(), (1, 1));
+    }
+
+    #[test]
+    fn misaligned_megapage_faults() {
+        let mut proc = hart();
+        proc.bus.write(ROOT + PTE_SIZE, PTE_SIZE, pte(0x1000, RW));
+        assert_eq!(translate(&mut proc, 0x0040_0000, 4, Access::Load), Err((csr::CAUSE_LOAD_PAGE_FAULT, 0x0040_0000)));
+        assert_eq!(translate(&mut proc, 0x0040_0004, 4, Access::Store), Err((csr::CAUSE_STORE_PAGE_FAULT, 0x0040_0004)));
+        assert_eq!(translate(&mut proc, 0x0040_0008, 4, Access::Fetch), Err((csr::CAUSE_INSTRUCTION_PAGE_FAULT, 0x0040_0008)));
+    }
+
+    #[test]
+    fn user_pages_follow_sum_and_mxr() {
+        let mut proc = hart();
+        map(&mut proc, 0x1000, 0x2000, RW | PTE_X | PTE_U);
+        map(&mut proc, 0x3000, 0x4000, RW);
+        map(&mut proc, 0x5000, 0x6000, PTE_V | PTE_X | PTE_A);
+
+        // S-mode reaches user pages only for loads and stores, and only with SUM
+        assert_eq!(translate(&mut proc, 0x1000, 4, Access::Load), Err((csr::CAUSE_LOAD_PAGE_FAULT, 0x1000)));
+        proc.csrs.write(csr::MSTATUS, csr::MSTATUS_SUM);
+        assert_eq!(translate(&mut proc, 0x1000, 4, Access::Load), Ok(0x2000));
+        assert_eq!(translate(&mut proc, 0x1004, 4, Access::Store), Ok(0x2004));
+        assert!(translate(&mut proc, 0x1008, 4, Access::Fetch).is_err());
+
+        // U-mode reaches only user pages, whatever SUM says
+        proc.mode = Mode::User;
+        assert_eq!(translate(&mut proc, 0x1008, 4, Access::Fetch), Ok(0x2008));
+        assert_eq!(translate(&mut proc, 0x3000, 4, Access::Load), Err((csr::CAUSE_LOAD_PAGE_FAULT, 0x3000)));
+
+        // Execute-only pages are readable only with MXR
+        proc.mode = Mode::Supervisor;
+        assert_eq!(translate(&mut proc, 0x5000, 4, Access::Load), Err((csr::CAUSE_LOAD_PAGE_FAULT, 0x5000)));
+        proc.csrs.write(csr::MSTATUS, csr::MSTATUS_MXR);
+        assert_eq!(translate(&mut proc, 0x5000, 4, Access::Load), Ok(0x6000));
+        assert_eq!(translate(&mut proc, 0x5000, 4, Access::Store), Err((csr::CAUSE_STORE_PAGE_FAULT, 0x5000)));
+
+        // M-mode ignores translation unless MPRV asks for it
+        proc.mode = Mode::Machine;
+        assert_eq!(translate(&mut proc, 0x5000, 4, Access::Store), Ok(0x5000));
+        proc.csrs.write(csr::MSTATUS, csr::MSTATUS_MPRV | 1 << 11);     // MPP = S
+        assert_eq!(translate(&mut proc, 0x3000, 4, Access::Load), Ok(0x4000));
+    }
+
+    #[test]
+    fn accessed_and_dirty_are_set_by_hardware() {
+        let mut proc = hart();
+        map(&mut proc, 0x1000, 0x2000, PTE_V | PTE_R | PTE_W);
+        map(&mut proc, 0x3000, 0x4000, PTE_V | PTE_R);
+        assert_eq!(translate(&mut proc, 0x1000, 4, Access::Load), Ok(0x2000));
+        assert_eq!(leaf(&mut proc, 0x1000) & (PTE_A | PTE_D), PTE_A);
+        // A store through a clean TLB entry walks again and marks the page dirty
+        assert_eq!(translate(&mut proc, 0x1000, 4, Access::Store), Ok(0x2000));
+        assert_eq!(leaf(&mut proc, 0x1000) & (PTE_A | PTE_D), PTE_A | PTE_D);
+        // A faulting store leaves the PTE alone
+        assert!(translate(&mut proc, 0x3000, 4, Access::Store).is_err());
+        assert_eq!(leaf(&mut proc, 0x3000) & (PTE_A | PTE_D), 0);
+    }
+
+    #[test]
+    fn pte_updates_are_checked_by_pmp() {
+        let mut proc = hart();
+        map(&mut proc, 0x1000, 0x2000, PTE_V | PTE_R | PTE_W);
+        map(&mut proc, 0x3000, 0x4000, RW);
+        // Entry 0: the page tables are read-only; entry 1: everything else RWX
+        proc.csrs.write(csr::PMPADDR0, ((ROOT >> 2) | 0x3FF) as isize);
+        proc.csrs.write(csr::PMPADDR0 + 1, -1);
+        proc.csrs.write(csr::PMPCFG0, 0x1F19);
+        assert_eq!(translate(&mut proc, 0x1000, 4, Access::Load), Err((csr::CAUSE_LOAD_ACCESS_FAULT, 0x1000)));
+        assert_eq!(leaf(&mut proc, 0x1000) & PTE_A, 0);
+        // Nothing to write back: the walk alone is allowed
+        assert_eq!(translate(&mut proc, 0x3000, 4, Access::Store), Ok(0x4000));
+    }
+
+    #[test]
+    fn sfence_vma_drops_stale_translations() {
+        let mut proc = hart();
+        map(&mut proc, 0x1000, 0x2000, RW);
+        map(&mut proc, 0x3000, 0x4000, RW);
+        assert_eq!(translate(&mut proc, 0x1000, 4, Access::Load), Ok(0x2000));
+        assert_eq!(translate(&mut proc, 0x3000, 4, Access::Load), Ok(0x4000));
+        map(&mut proc, 0x1000, 0x6000, RW);
+        map(&mut proc, 0x3000, 0x7000, RW);
+        assert_eq!(translate(&mut proc, 0x1000, 4, Access::Load), Ok(0x2000));
+
+        // sfence.vma x5, x0 from M-mode drops only the page in x5
+        proc.bus.write(0x100, 4, SFENCE_X5);
+        proc.bus.write(0x104, 4, SFENCE_ALL);
+        proc.regs.write(5, 0x1000);
+        proc.mode = Mode::Machine;
+        run_hart(&mut proc, 0x100, 1);
+        proc.mode = Mode::Supervisor;
+        assert_eq!(translate(&mut proc, 0x1000, 4, Access::Load), Ok(0x6000));
+        assert_eq!(translate(&mut proc, 0x3000, 4, Access::Load), Ok(0x4000));
+
+        // sfence.vma x0, x0 drops everything
+        proc.mode = Mode::Machine;
+        run_hart(&mut proc, 0x104, 1);
+        proc.mode = Mode::Supervisor;
+        assert_eq!(translate(&mut proc, 0x3000, 4, Access::Load), Ok(0x7000));
+    }
+
+    #[test]
+    fn flush_by_asid_keeps_global_pages() {
+        let mut proc = hart();
+        map(&mut proc, 0x1000, 0x2000, RW | PTE_G);
+        map(&mut proc, 0x3000, 0x4000, RW);
+        translate(&mut proc, 0x1000, 4, Access::Load).unwrap();
+        translate(&mut proc, 0x3000, 4, Access::Load).unwrap();
+        map(&mut proc, 0x1000, 0x6000, RW | PTE_G);
+        map(&mut proc, 0x3000, 0x7000, RW);
+        proc.tlb.flush(None, Some(ASID));
+        assert_eq!(translate(&mut proc, 0x1000, 4, Access::Load), Ok(0x2000));
+        assert_eq!(translate(&mut proc, 0x3000, 4, Access::Load), Ok(0x7000));
+    }
+}
@@ -0,0 +1,169 @@
+/* RISCulator - RISC-V Emulator */
+/*  Newlib/libgloss syscall shim */
+
+// Libraries here
+use crate::{utils, Register, Vproc, RTC_BASE};
+use crate::linux::{read_cstr, read_mem, signed, write_mem, Fds, OpenFlags, IO_CHUNK};
+use crate::rtc;
+
+/*
+ * Host services for bare-metal newlib/picolibc programs (--newlib)
+ *
+ * libgloss' RISC-V port issues ECALL with the syscall number in a7 and arguments in
+ * a0-a3, expecting the result (or -errno) in a0. ECALLs from M-mode are served here
+ * instead of trapping, so printf and exit() work without any devices.
+ */
+
+// Syscall numbers (libgloss/riscv/machine/syscall.h)
+const SYS_CLOSE: isize = 57;
+const SYS_LSEEK: isize = 62;
+const SYS_READ: isize = 63;
+const SYS_WRITE: isize = 64;
+const SYS_FSTAT: isize = 80;
+const SYS_EXIT: isize = 93;
+const SYS_GETTIMEOFDAY: isize = 169;
+const SYS_BRK: isize = 214;
+const SYS_CLOCK_GETTIME64: isize = 403;     // gettimeofday on RV32 libgloss
+const SYS_OPEN: isize = 1024;
+
+// Error numbers (returned negated)
+const EBADF: isize = 9;
+const EFAULT: isize = 14;
+const ENOSYS: isize = 38;
+
+// newlib open flags (not the Linux values)
+const NEWLIB_OPEN: OpenFlags = OpenFlags { creat: 0x200, excl: 0x800, trunc: 0x400, append: 0x8 };
+
+// Newlib shim state
+#[derive(Debug)]
+pub struct Newlib {
+    fds: Fds,
+    brk_start: usize,
+    brk: usize,
+    brk_max: usize,
+}
+
+// Newlib impl
+impl Newlib {
+    // Heap from `heap` (end of the loaded image) up to `limit`
+    pub fn new(heap: usize, limit: usize) -> Self {
+        let heap = (heap + 0xF) & !0xF;
+        Self {
+            fds: Fds::new(),
+            brk_start: heap,
+            brk: heap,
+            brk_max: limit.max(heap),
+        }
+    }
+
+    // Run one system call, returning the value for a0 (negative errno on failure)
+    fn call(&mut self, proc: &mut Vproc, nr: isize, a: &[isize]) -> isize {
+        match nr {
+            SYS_WRITE => match read_mem(&mut proc.bus, a[1] as usize, (a[2] as usize).min(IO_CHUNK)) {
+                Some(buf) => self.fds.write(signed(a[0]), &buf),
+                None => -EFAULT,
+            },
+            SYS_READ => self.fds.read(&mut proc.bus, signed(a[0]), a[1] as usize, a[2] as usize),
+            SYS_OPEN => match read_cstr(&mut proc.bus, a[0] as usize) {
+                Some(path) => self.fds.open(&path, a[1], a[2], &NEWLIB_OPEN),
+                None => -EFAULT,
+            },
+            SYS_CLOSE => self.fds.close(signed(a[0])),
+            SYS_LSEEK => match self.fds.get(signed(a[0])).map(|file| file.seek(signed(a[1]) as i64, a[2])) {
+                Some(Ok(pos)) => pos as isize,
+                Some(Err(err)) => err,
+                None => -EBADF,
+            },
+            SYS_FSTAT => {
+                let stat = match self.fds.get(signed(a[0])).map(|file| file.stat()) {
+                    Some(Ok(stat)) => stat,
+                    Some(Err(err)) => return err,
+                    None => return -EBADF,
+                };
+                if write_mem(&mut proc.bus, a[1] as usize, &stat.to_stat64()) { 0 } else { -EFAULT }
+            }
+            SYS_BRK => {
+                // brk(0) reports the heap start; a refused request returns the old break
+                let addr = a[0] as usize;
+                if addr >= self.brk_start && addr <= self.brk_max {
+                    self.brk = addr;
+                }
+                self.brk as isize
+            }
+            SYS_GETTIMEOFDAY | SYS_CLOCK_GETTIME64 => {
+                // Wall-clock time comes from the RTC, so --rtc-epoch makes it reproducible
+                let low = proc.bus.read(RTC_BASE + rtc::TIME_LOW, 4).unwrap_or(0) as u64 & 0xFFFF_FFFF;
+                let high = proc.bus.read(RTC_BASE + rtc::TIME_HIGH, 4).unwrap_or(0) as u64 & 0xFFFF_FFFF;
+                let ns = high << 32 | low;
+                let (sec, frac) = (ns / 1_000_000_000, ns % 1_000_000_000);
+                let bytes: Vec<u8> = if nr == SYS_GETTIMEOFDAY {
+                    [(sec as u32).to_le_bytes(), ((frac / 1000) as u32).to_le_bytes()].concat()
+                } else {
+                    [sec.to_le_bytes(), frac.to_le_bytes()].concat()
+                };
+                if write_mem(&mut proc.bus, a[if nr == SYS_GETTIMEOFDAY { 0 } else { 1 }] as usize, &bytes) { 0 } else { -EFAULT }
+            }
+            SYS_EXIT => {
+                let status = signed(a[0]) as i32;
+                log::info!("Program exited with status {}", status);
+                utils::exit(proc, status);
+            }
+            default => {
+                log::warn!("Newlib: unimplemented syscall {} ({:#x}, {:#x}, {:#x})", nr, a[0], a[1], a[2]);
+                -ENOSYS
+            }
+        }
+    }
+}
+
+// Serve an ECALL from M-mode
+pub fn syscall(proc: &mut Vproc, regs: &mut Register) {
+    let mut newlib = match proc.newlib.take() {
+        Some(newlib) => newlib,
+        None => return,
+    };
+    let args: Vec<isize> = (10..14).map(|reg| proc.regs.regs[reg] & 0xFFFF_FFFF).collect();
+    let nr = proc.regs.regs[17] & 0xFFFF_FFFF;
+    let ret = newlib.call(proc, nr, &args);
+    log::info!("Newlib: syscall {} = {}", nr, ret);
+    regs.write(10, ret);
+    proc.newlib = Some(newlib);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{console, test_hart, RAM_BASE};
+    use std::io::Cursor;
+
+    const BUF: usize = RAM_BASE + 0x1000;
+
+    #[test]
+    fn reads_stdin_through_the_shared_console() {
+        console::set_source(Box::new(Cursor::new(b"42\nrest".to_vec())));
+        let mut proc = test_hart();
+        let mut newlib = Newlib::new(RAM_BASE + 0x2000, RAM_BASE + 0x3000);
+        let mut input = vec![];
+        loop {
+            let n = newlib.call(&mut proc, SYS_READ, &[0, BUF as isize, 16, 0]);
+            assert!(n >= 0);
+            if n == 0 {
+                break;
+            }
+            input.extend(read_mem(&mut proc.bus, BUF, n as usize).unwrap());
+        }
+        assert_eq!(input, b"42\nrest");
+        assert_eq!(newlib.call(&mut proc, SYS_READ, &[7, BUF as isize, 16, 0]), -EBADF);
+    }
+
+    #[test]
+    fn brk_stays_inside_the_heap() {
+        let mut proc = test_hart();
+        let mut newlib = Newlib::new(RAM_BASE + 0x2001, RAM_BASE + 0x3000);
+        let start = (RAM_BASE + 0x2010) as isize;
+        assert_eq!(newlib.call(&mut proc, SYS_BRK, &[0, 0, 0, 0]), start);
+        assert_eq!(newlib.call(&mut proc, SYS_BRK, &[start + 0x100, 0, 0, 0]), start + 0x100);
+        assert_eq!(newlib.call(&mut proc, SYS_BRK, &[(RAM_BASE + 0x3001) as isize, 0, 0, 0]), start + 0x100);
+        assert_eq!(newlib.call(&mut proc, SYS_BRK, &[start - 1, 0, 0, 0]), start + 0x100);
+    }
+}
@@ -0,0 +1,48 @@
+/* RISCulator - RISC-V Emulator */
+/*     Pin activity log         */
+
+// Libraries here
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::Write;
+use std::rc::Rc;
+
+// Pin log struct: one line per event, "<clock> <device> <event>"
+#[derive(Debug)]
+pub struct PinLog {
+    file: Option<File>,
+    events: u64,
+}
+
+// Pin log struct impl
+impl PinLog {
+    // Log to a file, or only to the emulator log when none is given
+    pub fn new(path: Option<&str>) -> Result<Rc<RefCell<PinLog>>, String> {
+        let file = match path {
+            Some(path) => match File::create(path) {
+                Ok(file) => Some(file),
+                Err(err) => return Err(format!("{}: {}", path, err)),
+            },
+            None => None,
+        };
+        Ok(Rc::new(RefCell::new(Self {
+            file,
+            events: 0,
+        })))
+    }
+
+    // Record one event
+    pub fn record(&mut self, clock: u64, device: &str, event: &str) {
+        self.events += 1;
+        log::info!("Pins: {} {} {}", clock, device, event);
+        if let Some(file) = self.file.as_mut() {
+            if let Err(err) = writeln!(file, "{} {} {}", clock, device, event) {
+                log::error!("Pins: could not write the pin log: {}", err);
+            }
+        }
+    }
+
+    pub fn events(&self) -> u64 {
+        self.events
+    }
+}
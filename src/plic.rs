@@ -0,0 +1,349 @@
+/* RISCulator - RISC-V Emulator */
+/* Platform-Level Interrupt Ctrl */
+
+// Libraries here
+use std::cell::RefCell;
+use std::rc::Rc;
+use colored::*;
+use crate::bus::{size_mask, Device};
+use crate::csr::{IRQ_MEI, IRQ_SEI};
+use crate::fdt::{Fdt, PHANDLE_CPU_INTC, PHANDLE_PLIC};
+
+// Register layout
+const PRIORITY: usize = 0x000000;           // 4 bytes per source
+const PENDING: usize = 0x001000;            // 1 bit per source
+const ENABLE: usize = 0x002000;             // 0x80 bytes per context
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT: usize = 0x200000;            // threshold, then claim/complete
+const CONTEXT_STRIDE: usize = 0x1000;
+pub const PLIC_SIZE: usize = 0x4000000;
+pub const PLIC_SOURCES: usize = 64;         // Source 0 is reserved
+const PRIORITY_MASK: u32 = 0x7;             // 3 priority bits
+
+// PLIC struct
+#[derive(Debug)]
+pub struct Plic {
+    sources: usize,
+    contexts: usize,
+    priority: Vec<u32>,
+    pending: Vec<bool>,
+    claimed: Vec<bool>,                     // In service: claimed but not completed
+    enable: Vec<Vec<bool>>,                 // [context][source]
+    threshold: Vec<u32>,
+}
+
+// PLIC struct impl
+impl Plic {
+    // Initialize a PLIC with a number of sources (including the reserved 0) and contexts
+    pub fn new(sources: usize, contexts: usize) -> Self {
+        Self {
+            sources,
+            contexts,
+            priority: vec![0; sources],
+            pending: vec![false; sources],
+            claimed: vec![false; sources],
+            enable: vec![vec![false; sources]; contexts],
+            threshold: vec![0; contexts],
+        }
+    }
+
+    // Wrap in the shared handle used by both the bus and the hart
+    pub fn shared(self) -> Rc<RefCell<Plic>> {
+        Rc::new(RefCell::new(self))
+    }
+
+    // Level-triggered gateways: an asserted line becomes pending unless already in service
+    pub fn update(&mut self, lines: &[usize]) {
+        for &source in lines {
+            if source > 0 && source < self.sources && !self.claimed[source] {
+                self.pending[source] = true;
+            }
+        }
+    }
+
+    // Best pending and enabled source above the context threshold (lowest ID wins ties)
+    fn best(&self, context: usize) -> Option<usize> {
+        let mut best: Option<usize> = None;
+        for source in 1..self.sources {
+            if self.pending[source] && self.enable[context][source] && self.priority[source] > self.threshold[context] {
+                match best {
+                    Some(b) if self.priority[b] >= self.priority[source] => {}
+                    _ => best = Some(source),
+                }
+            }
+        }
+        best
+    }
+
+    // External interrupt line into a hart context
+    pub fn eip(&self, context: usize) -> bool {
+        context < self.contexts && self.best(context).is_some()
+    }
+
+    // Claim the best interrupt for a context (0 if none)
+    fn claim(&mut self, context: usize) -> u32 {
+        match self.best(context) {
+            Some(source) => {
+                self.pending[source] = false;
+                self.claimed[source] = true;
+                source as u32
+            }
+            None => 0,
+        }
+    }
+
+    // Complete an interrupt, ignored unless the source is enabled for the context
+    fn complete(&mut self, context: usize, source: usize) {
+        if source > 0 && source < self.sources && self.enable[context][source] {
+            self.claimed[source] = false;
+        }
+    }
+
+    // Read a 32-bit register
+    fn read_word(&mut self, offset: usize) -> Option<u32> {
+        if offset < PENDING {
+            let source = (offset - PRIORITY) / 4;
+            return if source < self.sources { Some(self.priority[source]) } else { None };
+        }
+        if offset < ENABLE {
+            let word = (offset - PENDING) / 4;
+            return Some(pack(&self.pending, word));
+        }
+        if offset < CONTEXT {
+            let context = (offset - ENABLE) / ENABLE_STRIDE;
+            let word = (offset - ENABLE) % ENABLE_STRIDE / 4;
+            return if context < self.contexts { Some(pack(&self.enable[context], word)) } else { None };
+        }
+        let context = (offset - CONTEXT) / CONTEXT_STRIDE;
+        if context >= self.contexts {
+            return None;
+        }
+        match (offset - CONTEXT) % CONTEXT_STRIDE {
+            0 => Some(self.threshold[context]),
+            4 => Some(self.claim(context)),
+            default => Some(0),
+        }
+    }
+
+    // Write a 32-bit register
+    fn write_word(&mut self, offset: usize, value: u32) -> bool {
+        if offset < PENDING {
+            let source = (offset - PRIORITY) / 4;
+            if source == 0 || source >= self.sources {
+                return source == 0;     // Source 0 has no priority
+            }
+            self.priority[source] = value & PRIORITY_MASK;
+            return true;
+        }
+        if offset < ENABLE {
+            return true;                // Pending bits are read-only
+        }
+        if offset < CONTEXT {
+            let context = (offset - ENABLE) / ENABLE_STRIDE;
+            let word = (offset - ENABLE) % ENABLE_STRIDE / 4;
+            if context >= self.contexts {
+                return false;
+            }
+            for bit in 0..32 {
+                let source = word * 32 + bit;
+                if source > 0 && source < self.sources {
+                    self.enable[context][source] = value & (1 << bit) != 0;
+                }
+            }
+            return true;
+        }
+        let context = (offset - CONTEXT) / CONTEXT_STRIDE;
+        if context >= self.contexts {
+            return false;
+        }
+        match (offset - CONTEXT) % CONTEXT_STRIDE {
+            0 => self.threshold[context] = value & PRIORITY_MASK,
+            4 => self.complete(context, value as usize),
+            default => {}
+        }
+        true
+    }
+}
+
+// Pack 32 flags starting at source word * 32 into a register
+fn pack(flags: &[bool], word: usize) -> u32 {
+    let mut value = 0;
+    for bit in 0..32 {
+        if flags.get(word * 32 + bit) == Some(&true) {
+            value |= 1 << bit;
+        }
+    }
+    value
+}
+
+// PLIC device: 32-bit registers only
+impl Device for Rc<RefCell<Plic>> {
+    fn name(&self) -> &str {
+        "plic"
+    }
+
+    fn read(&mut self, offset: usize, size: usize) -> Option<u64> {
+        if size != 4 || offset % 4 != 0 {
+            return None;
+        }
+        self.borrow_mut().read_word(offset).map(|value| value as u64 & size_mask(size))
+    }
+
+    fn write(&mut self, offset: usize, size: usize, value: u64) -> bool {
+        if size != 4 || offset % 4 != 0 {
+            return false;
+        }
+        self.borrow_mut().write_word(offset, value as u32)
+    }
+
+    fn reset(&mut self) {
+        let mut plic = self.borrow_mut();
+        let (sources, contexts) = (plic.sources, plic.contexts);
+        *plic = Plic::new(sources, contexts);
+    }
+
+    // Contexts alternate M and S mode, two per hart
+    fn describe(&self, fdt: &mut Fdt, base: usize, size: usize, irq: Option<usize>) {
+        let plic = self.borrow();
+        let mut wiring = vec![];
+        for context in 0..plic.contexts {
+            let cause = if context % 2 == 0 { IRQ_MEI } else { IRQ_SEI };
+            wiring.extend_from_slice(&[PHANDLE_CPU_INTC + (context / 2) as u32, cause.trailing_zeros()]);
+        }
+        fdt.soc_device("plic", &["sifive,plic-1.0.0", "riscv,plic0"], base, size, irq)
+            .u32("#address-cells", 0)
+            .u32("#interrupt-cells", 1)
+            .empty("interrupt-controller")
+            .u32("riscv,ndev", plic.sources as u32 - 1)
+            .cells("interrupts-extended", &wiring)
+            .u32("phandle", PHANDLE_PLIC);
+    }
+
+    fn print_dirty(&mut self) {
+        let plic = self.borrow();
+        println!("{}", "--------------------------------".green());
+        println!("{}", "PLIC".green());
+        println!("{}", "--------------------------------".green());
+        for source in 1..plic.sources {
+            if plic.priority[source] != 0 || plic.pending[source] || plic.claimed[source] {
+                println!("source {}: priority {} pending {} claimed {}", source, plic.priority[source], plic.pending[source] as u8, plic.claimed[source] as u8);
+            }
+        }
+        for context in 0..plic.contexts {
+            println!("context {}: threshold {} enable {:#018x}", context, plic.threshold[context],
+                pack(&plic.enable[context], 0) as u64 | (pack(&plic.enable[context], 1) as u64) << 32);
+        }
+        println!("{}", "--------------------------------".green());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Eight sources (1-7 usable) and two contexts
+    fn plic() -> Rc<RefCell<Plic>> {
+        Plic::new(8, 2).shared()
+    }
+
+    fn set_priority(plic: &mut Rc<RefCell<Plic>>, source: usize, priority: u64) {
+        assert!(plic.write(PRIORITY + 4 * source, 4, priority));
+    }
+
+    fn enable(plic: &mut Rc<RefCell<Plic>>, context: usize, mask: u64) {
+        assert!(plic.write(ENABLE + context * ENABLE_STRIDE, 4, mask));
+    }
+
+    fn threshold(plic: &mut Rc<RefCell<Plic>>, context: usize, value: u64) {
+        assert!(plic.write(CONTEXT + context * CONTEXT_STRIDE, 4, value));
+    }
+
+    fn claim(plic: &mut Rc<RefCell<Plic>>, context: usize) -> u64 {
+        plic.read(CONTEXT + context * CONTEXT_STRIDE + 4, 4).unwrap()
+    }
+
+    fn complete(plic: &mut Rc<RefCell<Plic>>, context: usize, source: u64) {
+        assert!(plic.write(CONTEXT + context * CONTEXT_STRIDE + 4, 4, source));
+    }
+
+    #[test]
+    fn highest_priority_above_the_threshold_wins() {
+        let mut plic = plic();
+        set_priority(&mut plic, 1, 1);
+        set_priority(&mut plic, 2, 3);
+        set_priority(&mut plic, 3, 3);
+        set_priority(&mut plic, 4, 0xF);        // Only three bits
+        assert_eq!(plic.read(PRIORITY + 16, 4), Some(7));
+        set_priority(&mut plic, 4, 0);
+        enable(&mut plic, 0, 0b1110);
+        plic.borrow_mut().update(&[1, 2, 3]);
+
+        threshold(&mut plic, 0, 3);
+        assert!(!plic.borrow().eip(0));
+        assert_eq!(claim(&mut plic, 0), 0);
+        threshold(&mut plic, 0, 2);
+        assert!(plic.borrow().eip(0));
+        // Equal priorities go to the lower ID
+        assert_eq!(claim(&mut plic, 0), 2);
+        assert_eq!(claim(&mut plic, 0), 3);
+        assert!(!plic.borrow().eip(0));
+        threshold(&mut plic, 0, 0);
+        assert_eq!(claim(&mut plic, 0), 1);
+        assert_eq!(claim(&mut plic, 0), 0);
+    }
+
+    #[test]
+    fn claimed_sources_wait_for_completion() {
+        let mut plic = plic();
+        set_priority(&mut plic, 5, 1);
+        enable(&mut plic, 0, 1 << 5);
+        plic.borrow_mut().update(&[5]);
+        assert_eq!(plic.read(PENDING, 4), Some(1 << 5));
+        assert_eq!(claim(&mut plic, 0), 5);
+        assert_eq!(plic.read(PENDING, 4), Some(0));
+
+        // The line is still high, but the source is in service
+        plic.borrow_mut().update(&[5]);
+        assert!(!plic.borrow().eip(0));
+        // Completing from a context that does not enable the source is ignored
+        complete(&mut plic, 1, 5);
+        plic.borrow_mut().update(&[5]);
+        assert!(!plic.borrow().eip(0));
+        complete(&mut plic, 0, 5);
+        plic.borrow_mut().update(&[5]);
+        assert!(plic.borrow().eip(0));
+        assert_eq!(claim(&mut plic, 0), 5);
+    }
+
+    #[test]
+    fn enables_are_per_context() {
+        let mut plic = plic();
+        set_priority(&mut plic, 4, 2);
+        enable(&mut plic, 1, 1 << 4 | 1);       // Source 0 cannot be enabled
+        assert_eq!(plic.read(ENABLE + ENABLE_STRIDE, 4), Some(1 << 4));
+        assert_eq!(plic.read(ENABLE, 4), Some(0));
+        plic.borrow_mut().update(&[4]);
+        assert!(!plic.borrow().eip(0));
+        assert!(plic.borrow().eip(1));
+        assert_eq!(claim(&mut plic, 0), 0);
+        assert_eq!(claim(&mut plic, 1), 4);
+        // No third context
+        assert_eq!(plic.read(CONTEXT + 2 * CONTEXT_STRIDE + 4, 4), None);
+        assert!(!plic.write(ENABLE + 2 * ENABLE_STRIDE, 4, 1));
+    }
+
+    #[test]
+    fn priority_zero_never_interrupts() {
+        let mut plic = plic();
+        enable(&mut plic, 0, 0xFE);
+        plic.borrow_mut().update(&[6]);
+        assert_eq!(plic.read(PENDING, 4), Some(1 << 6));
+        assert!(!plic.borrow().eip(0));
+        assert_eq!(claim(&mut plic, 0), 0);
+        // Source 0 is reserved: its priority stays zero and its line is ignored
+        set_priority(&mut plic, 0, 7);
+        assert_eq!(plic.read(PRIORITY, 4), Some(0));
+        plic.borrow_mut().update(&[0]);
+        assert_eq!(plic.read(PENDING, 4), Some(1 << 6));
+    }
+}
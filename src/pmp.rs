@@ -0,0 +1,155 @@
+/* RISCulator - RISC-V Emulator */
+/*  Physical Memory Protection  */
+
+// Libraries here
+use crate::Mode;
+use crate::csr;
+use crate::csr::Csr;
+use crate::mmu::Access;
+
+// pmpcfg fields
+const PMP_R: isize = 1 << 0;
+const PMP_W: isize = 1 << 1;
+const PMP_X: isize = 1 << 2;
+const PMP_L: isize = 1 << 7;
+
+// Address matching modes (pmpcfg.A)
+const A_OFF: isize = 0;
+const A_TOR: isize = 1;
+const A_NA4: isize = 2;
+const A_NAPOT: isize = 3;
+
+// Check a physical access of `size` bytes against the PMP entries
+pub fn check(csrs: &Csr, paddr: usize, size: usize, mode: Mode, access: Access) -> bool {
+    if csrs.pmp_entries() == 0 {
+        return true;
+    }
+    let start = paddr as u64;
+    let end = start + size as u64;
+    let mut prev_top: u64 = 0;
+
+    // The lowest-numbered matching entry decides
+    for entry in 0..csrs.pmp_entries() {
+        let cfg = csrs.pmpcfg(entry);
+        let pmpaddr = (csrs.read(csr::PMPADDR0 + entry) as u64) & 0xFFFF_FFFF;
+        let (base, top) = match (cfg >> 3) & 0x3 {
+            A_TOR => (prev_top, pmpaddr << 2),
+            A_NA4 => (pmpaddr << 2, (pmpaddr << 2) + 4),
+            A_NAPOT => {
+                let ones = pmpaddr.trailing_ones() as u64;
+                let span = 1u64 << (ones + 3);
+                let base = (pmpaddr & !((1u64 << ones) - 1)) << 2;
+                (base, base + span)
+            }
+            _ => (0, 0),
+        };
+        prev_top = pmpaddr << 2;
+        if (cfg >> 3) & 0x3 == A_OFF || end <= base || start >= top {
+            continue;
+        }
+        if start < base || end > top {
+            log::warn!("PMP: access {:#010x}+{} only partially matches entry {}", paddr, size, entry);
+            return false;
+        }
+        // M-mode is only bound by locked entries
+        if mode == Mode::Machine && cfg & PMP_L == 0 {
+            return true;
+        }
+        let allowed = match access {
+            Access::Fetch => cfg & PMP_X != 0,
+            Access::Load => cfg & PMP_R != 0,
+            Access::Store => cfg & PMP_W != 0,
+        };
+        if !allowed {
+            log::warn!("PMP: {:?} at {:#010x} denied by entry {} (cfg {:#04x})", access, paddr, entry, cfg);
+        }
+        return allowed;
+    }
+
+    // No match: M-mode succeeds, S/U-mode fails
+    mode == Mode::Machine
+}
+
+// Access fault cause for each access type
+pub fn access_fault_cause(access: Access) -> isize {
+    match access {
+        Access::Fetch => csr::CAUSE_INSTRUCTION_ACCESS_FAULT,
+        Access::Load => csr::CAUSE_LOAD_ACCESS_FAULT,
+        Access::Store => csr::CAUSE_STORE_ACCESS_FAULT,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // pmpcfg byte: A field and R/W/X/L bits
+    fn cfg(a: isize, perms: isize) -> isize {
+        (a << 3) | perms
+    }
+
+    #[test]
+    fn tor_na4_and_napot_ranges() {
+        let mut csrs = Csr::new(16, csr::HPM_COUNTERS);
+        csrs.write(csr::PMPADDR0, 0x1000 >> 2);                     // TOR [0, 0x1000)
+        csrs.write(csr::PMPADDR0 + 1, 0x2000 >> 2);                 // NA4 [0x2000, 0x2004)
+        csrs.write(csr::PMPADDR0 + 2, (0x4000 >> 2) | 0x1FF);       // NAPOT [0x4000, 0x5000)
+        csrs.write(csr::PMPCFG0, cfg(A_TOR, PMP_R | PMP_X) | cfg(A_NA4, PMP_R | PMP_W) << 8 | cfg(A_NAPOT, PMP_R) << 16);
+
+        assert!(check(&csrs, 0x0, 4, Mode::User, Access::Fetch));
+        assert!(check(&csrs, 0xFFC, 4, Mode::User, Access::Load));
+        assert!(!check(&csrs, 0xFFC, 4, Mode::User, Access::Store));
+        assert!(check(&csrs, 0x2000, 4, Mode::Supervisor, Access::Store));
+        assert!(!check(&csrs, 0x2004, 4, Mode::Supervisor, Access::Store));     // Past NA4, no match
+        assert!(check(&csrs, 0x4FFC, 4, Mode::User, Access::Load));
+        assert!(!check(&csrs, 0x4FFC, 4, Mode::User, Access::Store));
+        assert!(!check(&csrs, 0x5000, 4, Mode::User, Access::Load));
+        // Straddling the top of an entry only partially matches it
+        assert!(!check(&csrs, 0xFFE, 4, Mode::User, Access::Load));
+    }
+
+    #[test]
+    fn lowest_entry_wins_and_machine_mode_needs_a_lock() {
+        let mut csrs = Csr::new(16, csr::HPM_COUNTERS);
+        csrs.write(csr::PMPADDR0, (0x1000 >> 2) | 0x1FF);           // NAPOT [0x1000, 0x2000), no access
+        csrs.write(csr::PMPADDR0 + 1, 0x3_FFFF_FFFF_u64 as isize);  // NAPOT over everything, RWX
+        csrs.write(csr::PMPCFG0, cfg(A_NAPOT, 0) | cfg(A_NAPOT, PMP_R | PMP_W | PMP_X) << 8);
+
+        assert!(!check(&csrs, 0x1800, 4, Mode::User, Access::Load));
+        assert!(check(&csrs, 0x2800, 4, Mode::User, Access::Load));
+        assert!(check(&csrs, 0x1800, 4, Mode::Machine, Access::Load));
+
+        csrs.write(csr::PMPCFG0, cfg(A_NAPOT, PMP_L) | cfg(A_NAPOT, PMP_R | PMP_W | PMP_X) << 8);
+        assert!(!check(&csrs, 0x1800, 4, Mode::Machine, Access::Load));
+        // Locked entries ignore later writes
+        csrs.write(csr::PMPCFG0, cfg(A_NAPOT, PMP_R));
+        assert_eq!(csrs.pmpcfg(0), cfg(A_NAPOT, PMP_L));
+    }
+
+    #[test]
+    fn unmatched_accesses_fail_below_machine_mode() {
+        let mut csrs = Csr::new(16, csr::HPM_COUNTERS);
+        csrs.write(csr::PMPADDR0, 0x1000 >> 2);
+        csrs.write(csr::PMPCFG0, cfg(A_TOR, PMP_R));
+        assert!(!check(&csrs, 0x8000, 4, Mode::User, Access::Load));
+        assert!(check(&csrs, 0x8000, 4, Mode::Machine, Access::Load));
+    }
+
+    #[test]
+    fn entry_count_follows_the_configuration() {
+        // Without PMP every access is allowed
+        let csrs = Csr::new(0, csr::HPM_COUNTERS);
+        assert!(check(&csrs, 0x8000, 4, Mode::User, Access::Store));
+
+        // Entries past the configured count stay zero
+        let mut csrs = Csr::new(16, csr::HPM_COUNTERS);
+        csrs.write(csr::PMPCFG0 + 4, cfg(A_NA4, PMP_R));
+        assert_eq!(csrs.pmpcfg(16), 0);
+        let mut csrs = Csr::new(64, csr::HPM_COUNTERS);
+        csrs.write(csr::PMPCFG0 + 15, cfg(A_NA4, PMP_R) << 24);
+        assert_eq!(csrs.pmpcfg(63), cfg(A_NA4, PMP_R));
+        csrs.write(csr::PMPADDR0 + 63, 0x40 >> 2);
+        assert!(check(&csrs, 0x40, 4, Mode::User, Access::Load));
+        assert!(!check(&csrs, 0x40, 4, Mode::User, Access::Store));
+    }
+}
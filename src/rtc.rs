@@ -0,0 +1,207 @@
+/* RISCulator - RISC-V Emulator */
+/*   Goldfish real-time clock   */
+
+// Libraries here
+use std::time::{SystemTime, UNIX_EPOCH};
+use colored::*;
+use crate::bus::{size_mask, Device};
+use crate::clint::CPU_FREQ;
+use crate::fdt::Fdt;
+
+// Register offsets
+pub const TIME_LOW: usize = 0x00;   // Reading latches TIME_HIGH
+pub const TIME_HIGH: usize = 0x04;
+const ALARM_LOW: usize = 0x08;      // Writing arms the alarm
+const ALARM_HIGH: usize = 0x0C;
+const IRQ_ENABLED: usize = 0x10;
+const CLEAR_ALARM: usize = 0x14;
+const ALARM_STATUS: usize = 0x18;
+const CLEAR_INTERRUPT: usize = 0x1C;
+pub const RTC_SIZE: usize = 0x1000;
+
+const NS_PER_CLOCK: u64 = 1_000_000_000 / CPU_FREQ;
+
+// RTC struct: nanoseconds since the Unix epoch
+pub struct Rtc {
+    epoch: Option<u64>,         // Fixed start time, None for host time
+    clock: u64,
+    offset: i128,               // Software adjustment to the time
+    latched_high: u32,
+    alarm: u64,
+    alarm_high: u32,
+    armed: bool,
+    irq_enabled: bool,
+    alarm_status: bool,
+}
+
+// RTC struct impl
+impl Rtc {
+    // Host time, or a fixed epoch in seconds advanced by emulated time
+    pub fn new(epoch: Option<u64>) -> Self {
+        Self {
+            epoch: epoch.map(|secs| secs * 1_000_000_000),
+            clock: 0,
+            offset: 0,
+            latched_high: 0,
+            alarm: 0,
+            alarm_high: 0,
+            armed: false,
+            irq_enabled: false,
+            alarm_status: false,
+        }
+    }
+
+    // Current time in nanoseconds
+    fn now(&self) -> u64 {
+        let base = match self.epoch {
+            Some(epoch) => epoch + self.clock * NS_PER_CLOCK,
+            None => SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64),
+        };
+        (base as i128 + self.offset) as u64
+    }
+
+    // Set the time by moving the software offset
+    fn set(&mut self, time: u64) {
+        self.offset += time as i128 - self.now() as i128;
+    }
+}
+
+// RTC device
+impl Device for Rtc {
+    fn name(&self) -> &str {
+        "rtc"
+    }
+
+    fn read(&mut self, offset: usize, size: usize) -> Option<u64> {
+        let value = match offset {
+            TIME_LOW => {
+                let now = self.now();
+                self.latched_high = (now >> 32) as u32;
+                now as u32
+            }
+            TIME_HIGH => self.latched_high,
+            ALARM_LOW => self.alarm as u32,
+            ALARM_HIGH => (self.alarm >> 32) as u32,
+            IRQ_ENABLED => self.irq_enabled as u32,
+            ALARM_STATUS => self.armed as u32,
+            CLEAR_ALARM | CLEAR_INTERRUPT => 0,
+            default => return None,
+        };
+        Some(value as u64 & size_mask(size))
+    }
+
+    fn write(&mut self, offset: usize, size: usize, value: u64) -> bool {
+        let value = value as u32;
+        match offset {
+            TIME_LOW => {
+                let time = (self.latched_high as u64) << 32 | value as u64;
+                self.set(time);
+            }
+            TIME_HIGH => self.latched_high = value,
+            ALARM_LOW => {
+                self.alarm = (self.alarm_high as u64) << 32 | value as u64;
+                self.armed = true;
+            }
+            ALARM_HIGH => self.alarm_high = value,
+            IRQ_ENABLED => self.irq_enabled = value & 1 != 0,
+            CLEAR_ALARM => self.armed = false,
+            CLEAR_INTERRUPT => self.alarm_status = false,
+            ALARM_STATUS => {}
+            default => return false,
+        }
+        true
+    }
+
+    fn tick(&mut self) {
+        self.clock += 1;
+        if self.armed && self.now() >= self.alarm {
+            self.armed = false;
+            self.alarm_status = true;
+        }
+    }
+
+    fn advance(&mut self, clocks: u64) {
+        self.clock += clocks.saturating_sub(1);
+        self.tick();
+    }
+
+    fn interrupt(&self) -> bool {
+        self.irq_enabled && self.alarm_status
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        if !self.armed || !self.irq_enabled {
+            return None;
+        }
+        match self.epoch {
+            Some(_) => Some((self.alarm.saturating_sub(self.now()) / NS_PER_CLOCK).max(1)),
+            None => Some(1),        // Host time keeps moving on its own
+        }
+    }
+
+    fn reset(&mut self) {
+        self.offset = 0;
+        self.armed = false;
+        self.irq_enabled = false;
+        self.alarm_status = false;
+    }
+
+    fn describe(&self, fdt: &mut Fdt, base: usize, size: usize, irq: Option<usize>) {
+        fdt.soc_device("rtc", &["google,goldfish-rtc"], base, size, irq);
+    }
+
+    fn print_dirty(&mut self) {
+        println!("{}", "--------------------------------".green());
+        println!("{}", "RTC".green());
+        println!("{}", "--------------------------------".green());
+        println!("time: {} ns ({})", self.now(), if self.epoch.is_some() { "fixed epoch" } else { "host" });
+        println!("{}", "--------------------------------".green());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reading_time_low_latches_time_high() {
+        let mut rtc = Rtc::new(Some(0));
+        // Set the time just below a 32-bit rollover: high half first, the low write commits
+        rtc.write(TIME_HIGH, 4, 1);
+        rtc.write(TIME_LOW, 4, 0xFFFF_FF00);
+        assert_eq!(rtc.read(TIME_LOW, 4), Some(0xFFFF_FF00));
+
+        // The low half rolls over, but TIME_HIGH keeps the value latched with it
+        rtc.advance(100);
+        assert_eq!(rtc.read(TIME_HIGH, 4), Some(1));
+        assert_eq!(rtc.read(TIME_LOW, 4), Some((0xFFFF_FF00u64 + 100 * NS_PER_CLOCK) & 0xFFFF_FFFF));
+        assert_eq!(rtc.read(TIME_HIGH, 4), Some(2));
+    }
+
+    #[test]
+    fn alarm_raises_the_interrupt_when_enabled() {
+        let mut rtc = Rtc::new(Some(0));
+        rtc.write(IRQ_ENABLED, 4, 1);
+        rtc.write(ALARM_HIGH, 4, 0);
+        rtc.write(ALARM_LOW, 4, 1000);
+        assert_eq!(rtc.read(ALARM_STATUS, 4), Some(1));
+        assert_eq!(rtc.next_event(), Some(1000 / NS_PER_CLOCK));
+
+        rtc.advance(50);
+        assert!(!rtc.interrupt());
+        rtc.advance(50);
+        assert!(rtc.interrupt());
+        assert_eq!(rtc.read(ALARM_STATUS, 4), Some(0));
+        assert_eq!(rtc.next_event(), None);
+        rtc.write(CLEAR_INTERRUPT, 4, 1);
+        assert!(!rtc.interrupt());
+
+        // A masked alarm still fires, but the line stays low until it is enabled
+        rtc.write(IRQ_ENABLED, 4, 0);
+        rtc.write(ALARM_LOW, 4, 2000);
+        rtc.advance(100);
+        assert!(!rtc.interrupt());
+        rtc.write(IRQ_ENABLED, 4, 1);
+        assert!(rtc.interrupt());
+    }
+}
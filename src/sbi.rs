@@ -0,0 +1,255 @@
+/* RISCulator - RISC-V Emulator */
+/*  Built-in SBI (no firmware)   */
+
+// Libraries here
+use crate::{Mode, Register, Vproc, UART_BASE};
+use crate::clint::HARTS;
+use crate::csr;
+use crate::uart;
+use crate::utils;
+
+/*
+ * Supervisor Binary Interface handled by the emulator itself (--sbi)
+ *
+ * The hart starts in S-mode with the delegation and PMP set up the way firmware
+ * would leave them, and ECALLs from S-mode are served here instead of trapping to
+ * M-mode: a7 holds the extension, a6 the function, a0-a5 the arguments; a0/a1
+ * return the error and value (legacy extensions return only a0).
+ */
+
+// Extension IDs
+const EXT_LEGACY_PUTCHAR: isize = 0x01;
+const EXT_LEGACY_GETCHAR: isize = 0x02;
+const EXT_BASE: isize = 0x10;
+const EXT_TIME: isize = 0x5449_4D45;
+const EXT_IPI: isize = 0x0073_5049;
+const EXT_RFENCE: isize = 0x5246_4E43;
+const EXT_HSM: isize = 0x0048_534D;
+const EXT_SRST: isize = 0x5352_5354;
+const EXTENSIONS: [isize; 8] = [EXT_LEGACY_PUTCHAR, EXT_LEGACY_GETCHAR, EXT_BASE, EXT_TIME, EXT_IPI, EXT_RFENCE, EXT_HSM, EXT_SRST];
+
+// Error codes
+const SUCCESS: isize = 0;
+const ERR_NOT_SUPPORTED: isize = -2;
+const ERR_INVALID_PARAM: isize = -3;
+const ERR_ALREADY_AVAILABLE: isize = -6;
+
+// Base extension values
+const SPEC_VERSION: isize = 2 << 24;        // SBI v2.0
+const IMPL_ID: isize = 0x5249_5343;         // "RISC", not a registered implementation ID
+const IMPL_VERSION: isize = 1;
+
+// HSM states and suspend types
+const HART_STARTED: isize = 0;
+const SUSPEND_RETENTIVE: isize = 0x0000_0000;
+const SUSPEND_NON_RETENTIVE: isize = 0x8000_0000;
+
+// Larger remote SFENCE.VMA ranges flush the whole TLB
+const SFENCE_PAGES_MAX: usize = 64;
+
+// Firmware-style setup: delegate traps and interrupts to S-mode, open PMP, drop to S-mode
+pub fn boot(proc: &mut Vproc) {
+    proc.csrs.write(csr::MEDELEG, !(1 << csr::CAUSE_SUPERVISOR_ECALL));
+    proc.csrs.write(csr::MIDELEG, csr::IRQ_SSI | csr::IRQ_STI | csr::IRQ_SEI);
+    proc.csrs.write(csr::MCOUNTEREN, -1);
+    proc.csrs.write(csr::PMPADDR0, -1);
+    proc.csrs.write(csr::PMPCFG0, 0x1F);        // NAPOT over the whole address space, RWX
+    proc.mode = Mode::Supervisor;
+    log::info!("SBI: built-in SBI v2.0, hart 0 starting in S-mode");
+}
+
+// Serve an ECALL from S-mode; returns where to resume when it is not the next instruction
+pub fn ecall(proc: &mut Vproc, regs: &mut Register) -> Option<isize> {
+    let args: Vec<isize> = (10..18).map(|reg| proc.regs.regs[reg] & 0xFFFF_FFFF).collect();
+    let arg = |n: usize| args[n];
+    let (eid, fid) = (arg(7), arg(6));
+    log::info!("SBI call: extension {:#x}, function {}", eid, fid);
+    let mut resume = None;
+    let (error, value) = match (eid, fid) {
+        (EXT_LEGACY_PUTCHAR, _) => {
+            proc.bus.write(UART_BASE + uart::THR, 1, arg(0) & 0xFF);
+            regs.write(10, 0);
+            return None;
+        }
+        (EXT_LEGACY_GETCHAR, _) => {
+            let ready = proc.bus.read(UART_BASE + uart::LSR, 1).map_or(false, |lsr| lsr & uart::LSR_DR as isize != 0);
+            let ch = if ready { proc.bus.read(UART_BASE + uart::RBR, 1).unwrap_or(-1) } else { -1 };
+            regs.write(10, ch);
+            return None;
+        }
+        (EXT_BASE, 0) => (SUCCESS, SPEC_VERSION),
+        (EXT_BASE, 1) => (SUCCESS, IMPL_ID),
+        (EXT_BASE, 2) => (SUCCESS, IMPL_VERSION),
+        (EXT_BASE, 3) => (SUCCESS, EXTENSIONS.contains(&arg(0)) as isize),
+        (EXT_BASE, 4) => (SUCCESS, proc.csrs.read(csr::MVENDORID)),
+        (EXT_BASE, 5) => (SUCCESS, proc.csrs.read(csr::MARCHID)),
+        (EXT_BASE, 6) => (SUCCESS, proc.csrs.read(csr::MIMPID)),
+        (EXT_TIME, 0) => {
+            // The CLINT's mtimecmp drives STIP directly (see sync_irqs)
+            let stime = (arg(1) as u64) << 32 | arg(0) as u64;
+            proc.clint.borrow_mut().set_mtimecmp(0, stime);
+            (SUCCESS, 0)
+        }
+        (EXT_IPI, 0) => match targets(arg(0), arg(1)) {
+            Ok(harts) => {
+                if harts.contains(&0) {
+                    proc.csrs.write(csr::SIP, proc.csrs.read(csr::SIP) | csr::IRQ_SSI);
+                }
+                (SUCCESS, 0)
+            }
+            Err(error) => (error, 0),
+        },
+        (EXT_RFENCE, 0..=2) => match targets(arg(0), arg(1)) {
+            Ok(harts) => {
+                if harts.contains(&0) {
+                    rfence(proc, fid, arg(2) as usize, arg(3) as usize, arg(4) as usize);
+                }
+                (SUCCESS, 0)
+            }
+            Err(error) => (error, 0),
+        },
+        (EXT_HSM, 0) if (arg(0) as usize) < HARTS => (ERR_ALREADY_AVAILABLE, 0),
+        (EXT_HSM, 1) => {
+            log::warn!("SBI: hart 0 stopped with no other hart running, stopping");
+            utils::exit(proc, 0);
+        }
+        (EXT_HSM, 2) if (arg(0) as usize) < HARTS => (SUCCESS, HART_STARTED),
+        (EXT_HSM, 0) | (EXT_HSM, 2) => (ERR_INVALID_PARAM, 0),
+        (EXT_HSM, 3) => match arg(0) {
+            // Idle in WFI; a non-retentive suspend resumes at the given address
+            SUSPEND_RETENTIVE => {
+                proc.wfi = true;
+                (SUCCESS, 0)
+            }
+            SUSPEND_NON_RETENTIVE => {
+                proc.wfi = true;
+                proc.csrs.write(csr::SATP, 0);
+                proc.csrs.write(csr::SSTATUS, proc.csrs.read(csr::SSTATUS) & !csr::MSTATUS_SIE);
+                resume = Some(arg(1));
+                (0, arg(2))         // Resumes with a0 = hart ID, a1 = opaque
+            }
+            default => (ERR_INVALID_PARAM, 0),
+        },
+        (EXT_SRST, 0) if arg(0) <= 2 && arg(1) <= 1 => {
+            let kind = ["shutdown", "cold reboot", "warm reboot"][arg(0) as usize];
+            let reason = if arg(1) == 1 { "system failure" } else { "no reason" };
+            log::info!("SBI: system reset ({}, {}), stopping", kind, reason);
+            utils::exit(proc, arg(1) as i32);
+        }
+        (EXT_SRST, 0) => (ERR_INVALID_PARAM, 0),
+        default => {
+            log::warn!("SBI: unsupported extension {:#x} function {}", eid, fid);
+            (ERR_NOT_SUPPORTED, 0)
+        }
+    };
+    regs.write(10, error);
+    regs.write(11, value);
+    resume
+}
+
+// Harts selected by a hart mask and base (base -1 selects every hart)
+fn targets(mask: isize, base: isize) -> Result<Vec<usize>, isize> {
+    if base == 0xFFFF_FFFF {
+        return Ok((0..HARTS).collect());
+    }
+    let mut harts = vec![];
+    for bit in 0..32 {
+        if mask & (1 << bit) != 0 {
+            let hart = base as usize + bit;
+            if hart >= HARTS {
+                return Err(ERR_INVALID_PARAM);
+            }
+            harts.push(hart);
+        }
+    }
+    Ok(harts)
+}
+
+// Remote FENCE.I (0), SFENCE.VMA (1) and SFENCE.VMA.ASID (2) on this hart
+fn rfence(proc: &mut Vproc, fid: isize, start: usize, size: usize, asid: usize) {
+    if fid == 0 {
+        proc.icache.invalidate();
+        return;
+    }
+    let asid = if fid == 2 { Some(asid & 0x1FF) } else { None };
+    let pages = size.div_ceil(4096);
+    if size == 0 || pages > SFENCE_PAGES_MAX {
+        proc.tlb.flush(None, asid);
+        return;
+    }
+    for page in 0..pages {
+        proc.tlb.flush(Some((start + page * 4096) & 0xFFFF_FFFF), asid);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_hart;
+
+    // Make an SBI call with a7 = eid, a6 = fid and a0.. = args, returning (a0, a1)
+    fn call(proc: &mut Vproc, eid: isize, fid: isize, args: &[isize]) -> (isize, isize) {
+        for (n, &arg) in args.iter().enumerate() {
+            proc.regs.write(10 + n, arg);
+        }
+        proc.regs.write(16, fid);
+        proc.regs.write(17, eid);
+        let mut regs = Register::new();
+        assert_eq!(ecall(proc, &mut regs), None);
+        (regs.read(10), regs.read(11))
+    }
+
+    #[test]
+    fn unknown_calls_are_not_supported() {
+        let mut proc = test_hart();
+        assert_eq!(call(&mut proc, 0x0A00_0000, 0, &[]), (ERR_NOT_SUPPORTED, 0));
+        assert_eq!(call(&mut proc, EXT_BASE, 7, &[]), (ERR_NOT_SUPPORTED, 0));
+        assert_eq!(call(&mut proc, EXT_TIME, 1, &[]), (ERR_NOT_SUPPORTED, 0));
+        assert_eq!(call(&mut proc, EXT_RFENCE, 3, &[1, 0]), (ERR_NOT_SUPPORTED, 0));
+
+        // Base probe agrees with what is served
+        assert_eq!(call(&mut proc, EXT_BASE, 0, &[]), (SUCCESS, SPEC_VERSION));
+        assert_eq!(call(&mut proc, EXT_BASE, 3, &[EXT_RFENCE]), (SUCCESS, 1));
+        assert_eq!(call(&mut proc, EXT_BASE, 3, &[0x0A00_0000]), (SUCCESS, 0));
+    }
+
+    #[test]
+    fn set_timer_clears_a_pending_stip() {
+        let mut proc = test_hart();
+        proc.sbi = true;
+        proc.clint.borrow_mut().set_mtimecmp(0, 0);
+        utils::sync_irqs(&mut proc);
+        assert!(proc.csrs.read(csr::MIP) & csr::IRQ_STI != 0);
+
+        // Moving the deadline into the future drops STIP on the next sync
+        assert_eq!(call(&mut proc, EXT_TIME, 0, &[0x1000, 0]), (SUCCESS, 0));
+        utils::sync_irqs(&mut proc);
+        assert_eq!(proc.csrs.read(csr::MIP) & csr::IRQ_STI, 0);
+        assert_eq!(proc.csrs.read(csr::MIP) & csr::IRQ_MTI, 0);
+    }
+
+    #[test]
+    fn rfence_follows_the_hart_mask() {
+        let mut proc = test_hart();
+        let cached = |proc: &Vproc| proc.icache.lookup(0x100).is_some();
+
+        // An empty mask selects no hart: the cache is left alone
+        proc.icache.insert(0x100, "nop".to_string());
+        assert_eq!(call(&mut proc, EXT_RFENCE, 0, &[0, 0]), (SUCCESS, 0));
+        assert!(cached(&proc));
+
+        // Harts past the last one are rejected without fencing
+        assert_eq!(call(&mut proc, EXT_RFENCE, 0, &[1, HARTS as isize]), (ERR_INVALID_PARAM, 0));
+        assert_eq!(call(&mut proc, EXT_RFENCE, 0, &[0b11, 0]), (ERR_INVALID_PARAM, 0));
+        assert!(cached(&proc));
+
+        // Bit 0 from base 0 is this hart
+        assert_eq!(call(&mut proc, EXT_RFENCE, 0, &[1, 0]), (SUCCESS, 0));
+        assert!(!cached(&proc));
+
+        // Base -1 selects every hart whatever the mask
+        proc.icache.insert(0x100, "nop".to_string());
+        assert_eq!(call(&mut proc, EXT_RFENCE, 0, &[0, -1]), (SUCCESS, 0));
+        assert!(!cached(&proc));
+    }
+}
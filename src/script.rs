@@ -0,0 +1,154 @@
+/* RISCulator - RISC-V Emulator */
+/*  Scripted peripheral config  */
+
+// Libraries here
+use std::collections::HashMap;
+use std::fs;
+use crate::gpio::Waveform;
+use crate::i2c::CannedRegs;
+use crate::spi::SpiFlash;
+
+/*
+ * Peripheral script: one directive per line, '#' starts a comment
+ *
+ *   gpio.input <clock> <value>              GPIO inputs take <value> from <clock> on
+ *   spi.flash <cs> <image>                  SPI NOR flash backed by <image>
+ *   i2c.regs <addr> <reg>=<value> ...       I2C target returning canned register values
+ *
+ * Numbers may be decimal or 0x-prefixed hex.
+ */
+
+// External side of the GPIO, SPI and I2C devices
+pub struct Script {
+    pub gpio: Waveform,
+    pub spi: Vec<(usize, SpiFlash)>,
+    pub i2c: Vec<(u8, CannedRegs)>,
+}
+
+// Script impl
+impl Script {
+    // Nothing attached and all GPIO inputs low
+    pub fn empty() -> Self {
+        Self {
+            gpio: Waveform::new(),
+            spi: vec![],
+            i2c: vec![],
+        }
+    }
+
+    // Parse a script file, reporting the first bad line
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) => return Err(format!("{}: {}", path, err)),
+        };
+        let mut script = Script::empty();
+        let mut i2c: HashMap<u8, CannedRegs> = HashMap::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let bad = |msg: &str| format!("{}:{}: {}", path, n + 1, msg);
+            match words[0] {
+                "gpio.input" if words.len() == 3 => {
+                    let clock = number(words[1]).ok_or(bad("invalid clock"))?;
+                    let value = number(words[2]).ok_or(bad("invalid value"))?;
+                    script.gpio.add(clock, value as u32);
+                }
+                "spi.flash" if words.len() == 3 => {
+                    let cs = number(words[1]).ok_or(bad("invalid chip select"))? as usize;
+                    let data = match fs::read(words[2]) {
+                        Ok(data) => data,
+                        Err(err) => return Err(bad(&format!("{}: {}", words[2], err))),
+                    };
+                    script.spi.push((cs, SpiFlash::new(data)));
+                }
+                "i2c.regs" if words.len() >= 2 => {
+                    let addr = number(words[1]).filter(|addr| *addr < 0x80).ok_or(bad("invalid 7-bit address"))? as u8;
+                    let target = i2c.entry(addr).or_insert_with(CannedRegs::new);
+                    for pair in &words[2..] {
+                        let (reg, value) = pair.split_once('=').ok_or(bad("expected <reg>=<value>"))?;
+                        let reg = number(reg).filter(|reg| *reg < 256).ok_or(bad("invalid register"))?;
+                        let value = number(value).filter(|value| *value < 256).ok_or(bad("invalid register value"))?;
+                        target.set(reg as u8, value as u8);
+                    }
+                }
+                default => return Err(bad(&format!("unknown or incomplete directive '{}'", line))),
+            }
+        }
+        script.i2c = i2c.into_iter().collect();
+        Ok(script)
+    }
+}
+
+// Decimal or 0x-prefixed hex
+fn number(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpio::GpioInput;
+    use crate::i2c::I2cDevice;
+    use crate::spi::SpiDevice;
+
+    // Write `text` to a script file and load it
+    fn load(tag: &str, text: &str) -> Result<Script, String> {
+        let path = std::env::temp_dir().join(format!("risculator-script-{}-{}", tag, std::process::id()));
+        fs::write(&path, text).unwrap();
+        let script = Script::load(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        script
+    }
+
+    #[test]
+    fn parses_every_directive() {
+        let flash = std::env::temp_dir().join(format!("risculator-script-flash-{}", std::process::id()));
+        fs::write(&flash, [0xAB, 0xCD]).unwrap();
+        let text = format!("# inputs\n\ngpio.input 10 0x3   # two pins\ngpio.input 5 1\nspi.flash 2 {}\ni2c.regs 0x48 0=0x19 1=128\ni2c.regs 0x48 2=7\n", flash.display());
+        let mut script = load("good", &text).unwrap();
+        fs::remove_file(&flash).unwrap();
+
+        assert_eq!([4, 5, 9, 10].map(|clock| script.gpio.sample(clock)), [0, 1, 1, 3]);
+        assert_eq!(script.spi.len(), 1);
+        let (cs, flash) = &mut script.spi[0];
+        assert_eq!(*cs, 2);
+        flash.select();
+        let bytes: Vec<u8> = [0x03, 0, 0, 0, 0, 0].iter().map(|byte| flash.transfer(*byte)).collect();
+        assert_eq!(&bytes[4..], [0xAB, 0xCD]);
+        // Both lines for 0x48 feed one target
+        assert_eq!(script.i2c.len(), 1);
+        let (addr, regs) = &mut script.i2c[0];
+        assert_eq!(*addr, 0x48);
+        regs.start(true);
+        assert_eq!([regs.read(), regs.read(), regs.read()], [0x19, 0x80, 7]);
+    }
+
+    #[test]
+    fn reports_the_first_bad_line() {
+        for (text, line, message) in [
+            ("frobnicate 1 2", 1, "unknown or incomplete directive"),
+            ("# ok\ngpio.input 1", 2, "unknown or incomplete directive"),
+            ("gpio.input 1 2 3", 1, "unknown or incomplete directive"),
+            ("gpio.input soon 1", 1, "invalid clock"),
+            ("gpio.input 1 0xZZ", 1, "invalid value"),
+            ("spi.flash cs0 image", 1, "invalid chip select"),
+            ("spi.flash 0 /nonexistent/flash.bin", 1, "/nonexistent/flash.bin"),
+            ("i2c.regs 0x80 0=1", 1, "invalid 7-bit address"),
+            ("\n\ni2c.regs 0x48 0:1", 3, "expected <reg>=<value>"),
+            ("i2c.regs 0x48 256=1", 1, "invalid register"),
+            ("i2c.regs 0x48 1=0x100", 1, "invalid register value"),
+        ] {
+            let err = load("bad", text).err().unwrap();
+            assert!(err.contains(&format!(":{}: ", line)), "{}: {}", text, err);
+            assert!(err.contains(message), "{}: {}", text, err);
+        }
+        assert!(Script::load("/nonexistent/script").is_err());
+    }
+}
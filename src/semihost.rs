@@ -0,0 +1,383 @@
+/* RISCulator - RISC-V Emulator */
+/*     RISC-V semihosting       */
+
+// Libraries here
+use std::collections::HashMap;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use crate::{utils, Register, Vproc, RTC_BASE};
+use crate::linux::{read_cstr, read_mem, write_mem, Fd, IO_CHUNK};
+use crate::mmu;
+use crate::mmu::Access;
+use crate::rtc;
+
+/*
+ * Semihosting (--semihosting): an EBREAK between `slli x0, x0, 0x1f` and
+ * `srai x0, x0, 7` is a request to the host, with the operation in a0, a pointer
+ * to the parameter block in a1 and the result returned in a0. Host files are
+ * only reachable below the sandbox directory (--semihost-root).
+ */
+
+// The instructions around the EBREAK
+const ENTRY_NOP: isize = 0x01F0_1013;      // slli x0, x0, 0x1f
+const EXIT_NOP: isize = 0x4070_5013;       // srai x0, x0, 7
+
+// Operations
+const SYS_OPEN: isize = 0x01;
+const SYS_CLOSE: isize = 0x02;
+const SYS_WRITEC: isize = 0x03;
+const SYS_WRITE0: isize = 0x04;
+const SYS_WRITE: isize = 0x05;
+const SYS_READ: isize = 0x06;
+const SYS_ISTTY: isize = 0x09;
+const SYS_SEEK: isize = 0x0A;
+const SYS_FLEN: isize = 0x0C;
+const SYS_CLOCK: isize = 0x10;
+const SYS_TIME: isize = 0x11;
+const SYS_ERRNO: isize = 0x13;
+const SYS_GET_CMDLINE: isize = 0x15;
+const SYS_HEAPINFO: isize = 0x16;
+const SYS_EXIT: isize = 0x18;
+const SYS_EXIT_EXTENDED: isize = 0x20;
+
+const ADP_STOPPED_APPLICATION_EXIT: isize = 0x20026;
+const EACCES: isize = 13;
+const EBADF: isize = 9;
+
+// Semihosting state
+#[derive(Debug)]
+pub struct Semihost {
+    root: PathBuf,
+    cmdline: String,
+    files: HashMap<isize, Fd>,
+    next_handle: isize,
+    errno: isize,
+    heap: [usize; 4],       // Heap base and limit, stack base and limit (SYS_HEAPINFO)
+}
+
+// Semihost impl
+impl Semihost {
+    // Host files are looked up below `root`
+    pub fn new(root: &str, cmdline: &str) -> Result<Self, String> {
+        let root = fs::canonicalize(root).map_err(|err| format!("{}: {}", root, err))?;
+        Ok(Self {
+            root,
+            cmdline: cmdline.to_string(),
+            files: HashMap::new(),
+            next_handle: 1,
+            errno: 0,
+            heap: [0; 4],
+        })
+    }
+
+    // Memory layout reported by SYS_HEAPINFO
+    pub fn set_heap(&mut self, heap_base: usize, heap_limit: usize, stack_base: usize, stack_limit: usize) {
+        self.heap = [heap_base, heap_limit, stack_base, stack_limit];
+    }
+
+    // A guest path inside the sandbox: relative, no `..`, and no symlinks at all. A
+    // dangling link would otherwise let a create follow it out of the root.
+    fn resolve(&self, name: &str) -> Option<PathBuf> {
+        let path = Path::new(name);
+        if !path.components().all(|part| matches!(part, Component::Normal(_) | Component::CurDir)) {
+            return None;
+        }
+        let mut full = self.root.clone();
+        for part in path.components() {
+            full.push(part);
+            if let Ok(meta) = fs::symlink_metadata(&full) {
+                if meta.file_type().is_symlink() {
+                    return None;
+                }
+            }
+        }
+        Some(full)
+    }
+
+    fn add(&mut self, fd: Fd) -> isize {
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        self.files.insert(handle, fd);
+        handle
+    }
+
+    // Record a host error for SYS_ERRNO and return the failure value
+    fn fail(&mut self, errno: isize) -> isize {
+        self.errno = errno;
+        -1
+    }
+
+    // Run one operation with parameter block (or value) `arg`
+    fn call(&mut self, proc: &mut Vproc, op: isize, arg: usize) -> isize {
+        let word = |proc: &mut Vproc, n: usize| -> isize {
+            proc.bus.read(arg + 4 * n, 4).map_or(0, |value| value & 0xFFFF_FFFF)
+        };
+        match op {
+            SYS_OPEN => {
+                let (name, mode) = (word(proc, 0) as usize, word(proc, 1));
+                let name = match read_cstr(&mut proc.bus, name) {
+                    Some(name) => name,
+                    None => return self.fail(EACCES),
+                };
+                // ":tt" is the console: read modes give stdin, write stdout, append stderr
+                if name == ":tt" {
+                    let fd = match mode {
+                        0..=3 => Fd::Stdin,
+                        4..=7 => Fd::Stdout,
+                        default => Fd::Stderr,
+                    };
+                    return self.add(fd);
+                }
+                let path = match self.resolve(&name) {
+                    Some(path) => path,
+                    None => {
+                        log::warn!("Semihosting: {} is outside {}", name, self.root.display());
+                        return self.fail(EACCES);
+                    }
+                };
+                // fopen modes r, rb, r+, r+b, w, wb, w+, w+b, a, ab, a+, a+b
+                let plus = mode & 2 != 0;
+                let mut options = OpenOptions::new();
+                match mode >> 2 {
+                    0 => options.read(true).write(plus),
+                    1 => options.write(true).read(plus).create(true).truncate(true),
+                    default => options.append(true).read(plus).create(true),
+                };
+                match options.open(&path) {
+                    Ok(file) => self.add(Fd::File(file)),
+                    Err(err) => self.fail(err.raw_os_error().unwrap_or(EACCES as i32) as isize),
+                }
+            }
+            SYS_CLOSE => match self.files.remove(&word(proc, 0)) {
+                Some(_) => 0,
+                None => self.fail(EBADF),
+            },
+            SYS_WRITEC => {
+                if let Some(byte) = proc.bus.read(arg, 1) {
+                    let _ = io::stdout().write_all(&[byte as u8]).and_then(|_| io::stdout().flush());
+                }
+                0
+            }
+            SYS_WRITE0 => {
+                if let Some(text) = read_cstr(&mut proc.bus, arg) {
+                    let _ = io::stdout().write_all(text.as_bytes()).and_then(|_| io::stdout().flush());
+                }
+                0
+            }
+            SYS_WRITE => {
+                // Returns the number of bytes not written; long writes are cut to a chunk
+                let (handle, buf, len) = (word(proc, 0), word(proc, 1) as usize, word(proc, 2) as usize);
+                let data = match read_mem(&mut proc.bus, buf, len.min(IO_CHUNK)) {
+                    Some(data) => data,
+                    None => return len as isize,
+                };
+                let result = match self.files.get_mut(&handle) {
+                    Some(file) => file.write(&data),
+                    None => {
+                        self.errno = EBADF;
+                        return len as isize;
+                    }
+                };
+                match result {
+                    Ok(()) => (len - data.len()) as isize,
+                    Err(err) => {
+                        self.errno = err.raw_os_error().unwrap_or(0) as isize;
+                        len as isize
+                    }
+                }
+            }
+            SYS_READ => {
+                // Returns the number of bytes not read (all of them at end of file)
+                let (handle, buf, len) = (word(proc, 0), word(proc, 1) as usize, word(proc, 2) as usize);
+                let mut data = vec![0u8; len.min(IO_CHUNK)];
+                let count = match self.files.get_mut(&handle) {
+                    Some(file) => file.read(&mut data),
+                    None => {
+                        self.errno = EBADF;
+                        return -1;
+                    }
+                };
+                match count {
+                    Ok(n) if write_mem(&mut proc.bus, buf, &data[..n]) => (len - n) as isize,
+                    Ok(_) => -1,
+                    Err(err) => self.fail(err.raw_os_error().unwrap_or(0) as isize),
+                }
+            }
+            SYS_ISTTY => match self.files.get(&word(proc, 0)) {
+                Some(Fd::File(_)) => 0,
+                Some(default) => 1,
+                None => self.fail(EBADF),
+            },
+            SYS_SEEK => {
+                let (handle, pos) = (word(proc, 0), word(proc, 1) as u64);
+                match self.files.get_mut(&handle) {
+                    Some(Fd::File(file)) => match file.seek(SeekFrom::Start(pos)) {
+                        Ok(_) => 0,
+                        Err(err) => self.fail(err.raw_os_error().unwrap_or(0) as isize),
+                    },
+                    default => self.fail(EBADF),
+                }
+            }
+            SYS_FLEN => match self.files.get(&word(proc, 0)) {
+                Some(Fd::File(file)) => file.metadata().map_or(-1, |meta| meta.len() as isize),
+                default => self.fail(EBADF),
+            },
+            SYS_CLOCK => {
+                // Centiseconds of emulated time
+                let clint = proc.clint.borrow();
+                (clint.mtime() * 100 / clint.timebase()) as isize
+            }
+            SYS_TIME => {
+                // From the RTC, so --rtc-epoch makes it reproducible
+                let low = proc.bus.read(RTC_BASE + rtc::TIME_LOW, 4).unwrap_or(0) as u64 & 0xFFFF_FFFF;
+                let high = proc.bus.read(RTC_BASE + rtc::TIME_HIGH, 4).unwrap_or(0) as u64 & 0xFFFF_FFFF;
+                ((high << 32 | low) / 1_000_000_000) as isize
+            }
+            SYS_ERRNO => self.errno,
+            SYS_GET_CMDLINE => {
+                let (buf, len) = (word(proc, 0) as usize, word(proc, 1) as usize);
+                let mut text = self.cmdline.clone().into_bytes();
+                if text.len() + 1 > len {
+                    return -1;
+                }
+                text.push(0);
+                let length = (text.len() as u32 - 1).to_le_bytes();
+                if write_mem(&mut proc.bus, buf, &text) && write_mem(&mut proc.bus, arg + 4, &length) { 0 } else { -1 }
+            }
+            SYS_HEAPINFO => {
+                // a1 points to the address of the four-word block
+                let block = word(proc, 0) as usize;
+                let bytes: Vec<u8> = self.heap.iter().flat_map(|value| (*value as u32).to_le_bytes()).collect();
+                write_mem(&mut proc.bus, block, &bytes);
+                0
+            }
+            SYS_EXIT | SYS_EXIT_EXTENDED => {
+                // RV32 SYS_EXIT passes the reason itself; SYS_EXIT_EXTENDED a reason and subcode
+                let (reason, subcode) = if op == SYS_EXIT { (arg as isize, 0) } else { (word(proc, 0), word(proc, 1)) };
+                let status = if reason == ADP_STOPPED_APPLICATION_EXIT { subcode as i32 } else { 1 };
+                log::info!("Semihosting: exit (reason {:#x}) with status {}", reason, status);
+                utils::exit(proc, status);
+            }
+            default => {
+                log::warn!("Semihosting: unsupported operation {:#x}", op);
+                -1
+            }
+        }
+    }
+}
+
+// Whether the EBREAK at `pc` sits inside the semihosting sequence
+pub fn is_request(proc: &mut Vproc, pc: isize) -> bool {
+    let mut fetch = |addr: isize| match mmu::translate(proc, addr as usize, 4, Access::Fetch) {
+        Ok(phys) => proc.bus.read(phys, 4).map(|word| word & 0xFFFF_FFFF),
+        Err(_) => None,
+    };
+    fetch(pc - 4) == Some(ENTRY_NOP) && fetch(pc + 4) == Some(EXIT_NOP)
+}
+
+// Serve a semihosting request
+pub fn call(proc: &mut Vproc, regs: &mut Register) {
+    let mut semihost = match proc.semihost.take() {
+        Some(semihost) => semihost,
+        None => return,
+    };
+    let op = proc.regs.regs[10] & 0xFFFF_FFFF;
+    let arg = (proc.regs.regs[11] & 0xFFFF_FFFF) as usize;
+    let ret = semihost.call(proc, op, arg);
+    log::info!("Semihosting: operation {:#x} = {}", op, ret);
+    regs.write(10, ret);
+    proc.semihost = Some(semihost);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{console, test_hart, RAM_BASE};
+    use std::io::Cursor;
+    use std::os::unix::fs::symlink;
+
+    const BLOCK: usize = RAM_BASE + 0x1000;
+    const NAME: usize = RAM_BASE + 0x1100;
+    const BUF: usize = RAM_BASE + 0x1200;
+
+    // A fresh sandbox holding `inside/file`, a link inside it, one out of it and a dangling one
+    fn sandbox(tag: &str) -> (PathBuf, Semihost) {
+        let root = std::env::temp_dir().join(format!("risculator-semihost-{}-{}", tag, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("inside")).unwrap();
+        fs::write(root.join("inside/file"), b"data").unwrap();
+        symlink(root.join("inside/file"), root.join("inner")).unwrap();
+        symlink("/etc", root.join("outer")).unwrap();
+        symlink(root.join("missing"), root.join("dangling")).unwrap();
+        let semihost = Semihost::new(root.to_str().unwrap(), "prog").unwrap();
+        (root, semihost)
+    }
+
+    fn words(proc: &mut Vproc, values: &[usize]) {
+        let bytes: Vec<u8> = values.iter().flat_map(|value| (*value as u32).to_le_bytes()).collect();
+        assert!(write_mem(&mut proc.bus, BLOCK, &bytes));
+    }
+
+    // SYS_OPEN `name` with fopen mode `mode`
+    fn open(semihost: &mut Semihost, proc: &mut Vproc, name: &str, mode: usize) -> isize {
+        assert!(write_mem(&mut proc.bus, NAME, format!("{}\0", name).as_bytes()));
+        words(proc, &[NAME, mode, name.len()]);
+        semihost.call(proc, SYS_OPEN, BLOCK)
+    }
+
+    #[test]
+    fn resolve_keeps_to_the_sandbox() {
+        let (root, semihost) = sandbox("resolve");
+        assert_eq!(semihost.resolve("inside/file"), Some(semihost.root.join("inside/file")));
+        assert_eq!(semihost.resolve("./inside/new"), Some(semihost.root.join("inside/new")));
+        assert_eq!(semihost.resolve("../etc/passwd"), None);
+        assert_eq!(semihost.resolve("inside/../../etc/passwd"), None);
+        assert_eq!(semihost.resolve("/etc/passwd"), None);
+        assert_eq!(semihost.resolve("inner"), None);
+        assert_eq!(semihost.resolve("outer/passwd"), None);
+        assert_eq!(semihost.resolve("dangling"), None);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn refused_opens_report_eacces() {
+        let (root, mut semihost) = sandbox("open");
+        let mut proc = test_hart();
+        // Mode 4 is "w": a create through the dangling link must not escape
+        assert_eq!(open(&mut semihost, &mut proc, "dangling", 4), -1);
+        assert_eq!(semihost.call(&mut proc, SYS_ERRNO, 0), EACCES);
+        assert!(!root.join("missing").exists());
+        assert_eq!(open(&mut semihost, &mut proc, "/etc/passwd", 0), -1);
+
+        let handle = open(&mut semihost, &mut proc, "inside/file", 0);
+        assert!(handle > 0);
+        words(&mut proc, &[handle as usize, BUF, 8]);
+        assert_eq!(semihost.call(&mut proc, SYS_READ, BLOCK), 4);
+        assert_eq!(read_mem(&mut proc.bus, BUF, 4).unwrap(), b"data");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn console_reads_come_from_the_shared_source() {
+        console::set_source(Box::new(Cursor::new(b"typed".to_vec())));
+        let (root, mut semihost) = sandbox("tt");
+        let mut proc = test_hart();
+        let handle = open(&mut semihost, &mut proc, ":tt", 0);
+        words(&mut proc, &[handle as usize]);
+        assert_eq!(semihost.call(&mut proc, SYS_ISTTY, BLOCK), 1);
+        let mut input = vec![];
+        loop {
+            words(&mut proc, &[handle as usize, BUF, 8]);
+            let missing = semihost.call(&mut proc, SYS_READ, BLOCK) as usize;
+            if missing == 8 {
+                break;
+            }
+            input.extend(read_mem(&mut proc.bus, BUF, 8 - missing).unwrap());
+        }
+        assert_eq!(input, b"typed");
+        fs::remove_dir_all(root).unwrap();
+    }
+}
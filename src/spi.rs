@@ -0,0 +1,309 @@
+/* RISCulator - RISC-V Emulator */
+/*       SPI master device      */
+
+// Libraries here
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use colored::*;
+use crate::bus::{size_mask, Device};
+use crate::fdt::Fdt;
+use crate::pins::PinLog;
+
+// Register offsets
+const CS_ID: usize = 0x00;          // Chip select to use
+const CS_ACTIVE: usize = 0x04;      // 1 asserts the chip select, 0 releases it
+const TXDATA: usize = 0x08;         // Write a byte to shift it out
+const RXDATA: usize = 0x0C;         // Bit 31 set when the FIFO is empty
+const STATUS: usize = 0x10;         // Bit 0: receive data available
+pub const SPI_SIZE: usize = 0x1000;
+pub const CHIP_SELECTS: usize = 4;
+const RX_EMPTY: u32 = 1 << 31;
+
+// External side of one chip select
+pub trait SpiDevice {
+    fn select(&mut self) {}
+
+    // Exchange one byte: MOSI in, MISO out
+    fn transfer(&mut self, mosi: u8) -> u8;
+
+    fn deselect(&mut self) {}
+}
+
+// Rust callbacks can act as SPI devices
+impl<F: FnMut(u8) -> u8> SpiDevice for F {
+    fn transfer(&mut self, mosi: u8) -> u8 {
+        self(mosi)
+    }
+}
+
+// SPI master struct
+pub struct Spi {
+    devices: Vec<Option<Box<dyn SpiDevice>>>,
+    cs_id: u32,
+    active: bool,
+    rx: VecDeque<u8>,
+    clock: u64,
+    log: Rc<RefCell<PinLog>>,
+}
+
+// SPI master struct impl
+impl Spi {
+    pub fn new(log: Rc<RefCell<PinLog>>) -> Self {
+        Self {
+            devices: (0..CHIP_SELECTS).map(|_| None).collect(),
+            cs_id: 0,
+            active: false,
+            rx: VecDeque::new(),
+            clock: 0,
+            log,
+        }
+    }
+
+    // Connect a device to a chip select
+    pub fn connect(&mut self, cs: usize, device: Box<dyn SpiDevice>) -> Result<(), String> {
+        if cs >= CHIP_SELECTS {
+            return Err(format!("SPI chip select {} out of range (0-{})", cs, CHIP_SELECTS - 1));
+        }
+        self.devices[cs] = Some(device);
+        Ok(())
+    }
+
+    fn selected(&mut self) -> Option<&mut Box<dyn SpiDevice>> {
+        self.devices.get_mut(self.cs_id as usize).and_then(|device| device.as_mut())
+    }
+}
+
+// SPI master device
+impl Device for Spi {
+    fn name(&self) -> &str {
+        "spi"
+    }
+
+    fn read(&mut self, offset: usize, size: usize) -> Option<u64> {
+        let value = match offset {
+            CS_ID => self.cs_id,
+            CS_ACTIVE => self.active as u32,
+            RXDATA => self.rx.pop_front().map_or(RX_EMPTY, |byte| byte as u32),
+            STATUS => !self.rx.is_empty() as u32,
+            TXDATA => 0,
+            default => return None,
+        };
+        Some(value as u64 & size_mask(size))
+    }
+
+    fn write(&mut self, offset: usize, size: usize, value: u64) -> bool {
+        match offset {
+            CS_ID => self.cs_id = value as u32,
+            CS_ACTIVE => {
+                let active = value & 1 != 0;
+                if active != self.active {
+                    self.active = active;
+                    let clock = self.clock;
+                    let cs = self.cs_id;
+                    if let Some(device) = self.selected() {
+                        if active { device.select() } else { device.deselect() }
+                    }
+                    self.log.borrow_mut().record(clock, "spi", &format!("cs{} {}", cs, if active { "low" } else { "high" }));
+                }
+            }
+            TXDATA => {
+                let mosi = value as u8;
+                let active = self.active;
+                let miso = match self.selected() {
+                    Some(device) if active => device.transfer(mosi),
+                    default => 0xFF,        // Nothing drives MISO
+                };
+                self.rx.push_back(miso);
+                self.log.borrow_mut().record(self.clock, "spi", &format!("cs{} mosi={:#04x} miso={:#04x}", self.cs_id, mosi, miso));
+            }
+            RXDATA | STATUS => {}
+            default => return false,
+        }
+        true
+    }
+
+    fn tick(&mut self) {
+        self.clock += 1;
+    }
+
+    fn reset(&mut self) {
+        self.cs_id = 0;
+        self.active = false;
+        self.rx.clear();
+    }
+
+    fn describe(&self, fdt: &mut Fdt, base: usize, size: usize, irq: Option<usize>) {
+        fdt.soc_device("spi", &["risculator,spi"], base, size, irq)
+            .u32("#address-cells", 1)
+            .u32("#size-cells", 0);
+    }
+
+    fn print_dirty(&mut self) {
+        println!("{}", "--------------------------------".green());
+        println!("{}", "SPI".green());
+        println!("{}", "--------------------------------".green());
+        println!("cs{} {}, {} bytes waiting", self.cs_id, if self.active { "active" } else { "idle" }, self.rx.len());
+        println!("{}", "--------------------------------".green());
+    }
+}
+
+// SPI NOR flash with the common command set, backed by an image (writes stay in memory)
+pub struct SpiFlash {
+    data: Vec<u8>,
+    command: Option<u8>,
+    addr: u32,
+    count: usize,           // Bytes received since the command
+    wel: bool,              // Write enable latch
+}
+
+// SPI flash commands
+const CMD_PAGE_PROGRAM: u8 = 0x02;
+const CMD_READ: u8 = 0x03;
+const CMD_WRITE_DISABLE: u8 = 0x04;
+const CMD_READ_STATUS: u8 = 0x05;
+const CMD_WRITE_ENABLE: u8 = 0x06;
+const CMD_FAST_READ: u8 = 0x0B;
+const CMD_SECTOR_ERASE: u8 = 0x20;
+const CMD_READ_ID: u8 = 0x9F;
+const JEDEC_ID: [u8; 3] = [0xEF, 0x40, 0x18];      // Winbond W25Q128
+
+// SPI flash impl
+impl SpiFlash {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            command: None,
+            addr: 0,
+            count: 0,
+            wel: false,
+        }
+    }
+}
+
+impl SpiDevice for SpiFlash {
+    fn select(&mut self) {
+        self.command = None;
+        self.count = 0;
+        self.addr = 0;
+    }
+
+    fn transfer(&mut self, mosi: u8) -> u8 {
+        let command = match self.command {
+            Some(command) => command,
+            None => {
+                self.command = Some(mosi);
+                match mosi {
+                    CMD_WRITE_ENABLE => self.wel = true,
+                    CMD_WRITE_DISABLE => self.wel = false,
+                    default => {}
+                }
+                return 0xFF;
+            }
+        };
+        self.count += 1;
+        let len = self.data.len().max(1);
+        match command {
+            CMD_READ_ID => *JEDEC_ID.get(self.count - 1).unwrap_or(&0),
+            CMD_READ_STATUS => (self.wel as u8) << 1,
+            CMD_READ | CMD_FAST_READ | CMD_PAGE_PROGRAM | CMD_SECTOR_ERASE if self.count <= 3 => {
+                self.addr = (self.addr << 8) | mosi as u32;
+                if command == CMD_SECTOR_ERASE && self.count == 3 && self.wel {
+                    let start = (self.addr as usize & !0xFFF) % len;
+                    let end = (start + 0x1000).min(self.data.len());
+                    self.data[start..end].iter_mut().for_each(|b| *b = 0xFF);
+                    self.wel = false;
+                }
+                0xFF
+            }
+            CMD_FAST_READ if self.count == 4 => 0xFF,       // Dummy byte
+            CMD_READ | CMD_FAST_READ => {
+                let byte = *self.data.get(self.addr as usize % len).unwrap_or(&0xFF);
+                self.addr = self.addr.wrapping_add(1);
+                byte
+            }
+            CMD_PAGE_PROGRAM if self.wel => {
+                // Programming can only clear bits, and wraps within the 256-byte page
+                let page = self.addr as usize & !0xFF;
+                let i = (page | (self.addr as usize + self.count - 4) & 0xFF) % len;
+                if let Some(byte) = self.data.get_mut(i) {
+                    *byte &= mosi;
+                }
+                0xFF
+            }
+            default => 0xFF,
+        }
+    }
+
+    fn deselect(&mut self) {
+        if self.command == Some(CMD_PAGE_PROGRAM) {
+            self.wel = false;
+        }
+        self.command = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An SPI master with a 8 KiB flash on chip select 1
+    fn spi() -> Spi {
+        let mut spi = Spi::new(PinLog::new(None).unwrap());
+        let data: Vec<u8> = (0..0x2000).map(|i| i as u8).collect();
+        spi.connect(1, Box::new(SpiFlash::new(data))).unwrap();
+        spi
+    }
+
+    // One transaction on chip select `cs`, returning the bytes shifted in
+    fn exchange(spi: &mut Spi, cs: u64, mosi: &[u8]) -> Vec<u8> {
+        spi.write(CS_ID, 4, cs);
+        spi.write(CS_ACTIVE, 4, 1);
+        for byte in mosi {
+            spi.write(TXDATA, 4, *byte as u64);
+        }
+        spi.write(CS_ACTIVE, 4, 0);
+        let mut miso = vec![];
+        while spi.read(STATUS, 4) == Some(1) {
+            miso.push(spi.read(RXDATA, 4).unwrap() as u8);
+        }
+        miso
+    }
+
+    #[test]
+    fn received_bytes_queue_until_read() {
+        let mut spi = spi();
+        assert_eq!(spi.read(RXDATA, 4), Some(RX_EMPTY as u64));
+        assert_eq!(exchange(&mut spi, 1, &[CMD_READ_ID, 0, 0, 0]), [0xFF, 0xEF, 0x40, 0x18]);
+        assert_eq!(spi.read(STATUS, 4), Some(0));
+        // Nothing on chip select 2, and an idle chip select drives nothing
+        assert_eq!(exchange(&mut spi, 2, &[CMD_READ_ID, 0]), [0xFF, 0xFF]);
+        spi.write(CS_ID, 4, 1);
+        spi.write(TXDATA, 4, CMD_READ_ID as u64);
+        assert_eq!(spi.read(RXDATA, 4), Some(0xFF));
+        assert!(spi.connect(CHIP_SELECTS, Box::new(|byte: u8| byte)).is_err());
+        assert_eq!(spi.read(0x14, 4), None);
+    }
+
+    #[test]
+    fn flash_reads_programs_and_erases() {
+        let mut spi = spi();
+        assert_eq!(exchange(&mut spi, 1, &[CMD_READ, 0x00, 0x10, 0x20, 0, 0])[4..], [0x20, 0x21]);
+        assert_eq!(exchange(&mut spi, 1, &[CMD_FAST_READ, 0x00, 0x10, 0x20, 0, 0])[5..], [0x20]);
+
+        // Programming needs the write enable latch and only clears bits
+        exchange(&mut spi, 1, &[CMD_PAGE_PROGRAM, 0x00, 0x10, 0x20, 0x00]);
+        assert_eq!(exchange(&mut spi, 1, &[CMD_READ, 0x00, 0x10, 0x20, 0])[4], 0x20);
+        exchange(&mut spi, 1, &[CMD_WRITE_ENABLE]);
+        assert_eq!(exchange(&mut spi, 1, &[CMD_READ_STATUS, 0])[1], 0x02);
+        exchange(&mut spi, 1, &[CMD_PAGE_PROGRAM, 0x00, 0x10, 0x20, 0x0F, 0xFF]);
+        assert_eq!(exchange(&mut spi, 1, &[CMD_READ, 0x00, 0x10, 0x20, 0, 0])[4..], [0x00, 0x21]);
+        assert_eq!(exchange(&mut spi, 1, &[CMD_READ_STATUS, 0])[1], 0x00);
+
+        // Sector erase sets a whole 4 KiB sector to 0xFF
+        exchange(&mut spi, 1, &[CMD_WRITE_ENABLE]);
+        exchange(&mut spi, 1, &[CMD_SECTOR_ERASE, 0x00, 0x10, 0x80]);
+        assert_eq!(exchange(&mut spi, 1, &[CMD_READ, 0x00, 0x0F, 0xFF, 0, 0])[4..], [0xFF, 0xFF]);
+        assert_eq!(exchange(&mut spi, 1, &[CMD_READ, 0x00, 0x00, 0x05, 0])[4], 0x05);
+    }
+}
@@ -0,0 +1,400 @@
+/* RISCulator - RISC-V Emulator */
+/*  Compliance test-suite runner */
+
+// Libraries here
+use std::env;
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Instant;
+use colored::*;
+use crate::elf;
+use crate::htif;
+
+/*
+ * `risculator test-suite <dir>` runs every test ELF below <dir> (riscv-tests,
+ * riscv-arch-test) in its own emulator process with an instruction budget. A test
+ * passes when it reports success through HTIF and, when it has a signature region
+ * and a reference signature exists, when the dumped signature matches.
+ *
+ * Options: --budget <steps>     instruction budget per test (1000000)
+ *          --out <dir>          signatures and junit.xml (test-suite-out)
+ *          --references <dir>   <name>.reference_output files (default: next to each ELF)
+ *          -- <options>...      passed to every test run (e.g. --isa)
+ */
+
+// Exit statuses of a test run. 0 is an HTIF pass and 1..=htif::FAIL_MAX the failing
+// test number; Rust panics exit with 101.
+pub const BUDGET_EXHAUSTED: i32 = 124;      // --max-steps used up without an HTIF result
+pub const NO_RESULT: i32 = 125;             // Stopped some other way before HTIF reported
+pub const SETUP_ERROR: i32 = 126;           // Bad options or images; nothing ran
+const DEFAULT_BUDGET: usize = 1_000_000;
+const DEFAULT_OUT: &str = "test-suite-out";
+
+// Runner options
+struct SuiteOptions {
+    dir: PathBuf,
+    budget: usize,
+    out: PathBuf,
+    references: Option<PathBuf>,
+    emulator_args: Vec<String>,
+}
+
+// How a test ended
+enum Outcome {
+    Pass,
+    Fail(String),
+    Timeout,
+    Error(String),
+}
+
+// One row of the summary
+struct TestResult {
+    name: String,
+    outcome: Outcome,
+    seconds: f64,
+}
+
+fn parse_args(args: &[String]) -> Result<SuiteOptions, String> {
+    let (args, emulator_args) = match args.iter().position(|arg| arg == "--") {
+        Some(pos) => (&args[..pos], args[pos + 1..].to_vec()),
+        None => (args, vec![]),
+    };
+    if args.is_empty() {
+        return Err("test-suite expects the directory holding the test ELFs".to_string());
+    }
+    let mut opts = SuiteOptions {
+        dir: PathBuf::from(&args[0]),
+        budget: DEFAULT_BUDGET,
+        out: PathBuf::from(DEFAULT_OUT),
+        references: None,
+        emulator_args,
+    };
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--budget" if i + 1 < args.len() => {
+                opts.budget = match args[i + 1].parse::<usize>() {
+                    Ok(steps) if steps > 0 => steps,
+                    _ => return Err(format!("--budget: invalid step count {}", args[i + 1])),
+                };
+                i += 1;
+            }
+            "--out" if i + 1 < args.len() => {
+                opts.out = PathBuf::from(&args[i + 1]);
+                i += 1;
+            }
+            "--references" if i + 1 < args.len() => {
+                opts.references = Some(PathBuf::from(&args[i + 1]));
+                i += 1;
+            }
+            other => return Err(format!("Unknown or incomplete test-suite option: {}", other)),
+        }
+        i += 1;
+    }
+    Ok(opts)
+}
+
+// Every ELF below `dir`, in a stable order
+fn discover(dir: &Path, tests: &mut Vec<PathBuf>) {
+    let mut entries: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok().map(|entry| entry.path())).collect(),
+        Err(err) => {
+            log::warn!("test-suite: cannot read {}: {}", dir.display(), err);
+            return;
+        }
+    };
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            discover(&path, tests);
+        }
+        else if elf::is_elf(&path.to_string_lossy()) {
+            tests.push(path);
+        }
+    }
+}
+
+// Run the suite; returns the process exit status (0 when every test passed)
+pub fn run(args: &[String]) -> i32 {
+    let opts = match parse_args(args) {
+        Ok(opts) => opts,
+        Err(err) => {
+            println!("{}", err);
+            println!("Usage: test-suite <dir> [--budget <steps>] [--out <dir>] [--references <dir>] [-- <options>...]");
+            return 2;
+        }
+    };
+    let exe = match env::current_exe() {
+        Ok(exe) => exe,
+        Err(err) => {
+            println!("test-suite: cannot locate the emulator binary: {}", err);
+            return 2;
+        }
+    };
+    if let Err(err) = fs::create_dir_all(&opts.out) {
+        println!("test-suite: cannot create {}: {}", opts.out.display(), err);
+        return 2;
+    }
+    let mut tests = vec![];
+    discover(&opts.dir, &mut tests);
+    if tests.is_empty() {
+        println!("test-suite: no ELF files under {}", opts.dir.display());
+        return 2;
+    }
+
+    let start = Instant::now();
+    let mut results = vec![];
+    for path in &tests {
+        let name = path.strip_prefix(&opts.dir).unwrap_or(path).with_extension("").to_string_lossy().into_owned();
+        print!("{:<40} ", name);
+        let _ = io::stdout().flush();
+        let begin = Instant::now();
+        let outcome = run_test(&opts, &exe, path, &name);
+        let seconds = begin.elapsed().as_secs_f64();
+        println!("{} ({:.2}s)", label(&outcome), seconds);
+        results.push(TestResult { name, outcome, seconds });
+    }
+    summary(&results, start.elapsed().as_secs_f64());
+
+    let junit = opts.out.join("junit.xml");
+    match fs::write(&junit, junit_xml(&opts.dir, &results, start.elapsed().as_secs_f64())) {
+        Ok(()) => println!("JUnit report written to {}", junit.display()),
+        Err(err) => println!("test-suite: cannot write {}: {}", junit.display(), err),
+    }
+    if results.iter().all(|result| matches!(result.outcome, Outcome::Pass)) { 0 } else { 1 }
+}
+
+// One test in a child emulator; the verdict is its exit status and stderr (the log)
+// only supplies the detail for a failure
+fn run_test(opts: &SuiteOptions, exe: &Path, path: &Path, name: &str) -> Outcome {
+    let path_str = path.to_string_lossy().into_owned();
+    let symbols = match elf::read_symbols(&path_str) {
+        Ok(symbols) => symbols,
+        Err(err) => return Outcome::Error(err),
+    };
+    if !symbols.contains_key("tohost") && !opts.emulator_args.iter().any(|arg| arg == "--tohost") {
+        return Outcome::Error("no tohost symbol to report a result through".to_string());
+    }
+    let signature = if symbols.contains_key("begin_signature") && symbols.contains_key("end_signature") {
+        Some(opts.out.join(format!("{}.signature", name.replace('/', "_"))))
+    } else {
+        None
+    };
+
+    let mut command = Command::new(exe);
+    command.arg(path).arg("--max-steps").arg(opts.budget.to_string());
+    if let Some(signature) = &signature {
+        let _ = fs::remove_file(signature);
+        command.arg("--signature").arg(signature);
+    }
+    command.args(&opts.emulator_args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(err) => return Outcome::Error(format!("cannot start the emulator: {}", err)),
+    };
+    let mut last_error = None;
+    if let Some(stderr) = child.stderr.take() {
+        for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+            if line.contains("[ERROR]") {
+                last_error = line.split(" - ").nth(1).map(|msg| msg.to_string());
+            }
+            else if line.contains("panicked at") {
+                last_error = Some(line.trim().to_string());
+            }
+        }
+    }
+    let status = match child.wait() {
+        Ok(status) => status.code(),
+        Err(err) => return Outcome::Error(err.to_string()),
+    };
+
+    let detail = |default: String| last_error.clone().unwrap_or(default);
+    match status {
+        Some(0) => match (&signature, reference(opts, path)) {
+            (Some(signature), Some(reference)) => compare(signature, &reference),
+            default => Outcome::Pass,
+        },
+        Some(code) if code > 0 && code as u64 <= htif::FAIL_MAX => Outcome::Fail(format!("failing test {}", code)),
+        Some(BUDGET_EXHAUSTED) => Outcome::Timeout,
+        Some(NO_RESULT) => Outcome::Error(detail("stopped without an HTIF result".to_string())),
+        Some(SETUP_ERROR) => Outcome::Error("emulator setup failed (run the test alone for the reason)".to_string()),
+        Some(code) => Outcome::Error(detail(format!("exit status {}", code))),
+        None => Outcome::Error("emulator killed by a signal".to_string()),
+    }
+}
+
+// <name>.reference_output in --references, or next to the ELF
+fn reference(opts: &SuiteOptions, path: &Path) -> Option<PathBuf> {
+    let file = format!("{}.reference_output", path.file_stem()?.to_string_lossy());
+    let candidate = match &opts.references {
+        Some(dir) => dir.join(file),
+        None => path.with_file_name(file),
+    };
+    if candidate.is_file() { Some(candidate) } else { None }
+}
+
+// Word-by-word signature comparison
+fn compare(signature: &Path, reference: &Path) -> Outcome {
+    let (actual, expected) = match (fs::read_to_string(signature), fs::read_to_string(reference)) {
+        (Ok(actual), Ok(expected)) => (actual, expected),
+        (Err(err), _) => return Outcome::Error(format!("{}: {}", signature.display(), err)),
+        (_, Err(err)) => return Outcome::Error(format!("{}: {}", reference.display(), err)),
+    };
+    let actual: Vec<String> = actual.lines().map(|line| line.trim().to_lowercase()).filter(|line| !line.is_empty()).collect();
+    let expected: Vec<String> = expected.lines().map(|line| line.trim().to_lowercase()).filter(|line| !line.is_empty()).collect();
+    if let Some(i) = (0..actual.len().max(expected.len())).find(|i| actual.get(*i) != expected.get(*i)) {
+        return Outcome::Fail(format!(
+            "signature mismatch at word {}: {} (expected {})",
+            i,
+            actual.get(i).map_or("missing", |word| word.as_str()),
+            expected.get(i).map_or("missing", |word| word.as_str())
+        ));
+    }
+    Outcome::Pass
+}
+
+fn label(outcome: &Outcome) -> ColoredString {
+    match outcome {
+        Outcome::Pass => "PASS".green(),
+        Outcome::Fail(reason) => format!("FAIL: {}", reason).red(),
+        Outcome::Timeout => "TIMEOUT".yellow(),
+        Outcome::Error(reason) => format!("ERROR: {}", reason).red(),
+    }
+}
+
+fn summary(results: &[TestResult], seconds: f64) {
+    let count = |f: fn(&Outcome) -> bool| results.iter().filter(|result| f(&result.outcome)).count();
+    let passed = count(|outcome| matches!(outcome, Outcome::Pass));
+    let failed = count(|outcome| matches!(outcome, Outcome::Fail(_)));
+    let timeouts = count(|outcome| matches!(outcome, Outcome::Timeout));
+    let errors = count(|outcome| matches!(outcome, Outcome::Error(_)));
+    println!("{}", "--------------------------------".green());
+    println!("{}", "Test suite summary".green());
+    println!("{}", "--------------------------------".green());
+    println!("{:<40} {:<8} {:>8}", "Test", "Result", "Time");
+    for result in results {
+        let short = match result.outcome {
+            Outcome::Pass => "PASS".green(),
+            Outcome::Fail(_) => "FAIL".red(),
+            Outcome::Timeout => "TIMEOUT".yellow(),
+            Outcome::Error(_) => "ERROR".red(),
+        };
+        println!("{:<40} {:<8} {:>7.2}s", result.name, short, result.seconds);
+    }
+    println!("{}", "--------------------------------".green());
+    println!("{} tests: {} passed, {} failed, {} timed out, {} errors ({:.2}s)",
+             results.len(), passed, failed, timeouts, errors, seconds);
+}
+
+// Escape for an attribute value; control characters other than tab/newline are not allowed in XML 1.0
+fn xml_escape(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_control() || *c == '\t' || *c == '\n')
+        .collect::<String>()
+        .replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&apos;")
+}
+
+fn junit_xml(dir: &Path, results: &[TestResult], seconds: f64) -> String {
+    let failures = results.iter().filter(|result| matches!(result.outcome, Outcome::Fail(_) | Outcome::Timeout)).count();
+    let errors = results.iter().filter(|result| matches!(result.outcome, Outcome::Error(_))).count();
+    let suite = xml_escape(&dir.to_string_lossy());
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!("<testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\">\n",
+                          suite, results.len(), failures, errors, seconds));
+    for result in results {
+        let head = format!("  <testcase classname=\"{}\" name=\"{}\" time=\"{:.3}\"", suite, xml_escape(&result.name), result.seconds);
+        match &result.outcome {
+            Outcome::Pass => xml.push_str(&format!("{}/>\n", head)),
+            Outcome::Fail(reason) => xml.push_str(&format!("{}>\n    <failure message=\"{}\"/>\n  </testcase>\n", head, xml_escape(reason))),
+            Outcome::Timeout => xml.push_str(&format!("{}>\n    <failure message=\"instruction budget exhausted\"/>\n  </testcase>\n", head)),
+            Outcome::Error(reason) => xml.push_str(&format!("{}>\n    <error message=\"{}\"/>\n  </testcase>\n", head, xml_escape(reason))),
+        }
+    }
+    xml.push_str("</testsuite>\n");
+    xml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A scratch file under the system temp directory
+    fn scratch(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("riscsuite-{}-{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn escapes_xml_attributes() {
+        assert_eq!(xml_escape("a<b> & \"c\" 'd'"), "a&lt;b&gt; &amp; &quot;c&quot; &apos;d&apos;");
+        // ANSI colour codes from the emulator's stderr would make the report unparsable
+        assert_eq!(xml_escape("\u{1b}[31mERROR\u{1b}[0m"), "[31mERROR[0m");
+    }
+
+    #[test]
+    fn writes_junit_xml() {
+        let results = vec![
+            TestResult { name: "rv32ui-add".to_string(), outcome: Outcome::Pass, seconds: 0.25 },
+            TestResult { name: "rv32ui-sub".to_string(), outcome: Outcome::Fail("failing test 3".to_string()), seconds: 0.5 },
+            TestResult { name: "loop".to_string(), outcome: Outcome::Timeout, seconds: 1.0 },
+            TestResult { name: "a&b".to_string(), outcome: Outcome::Error("bad <elf>".to_string()), seconds: 0.0 },
+        ];
+        let xml = junit_xml(Path::new("tests/\"isa\""), &results, 1.75);
+        assert_eq!(xml, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<testsuite name=\"tests/&quot;isa&quot;\" tests=\"4\" failures=\"2\" errors=\"1\" time=\"1.750\">
+  <testcase classname=\"tests/&quot;isa&quot;\" name=\"rv32ui-add\" time=\"0.250\"/>
+  <testcase classname=\"tests/&quot;isa&quot;\" name=\"rv32ui-sub\" time=\"0.500\">
+    <failure message=\"failing test 3\"/>
+  </testcase>
+  <testcase classname=\"tests/&quot;isa&quot;\" name=\"loop\" time=\"1.000\">
+    <failure message=\"instruction budget exhausted\"/>
+  </testcase>
+  <testcase classname=\"tests/&quot;isa&quot;\" name=\"a&amp;b\" time=\"0.000\">
+    <error message=\"bad &lt;elf&gt;\"/>
+  </testcase>
+</testsuite>
+");
+    }
+
+    #[test]
+    fn compares_signatures_word_by_word() {
+        let signature = scratch("sig", "deadbeef\n00000001\n");
+        let same = scratch("same", "DEADBEEF\r\n  00000001\n\n");
+        let differs = scratch("differs", "deadbeef\n00000002\n");
+        let longer = scratch("longer", "deadbeef\n00000001\n00000000\n");
+
+        assert!(matches!(compare(&signature, &same), Outcome::Pass));
+        match compare(&signature, &differs) {
+            Outcome::Fail(reason) => assert_eq!(reason, "signature mismatch at word 1: 00000001 (expected 00000002)"),
+            default => panic!("expected a mismatch"),
+        }
+        match compare(&signature, &longer) {
+            Outcome::Fail(reason) => assert_eq!(reason, "signature mismatch at word 2: missing (expected 00000000)"),
+            default => panic!("expected a mismatch"),
+        }
+        assert!(matches!(compare(&signature, Path::new("/nonexistent/ref")), Outcome::Error(_)));
+
+        for path in [signature, same, differs, longer] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn parses_runner_options() {
+        let args: Vec<String> = ["tests", "--budget", "500", "--out", "o", "--", "--isa", "rv32i"].iter().map(|a| a.to_string()).collect();
+        let opts = parse_args(&args).unwrap();
+        assert_eq!(opts.dir, PathBuf::from("tests"));
+        assert_eq!(opts.budget, 500);
+        assert_eq!(opts.out, PathBuf::from("o"));
+        assert_eq!(opts.emulator_args, ["--isa", "rv32i"]);
+        assert!(parse_args(&["tests".to_string(), "--budget".to_string(), "0".to_string()]).is_err());
+        assert!(parse_args(&[]).is_err());
+    }
+}
@@ -0,0 +1,295 @@
+/* RISCulator - RISC-V Emulator */
+/*     NS16550A UART device     */
+
+// Libraries here
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Write;
+use colored::*;
+use crate::bus::Device;
+use crate::console;
+use crate::fdt::Fdt;
+
+// Register offsets (DLAB = 0 unless noted)
+pub const RBR: usize = 0;       // Receive buffer (read)
+pub const THR: usize = 0;       // Transmit holding (write)
+const IER: usize = 1;       // Interrupt enable
+const IIR: usize = 2;       // Interrupt identification (read)
+const FCR: usize = 2;       // FIFO control (write)
+const LCR: usize = 3;       // Line control
+const MCR: usize = 4;       // Modem control
+pub const LSR: usize = 5;       // Line status
+const MSR: usize = 6;       // Modem status
+const SCR: usize = 7;       // Scratch
+pub const UART_SIZE: usize = 0x100;
+pub const UART_CLOCK: u32 = 3_686_400;     // Input clock advertised to drivers (divisor latch is not timed)
+
+// IER bits
+const IER_RDA: u8 = 1 << 0;     // Received data available
+const IER_THRE: u8 = 1 << 1;    // Transmit holding register empty
+
+// IIR values
+const IIR_NONE: u8 = 0x01;
+const IIR_THRE: u8 = 0x02;
+const IIR_RDA: u8 = 0x04;
+const IIR_FIFO: u8 = 0xC0;      // FIFOs enabled
+
+// LSR bits
+pub const LSR_DR: u8 = 1 << 0;      // Data ready
+const LSR_OE: u8 = 1 << 1;      // Overrun error
+const LSR_THRE: u8 = 1 << 5;    // THR empty
+const LSR_TEMT: u8 = 1 << 6;    // Transmitter empty
+
+const LCR_DLAB: u8 = 1 << 7;
+const MCR_LOOP: u8 = 1 << 4;
+const FIFO_DEPTH: usize = 16;
+
+// Where transmitted characters go
+pub enum Sink {
+    Stdout,
+    File(File),
+}
+
+// UART struct
+pub struct Uart {
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    lsr: u8,
+    scr: u8,
+    fcr: u8,
+    dll: u8,
+    dlm: u8,
+    thre_pending: bool,         // THRE interrupt armed until IIR is read or THR written
+    rx_fifo: VecDeque<u8>,
+    listening: bool,            // The guest has looked at the receiver, so host input is taken
+    sink: Sink,
+    tx_count: u64,
+    rx_count: u64,
+}
+
+// UART struct impl
+impl Uart {
+    // Initialize a UART transmitting to `sink` and receiving from the host console
+    pub fn new(sink: Sink) -> Self {
+        Self {
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            lsr: LSR_THRE | LSR_TEMT,
+            scr: 0,
+            fcr: 0,
+            dll: 0,
+            dlm: 0,
+            thre_pending: false,
+            rx_fifo: VecDeque::new(),
+            listening: false,
+            sink,
+            tx_count: 0,
+            rx_count: 0,
+        }
+    }
+
+    // Send a character to the host
+    fn transmit(&mut self, byte: u8) {
+        self.tx_count += 1;
+        if self.mcr & MCR_LOOP != 0 {
+            self.receive(byte);         // Loopback mode feeds TX straight into RX
+            return;
+        }
+        let result = match &mut self.sink {
+            Sink::Stdout => {
+                let mut out = std::io::stdout();
+                out.write_all(&[byte]).and_then(|_| out.flush())
+            }
+            Sink::File(file) => file.write_all(&[byte]).and_then(|_| file.flush()),
+        };
+        if let Err(err) = result {
+            log::error!("UART: transmit failed: {}", err);
+        }
+    }
+
+    // Queue a received character, flagging an overrun when the FIFO is full
+    fn receive(&mut self, byte: u8) {
+        if self.rx_fifo.len() >= FIFO_DEPTH {
+            self.lsr |= LSR_OE;
+            return;
+        }
+        self.rx_count += 1;
+        self.rx_fifo.push_back(byte);
+    }
+
+    // First look at the receiver: from now on host input belongs to this UART
+    fn listen(&mut self) {
+        if !self.listening {
+            self.listening = true;
+            self.tick();
+        }
+    }
+
+    // Highest-priority interrupt source, as reported by IIR
+    fn iir(&self) -> u8 {
+        let fifo = if self.fcr & 1 != 0 { IIR_FIFO } else { 0 };
+        if self.ier & IER_RDA != 0 && !self.rx_fifo.is_empty() {
+            IIR_RDA | fifo
+        }
+        else if self.ier & IER_THRE != 0 && self.thre_pending {
+            IIR_THRE | fifo
+        }
+        else {
+            IIR_NONE | fifo
+        }
+    }
+}
+
+// UART device: byte-wide registers at offsets 0-7
+impl Device for Uart {
+    fn name(&self) -> &str {
+        "uart"
+    }
+
+    fn read(&mut self, offset: usize, size: usize) -> Option<u64> {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        let value = match offset {
+            RBR if dlab => self.dll,
+            RBR => {
+                self.listen();
+                self.rx_fifo.pop_front().unwrap_or(0)
+            }
+            IER if dlab => self.dlm,
+            IER => self.ier,
+            IIR => {
+                let iir = self.iir();
+                if iir & 0x0F == IIR_THRE {
+                    self.thre_pending = false;      // Reading IIR clears a THRE interrupt
+                }
+                iir
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                self.listen();
+                let lsr = self.lsr | if self.rx_fifo.is_empty() { 0 } else { LSR_DR };
+                self.lsr &= !LSR_OE;
+                lsr
+            }
+            MSR => 0xB0,        // CTS, DSR and DCD asserted
+            SCR => self.scr,
+            _ => return None,
+        };
+        Some(value as u64 & crate::bus::size_mask(size))
+    }
+
+    fn write(&mut self, offset: usize, size: usize, value: u64) -> bool {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        let byte = value as u8;
+        match offset {
+            THR if dlab => self.dll = byte,
+            THR => {
+                self.transmit(byte);
+                self.thre_pending = true;       // Transmission is instantaneous
+            }
+            IER if dlab => self.dlm = byte,
+            IER => {
+                // Enabling THRE with an empty THR raises the interrupt straight away
+                if byte & IER_THRE != 0 && self.ier & IER_THRE == 0 {
+                    self.thre_pending = true;
+                }
+                if byte & IER_RDA != 0 {
+                    self.listen();
+                }
+                self.ier = byte & 0x0F;
+            }
+            FCR => {
+                self.fcr = byte;
+                if byte & 0x02 != 0 {
+                    self.rx_fifo.clear();
+                }
+            }
+            LCR => self.lcr = byte,
+            MCR => self.mcr = byte & 0x1F,
+            LSR | MSR => {}     // Read-only
+            SCR => self.scr = byte,
+            _ => return false,
+        }
+        true
+    }
+
+    fn tick(&mut self) {
+        // Move whatever the host has typed into the receive FIFO
+        while self.listening && self.rx_fifo.len() < FIFO_DEPTH {
+            match console::try_read() {
+                Some(byte) => self.receive(byte),
+                None => break,
+            }
+        }
+    }
+
+    fn interrupt(&self) -> bool {
+        self.iir() & IIR_NONE == 0
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        // Input may still arrive while the console is open
+        if self.ier & IER_RDA != 0 && console::is_open() { Some(1) } else { None }
+    }
+
+    fn reset(&mut self) {
+        self.ier = 0;
+        self.lcr = 0;
+        self.mcr = 0;
+        self.lsr = LSR_THRE | LSR_TEMT;
+        self.scr = 0;
+        self.fcr = 0;
+        self.thre_pending = false;
+        self.rx_fifo.clear();
+    }
+
+    fn describe(&self, fdt: &mut Fdt, base: usize, size: usize, irq: Option<usize>) {
+        fdt.soc_device("serial", &["ns16550a"], base, size, irq)
+            .u32("clock-frequency", UART_CLOCK);
+    }
+
+    fn print_dirty(&mut self) {
+        println!("{}", "--------------------------------".green());
+        println!("{}", "UART".green());
+        println!("{}", "--------------------------------".green());
+        println!("IER: {:#04x} LCR: {:#04x} MCR: {:#04x} LSR: {:#04x}", self.ier, self.lcr, self.mcr, self.lsr);
+        println!("Characters transmitted: {} received: {}", self.tx_count, self.rx_count);
+        println!("{}", "--------------------------------".green());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn takes_host_input_only_once_the_guest_looks() {
+        console::set_source(Box::new(Cursor::new(b"ab".to_vec())));
+        while !console::ready() {
+            std::thread::yield_now();
+        }
+        let mut uart = Uart::new(Sink::Stdout);
+        uart.tick();
+        assert!(uart.rx_fifo.is_empty());
+        assert!(console::ready());
+
+        // The first LSR poll starts receiving
+        let lsr = uart.read(LSR, 1).unwrap() as u8;
+        assert_eq!(lsr & LSR_DR, LSR_DR);
+        assert_eq!(uart.read(RBR, 1), Some(b'a' as u64));
+    }
+
+    #[test]
+    fn loopback_feeds_the_receiver() {
+        console::set_source(Box::new(Cursor::new(vec![])));
+        let mut uart = Uart::new(Sink::Stdout);
+        uart.write(MCR, 1, MCR_LOOP as u64);
+        uart.write(THR, 1, b'z' as u64);
+        assert_eq!(uart.read(LSR, 1).unwrap() as u8 & LSR_DR, LSR_DR);
+        assert_eq!(uart.read(RBR, 1), Some(b'z' as u64));
+        assert_eq!(uart.read(LSR, 1).unwrap() as u8 & LSR_DR, 0);
+    }
+}
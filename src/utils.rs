@@ -12,6 +12,19 @@ use log::LevelFilter;
 use crate::Register;
 use crate::RAM;
 use crate::Vproc;
+use crate::Mode;
+use crate::csr;
+use crate::csr::Event;
+use crate::isa;
+use crate::mmu;
+use crate::mmu::Access;
+use crate::sbi;
+use crate::linux;
+use crate::newlib;
+use crate::semihost;
+use crate::htif;
+use crate::lockstep;
+use crate::suite;
 use std::thread;
 use std::time::Duration;
 use std::thread::spawn;
@@ -25,7 +38,7 @@ use std::process;
 const SPEED: usize = 1;
 const XLEN: usize = 32;
 const INI: usize = 4; // Address offset
-const PROGRAM_LENGTH: usize = 1000;
+pub const PROGRAM_LENGTH: usize = 1000;     // Default step limit (--max-steps)
 
 // Static
 static mut PROGRAM_LEN: usize = 0;      // UNSAFE not RUSTIC
@@ -33,10 +46,8 @@ static mut PC: isize = 0x0000;
 
 // Logo displaying function
 pub fn logo_display() {
-    /* RISCulator logo */
-    let filename = "src/assets/logo.txt";
-    let logo_con = fs::read_to_string(filename)
-        .expect("Failed to read the file");
+    /* RISCulator logo, built in so the emulator runs from any directory */
+    let logo_con = include_str!("assets/logo.txt");
     println!("{}", logo_con.yellow());
 }
 
@@ -60,7 +71,7 @@ pub fn boot_seq(xlen: usize, extension: &str, reg_size: usize, ram_size: usize)
     log::info!("Boot Sequence Starting");
     log::info!("Loading configurations");
     log::info!("Instruction length: {}", xlen);
-    log::info!("Extension: RV{}{}", xlen, extension);
+    log::info!("ISA: {}", extension);
     log::info!("RAM size: {}", ram_size);
 }
 
@@ -126,31 +137,129 @@ pub fn program_parser(path: &str,  ram: &mut RAM) {
      }
  }
 
-// stage2 -> Decode + Execute
-pub fn stage2 (proc: &mut Vproc) {
+// End of the program loaded from the disassembly
+pub fn program_end() -> usize {
+    unsafe{PROGRAM_LEN * INI}
+}
+
+// Start execution somewhere other than the reset vector
+pub fn set_pc(pc: isize) {
+    unsafe{PC = pc};
+}
+
+pub fn pc() -> isize {
+    unsafe{PC}
+}
+
+// Stop the emulator with the guest's exit status, reporting the counters. A program
+// that has HTIF reports its result there, so leaving any other way is not a pass.
+pub fn exit(proc: &mut Vproc, status: i32) -> ! {
+    let status = if proc.htif.is_some() && status == 0 {
+        log::error!("HTIF: program stopped without a result on tohost");
+        suite::NO_RESULT
+    } else {
+        status
+    };
+    let _ = std::io::stdout().flush();
+    println!("Cycles: {:?}", proc.csrs.read(csr::MCYCLE));
+    println!("Instructions retired: {:?}", proc.csrs.read(csr::MINSTRET));
+    process::exit(status);
+}
+
+// stage2 -> Decode + Execute, for at most `steps` steps; true when the budget ran out,
+// false when the hart stopped in WFI with nothing left to wake it
+pub fn stage2 (proc: &mut Vproc, steps: usize) -> bool {
     let mut instr: isize = 0;
-    let mut cycles: isize = 0;
-    proc.regs.write(2, 2147483632);
-
-    for iter in 0..PROGRAM_LENGTH {
-        let mut pcby4: usize = (unsafe{PC/4}).try_into().unwrap();
-        if proc.ram_module.dirty_bit[pcby4] == 1 {
-            instr = proc.ram_module.read(pcby4.try_into().unwrap());
-            let mut instr_str = format!("{:032b}", instr).to_string();
+
+    for iter in 0..steps {
+        // Devices advance one clock and update their interrupt lines
+        proc.bus.tick();
+        sync_irqs(proc);
+        htif::poll(proc);
+
+        // A hart in WFI idles until an enabled interrupt is pending
+        if proc.wfi {
+            if !proc.csrs.wfi_wakeup() {
+                match next_wakeup(proc) {
+                    Some(clocks) => {
+                        log::info!("WFI: fast-forwarding {} clocks", clocks);
+                        proc.csrs.advance(clocks);
+                        proc.bus.advance(clocks);
+                        sync_irqs(proc);
+                        continue;
+                    }
+                    None => {
+                        log::warn!("WFI with no interrupt source that can wake the hart, stopping");
+                        return false;
+                    }
+                }
+            }
+            proc.wfi = false;
+        }
+
+        // Interrupts are taken between instructions
+        if let Some(code) = proc.csrs.pending_interrupt(proc.mode) {
+            proc.csrs.tick();
+            take_trap(proc, csr::INTERRUPT_BIT | code, 0);
+            proc.csrs.retire();
+            continue;
+        }
+
+        // Effects of this instruction, for --lockstep
+        lockstep::begin(proc);
+        let (pc, mode) = (unsafe{PC}, proc.mode);
+
+        let mut pc_addr: usize = match mmu::translate(proc, unsafe{PC} as usize, 4, Access::Fetch) {
+            Ok(addr) => addr,
+            Err((cause, tval)) => {
+                proc.csrs.tick();
+                take_trap(proc, cause, tval);
+                proc.csrs.retire();
+                continue;
+            }
+        };
+        let mut cached = proc.icache.lookup(pc_addr);
+        if cached.is_none() {
+            instr = match proc.bus.read(pc_addr, 4) {
+                Some(instr) => instr,
+                None => {
+                    proc.csrs.tick();
+                    take_trap(proc, csr::CAUSE_INSTRUCTION_ACCESS_FAULT, unsafe{PC});
+                    proc.csrs.retire();
+                    continue;
+                }
+            };
+            let line = format!("{:032b}", instr).to_string();
+            proc.icache.insert(pc_addr, line.clone());
+            cached = Some(line);
+        }
+        if let Some(mut instr_str) = cached {
             let mut instr_str_split = instr_str.split("").collect::<Vec<_>>();
             instr_str_split.remove(0);
             instr_str_split.remove(instr_str_split.len()-1);
-            let mut temp = instruction_decoder(instr_str_split, proc, cycles);
+            proc.csrs.tick();
+            if let Err(reason) = isa::permitted(&instr_str_split, &proc.isa) {
+                let bits = isize::from_str_radix(&instr_str, 2).unwrap();
+                log::error!("Instruction {:#010x}: {}", bits, reason);
+                take_trap(proc, csr::CAUSE_ILLEGAL_INSTRUCTION, bits);
+                lockstep::check(proc, mode, pc, bits, &Register::new());
+                proc.csrs.retire();
+                continue;
+            }
+            let bits = isize::from_str_radix(&instr_str, 2).unwrap();
+            let mut temp = instruction_decoder(instr_str_split, proc);
             println!("{}", unsafe{PC});
+            lockstep::check(proc, mode, pc, bits, &temp);
             proc.update_regs(temp);
             proc.regs.print_dirty();
-            cycles += 1;
+            proc.csrs.retire();
         }
     }
+    true
 }
 
 // Instruction Decoder
-pub fn instruction_decoder(instr: Vec<&str>, proc: &mut Vproc, cycles: isize) -> Register {
+pub fn instruction_decoder(instr: Vec<&str>, proc: &mut Vproc) -> Register {
     /*
      * This decoder is based on the RISC-V Unprivileged Spec v2.2
      */
@@ -168,7 +277,6 @@ pub fn instruction_decoder(instr: Vec<&str>, proc: &mut Vproc, cycles: isize) ->
      */
 
     let mut temp_regs = Register::new();
-    let mut temp_ram = RAM::new();
 
     let opcode_slice = &instr[25..];    // opcode field
     let opcode_slice_joined = opcode_slice.join("");
@@ -177,6 +285,7 @@ pub fn instruction_decoder(instr: Vec<&str>, proc: &mut Vproc, cycles: isize) ->
 
     match opcode_slice_joined.as_str() {
         "0000011" => {      // Load Instructions
+            proc.csrs.event(Event::Load);
             let funct3_slice = &instr[17..20];
             let funct3_slice_joined = funct3_slice.join("");
             let rd_slice = &instr[20..25];
@@ -216,7 +325,12 @@ pub fn instruction_decoder(instr: Vec<&str>, proc: &mut Vproc, cycles: isize) ->
 
                     /* Execution step */
                     let mut op1 = proc.regs.read(rs1_bits.try_into().unwrap());
-                    let mut out = op1 + imm_bits;
+                    let mut addr = op1 + imm_bits;
+                    let mut out = match load(proc, addr, 1, true) {
+                        Some(value) => value,
+                        None => return temp_regs,
+                    };
+                    log::info!("Effective address       : {:#010x}", addr & 0xFFFF_FFFF);
                     log::info!("Register One contents   : {:032b}", op1);
                     log::info!("Immediate value         : {:032b}", imm_bits);
                     log::info!("RD after LB operation   : {:032b}", out);
@@ -234,7 +348,12 @@ pub fn instruction_decoder(instr: Vec<&str>, proc: &mut Vproc, cycles: isize) ->
 
                     /* Execution step */
                     let mut op1 = proc.regs.read(rs1_bits.try_into().unwrap());
-                    let mut out = op1 + imm_bits;
+                    let mut addr = op1 + imm_bits;
+                    let mut out = match load(proc, addr, 2, true) {
+                        Some(value) => value,
+                        None => return temp_regs,
+                    };
+                    log::info!("Effective address       : {:#010x}", addr & 0xFFFF_FFFF);
                     log::info!("Register One contents   : {:032b}", op1);
                     log::info!("Immediate value         : {:032b}", imm_bits);
                     log::info!("RD after LH operation   : {:032b}", out);
@@ -251,12 +370,17 @@ pub fn instruction_decoder(instr: Vec<&str>, proc: &mut Vproc, cycles: isize) ->
                     log::info!("{}", "--------------------------------".green());
 
                     /* Execution step */
-                    let mut op2_sw = (rs1_bits + imm_bits) & 0xffff;
-                    let mut op1 = proc.ram_module.read_from_addr(op2_sw.try_into().unwrap());
-                    println!("{}", op2_sw);
+                    let mut op1 = proc.regs.read(rs1_bits.try_into().unwrap());
+                    let mut addr = op1 + imm_bits;
+                    let mut out = match load(proc, addr, 4, true) {
+                        Some(value) => value,
+                        None => return temp_regs,
+                    };
+                    log::info!("Effective address       : {:#010x}", addr & 0xFFFF_FFFF);
                     log::info!("Register One contents   : {:032b}", op1);
                     log::info!("Immediate value         : {:032b}", imm_bits);
-                    temp_regs.write(rd_bits.try_into().unwrap(), op1);
+                    log::info!("RD after LW operation   : {:032b}", out);
+                    temp_regs.write(rd_bits.try_into().unwrap(), out);
                     unsafe{PC += 0x0004};
                     temp_regs
                 }
@@ -270,7 +394,12 @@ pub fn instruction_decoder(instr: Vec<&str>, proc: &mut Vproc, cycles: isize) ->
 
                     /* Execution step */
                     let mut op1 = proc.regs.read(rs1_bits.try_into().unwrap());
-                    let mut out = op1 + imm_bits;
+                    let mut addr = op1 + imm_bits;
+                    let mut out = match load(proc, addr, 1, false) {
+                        Some(value) => value,
+                        None => return temp_regs,
+                    };
+                    log::info!("Effective address       : {:#010x}", addr & 0xFFFF_FFFF);
                     log::info!("Register One contents   : {:032b}", op1);
                     log::info!("Immediate value         : {:032b}", imm_bits);
                     log::info!("RD after LBU operation  : {:032b}", out);
@@ -288,7 +417,12 @@ pub fn instruction_decoder(instr: Vec<&str>, proc: &mut Vproc, cycles: isize) ->
 
                     /* Execution step */
                     let mut op1 = proc.regs.read(rs1_bits.try_into().unwrap());
-                    let mut out = op1 + imm_bits;
+                    let mut addr = op1 + imm_bits;
+                    let mut out = match load(proc, addr, 2, false) {
+                        Some(value) => value,
+                        None => return temp_regs,
+                    };
+                    log::info!("Effective address       : {:#010x}", addr & 0xFFFF_FFFF);
                     log::info!("Register One contents   : {:032b}", op1);
                     log::info!("Immediate value         : {:032b}", imm_bits);
                     log::info!("RD after LHU operation  : {:032b}", out);
@@ -305,6 +439,7 @@ pub fn instruction_decoder(instr: Vec<&str>, proc: &mut Vproc, cycles: isize) ->
         }
 
         "0100011" => {      // Store Instructions
+            proc.csrs.event(Event::Store);
             let funct3_slice = &instr[17..20];
             let funct3_slice_joined = funct3_slice.join("");
             let rs2_slice = &instr[7..12];
@@ -344,6 +479,18 @@ pub fn instruction_decoder(instr: Vec<&str>, proc: &mut Vproc, cycles: isize) ->
                     log::info!("Immediate value: {}", imm_bits);
                     log::info!("SB x{}, {}(x{})", rs2_bits, imm_bits, rs1_bits);
                     log::info!("{}", "--------------------------------".green());
+
+                    /* Execution step */
+                    let mut op1 = proc.regs.read(rs1_bits.try_into().unwrap());
+                    let mut op2 = proc.regs.read(rs2_bits.try_into().unwrap());
+                    let mut addr = op1 + imm_bits;
+                    log::info!("Effective address       : {:#010x}", addr & 0xFFFF_FFFF);
+                    log::info!("Register One contents   : {:032b}", op1);
+                    log::info!("Register Two contents   : {:032b}", op2);
+                    log::info!("Immediate value         : {:032b}", imm_bits);
+                    if !store(proc, addr, 1, op2) {
+                        return temp_regs;
+                    }
                     unsafe{PC += 0x0004};
                     temp_regs
                 }
@@ -354,6 +501,18 @@ pub fn instruction_decoder(instr: Vec<&str>, proc: &mut Vproc, cycles: isize) ->
                     log::info!("Immediate value: {}", imm_bits);
                     log::info!("SH x{}, {}(x{})", rs2_bits, imm_bits, rs1_bits);
                     log::info!("{}", "--------------------------------".green());
+
+                    /* Execution step */
+                    let mut op1 = proc.regs.read(rs1_bits.try_into().unwrap());
+                    let mut op2 = proc.regs.read(rs2_bits.try_into().unwrap());
+                    let mut addr = op1 + imm_bits;
+                    log::info!("Effective address       : {:#010x}", addr & 0xFFFF_FFFF);
+                    log::info!("Register One contents   : {:032b}", op1);
+                    log::info!("Register Two contents   : {:032b}", op2);
+                    log::info!("Immediate value         : {:032b}", imm_bits);
+                    if !store(proc, addr, 2, op2) {
+                        return temp_regs;
+                    }
                     unsafe{PC += 0x0004};
                     temp_regs
                 }
@@ -368,15 +527,14 @@ pub fn instruction_decoder(instr: Vec<&str>, proc: &mut Vproc, cycles: isize) ->
                     /* Execution step */
                     let mut op1 = proc.regs.read(rs1_bits.try_into().unwrap());
                     let mut op2 = proc.regs.read(rs2_bits.try_into().unwrap());
-                    let mut op2_sw = rs1_bits + imm_bits;
-                    op2_sw = (op2_sw & 0xffff);
+                    let mut addr = op1 + imm_bits;
+                    log::info!("Effective address       : {:#010x}", addr & 0xFFFF_FFFF);
                     log::info!("Register One contents   : {:032b}", op1);
                     log::info!("Register Two contents   : {:032b}", op2);
                     log::info!("Immediate value         : {:032b}", imm_bits);
-                    temp_ram.write_to_addr(op2_sw.try_into().unwrap(), op2);
-                    temp_ram.print_dirty();
-                    proc.update_ram(temp_ram);
-                    proc.ram_module.print_dirty();
+                    if !store(proc, addr, 4, op2) {
+                        return temp_regs;
+                    }
                     unsafe{PC += 0x0004};
                     temp_regs
                 }
@@ -496,6 +654,39 @@ pub fn instruction_decoder(instr: Vec<&str>, proc: &mut Vproc, cycles: isize) ->
                     unsafe{PC += 0x0004};
                     temp_regs
                 }
+                "001" | "101" => {      // Shift immediates
+                    let shamt = isize::from_str_radix(&instr[7..12].join(""), 2).unwrap();
+                    let funct7 = instr[0..7].join("");
+                    let (name, kind) = match (funct3_slice_joined.as_str(), funct7.as_str()) {
+                        ("001", "0000000") => ("SLLI", 0),
+                        ("101", "0000000") => ("SRLI", 1),
+                        ("101", "0100000") => ("SRAI", 2),
+                        default => {
+                            let bits = isize::from_str_radix(&instr.join(""), 2).unwrap();
+                            take_trap(proc, csr::CAUSE_ILLEGAL_INSTRUCTION, bits);
+                            return temp_regs;
+                        }
+                    };
+                    log::info!("Shift Immediate ({}) instruction decoded", name);
+                    log::info!("Destination Register address: x{}", rd_bits);
+                    log::info!("Register One address: x{}", rs1_bits);
+                    log::info!("Shift amount: {}", shamt);
+                    log::info!("{} x{}, x{}, {}", name, rd_bits, rs1_bits, shamt);
+                    log::info!("{}", "--------------------------------".green());
+
+                    /* Execution step */
+                    let mut op1 = proc.regs.read(rs1_bits.try_into().unwrap());
+                    let mut out = match kind {
+                        0 => ((op1 as u32) << shamt) as i32 as isize,
+                        1 => ((op1 as u32) >> shamt) as i32 as isize,
+                        default => ((op1 as i32) >> shamt) as isize,
+                    };
+                    log::info!("Register One contents   : {:032b}", op1);
+                    log::info!("RD after {} operation : {:032b}", name, out);
+                    temp_regs.write(rd_bits.try_into().unwrap(), out);
+                    unsafe{PC += 0x0004};
+                    temp_regs
+                }
                 "110" => {      // OR Immediate
                     log::info!("OR Immediate (ORI) instruction decoded");
                     log::info!("Destination Register address: x{}", rd_bits);
@@ -572,7 +763,7 @@ pub fn instruction_decoder(instr: Vec<&str>, proc: &mut Vproc, cycles: isize) ->
             log::info!("{}", "--------------------------------".green());
 
             /* Execution step */
-            let mut out = imm_bits >> 12;
+            let mut out = (imm_bits << 12) as i32 as isize;
             log::info!("RD after LUI operation : {:032b}", out);
             temp_regs.write(rd_bits.try_into().unwrap(), out);
             unsafe{PC += 0x0004}      // Program counter
@@ -610,8 +801,7 @@ pub fn instruction_decoder(instr: Vec<&str>, proc: &mut Vproc, cycles: isize) ->
             log::info!("{}", "--------------------------------".green());
 
             /* Execution step */
-            unsafe{PC += (imm_bits >> 12)};
-            let mut out = unsafe{PC} + 0x0004;
+            let mut out = (unsafe{PC} + (imm_bits << 12)) as i32 as isize;
             log::info!("RD after AUIPC operation : {:032b}", out);
             temp_regs.write(rd_bits.try_into().unwrap(), out);
             unsafe{PC += 0x0004};
@@ -623,18 +813,19 @@ pub fn instruction_decoder(instr: Vec<&str>, proc: &mut Vproc, cycles: isize) ->
             let rd_slice_joined = rd_slice.join("");
             let imm_slice = &instr[0..20];
             let imm_slice_joined = imm_slice.join("");
-            let imm_slice_1 = imm_slice[0].to_string();        // imm[19]
-            //let imm_slice_1_joined = imm_slice_1.join("");
-            let imm_slice_2 = &imm_slice[1..10];    // imm[7:0]
+            let imm_slice_1 = imm_slice[0].to_string();        // imm[20]
+            let imm_slice_2 = &imm_slice[1..11];    // imm[10:1]
             let imm_slice_2_joined = imm_slice_2.join("");
-            let imm_slice_3 = imm_slice[10].to_string();       // imm[8]
-           // let imm_slice_3_joined = imm_slice_3.join("");
-            let imm_slice_4 = &imm_slice[11..20];   // imm[18:9]
+            let imm_slice_3 = imm_slice[11].to_string();       // imm[11]
+            let imm_slice_4 = &imm_slice[12..20];   // imm[19:12]
             let imm_slice_4_joined = imm_slice_4.join("");
-            let mut imm_final = imm_slice_2_joined + &imm_slice_3 + &imm_slice_4_joined + &imm_slice_1;
+            let mut imm_final = imm_slice_1.clone() + &imm_slice_4_joined + &imm_slice_3 + &imm_slice_2_joined + "0";
 
             let rd_bits = isize::from_str_radix(&rd_slice_joined, 2).unwrap();
             let mut imm_bits = isize::from_str_radix(&imm_final, 2).unwrap();
+            if imm_slice_1 == "1" {
+                imm_bits -= 1 << 21;
+            }
 
             log::info!("Jump and Link (JAL) instruction decoded");
             log::info!("Destination Register address: x{}", rd_bits);
@@ -643,9 +834,14 @@ pub fn instruction_decoder(instr: Vec<&str>, proc: &mut Vproc, cycles: isize) ->
             log::info!("{}", "--------------------------------".green());
 
             /* Execution step */
-            unsafe{PC += 0x0004};
+            let target = (unsafe{PC} + imm_bits) & 0xFFFF_FFFF;
+            if target & 0x3 != 0 {
+                take_trap(proc, csr::CAUSE_INSTRUCTION_ADDRESS_MISALIGNED, target);
+                return temp_regs;
+            }
+            proc.csrs.event(Event::Branch);
             let mut out = unsafe{PC} + 0x0004;
-            unsafe{PC += imm_bits};
+            unsafe{PC = target};
             log::info!("PC after JAL operation : {:032b}", unsafe{PC});
             temp_regs.write(rd_bits.try_into().unwrap(), out);
             temp_regs
@@ -686,18 +882,87 @@ pub fn instruction_decoder(instr: Vec<&str>, proc: &mut Vproc, cycles: isize) ->
             log::info!("{}", "--------------------------------".green());
 
             /* Execution step */
-            unsafe{PC += 0x0004};
-            let mut out = unsafe{PC};
-            unsafe{PC = (rs1_bits + imm_bits & !1)};
+            let target = (proc.regs.read(rs1_bits.try_into().unwrap()) + imm_bits) & 0xFFFF_FFFE;
+            // `ret` to address 0 leaves the top level: that is how a program ends
+            if rd_bits == 0 && rs1_bits == 1 && imm_bits == 0 && target == 0 {
+                log::info!("Execution successful!");
+                exit(proc, 0);
+            }
+            if target & 0x3 != 0 {
+                take_trap(proc, csr::CAUSE_INSTRUCTION_ADDRESS_MISALIGNED, target);
+                return temp_regs;
+            }
+            proc.csrs.event(Event::Branch);
+            let mut out = unsafe{PC} + 0x0004;
+            unsafe{PC = target};
             log::info!("RD after JALR operation : {:032b}", out);
             log::info!("PC after JALR operation : {:032b}", unsafe{PC});
             temp_regs.write(rd_bits.try_into().unwrap(), out);
+            temp_regs
+        }
 
-            if rd_bits == 0 && rs1_bits == 1 && imm_bits == 0 {
-                log::info!("Execution successful!");
-                println!("Cycles: {:?}", &cycles);
-                process::exit(0);
+        "1100011" => {      // Conditional branches
+            let funct3_slice = &instr[17..20];
+            let funct3_slice_joined = funct3_slice.join("");
+            let rs2_slice = &instr[7..12];
+            let rs2_slice_joined = rs2_slice.join("");
+            let rs1_slice = &instr[12..17];
+            let rs1_slice_joined = rs1_slice.join("");
+            // imm[12|10:5] sits in bits 31:25 and imm[4:1|11] in bits 11:7
+            let imm_final = instr[0].to_string() + instr[24] + &instr[1..7].join("") + &instr[20..24].join("") + "0";
+
+            let rs1_bits = isize::from_str_radix(&rs1_slice_joined, 2).unwrap();
+            let rs2_bits = isize::from_str_radix(&rs2_slice_joined, 2).unwrap();
+            let mut imm_bits = isize::from_str_radix(&imm_final, 2).unwrap();
+            if instr[0] == "1" {
+                imm_bits -= 1 << 13;
             }
+
+            let name = match funct3_slice_joined.as_str() {
+                "000" => "BEQ",
+                "001" => "BNE",
+                "100" => "BLT",
+                "101" => "BGE",
+                "110" => "BLTU",
+                "111" => "BGEU",
+                default => {
+                    let bits = isize::from_str_radix(&instr.join(""), 2).unwrap();
+                    take_trap(proc, csr::CAUSE_ILLEGAL_INSTRUCTION, bits);
+                    return temp_regs;
+                }
+            };
+            log::info!("Branch ({}) instruction decoded", name);
+            log::info!("Register One address: x{}", rs1_bits);
+            log::info!("Register Two address: x{}", rs2_bits);
+            log::info!("Immediate value: {}", imm_bits);
+            log::info!("{} x{}, x{}, {}", name, rs1_bits, rs2_bits, imm_bits);
+            log::info!("{}", "--------------------------------".green());
+
+            /* Execution step */
+            let mut op1 = proc.regs.read(rs1_bits.try_into().unwrap());
+            let mut op2 = proc.regs.read(rs2_bits.try_into().unwrap());
+            let taken = match name {
+                "BEQ" => op1 as u32 == op2 as u32,
+                "BNE" => op1 as u32 != op2 as u32,
+                "BLT" => (op1 as i32) < (op2 as i32),
+                "BGE" => (op1 as i32) >= (op2 as i32),
+                "BLTU" => (op1 as u32) < (op2 as u32),
+                default => (op1 as u32) >= (op2 as u32),
+            };
+            log::info!("Register One contents   : {:032b}", op1);
+            log::info!("Register Two contents   : {:032b}", op2);
+            log::info!("Branch taken            : {}", taken);
+            if !taken {
+                unsafe{PC += 0x0004};
+                return temp_regs;
+            }
+            let target = (unsafe{PC} + imm_bits) & 0xFFFF_FFFF;
+            if target & 0x3 != 0 {
+                take_trap(proc, csr::CAUSE_INSTRUCTION_ADDRESS_MISALIGNED, target);
+                return temp_regs;
+            }
+            proc.csrs.event(Event::Branch);
+            unsafe{PC = target};
             temp_regs
         }
 
@@ -772,7 +1037,7 @@ pub fn instruction_decoder(instr: Vec<&str>, proc: &mut Vproc, cycles: isize) ->
                     /* Execution step */
                     let mut op1 = proc.regs.read(rs1_bits.try_into().unwrap());
                     let mut op2 = proc.regs.read(rs2_bits.try_into().unwrap());
-                    let mut out = op1 << op2;
+                    let mut out = ((op1 as u32) << (op2 & 0x1F)) as i32 as isize;
                     log::info!("Register One contents         : {:032b}", op1);
                     log::info!("Register Two contents         : {:032b}", op2);
                     log::info!("RD after Shift Left operation : {:032b}", out);
@@ -852,6 +1117,15 @@ pub fn instruction_decoder(instr: Vec<&str>, proc: &mut Vproc, cycles: isize) ->
                             log::info!("Register Two value: {}", rs2_bits);
                             log::info!("SRL x{}, x{}, x{}", rd_bits, rs1_bits, rs2_bits);
                             log::info!("{}", "--------------------------------".green());
+
+                            /* Execution step */
+                            let mut op1 = proc.regs.read(rs1_bits.try_into().unwrap());
+                            let mut op2 = proc.regs.read(rs2_bits.try_into().unwrap());
+                            let mut out = ((op1 as u32) >> (op2 & 0x1F)) as i32 as isize;
+                            log::info!("Register One contents  : {:032b}", op1);
+                            log::info!("Register Two contents  : {:032b}", op2);
+                            log::info!("RD after SRL operation : {:032b}", out);
+                            temp_regs.write(rd_bits.try_into().unwrap(), out);
                             unsafe{PC += 0x0004};
                             temp_regs
                         }
@@ -862,6 +1136,15 @@ pub fn instruction_decoder(instr: Vec<&str>, proc: &mut Vproc, cycles: isize) ->
                             log::info!("Register Two value: {}", rs2_bits);
                             log::info!("SRA x{}, x{}, x{}", rd_bits, rs1_bits, rs2_bits);
                             log::info!("{}", "--------------------------------".green());
+
+                            /* Execution step */
+                            let mut op1 = proc.regs.read(rs1_bits.try_into().unwrap());
+                            let mut op2 = proc.regs.read(rs2_bits.try_into().unwrap());
+                            let mut out = ((op1 as i32) >> (op2 & 0x1F)) as isize;
+                            log::info!("Register One contents  : {:032b}", op1);
+                            log::info!("Register Two contents  : {:032b}", op2);
+                            log::info!("RD after SRA operation : {:032b}", out);
+                            temp_regs.write(rd_bits.try_into().unwrap(), out);
                             unsafe{PC += 0x0004};
                             temp_regs
                         }
@@ -909,10 +1192,471 @@ pub fn instruction_decoder(instr: Vec<&str>, proc: &mut Vproc, cycles: isize) ->
             &_ => todo!()
             }
         }
+        "0001111" => {      // Memory ordering instructions
+            let funct3_slice = &instr[17..20];
+            let funct3_slice_joined = funct3_slice.join("");
+            let fm_slice = &instr[0..4];
+            let fm_slice_joined = fm_slice.join("");
+            let pred_slice = &instr[4..8];
+            let pred_slice_joined = pred_slice.join("");
+            let succ_slice = &instr[8..12];
+            let succ_slice_joined = succ_slice.join("");
+
+            match funct3_slice_joined.as_str() {
+                "000" => {      // Fence (single hart, so ordering is already guaranteed)
+                    if fm_slice_joined == "1000" {
+                        log::info!("Fence Total Store Order (FENCE.TSO) instruction decoded");
+                    }
+                    else {
+                        log::info!("Fence (FENCE) instruction decoded");
+                    }
+                    log::info!("Predecessor set: {}", pred_slice_joined);
+                    log::info!("Successor set: {}", succ_slice_joined);
+                    log::info!("{}", "--------------------------------".green());
+                    unsafe{PC += 0x0004};
+                    temp_regs
+                }
+                "001" => {      // Fence instruction stream (Zifencei)
+                    log::info!("Fence Instruction (FENCE.I) instruction decoded");
+                    log::info!("{}", "--------------------------------".green());
+
+                    /* Execution step */
+                    proc.icache.invalidate();
+                    log::info!("Decoded-instruction cache invalidated");
+                    unsafe{PC += 0x0004};
+                    temp_regs
+                }
+                default => {
+                    log::error!("Instruction format error!");
+                    take_trap(proc, csr::CAUSE_ILLEGAL_INSTRUCTION, 0);
+                    temp_regs
+                }
+            }
+        }
+
+        "1110011" => {      // System instructions
+            let funct3_slice = &instr[17..20];
+            let funct3_slice_joined = funct3_slice.join("");
+            let rd_slice = &instr[20..25];
+            let rd_slice_joined = rd_slice.join("");
+            let rs1_slice = &instr[12..17];
+            let rs1_slice_joined = rs1_slice.join("");
+            let csr_slice = &instr[0..12];
+            let csr_slice_joined = csr_slice.join("");
+
+            let rd_bits = isize::from_str_radix(&rd_slice_joined, 2).unwrap();
+            let rs1_bits = isize::from_str_radix(&rs1_slice_joined, 2).unwrap();
+            let csr_bits = usize::from_str_radix(&csr_slice_joined, 2).unwrap();
+
+            match funct3_slice_joined.as_str() {
+                "000" => {
+                    match (csr_bits, rs1_bits, rd_bits) {
+                        (0x000, 0, 0) => {      // Environment call
+                            log::info!("Environment Call (ECALL) instruction decoded");
+                            log::info!("{}", "--------------------------------".green());
+
+                            /* Execution step */
+                            if proc.linux.is_some() && proc.mode == Mode::User {
+                                linux::syscall(proc, &mut temp_regs);
+                                unsafe{PC += 0x0004};
+                                return temp_regs;
+                            }
+                            if proc.newlib.is_some() && proc.mode == Mode::Machine {
+                                newlib::syscall(proc, &mut temp_regs);
+                                unsafe{PC += 0x0004};
+                                return temp_regs;
+                            }
+                            if proc.sbi && proc.mode == Mode::Supervisor {
+                                match sbi::ecall(proc, &mut temp_regs) {
+                                    Some(resume) => unsafe{PC = resume},
+                                    None => unsafe{PC += 0x0004},
+                                }
+                                return temp_regs;
+                            }
+                            take_trap(proc, csr::CAUSE_USER_ECALL + proc.mode as isize, 0);
+                            temp_regs
+                        }
+                        (0x001, 0, 0) => {      // Breakpoint
+                            log::info!("Environment Break (EBREAK) instruction decoded");
+                            log::info!("{}", "--------------------------------".green());
+
+                            /* Execution step */
+                            if proc.semihost.is_some() && semihost::is_request(proc, unsafe{PC}) {
+                                semihost::call(proc, &mut temp_regs);
+                                unsafe{PC += 0x0004};
+                                return temp_regs;
+                            }
+                            take_trap(proc, csr::CAUSE_BREAKPOINT, unsafe{PC});
+                            temp_regs
+                        }
+                        (0x302, 0, 0) => {      // Machine-mode trap return
+                            log::info!("Machine Return (MRET) instruction decoded");
+                            log::info!("{}", "--------------------------------".green());
+
+                            /* Execution step */
+                            if proc.mode != Mode::Machine {
+                                take_trap(proc, csr::CAUSE_ILLEGAL_INSTRUCTION, 0);
+                                return temp_regs;
+                            }
+                            let (mode, mepc) = proc.csrs.mret();
+                            proc.mode = mode;
+                            unsafe{PC = mepc};
+                            log::info!("Mode after MRET operation : {:?}", proc.mode);
+                            log::info!("PC after MRET operation   : {:032b}", unsafe{PC});
+                            temp_regs
+                        }
+                        (0x105, 0, 0) => {      // Wait for interrupt
+                            log::info!("Wait For Interrupt (WFI) instruction decoded");
+                            log::info!("{}", "--------------------------------".green());
+
+                            /* Execution step */
+                            let tw = proc.csrs.read(csr::MSTATUS) & csr::MSTATUS_TW != 0;
+                            if proc.mode == Mode::User || (proc.mode == Mode::Supervisor && tw) {
+                                take_trap(proc, csr::CAUSE_ILLEGAL_INSTRUCTION, 0);
+                                return temp_regs;
+                            }
+                            proc.wfi = true;
+                            unsafe{PC += 0x0004};
+                            temp_regs
+                        }
+                        (0x102, 0, 0) => {      // Supervisor-mode trap return
+                            log::info!("Supervisor Return (SRET) instruction decoded");
+                            log::info!("{}", "--------------------------------".green());
+
+                            /* Execution step */
+                            if proc.mode == Mode::User {
+                                take_trap(proc, csr::CAUSE_ILLEGAL_INSTRUCTION, 0);
+                                return temp_regs;
+                            }
+                            let (mode, sepc) = proc.csrs.sret();
+                            proc.mode = mode;
+                            unsafe{PC = sepc};
+                            log::info!("Mode after SRET operation : {:?}", proc.mode);
+                            log::info!("PC after SRET operation   : {:032b}", unsafe{PC});
+                            temp_regs
+                        }
+                        (csr_bits, rs1_bits, 0) if csr_bits >> 5 == 0b0001001 => {      // Supervisor memory-management fence
+                            let rs2_bits = csr_bits & 0x1F;
+                            log::info!("Supervisor Fence Virtual Memory (SFENCE.VMA) instruction decoded");
+                            log::info!("Register One address: x{}", rs1_bits);
+                            log::info!("Register Two address: x{}", rs2_bits);
+                            log::info!("SFENCE.VMA x{}, x{}", rs1_bits, rs2_bits);
+                            log::info!("{}", "--------------------------------".green());
+
+                            /* Execution step */
+                            let tvm = proc.csrs.read(csr::MSTATUS) & csr::MSTATUS_TVM != 0;
+                            if proc.mode == Mode::User || (proc.mode == Mode::Supervisor && tvm) {
+                                take_trap(proc, csr::CAUSE_ILLEGAL_INSTRUCTION, 0);
+                                return temp_regs;
+                            }
+                            let vaddr = if rs1_bits == 0 { None } else { Some(proc.regs.read(rs1_bits.try_into().unwrap()) as usize & 0xFFFF_FFFF) };
+                            let asid = if rs2_bits == 0 { None } else { Some(proc.regs.read(rs2_bits) as usize & 0x1FF) };
+                            proc.tlb.flush(vaddr, asid);
+                            let (hits, misses) = proc.tlb.stats();
+                            log::info!("TLB flushed (hits: {}, misses: {})", hits, misses);
+                            unsafe{PC += 0x0004};
+                            temp_regs
+                        }
+                        _ => {
+                            log::error!("Unsupported system instruction!");
+                            take_trap(proc, csr::CAUSE_ILLEGAL_INSTRUCTION, 0);
+                            temp_regs
+                        }
+                    }
+                }
+                "001" | "010" | "011" | "101" | "110" | "111" => {      // Zicsr instructions
+                    let (name, uimm) = match funct3_slice_joined.as_str() {
+                        "001" => ("CSRRW", false),
+                        "010" => ("CSRRS", false),
+                        "011" => ("CSRRC", false),
+                        "101" => ("CSRRWI", true),
+                        "110" => ("CSRRSI", true),
+                        _ => ("CSRRCI", true),
+                    };
+                    log::info!("Control and Status Register ({}) instruction decoded", name);
+                    log::info!("Destination Register address: x{}", rd_bits);
+                    if uimm {
+                        log::info!("Immediate value: {}", rs1_bits);
+                        log::info!("{} x{}, {:#05x}, {}", name, rd_bits, csr_bits, rs1_bits);
+                    }
+                    else {
+                        log::info!("Register One address: x{}", rs1_bits);
+                        log::info!("{} x{}, {:#05x}, x{}", name, rd_bits, csr_bits, rs1_bits);
+                    }
+                    log::info!("CSR address: {:#05x}", csr_bits);
+                    log::info!("{}", "--------------------------------".green());
+
+                    /* Execution step */
+                    let src = if uimm { rs1_bits } else { proc.regs.read(rs1_bits.try_into().unwrap()) };
+                    let writes = match funct3_slice_joined.as_str() {
+                        "001" | "101" => true,
+                        _ => rs1_bits != 0,
+                    };
+                    if !proc.csrs.accessible(csr_bits, proc.mode, writes) || !isa::csr_permitted(csr_bits, &proc.isa) {
+                        log::error!("Illegal CSR access to {:#05x} from {:?} mode", csr_bits, proc.mode);
+                        take_trap(proc, csr::CAUSE_ILLEGAL_INSTRUCTION, 0);
+                        return temp_regs;
+                    }
+                    let old = if csr_bits == csr::SEED { proc.csrs.read_seed() } else { proc.csrs.read(csr_bits) };
+                    if writes {
+                        let new = match funct3_slice_joined.as_str() {
+                            "001" | "101" => src,
+                            "010" | "110" => old | src,
+                            _ => old & !src,
+                        };
+                        proc.csrs.write(csr_bits, new);
+                        lockstep::csr_write(proc, csr_bits);
+                    }
+                    log::info!("CSR contents            : {:032b}", old);
+                    log::info!("Source operand          : {:032b}", src);
+                    log::info!("CSR after {} operation : {:032b}", name, proc.csrs.read(csr_bits));
+                    temp_regs.write(rd_bits.try_into().unwrap(), old);
+                    unsafe{PC += 0x0004};
+                    temp_regs
+                }
+                default => {
+                    log::error!("Instruction format error!");
+                    take_trap(proc, csr::CAUSE_ILLEGAL_INSTRUCTION, 0);
+                    temp_regs
+                }
+            }
+        }
         default => {
             log::error!("Opcode not found!");
+            let bits = isize::from_str_radix(&instr.join(""), 2).unwrap();
+            take_trap(proc, csr::CAUSE_ILLEGAL_INSTRUCTION, bits);
             temp_regs
         }
     &_ => todo!()
     }
 }
+
+// Data load through the MMU and the bus, sign- or zero-extended; None after raising a trap
+pub fn load(proc: &mut Vproc, vaddr: isize, size: usize, signed: bool) -> Option<isize> {
+    let vaddr = vaddr & 0xFFFF_FFFF;
+    if vaddr as usize % size != 0 {
+        take_trap(proc, csr::CAUSE_LOAD_ADDRESS_MISALIGNED, vaddr);
+        return None;
+    }
+    let paddr = match mmu::translate(proc, vaddr as usize, size, Access::Load) {
+        Ok(paddr) => paddr,
+        Err((cause, tval)) => {
+            take_trap(proc, cause, tval);
+            return None;
+        }
+    };
+    match proc.bus.read(paddr, size) {
+        Some(value) => {
+            let bits = 8 * size as u32;
+            if signed && bits < 64 {
+                Some((value << (64 - bits)) >> (64 - bits))
+            }
+            else {
+                Some(value)
+            }
+        }
+        None => {
+            take_trap(proc, csr::CAUSE_LOAD_ACCESS_FAULT, vaddr);
+            None
+        }
+    }
+}
+
+// Data store through the MMU and the bus; false after raising a trap
+pub fn store(proc: &mut Vproc, vaddr: isize, size: usize, value: isize) -> bool {
+    let vaddr = vaddr & 0xFFFF_FFFF;
+    if vaddr as usize % size != 0 {
+        take_trap(proc, csr::CAUSE_STORE_ADDRESS_MISALIGNED, vaddr);
+        return false;
+    }
+    let paddr = match mmu::translate(proc, vaddr as usize, size, Access::Store) {
+        Ok(paddr) => paddr,
+        Err((cause, tval)) => {
+            take_trap(proc, cause, tval);
+            return false;
+        }
+    };
+    if !proc.bus.write(paddr, size, value) {
+        take_trap(proc, csr::CAUSE_STORE_ACCESS_FAULT, vaddr);
+        return false;
+    }
+    lockstep::store(proc, vaddr, size, value);
+    true
+}
+
+// Clocks until a device raises an interrupt, if any device will
+pub fn next_wakeup(proc: &Vproc) -> Option<u64> {
+    proc.bus.next_event()
+}
+
+// Drive mip from the CLINT and the PLIC, and the time CSR from mtime
+pub fn sync_irqs(proc: &mut Vproc) {
+    let lines = proc.bus.irq_lines();
+    let mut plic = proc.plic.borrow_mut();
+    plic.update(&lines);
+    proc.csrs.set_irq(csr::IRQ_MEI, plic.eip(0));      // Hart 0 M-mode context
+    proc.csrs.set_irq(csr::IRQ_SEI, plic.eip(1));      // Hart 0 S-mode context
+    let clint = proc.clint.borrow();
+    if proc.sbi {
+        // No M-mode firmware: the SBI timer raises STIP instead
+        proc.csrs.set_irq(csr::IRQ_STI, clint.mtip(0));
+        proc.csrs.set_irq(csr::IRQ_MTI, false);
+    }
+    else {
+        proc.csrs.set_irq(csr::IRQ_MTI, clint.mtip(0));
+    }
+    proc.csrs.set_irq(csr::IRQ_MSI, clint.msip(0));
+    proc.csrs.set_time(clint.mtime());
+}
+
+// Raise an exception or interrupt: the CSR file records the trap and the PC moves to mtvec (or stvec)
+pub fn take_trap(proc: &mut Vproc, cause: isize, tval: isize) {
+    // In user-mode emulation there is no trap handler to go to
+    if proc.linux.is_some() && cause & csr::INTERRUPT_BIT == 0 {
+        linux::fatal_trap(cause, tval, unsafe{PC});
+    }
+    if cause & csr::INTERRUPT_BIT != 0 {
+        log::warn!("Interrupt taken: cause {}, epc {:#010x}", cause & !csr::INTERRUPT_BIT, unsafe{PC});
+    }
+    else {
+        log::warn!("Trap taken: cause {}, tval {:#010x}, epc {:#010x}", cause, tval, unsafe{PC});
+    }
+    if cause & csr::INTERRUPT_BIT == 0 {
+        lockstep::trap(proc, cause);
+    }
+    let (mode, new_pc) = proc.csrs.trap(proc.mode, unsafe{PC}, cause, tval);
+    proc.mode = mode;
+    unsafe{PC = new_pc};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clint, run_hart, test_hart, CLINT_BASE};
+
+    const WFI: isize = 0x1050_0073;
+    const LOOP: isize = 0x0000_006F;       // jal x0, 0
+
+    // WFI at 0x100 followed by a spin loop; the trap handler spins at 0x200
+    fn waiting_hart() -> Vproc {
+        let mut proc = test_hart();
+        let clint = proc.clint.clone();
+        proc.bus.attach(CLINT_BASE, clint::CLINT_SIZE, Box::new(clint)).unwrap();
+        proc.bus.write(0x100, 4, WFI);
+        proc.bus.write(0x104, 4, LOOP);
+        proc.bus.write(0x200, 4, LOOP);
+        proc.csrs.write(csr::MTVEC, 0x200);
+        proc.clint.borrow_mut().set_mtimecmp(0, 50);
+        proc
+    }
+
+    #[test]
+    fn wfi_wakes_on_an_enabled_interrupt_even_when_globally_masked() {
+        let mut proc = waiting_hart();
+        proc.csrs.write(csr::MIE, csr::IRQ_MTI);
+        let (exhausted, pc) = run_hart(&mut proc, 0x100, 100);
+        assert!(exhausted);
+        assert!(!proc.wfi);
+        // mstatus.MIE is clear: the hart resumes after the WFI without trapping
+        assert_eq!(pc, 0x104);
+        assert_eq!(proc.csrs.read(csr::MCAUSE), 0);
+        assert!(proc.csrs.read(csr::MIP) & csr::IRQ_MTI != 0);
+
+        // With MIE set the same wake-up traps
+        let mut proc = waiting_hart();
+        proc.csrs.write(csr::MIE, csr::IRQ_MTI);
+        proc.csrs.write(csr::MSTATUS, csr::MSTATUS_MIE);
+        let (_, pc) = run_hart(&mut proc, 0x100, 100);
+        assert_eq!(pc, 0x200);
+        assert_eq!(proc.csrs.read(csr::MCAUSE), csr::INTERRUPT_BIT | 7);
+        assert_eq!(proc.csrs.read(csr::MEPC), 0x104);
+    }
+
+    #[test]
+    fn wfi_ignores_interrupts_disabled_in_mie() {
+        let mut proc = waiting_hart();
+        proc.csrs.write(csr::MSTATUS, csr::MSTATUS_MIE);
+        let (exhausted, _) = run_hart(&mut proc, 0x100, 100);
+        assert!(exhausted);
+        assert!(proc.wfi);
+        assert!(proc.csrs.read(csr::MIP) & csr::IRQ_MTI != 0);
+        assert_eq!(proc.csrs.read(csr::MCAUSE), 0);
+    }
+
+    const CSRRW_SEED: isize = 0x0150_12F3;     // csrrw x5, seed, x0
+    const CSRRS_SEED: isize = 0x0150_2373;     // csrrs x6, seed, x0
+
+    // One seed access at 0x100 followed by a spin loop; the trap handler spins at 0x200.
+    // PMP opens all of memory so S-mode can fetch.
+    fn seed_hart(insn: isize, mode: Mode) -> Vproc {
+        let mut proc = test_hart();
+        proc.csrs.write(csr::PMPADDR0, -1);
+        proc.csrs.write(csr::PMPCFG0, 0x1F);
+        proc.bus.write(0x100, 4, insn);
+        proc.bus.write(0x104, 4, LOOP);
+        proc.bus.write(0x200, 4, LOOP);
+        proc.csrs.write(csr::MTVEC, 0x200);
+        proc.mode = mode;
+        proc
+    }
+
+    #[test]
+    fn seed_reads_report_es16() {
+        let mut proc = seed_hart(CSRRW_SEED, Mode::Machine);
+        for _ in 0..16 {
+            let (_, pc) = run_hart(&mut proc, 0x100, 1);
+            assert_eq!(pc, 0x104);
+            // OPST is always ES16 (never BIST, WAIT or DEAD) and bits [29:16] stay clear
+            let seed = proc.regs.read(5) as u32;
+            assert_eq!(seed >> 30, 0b10);
+            assert_eq!(seed & 0x3FFF_0000, 0);
+        }
+
+        // S-mode needs mseccfg.SSEED
+        let mut proc = seed_hart(CSRRW_SEED, Mode::Supervisor);
+        proc.csrs.write(csr::MSECCFG, csr::MSECCFG_SSEED);
+        let (_, pc) = run_hart(&mut proc, 0x100, 1);
+        assert_eq!(pc, 0x104);
+        assert_eq!(proc.regs.read(5) as u32 >> 30, 0b10);
+    }
+
+    #[test]
+    fn seed_traps_without_a_write() {
+        // A read-only access (csrrs with x0) is illegal even in M-mode
+        let mut proc = seed_hart(CSRRS_SEED, Mode::Machine);
+        let (_, pc) = run_hart(&mut proc, 0x100, 1);
+        assert_eq!(pc, 0x200);
+        assert_eq!(proc.csrs.read(csr::MCAUSE), csr::CAUSE_ILLEGAL_INSTRUCTION);
+        assert_eq!(proc.regs.read(6), 0);
+
+        // So is a read-write access from S-mode while mseccfg.SSEED is clear
+        let mut proc = seed_hart(CSRRW_SEED, Mode::Supervisor);
+        let (_, pc) = run_hart(&mut proc, 0x100, 1);
+        assert_eq!(pc, 0x200);
+        assert_eq!(proc.csrs.read(csr::MCAUSE), csr::CAUSE_ILLEGAL_INSTRUCTION);
+        assert_eq!(proc.mode, Mode::Machine);
+    }
+
+    #[test]
+    fn stores_to_cached_code_wait_for_fence_i() {
+        const ADDI_1: isize = 0x0012_8293;      // addi x5, x5, 1
+        const ADDI_16: isize = 0x0102_8293;     // addi x5, x5, 16
+        let mut proc = test_hart();
+        proc.bus.write(0xFC, 4, 0x0000_100F);           // fence.i
+        proc.bus.write(0x100, 4, ADDI_1);
+        proc.bus.write(0x104, 4, 0x1070_2023);          // sw x7, 0x100(x0)
+        proc.bus.write(0x108, 4, 0xFF9F_F06F);          // jal x0, -8
+        proc.regs.write(7, ADDI_16);
+
+        // The store patches memory, but the second pass runs the cached addi
+        let (_, pc) = run_hart(&mut proc, 0x100, 4);
+        assert_eq!(pc, 0x104);
+        assert_eq!(proc.bus.read(0x100, 4), Some(ADDI_16));
+        assert_eq!(proc.regs.read(5), 2);
+
+        // FENCE.I drops the stale line and the patched addi runs
+        let (_, pc) = run_hart(&mut proc, 0xFC, 2);
+        assert_eq!(pc, 0x104);
+        assert_eq!(proc.regs.read(5), 18);
+    }
+}
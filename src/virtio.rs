@@ -0,0 +1,649 @@
+/* RISCulator - RISC-V Emulator */
+/*  virtio-mmio block device    */
+
+// Libraries here
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use colored::*;
+use crate::bus::{size_mask, Bus, Device};
+use crate::fdt::Fdt;
+
+// virtio-mmio (version 2) register offsets
+const MAGIC_VALUE: usize = 0x000;
+const VERSION: usize = 0x004;
+const DEVICE_ID: usize = 0x008;
+const VENDOR_ID: usize = 0x00C;
+const DEVICE_FEATURES: usize = 0x010;
+const DEVICE_FEATURES_SEL: usize = 0x014;
+const DRIVER_FEATURES: usize = 0x020;
+const DRIVER_FEATURES_SEL: usize = 0x024;
+const QUEUE_SEL: usize = 0x030;
+const QUEUE_NUM_MAX: usize = 0x034;
+const QUEUE_NUM: usize = 0x038;
+const QUEUE_READY: usize = 0x044;
+const QUEUE_NOTIFY: usize = 0x050;
+const INTERRUPT_STATUS: usize = 0x060;
+const INTERRUPT_ACK: usize = 0x064;
+const STATUS: usize = 0x070;
+const QUEUE_DESC_LOW: usize = 0x080;
+const QUEUE_DESC_HIGH: usize = 0x084;
+const QUEUE_DRIVER_LOW: usize = 0x090;
+const QUEUE_DRIVER_HIGH: usize = 0x094;
+const QUEUE_DEVICE_LOW: usize = 0x0A0;
+const QUEUE_DEVICE_HIGH: usize = 0x0A4;
+const CONFIG_GENERATION: usize = 0x0FC;
+const CONFIG: usize = 0x100;
+pub const VIRTIO_SIZE: usize = 0x1000;
+
+const MAGIC: u32 = 0x7472_6976;         // "virt"
+const VENDOR: u32 = 0x4353_4952;        // "RISC"
+const BLOCK_DEVICE: u32 = 2;
+const QUEUE_SIZE: u32 = 128;
+
+// Feature bits
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+// Descriptor flags
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+// Request types and status
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+const SECTOR: u64 = 512;
+const DEVICE_STATUS_FAILED: u32 = 128;
+
+// Disk backing: the image file plus an optional in-memory copy-on-write overlay
+struct Disk {
+    file: File,
+    sectors: u64,
+    read_only: bool,
+    overlay: Option<HashMap<u64, Vec<u8>>>,
+}
+
+// Disk struct impl
+impl Disk {
+    // Read one sector, preferring the overlay
+    fn read_sector(&mut self, sector: u64) -> std::io::Result<Vec<u8>> {
+        if let Some(data) = self.overlay.as_ref().and_then(|overlay| overlay.get(&sector)) {
+            return Ok(data.clone());
+        }
+        let mut data = vec![0u8; SECTOR as usize];
+        self.file.seek(SeekFrom::Start(sector * SECTOR))?;
+        self.file.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    // Write one sector, into the overlay if there is one
+    fn write_sector(&mut self, sector: u64, data: Vec<u8>) -> std::io::Result<()> {
+        match self.overlay.as_mut() {
+            Some(overlay) => {
+                overlay.insert(sector, data);
+                Ok(())
+            }
+            None => {
+                self.file.seek(SeekFrom::Start(sector * SECTOR))?;
+                self.file.write_all(&data)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.overlay.is_some() { Ok(()) } else { self.file.sync_data() }
+    }
+}
+
+// Split virtqueue state
+#[derive(Default, Clone, Copy)]
+struct Queue {
+    num: u32,
+    ready: bool,
+    desc: u64,
+    driver: u64,            // Available ring
+    device: u64,            // Used ring
+    last_avail: u16,
+}
+
+// virtio block struct
+pub struct VirtioBlk {
+    disk: Disk,
+    id: String,
+    status: u32,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    queue_sel: u32,
+    queue: Queue,           // Block devices have a single request queue
+    notified: bool,
+    interrupt_status: u32,
+    requests: u64,
+}
+
+// virtio block struct impl
+impl VirtioBlk {
+    // Open a disk image; with `snapshot` writes stay in memory and the image is never modified
+    pub fn open(path: &str, snapshot: bool) -> Result<Self, String> {
+        let (file, read_only) = match OpenOptions::new().read(true).write(!snapshot).open(path) {
+            Ok(file) => (file, false),
+            Err(_) => match File::open(path) {
+                Ok(file) => (file, true),
+                Err(err) => return Err(format!("{}: {}", path, err)),
+            },
+        };
+        let len = match file.metadata() {
+            Ok(meta) => meta.len(),
+            Err(err) => return Err(format!("{}: {}", path, err)),
+        };
+        if len % SECTOR != 0 {
+            log::warn!("virtio-blk: {} is not a multiple of {} bytes, the tail is ignored", path, SECTOR);
+        }
+        let read_only = read_only && !snapshot;
+        log::info!("virtio-blk: {} ({} sectors{}{})", path, len / SECTOR,
+            if snapshot { ", snapshot" } else { "" }, if read_only { ", read-only" } else { "" });
+        Ok(Self {
+            disk: Disk {
+                file,
+                sectors: len / SECTOR,
+                read_only,
+                overlay: if snapshot { Some(HashMap::new()) } else { None },
+            },
+            id: path.rsplit('/').next().unwrap_or(path).chars().take(20).collect(),
+            status: 0,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            queue_sel: 0,
+            queue: Queue::default(),
+            notified: false,
+            interrupt_status: 0,
+            requests: 0,
+        })
+    }
+
+    fn device_features(&self) -> u64 {
+        let ro = if self.disk.read_only { VIRTIO_BLK_F_RO } else { 0 };
+        VIRTIO_F_VERSION_1 | VIRTIO_BLK_F_FLUSH | VIRTIO_BLK_F_BLK_SIZE | ro
+    }
+
+    // Device-specific configuration space: capacity (u64) and blk_size (u32 at 20)
+    fn config(&self, offset: usize) -> u8 {
+        let capacity = self.disk.sectors.to_le_bytes();
+        let blk_size = (SECTOR as u32).to_le_bytes();
+        match offset {
+            0..=7 => capacity[offset],
+            20..=23 => blk_size[offset - 20],
+            default => 0,
+        }
+    }
+
+    // Serve every request the driver has made available
+    fn process(&mut self, bus: &mut Bus) -> Result<(), String> {
+        let q = self.queue;
+        let avail_idx = read16(bus, q.driver + 2)?;
+        while self.queue.last_avail != avail_idx {
+            let slot = self.queue.last_avail as u64 % q.num as u64;
+            let head = read16(bus, q.driver + 4 + 2 * slot)?;
+            let written = self.request(bus, head)?;
+
+            // Publish the used element, then the index
+            let used_idx = read16(bus, q.device + 2)?;
+            let elem = q.device + 4 + 8 * (used_idx as u64 % q.num as u64);
+            write32(bus, elem, head as u32)?;
+            write32(bus, elem + 4, written)?;
+            write16(bus, q.device + 2, used_idx.wrapping_add(1))?;
+            self.queue.last_avail = self.queue.last_avail.wrapping_add(1);
+            self.requests += 1;
+        }
+        // Interrupt unless the driver suppressed it (VIRTQ_AVAIL_F_NO_INTERRUPT)
+        if read16(bus, q.driver)? & 1 == 0 {
+            self.interrupt_status |= 1;
+        }
+        Ok(())
+    }
+
+    // Serve one descriptor chain, returning the bytes written into guest memory
+    fn request(&mut self, bus: &mut Bus, head: u16) -> Result<u32, String> {
+        // Gather the chain: header, data buffers, status byte
+        let mut chain: Vec<(u64, u32, u16)> = vec![];
+        let mut index = head;
+        loop {
+            if chain.len() > self.queue.num as usize {
+                return Err("descriptor chain loops".to_string());
+            }
+            let desc = self.queue.desc + 16 * index as u64;
+            let addr = read32(bus, desc)? as u64 | (read32(bus, desc + 4)? as u64) << 32;
+            let len = read32(bus, desc + 8)?;
+            let flags = read16(bus, desc + 12)?;
+            chain.push((addr, len, flags));
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            index = read16(bus, desc + 14)?;
+        }
+        if chain.len() < 2 || chain[0].1 < 16 {
+            return Err("malformed block request".to_string());
+        }
+        let header = chain[0].0;
+        let kind = read32(bus, header)?;
+        let sector = read32(bus, header + 8)? as u64 | (read32(bus, header + 12)? as u64) << 32;
+        let (status_addr, _, _) = chain[chain.len() - 1];
+        let data = &chain[1..chain.len() - 1];
+        let mut written = 1;        // Status byte
+
+        let status = match kind {
+            VIRTIO_BLK_T_IN => {
+                let mut status = VIRTIO_BLK_S_OK;
+                let mut sector = sector;
+                for &(addr, len, flags) in data {
+                    if flags & VIRTQ_DESC_F_WRITE == 0 {
+                        status = VIRTIO_BLK_S_IOERR;
+                        break;
+                    }
+                    let mut done = 0;
+                    while done < len as u64 {
+                        if sector >= self.disk.sectors {
+                            status = VIRTIO_BLK_S_IOERR;
+                            break;
+                        }
+                        let bytes = self.disk.read_sector(sector).map_err(|err| err.to_string())?;
+                        let chunk = (len as u64 - done).min(SECTOR);
+                        write_bytes(bus, addr + done, &bytes[..chunk as usize])?;
+                        written += chunk as u32;
+                        done += chunk;
+                        sector += 1;
+                    }
+                }
+                status
+            }
+            VIRTIO_BLK_T_OUT if self.disk.read_only => VIRTIO_BLK_S_IOERR,
+            VIRTIO_BLK_T_OUT => {
+                let mut status = VIRTIO_BLK_S_OK;
+                let mut sector = sector;
+                for &(addr, len, flags) in data {
+                    let mut done = 0;
+                    while done < len as u64 {
+                        if sector >= self.disk.sectors {
+                            status = VIRTIO_BLK_S_IOERR;
+                            break;
+                        }
+                        let chunk = (len as u64 - done).min(SECTOR);
+                        let mut bytes = if chunk < SECTOR {
+                            self.disk.read_sector(sector).map_err(|err| err.to_string())?
+                        } else {
+                            vec![0u8; SECTOR as usize]
+                        };
+                        let new = read_bytes(bus, addr + done, chunk as usize)?;
+                        bytes[..chunk as usize].copy_from_slice(&new);
+                        self.disk.write_sector(sector, bytes).map_err(|err| err.to_string())?;
+                        done += chunk;
+                        sector += 1;
+                    }
+                }
+                status
+            }
+            VIRTIO_BLK_T_FLUSH => match self.disk.flush() {
+                Ok(()) => VIRTIO_BLK_S_OK,
+                Err(_) => VIRTIO_BLK_S_IOERR,
+            },
+            VIRTIO_BLK_T_GET_ID => {
+                // Up to 20 bytes, NUL-padded
+                let mut id = self.id.clone().into_bytes();
+                id.resize(20, 0);
+                match data.first() {
+                    Some(&(addr, len, _)) => {
+                        let n = (len as usize).min(20);
+                        write_bytes(bus, addr, &id[..n])?;
+                        written += n as u32;
+                        VIRTIO_BLK_S_OK
+                    }
+                    None => VIRTIO_BLK_S_IOERR,
+                }
+            }
+            default => VIRTIO_BLK_S_UNSUPP,
+        };
+        if status != VIRTIO_BLK_S_OK {
+            log::warn!("virtio-blk: request type {} at sector {} failed with status {}", kind, sector, status);
+        }
+        write_bytes(bus, status_addr, &[status])?;
+        Ok(written)
+    }
+}
+
+// Guest memory helpers for the device's bus-master accesses
+fn read16(bus: &mut Bus, addr: u64) -> Result<u16, String> {
+    bus.read(addr as usize, 2).map(|v| v as u16).ok_or(format!("DMA read fault at {:#010x}", addr))
+}
+
+fn read32(bus: &mut Bus, addr: u64) -> Result<u32, String> {
+    bus.read(addr as usize, 4).map(|v| v as u32).ok_or(format!("DMA read fault at {:#010x}", addr))
+}
+
+fn write16(bus: &mut Bus, addr: u64, value: u16) -> Result<(), String> {
+    if bus.write(addr as usize, 2, value as isize) { Ok(()) } else { Err(format!("DMA write fault at {:#010x}", addr)) }
+}
+
+fn write32(bus: &mut Bus, addr: u64, value: u32) -> Result<(), String> {
+    if bus.write(addr as usize, 4, value as isize) { Ok(()) } else { Err(format!("DMA write fault at {:#010x}", addr)) }
+}
+
+fn read_bytes(bus: &mut Bus, addr: u64, len: usize) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::with_capacity(len);
+    for i in 0..len as u64 {
+        match bus.read((addr + i) as usize, 1) {
+            Some(byte) => bytes.push(byte as u8),
+            None => return Err(format!("DMA read fault at {:#010x}", addr + i)),
+        }
+    }
+    Ok(bytes)
+}
+
+fn write_bytes(bus: &mut Bus, addr: u64, bytes: &[u8]) -> Result<(), String> {
+    for (i, byte) in bytes.iter().enumerate() {
+        if !bus.write(addr as usize + i, 1, *byte as isize) {
+            return Err(format!("DMA write fault at {:#010x}", addr as usize + i));
+        }
+    }
+    Ok(())
+}
+
+// virtio-mmio device: 32-bit registers, byte-wide config space
+impl Device for VirtioBlk {
+    fn name(&self) -> &str {
+        "virtio-blk"
+    }
+
+    fn read(&mut self, offset: usize, size: usize) -> Option<u64> {
+        if offset >= CONFIG {
+            let mut value = 0u64;
+            for i in 0..size {
+                value |= (self.config(offset - CONFIG + i) as u64) << (8 * i);
+            }
+            return Some(value);
+        }
+        if size != 4 {
+            return None;
+        }
+        let value: u32 = match offset {
+            MAGIC_VALUE => MAGIC,
+            VERSION => 2,
+            DEVICE_ID => BLOCK_DEVICE,
+            VENDOR_ID => VENDOR,
+            DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.device_features() as u32,
+                1 => (self.device_features() >> 32) as u32,
+                default => 0,
+            },
+            QUEUE_NUM_MAX => if self.queue_sel == 0 { QUEUE_SIZE } else { 0 },
+            QUEUE_READY => (self.queue_sel == 0 && self.queue.ready) as u32,
+            INTERRUPT_STATUS => self.interrupt_status,
+            STATUS => self.status,
+            CONFIG_GENERATION => 0,
+            default => 0,
+        };
+        Some(value as u64 & size_mask(size))
+    }
+
+    fn write(&mut self, offset: usize, size: usize, value: u64) -> bool {
+        if offset >= CONFIG {
+            return true;        // Block config is read-only
+        }
+        if size != 4 {
+            return false;
+        }
+        let value = value as u32;
+        let selected = self.queue_sel == 0;
+        match offset {
+            DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            DRIVER_FEATURES => match self.driver_features_sel {
+                0 => self.driver_features = (self.driver_features & !0xFFFF_FFFF) | value as u64,
+                1 => self.driver_features = (self.driver_features & 0xFFFF_FFFF) | (value as u64) << 32,
+                default => {}
+            },
+            QUEUE_SEL => self.queue_sel = value,
+            QUEUE_NUM if selected => self.queue.num = value.min(QUEUE_SIZE),
+            QUEUE_READY if selected => self.queue.ready = value & 1 != 0,
+            QUEUE_DESC_LOW if selected => self.queue.desc = (self.queue.desc & !0xFFFF_FFFF) | value as u64,
+            QUEUE_DESC_HIGH if selected => self.queue.desc = (self.queue.desc & 0xFFFF_FFFF) | (value as u64) << 32,
+            QUEUE_DRIVER_LOW if selected => self.queue.driver = (self.queue.driver & !0xFFFF_FFFF) | value as u64,
+            QUEUE_DRIVER_HIGH if selected => self.queue.driver = (self.queue.driver & 0xFFFF_FFFF) | (value as u64) << 32,
+            QUEUE_DEVICE_LOW if selected => self.queue.device = (self.queue.device & !0xFFFF_FFFF) | value as u64,
+            QUEUE_DEVICE_HIGH if selected => self.queue.device = (self.queue.device & 0xFFFF_FFFF) | (value as u64) << 32,
+            QUEUE_NOTIFY => self.notified = value == 0,
+            INTERRUPT_ACK => self.interrupt_status &= !value,
+            STATUS => {
+                if value == 0 {
+                    self.reset();       // Writing zero resets the device
+                }
+                else {
+                    self.status = value;
+                }
+            }
+            default => {}
+        }
+        true
+    }
+
+    fn dma_pending(&self) -> bool {
+        self.notified
+    }
+
+    fn dma(&mut self, bus: &mut Bus) {
+        self.notified = false;
+        if !self.queue.ready || self.queue.num == 0 {
+            return;
+        }
+        if let Err(err) = self.process(bus) {
+            log::error!("virtio-blk: {}, marking the device as failed", err);
+            self.status |= DEVICE_STATUS_FAILED;
+        }
+    }
+
+    fn interrupt(&self) -> bool {
+        self.interrupt_status != 0
+    }
+
+    fn reset(&mut self) {
+        self.status = 0;
+        self.device_features_sel = 0;
+        self.driver_features_sel = 0;
+        self.driver_features = 0;
+        self.queue_sel = 0;
+        self.queue = Queue::default();
+        self.notified = false;
+        self.interrupt_status = 0;
+    }
+
+    fn describe(&self, fdt: &mut Fdt, base: usize, size: usize, irq: Option<usize>) {
+        fdt.soc_device("virtio_mmio", &["virtio,mmio"], base, size, irq);
+    }
+
+    fn print_dirty(&mut self) {
+        println!("{}", "--------------------------------".green());
+        println!("{}", "virtio-blk".green());
+        println!("{}", "--------------------------------".green());
+        println!("Status: {:#04x} Requests served: {} Overlay sectors: {}", self.status, self.requests,
+            self.disk.overlay.as_ref().map_or(0, |overlay| overlay.len()));
+        println!("{}", "--------------------------------".green());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{INI, RAM, RAM_BASE, RAM_SIZE, VIRTIO_BASE, VIRTIO_IRQ};
+
+    // Guest memory layout of the test driver
+    const DESC: usize = 0x1000;
+    const AVAIL: usize = 0x2000;
+    const USED: usize = 0x3000;
+    const HEADER: usize = 0x4000;
+    const DATA: usize = 0x5000;
+    const STATUS_BYTE: usize = 0x6000;
+
+    // A four-sector image whose sector n is filled with n + 1
+    fn image(tag: &str) -> String {
+        let path = std::env::temp_dir().join(format!("risculator-virtio-{}-{}.img", tag, std::process::id()));
+        let data: Vec<u8> = (0..4u8).flat_map(|n| vec![n + 1; SECTOR as usize]).collect();
+        std::fs::write(&path, data).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn reg(bus: &mut Bus, offset: usize) -> u32 {
+        bus.read(VIRTIO_BASE + offset, 4).unwrap() as u32
+    }
+
+    fn set_reg(bus: &mut Bus, offset: usize, value: u32) {
+        assert!(bus.write(VIRTIO_BASE + offset, 4, value as isize));
+    }
+
+    // RAM and the device on a bus, with the driver handshake done and the queue set up
+    fn machine(blk: VirtioBlk) -> Bus {
+        let mut bus = Bus::new();
+        bus.attach(RAM_BASE, RAM_SIZE * INI as usize, Box::new(RAM::new())).unwrap();
+        bus.attach_irq(VIRTIO_BASE, VIRTIO_SIZE, Some(VIRTIO_IRQ), Box::new(blk)).unwrap();
+        set_reg(&mut bus, STATUS, 1 | 2);                   // ACKNOWLEDGE | DRIVER
+        set_reg(&mut bus, DRIVER_FEATURES_SEL, 1);
+        set_reg(&mut bus, DRIVER_FEATURES, 1);              // VIRTIO_F_VERSION_1
+        set_reg(&mut bus, STATUS, 1 | 2 | 8);               // FEATURES_OK
+        set_reg(&mut bus, QUEUE_SEL, 0);
+        set_reg(&mut bus, QUEUE_NUM, 8);
+        set_reg(&mut bus, QUEUE_DESC_LOW, DESC as u32);
+        set_reg(&mut bus, QUEUE_DRIVER_LOW, AVAIL as u32);
+        set_reg(&mut bus, QUEUE_DEVICE_LOW, USED as u32);
+        set_reg(&mut bus, QUEUE_READY, 1);
+        set_reg(&mut bus, STATUS, 1 | 2 | 8 | 4);           // DRIVER_OK
+        bus
+    }
+
+    fn put(bus: &mut Bus, addr: usize, size: usize, value: u64) {
+        assert!(bus.write(addr, size, value as isize));
+    }
+
+    // Queue a header / data / status chain as request number `n` and notify the device
+    fn submit(bus: &mut Bus, n: u16, kind: u32, sector: u64, len: u32) {
+        put(bus, HEADER, 4, kind as u64);
+        put(bus, HEADER + 8, 4, sector);
+        put(bus, HEADER + 12, 4, sector >> 32);
+        let data_flags = VIRTQ_DESC_F_NEXT | if kind == VIRTIO_BLK_T_IN { VIRTQ_DESC_F_WRITE } else { 0 };
+        for (i, (addr, len, flags)) in [
+            (HEADER, 16, VIRTQ_DESC_F_NEXT),
+            (DATA, len, data_flags),
+            (STATUS_BYTE, 1, VIRTQ_DESC_F_WRITE),
+        ].into_iter().enumerate() {
+            let desc = DESC + 16 * i;
+            put(bus, desc, 4, addr as u64);
+            put(bus, desc + 4, 4, 0);
+            put(bus, desc + 8, 4, len as u64);
+            put(bus, desc + 12, 2, flags as u64);
+            put(bus, desc + 14, 2, i as u64 + 1);
+        }
+        put(bus, STATUS_BYTE, 1, 0xFF);
+        put(bus, AVAIL + 4 + 2 * (n as usize % 8), 2, 0);
+        put(bus, AVAIL + 2, 2, n as u64 + 1);
+        set_reg(bus, QUEUE_NOTIFY, 0);
+        bus.tick();
+    }
+
+    #[test]
+    fn identifies_and_negotiates_features() {
+        let path = image("probe");
+        let mut blk = VirtioBlk::open(&path, false).unwrap();
+        assert_eq!(blk.read(MAGIC_VALUE, 4), Some(0x7472_6976));
+        assert_eq!(blk.read(VERSION, 4), Some(2));
+        assert_eq!(blk.read(DEVICE_ID, 4), Some(2));
+        assert_eq!(blk.read(VENDOR_ID, 4), Some(VENDOR as u64));
+        assert_eq!(blk.read(DEVICE_FEATURES, 4), Some(VIRTIO_BLK_F_FLUSH | VIRTIO_BLK_F_BLK_SIZE));
+        assert!(blk.write(DEVICE_FEATURES_SEL, 4, 1));
+        assert_eq!(blk.read(DEVICE_FEATURES, 4), Some(1));
+        // Capacity in sectors and the block size from the config space
+        assert_eq!(blk.read(CONFIG, 4), Some(4));
+        assert_eq!(blk.read(CONFIG + 20, 4), Some(SECTOR));
+        assert_eq!(blk.read(QUEUE_NUM_MAX, 4), Some(QUEUE_SIZE as u64));
+        assert!(blk.write(QUEUE_SEL, 4, 1));
+        assert_eq!(blk.read(QUEUE_NUM_MAX, 4), Some(0));
+        // Registers are 32 bits wide; writing 0 to status resets the device
+        assert_eq!(blk.read(MAGIC_VALUE, 2), None);
+        assert!(blk.write(STATUS, 4, 0xF));
+        assert!(blk.write(STATUS, 4, 0));
+        assert_eq!(blk.read(STATUS, 4), Some(0));
+        assert_eq!(blk.read(QUEUE_NUM_MAX, 4), Some(QUEUE_SIZE as u64));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn serves_reads_and_writes_from_the_image() {
+        let path = image("rw");
+        let mut bus = machine(VirtioBlk::open(&path, false).unwrap());
+
+        // Read sectors 1 and 2 into guest memory
+        submit(&mut bus, 0, VIRTIO_BLK_T_IN, 1, 2 * SECTOR as u32);
+        assert_eq!(bus.read(STATUS_BYTE, 1), Some(VIRTIO_BLK_S_OK as isize));
+        assert_eq!(bus.read(DATA, 1), Some(2));
+        assert_eq!(bus.read(DATA + SECTOR as usize, 1), Some(3));
+        assert_eq!(bus.read(USED + 2, 2), Some(1));
+        assert_eq!(bus.read(USED + 8, 4), Some(2 * SECTOR as isize + 1));
+        assert_eq!(bus.irq_lines(), [VIRTIO_IRQ]);
+        set_reg(&mut bus, INTERRUPT_ACK, 1);
+        assert!(bus.irq_lines().is_empty());
+
+        // Write sector 3 from guest memory
+        for i in 0..SECTOR as usize {
+            put(&mut bus, DATA + i, 1, 0xEE);
+        }
+        submit(&mut bus, 1, VIRTIO_BLK_T_OUT, 3, SECTOR as u32);
+        assert_eq!(bus.read(STATUS_BYTE, 1), Some(VIRTIO_BLK_S_OK as isize));
+        assert_eq!(bus.read(USED + 2, 2), Some(2));
+        let disk = std::fs::read(&path).unwrap();
+        assert!(disk[3 * SECTOR as usize..].iter().all(|byte| *byte == 0xEE));
+        assert_eq!(disk[2 * SECTOR as usize], 3);
+
+        // Past the end of the disk
+        submit(&mut bus, 2, VIRTIO_BLK_T_IN, 4, SECTOR as u32);
+        assert_eq!(bus.read(STATUS_BYTE, 1), Some(VIRTIO_BLK_S_IOERR as isize));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn read_only_images_refuse_writes() {
+        let path = image("ro");
+        let mut blk = VirtioBlk::open(&path, false).unwrap();
+        // As the fallback open would (root can open a 0444 file for writing anyway)
+        blk.disk.read_only = true;
+        assert_eq!(blk.read(DEVICE_FEATURES, 4), Some(VIRTIO_BLK_F_FLUSH | VIRTIO_BLK_F_BLK_SIZE | VIRTIO_BLK_F_RO));
+        let mut bus = machine(blk);
+        submit(&mut bus, 0, VIRTIO_BLK_T_OUT, 0, SECTOR as u32);
+        assert_eq!(bus.read(STATUS_BYTE, 1), Some(VIRTIO_BLK_S_IOERR as isize));
+        assert_eq!(std::fs::read(&path).unwrap()[0], 1);
+        // Reads still work
+        submit(&mut bus, 1, VIRTIO_BLK_T_IN, 0, SECTOR as u32);
+        assert_eq!(bus.read(STATUS_BYTE, 1), Some(VIRTIO_BLK_S_OK as isize));
+        assert_eq!(bus.read(DATA, 1), Some(1));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn snapshot_writes_stay_in_memory() {
+        let path = image("snapshot");
+        let mut bus = machine(VirtioBlk::open(&path, true).unwrap());
+        put(&mut bus, DATA, 4, 0xDDCC_BBAA);
+        submit(&mut bus, 0, VIRTIO_BLK_T_OUT, 0, 4);
+        assert_eq!(bus.read(STATUS_BYTE, 1), Some(VIRTIO_BLK_S_OK as isize));
+        put(&mut bus, DATA, 4, 0);
+        submit(&mut bus, 1, VIRTIO_BLK_T_IN, 0, 8);
+        assert_eq!(bus.read(DATA, 4), Some(0xDDCC_BBAA));
+        assert_eq!(bus.read(DATA + 4, 4), Some(0x0101_0101));
+        assert_eq!(std::fs::read(&path).unwrap()[0], 1);
+        std::fs::remove_file(path).unwrap();
+    }
+}